@@ -0,0 +1,270 @@
+//! Album mode: one shared gain per group of files.
+//!
+//! Per-track gain flattens intentional level differences between the songs of
+//! an album or the parts of a continuous mix. In album mode every member of a
+//! group receives the same gain, bounded by the group's loudest True Peak (and,
+//! with `--target-lufs`, by the group's combined loudness).
+
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::analyzer::{AudioAnalysis, GainLimit, GainTarget, GAIN_STEP};
+use crate::decoder;
+use crate::scanner::AudioFormat;
+
+/// How files are assigned to album groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AlbumGrouping {
+    /// Files in the same directory form one group.
+    Directory,
+    /// Files sharing album artist + album tags form one group.
+    Tag,
+}
+
+/// Outcome of one album group, for display.
+#[derive(Debug, Clone)]
+pub struct AlbumGroup {
+    pub name: String,
+    pub tracks: usize,
+    pub gain: f64,
+    pub limited_by: GainLimit,
+}
+
+/// Result of applying album gain to a set of analyses.
+#[derive(Debug, Default)]
+pub struct AlbumResult {
+    pub groups: Vec<AlbumGroup>,
+    /// Files that could not be grouped (no album tag) and kept their
+    /// per-track gain.
+    pub ungrouped: usize,
+}
+
+fn group_key(path: &Path, grouping: AlbumGrouping) -> Option<(String, String)> {
+    match grouping {
+        AlbumGrouping::Directory => {
+            let dir = path.parent()?;
+            let abs = std::path::absolute(dir).unwrap_or_else(|_| dir.to_path_buf());
+            let name = abs
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| abs.display().to_string());
+            Some((abs.display().to_string(), name))
+        }
+        AlbumGrouping::Tag => decoder::read_album_key(path).map(|k| (k.clone(), k)),
+    }
+}
+
+/// Gain shared by a group, the exact budget it was derived from, and the
+/// constraint that sets it.
+///
+/// The TP bound is the smallest member headroom. The LUFS bound uses the
+/// duration-weighted power mean of member loudness, i.e. the loudness of the
+/// album played end to end. Groups containing MP3/AAC snap down to the
+/// native step grid when at least one step fits (and the lossy policy
+/// accepts the loss), so lossy members stay lossless; otherwise every member
+/// needs the exact (re-encode) value. A group above the ceiling is
+/// attenuated only with `--attenuate`.
+fn group_gain(members: &[&AudioAnalysis], target: GainTarget) -> (f64, f64, GainLimit) {
+    let tp_headroom = members
+        .iter()
+        .map(|a| a.headroom)
+        .fold(f64::INFINITY, f64::min);
+
+    let total_secs: f64 = members.iter().map(|a| a.duration_secs).sum();
+    let album_power: f64 = members
+        .iter()
+        .map(|a| {
+            let weight = if total_secs > 0.0 {
+                a.duration_secs / total_secs
+            } else {
+                1.0 / members.len() as f64
+            };
+            weight * 10f64.powf(a.input_i / 10.0)
+        })
+        .sum();
+    let album_i = 10.0 * album_power.log10();
+
+    let (budget, limited_by) = if tp_headroom < 0.0 {
+        let cut = if target.attenuate { tp_headroom } else { 0.0 };
+        (cut, GainLimit::TruePeak)
+    } else {
+        let (budget, limit) = target.gain_budget(album_i, tp_headroom);
+        (budget.max(0.0), limit)
+    };
+
+    let has_lossy = members
+        .iter()
+        .any(|a| a.format_is(AudioFormat::is_mp3) || a.format_is(AudioFormat::is_aac));
+    let snapped = (budget / GAIN_STEP).floor() * GAIN_STEP;
+    let gain = if has_lossy
+        && !(0.0..GAIN_STEP).contains(&budget)
+        && (budget < 0.0 || target.accepts_native_loss(budget - snapped))
+    {
+        snapped
+    } else {
+        budget
+    };
+
+    (gain, budget, limited_by)
+}
+
+/// Group `analyses` and give every member its group's gain.
+pub fn apply_album_gain(
+    analyses: &mut [AudioAnalysis],
+    grouping: AlbumGrouping,
+    target: GainTarget,
+) -> AlbumResult {
+    let mut result = AlbumResult::default();
+    let mut groups: BTreeMap<String, (String, Vec<usize>)> = BTreeMap::new();
+    for (idx, analysis) in analyses.iter().enumerate() {
+        match group_key(&analysis.path, grouping) {
+            Some((key, name)) => groups.entry(key).or_insert((name, Vec::new())).1.push(idx),
+            None => result.ungrouped += 1,
+        }
+    }
+
+    for (name, indices) in groups.into_values() {
+        let members: Vec<&AudioAnalysis> = indices.iter().map(|&i| &analyses[i]).collect();
+        let (gain, budget, limited_by) = group_gain(&members, target);
+        for &i in &indices {
+            analyses[i].apply_fixed_gain(gain, budget, limited_by);
+            analyses[i].album = Some(name.clone());
+            analyses[i].album_budget = Some(budget);
+        }
+        result.groups.push(AlbumGroup {
+            name,
+            tracks: indices.len(),
+            gain: analyses[indices[0]].effective_gain,
+            limited_by,
+        });
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::{self, GainMethod, Measurement};
+    use crate::clipping::Clipping;
+    use crate::scanner;
+    use crate::silence::Silence;
+    use crate::tagger;
+
+    fn track(path: &str, input_i: f64, input_tp: f64, duration_secs: f64) -> AudioAnalysis {
+        let path = Path::new(path);
+        let m = Measurement {
+            input_i,
+            input_tp,
+            bitrate_kbps: Some(320),
+            duration_secs,
+            channels: Vec::new(),
+            channel_imbalance: None,
+            lra: Some(6.0),
+            max_short_term: Some(-8.0),
+            max_momentary: Some(-6.5),
+            sample_peak: -1.5,
+            dc_offset: 0.0,
+            stream: 0,
+            clipping: Clipping::default(),
+            silence: Silence::default(),
+            format: scanner::detect(path),
+            streams: Vec::new(),
+            encoder: None,
+            sample_format: None,
+        };
+        analyzer::analyze_measurement(path, &m, GainTarget::default())
+    }
+
+    #[test]
+    fn directory_groups_share_the_loudest_peak_gain() {
+        let mut analyses = vec![
+            track("a/1.flac", -14.0, -6.5, 200.0),
+            track("a/2.flac", -10.0, -2.5, 200.0),
+            track("b/1.flac", -14.0, -6.5, 200.0),
+        ];
+        let result = apply_album_gain(
+            &mut analyses,
+            AlbumGrouping::Directory,
+            GainTarget::default(),
+        );
+
+        assert_eq!(result.groups.len(), 2);
+        assert!((analyses[0].effective_gain - 2.0).abs() < 1e-9);
+        assert!((analyses[1].effective_gain - 2.0).abs() < 1e-9);
+        assert!((analyses[2].effective_gain - 6.0).abs() < 1e-9);
+        assert_eq!(analyses[0].album.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn mixed_lossy_group_snaps_to_native_steps() {
+        let mut analyses = vec![
+            track("a/1.mp3", -14.0, -4.0, 200.0),
+            track("a/2.flac", -14.0, -6.0, 200.0),
+        ];
+        apply_album_gain(
+            &mut analyses,
+            AlbumGrouping::Directory,
+            GainTarget::default(),
+        );
+
+        assert_eq!(analyses[0].gain_method, GainMethod::Mp3Lossless);
+        assert_eq!(analyses[0].lossless_gain_steps, 2);
+        assert_eq!(analyses[1].gain_method, GainMethod::Lossless);
+        assert!((analyses[1].effective_gain - 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn album_tags_use_the_unrounded_gain() {
+        let mut analyses = vec![
+            track("a/1.mp3", -14.0, -4.0, 200.0),
+            track("a/2.flac", -14.0, -6.0, 200.0),
+        ];
+        apply_album_gain(
+            &mut analyses,
+            AlbumGrouping::Directory,
+            GainTarget::default(),
+        );
+
+        let files: Vec<&AudioAnalysis> = analyses.iter().collect();
+        for tags in tagger::plan_tags(&files, &analyses) {
+            let (gain, _) = tags.album.unwrap();
+            assert!((gain - 3.5).abs() < 1e-9, "gain = {}", gain);
+        }
+    }
+
+    #[test]
+    fn lufs_target_uses_duration_weighted_album_loudness() {
+        // Equal-length tracks at -10 and -20 LUFS play back at about -12.6.
+        let members = [
+            track("a/1.flac", -10.0, -12.0, 100.0),
+            track("a/2.flac", -20.0, -20.0, 100.0),
+        ];
+        let target = GainTarget {
+            target_lufs: Some(-9.0),
+            ..GainTarget::default()
+        };
+        let (gain, _, limited_by) = group_gain(&members.iter().collect::<Vec<_>>(), target);
+        assert!((gain - 3.6).abs() < 0.05, "gain = {}", gain);
+        assert_eq!(limited_by, GainLimit::Loudness);
+    }
+
+    #[test]
+    fn over_ceiling_group_is_attenuated_only_when_enabled() {
+        let members = [
+            track("a/1.mp3", -8.0, 0.4, 100.0),
+            track("a/2.mp3", -12.0, -4.0, 100.0),
+        ];
+        let members: Vec<_> = members.iter().collect();
+        assert_eq!(group_gain(&members, GainTarget::default()).0, 0.0);
+
+        let target = GainTarget {
+            attenuate: true,
+            ..GainTarget::default()
+        };
+        let (gain, _, limited_by) = group_gain(&members, target);
+        assert!((gain + GAIN_STEP).abs() < 1e-9);
+        assert_eq!(limited_by, GainLimit::TruePeak);
+    }
+}
@@ -1,9 +1,17 @@
-use anyhow::{anyhow, Context, Result};
-use serde::Deserialize;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 use std::path::Path;
 use std::process::Command;
 
-use crate::scanner;
+use crate::clipping::Clipping;
+use crate::decoder::{self, AudioStream, DecodeSummary, Decoder, PcmFormat};
+use crate::encoder::{self, EncoderSettings};
+use crate::flac;
+use crate::loudness::{ChannelPeak, LoudnessMeter};
+use crate::pcm;
+use crate::scanner::{self, AudioFormat};
+use crate::silence::Silence;
 
 /// Default delivery True Peak ceiling for all formats (dBTP).
 ///
@@ -28,15 +36,42 @@ pub const HIGH_BITRATE_THRESHOLD: u32 = 256;
 /// Gain step size in dB (fixed by MP3/AAC format specification)
 pub const GAIN_STEP: f64 = mp3rgain::GAIN_STEP_DB;
 
+/// Default `--lossy-threshold`: with `max-loudness`, native steps are kept
+/// when they give up at most this much gain (dB).
+pub const DEFAULT_LOSSY_THRESHOLD: f64 = 0.5;
+
 /// Minimum effective gain threshold (dB)
 /// Files with less headroom than this are skipped
 const MIN_EFFECTIVE_GAIN: f64 = 0.05;
 
+/// Version of the measurement engine. Bump whenever `Measurement` fields or
+/// the way they are computed change, so cached results are re-measured.
+pub const MEASUREMENT_VERSION: u32 = 10;
+
+/// Default `--imbalance-threshold`: left/right loudness difference (LU)
+/// above which a file is flagged as unbalanced.
+pub const DEFAULT_IMBALANCE_THRESHOLD: f64 = 1.5;
+
+/// Default `--gap-threshold`: leading or trailing silence (seconds) above
+/// which a file is flagged.
+pub const DEFAULT_GAP_THRESHOLD: f64 = 2.0;
+
+/// Default `--dropout-threshold`: shortest internal silence (seconds)
+/// flagged as a dropout.
+pub const DEFAULT_DROPOUT_THRESHOLD: f64 = 0.5;
+
+/// Default `--abrupt-end-threshold`: level of the final 10 ms (dBFS) above
+/// which a file is flagged as ending abruptly.
+pub const DEFAULT_ABRUPT_END_THRESHOLD: f64 = -40.0;
+
 /// Processing method for the file
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum GainMethod {
-    /// Lossless files processed with ffmpeg volume filter
-    FfmpegLossless,
+    /// Lossless files with precise sample-level gain: in place for WAV/AIFF,
+    /// re-encoded for FLAC
+    #[serde(alias = "ffmpeg_lossless")]
+    Lossless,
     /// MP3 files with enough headroom for lossless gain (1.5dB steps)
     Mp3Lossless,
     /// MP3 files requiring re-encode for precise gain
@@ -45,36 +80,330 @@ pub enum GainMethod {
     AacLossless,
     /// AAC/M4A files requiring re-encode for precise gain
     AacReencode,
+    /// Opus files: the header output gain, applied by every decoder
+    /// (lossless, 1/256 dB steps)
+    OpusHeader,
+    /// Ogg Vorbis files: re-encode for precise gain
+    VorbisReencode,
+    /// APE and DSD files: no encoder can rewrite the audio, so the gain is
+    /// only written as tags (`--tag-only`)
+    TagOnly,
     /// No processing needed (no headroom)
     None,
 }
 
-#[derive(Debug, Clone)]
+/// Content-derived measurement of one file, independent of the TP target.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Measurement {
+    pub input_i: f64,
+    pub input_tp: f64,
+    pub bitrate_kbps: Option<u32>,
+    /// Decoded length; weights tracks when combining album loudness.
+    pub duration_secs: f64,
+    /// True and sample peak of each channel.
+    pub channels: Vec<ChannelPeak>,
+    /// Largest left/right loudness difference (LU); `None` for mono.
+    pub channel_imbalance: Option<f64>,
+    /// Loudness range (LU, EBU Tech 3342); `None` when too short or silent.
+    pub lra: Option<f64>,
+    /// Highest short-term (3 s) loudness; `None` for files under 3 s.
+    pub max_short_term: Option<f64>,
+    /// Highest momentary (400 ms) loudness.
+    pub max_momentary: Option<f64>,
+    /// Highest sample value (dBFS).
+    pub sample_peak: f64,
+    /// Largest per-channel mean, as a signed fraction of full scale.
+    pub dc_offset: f64,
+    /// Audio stream the values describe (0 = first).
+    pub stream: usize,
+    /// Full-scale clips and inter-sample overs.
+    pub clipping: Clipping,
+    /// Leading/trailing silence, dropouts and the final level.
+    pub silence: Silence,
+    /// Format detected from the content.
+    pub format: Option<AudioFormat>,
+    /// Every audio stream of the file.
+    pub streams: Vec<AudioStream>,
+    /// Source encoder settings (lossy files, first stream only).
+    pub encoder: Option<EncoderSettings>,
+    /// Stored sample format (lossless files).
+    pub sample_format: Option<PcmFormat>,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct AudioAnalysis {
     pub filename: String,
     pub path: std::path::PathBuf,
+    /// Format detected from the content when the file was analyzed.
+    #[serde(skip)]
+    pub format: Option<AudioFormat>,
     pub input_i: f64,
     pub input_tp: f64,
     pub bitrate_kbps: Option<u32>,
+    pub duration_secs: f64,
+    pub channels: Vec<ChannelPeak>,
+    pub channel_imbalance: Option<f64>,
+    /// Whether `channel_imbalance` exceeds `--imbalance-threshold`.
+    pub imbalanced: bool,
+    pub lra: Option<f64>,
+    pub max_short_term: Option<f64>,
+    pub max_momentary: Option<f64>,
+    pub sample_peak: f64,
+    /// Peak-to-loudness ratio: True Peak minus integrated loudness (dB).
+    pub plr: f64,
+    pub dc_offset: f64,
+    /// Audio stream that is measured and adjusted; the others are kept as is.
+    pub stream: usize,
+    /// Number of audio streams in the file.
+    pub stream_count: usize,
+    pub clipping: Clipping,
+    /// Dropouts are limited to those at least `--dropout-threshold` long.
+    pub silence: Silence,
+    /// Defects found by `flag_problems`.
+    pub problems: Vec<Problem>,
 
     pub target_tp: f64,
+    pub target_lufs: Option<f64>,
     pub headroom: f64,
+    pub limited_by: GainLimit,
     pub gain_method: GainMethod,
     pub effective_gain: f64,
     pub lossless_gain_steps: i32,
+    /// Gain native steps give up versus the exact budget (MP3/AAC native
+    /// files only; 0 otherwise).
+    pub lost_headroom: f64,
+    /// Album group whose shared gain replaced the per-track decision.
+    pub album: Option<String>,
+    /// Exact gain of the album group, before native step rounding; written
+    /// as the album gain tag.
+    pub album_budget: Option<f64>,
+    /// Source encoder settings of MP3/AAC files, reproduced on re-encode.
+    pub encoder: Option<EncoderSettings>,
+    /// Stored bit depth of lossless files, kept on output.
+    pub sample_format: Option<PcmFormat>,
+}
+
+/// Defect flagged by `ProblemThresholds`; files with any are problem files
+/// (`--problems-only`).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Problem {
+    /// Runs of full-scale samples.
+    Clipping,
+    ChannelImbalance,
+    LeadingSilence,
+    TrailingSilence,
+    Dropouts,
+    /// The audio is cut off rather than faded or followed by silence.
+    AbruptEnd,
+}
+
+impl Problem {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Problem::Clipping => "clipping",
+            Problem::ChannelImbalance => "channel imbalance",
+            Problem::LeadingSilence => "leading silence",
+            Problem::TrailingSilence => "trailing silence",
+            Problem::Dropouts => "dropouts",
+            Problem::AbruptEnd => "abrupt end",
+        }
+    }
+}
+
+/// Limits beyond which measurements are flagged as problems.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProblemThresholds {
+    /// Left/right loudness difference (LU).
+    pub imbalance: f64,
+    /// Leading or trailing silence (seconds).
+    pub gap_secs: f64,
+    /// Shortest internal silence counted as a dropout (seconds).
+    pub dropout_secs: f64,
+    /// Level of the final 10 ms (dBFS).
+    pub abrupt_end_db: f64,
+}
+
+impl Default for ProblemThresholds {
+    fn default() -> Self {
+        Self {
+            imbalance: DEFAULT_IMBALANCE_THRESHOLD,
+            gap_secs: DEFAULT_GAP_THRESHOLD,
+            dropout_secs: DEFAULT_DROPOUT_THRESHOLD,
+            abrupt_end_db: DEFAULT_ABRUPT_END_THRESHOLD,
+        }
+    }
+}
+
+/// Which ceiling bounds a file's gain.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GainLimit {
+    /// Gain stops at the True Peak ceiling.
+    TruePeak,
+    /// Gain stops at the integrated loudness target (`--target-lufs`).
+    Loudness,
+}
+
+impl GainLimit {
+    pub fn label(&self) -> &'static str {
+        match self {
+            GainLimit::TruePeak => "TP",
+            GainLimit::Loudness => "LUFS",
+        }
+    }
 }
 
 impl AudioAnalysis {
     pub fn requires_reencode(&self) -> bool {
         matches!(
             self.gain_method,
-            GainMethod::Mp3Reencode | GainMethod::AacReencode
+            GainMethod::Mp3Reencode | GainMethod::AacReencode | GainMethod::VorbisReencode
         )
     }
 
+    /// Whether the gain can only be written as tags.
+    pub fn is_tag_only(&self) -> bool {
+        self.gain_method == GainMethod::TagOnly
+    }
+
     pub fn has_headroom(&self) -> bool {
         !matches!(self.gain_method, GainMethod::None)
     }
+
+    /// Whether lossless gain is applied without ffmpeg: PCM WAV/AIFF in
+    /// place, or FLAC re-encoded in-process (integer, up to 24-bit).
+    pub fn is_native_lossless(&self) -> bool {
+        if self.gain_method != GainMethod::Lossless {
+            return false;
+        }
+        match (self.format, self.sample_format) {
+            (Some(AudioFormat::Flac), Some(sample_format)) => flac::can_encode(sample_format),
+            (Some(format), Some(_)) => pcm::is_native(format),
+            _ => false,
+        }
+    }
+
+    /// Whether applying the gain shells out to ffmpeg.
+    pub fn requires_ffmpeg(&self) -> bool {
+        match self.gain_method {
+            GainMethod::Lossless => !self.is_native_lossless(),
+            _ => self.requires_reencode(),
+        }
+    }
+
+    /// Processing method label for reports ("ffmpeg" / "native" /
+    /// "re-encode" / "tags").
+    pub fn method_label(&self) -> &'static str {
+        match self.gain_method {
+            GainMethod::Lossless if self.is_native_lossless() => "native",
+            GainMethod::Lossless => "ffmpeg",
+            GainMethod::Mp3Lossless | GainMethod::AacLossless | GainMethod::OpusHeader => "native",
+            GainMethod::Mp3Reencode | GainMethod::AacReencode | GainMethod::VorbisReencode => {
+                "re-encode"
+            }
+            GainMethod::TagOnly => "tags",
+            GainMethod::None => "none",
+        }
+    }
+
+    /// Unquantized per-track gain: the LUFS/TP budget, or the cut to the
+    /// ceiling for files above it. Unlike `effective_gain` this ignores
+    /// native step rounding and album grouping.
+    pub fn track_gain(&self) -> f64 {
+        match self.target_lufs {
+            Some(lufs) if self.headroom >= 0.0 => (lufs - self.input_i).min(self.headroom),
+            _ => self.headroom,
+        }
+    }
+
+    /// Whether the detected format satisfies `check`, e.g.
+    /// `AudioFormat::is_aac`.
+    pub fn format_is(&self, check: fn(AudioFormat) -> bool) -> bool {
+        self.format.is_some_and(check)
+    }
+
+    /// Whether the file is turned down to the ceiling (`--attenuate`).
+    pub fn is_attenuation(&self) -> bool {
+        self.has_headroom() && self.effective_gain < 0.0
+    }
+
+    /// Set `imbalanced`, drop dropouts shorter than the threshold and list
+    /// the problems `thresholds` flag.
+    pub fn flag_problems(&mut self, thresholds: &ProblemThresholds) {
+        self.imbalanced = self
+            .channel_imbalance
+            .is_some_and(|d| d > thresholds.imbalance);
+        self.silence
+            .dropouts
+            .retain(|d| d.duration_secs >= thresholds.dropout_secs);
+
+        let silence = &self.silence;
+        let checks = [
+            (Problem::Clipping, self.clipping.clip_events > 0),
+            (Problem::ChannelImbalance, self.imbalanced),
+            (
+                Problem::LeadingSilence,
+                silence.leading_secs > thresholds.gap_secs,
+            ),
+            (
+                Problem::TrailingSilence,
+                silence.trailing_secs > thresholds.gap_secs,
+            ),
+            (Problem::Dropouts, !silence.dropouts.is_empty()),
+            (
+                Problem::AbruptEnd,
+                silence.final_level > thresholds.abrupt_end_db,
+            ),
+        ];
+        self.problems = checks
+            .into_iter()
+            .filter_map(|(problem, found)| found.then_some(problem))
+            .collect();
+    }
+
+    /// Replace the per-track decision with a fixed gain shared by a group.
+    ///
+    /// Lossy files use native steps only when `gain` is an exact multiple of
+    /// `GAIN_STEP`; anything else needs a re-encode to hit the same value.
+    /// Negative gains attenuate. `budget` is the exact gain `gain` was
+    /// rounded from, for `lost_headroom`.
+    pub fn apply_fixed_gain(&mut self, gain: f64, budget: f64, limited_by: GainLimit) {
+        let is_aac = self.format_is(AudioFormat::is_aac);
+        let precise = precise_method(self.format);
+
+        let steps = (gain / GAIN_STEP).round();
+        let on_step_grid =
+            self.stream == 0 && steps != 0.0 && (steps * GAIN_STEP - gain).abs() < 1e-9;
+
+        let (gain_method, effective_gain, lossless_gain_steps) = if gain.abs() < MIN_EFFECTIVE_GAIN
+        {
+            (GainMethod::None, 0.0, 0)
+        } else if let Some(method) = precise {
+            (method, gain, 0)
+        } else if on_step_grid {
+            let method = if is_aac {
+                GainMethod::AacLossless
+            } else {
+                GainMethod::Mp3Lossless
+            };
+            (method, gain, steps as i32)
+        } else if is_aac {
+            (GainMethod::AacReencode, gain, 0)
+        } else {
+            (GainMethod::Mp3Reencode, gain, 0)
+        };
+
+        self.gain_method = gain_method;
+        self.effective_gain = effective_gain;
+        self.lossless_gain_steps = lossless_gain_steps;
+        self.lost_headroom = if lossless_gain_steps != 0 {
+            budget - effective_gain
+        } else {
+            0.0
+        };
+        self.limited_by = limited_by;
+    }
 }
 
 impl GainMethod {
@@ -83,26 +412,12 @@ impl GainMethod {
         match self {
             GainMethod::Mp3Lossless | GainMethod::Mp3Reencode => "MP3",
             GainMethod::AacLossless | GainMethod::AacReencode => "AAC",
-            GainMethod::FfmpegLossless => "Lossless",
+            GainMethod::Lossless | GainMethod::TagOnly => "Lossless",
+            GainMethod::OpusHeader => "Opus",
+            GainMethod::VorbisReencode => "Vorbis",
             GainMethod::None => "-",
         }
     }
-
-    /// Processing method label for reports ("ffmpeg" / "native" / "re-encode").
-    pub fn method_label(&self) -> &'static str {
-        match self {
-            GainMethod::FfmpegLossless => "ffmpeg",
-            GainMethod::Mp3Lossless | GainMethod::AacLossless => "native",
-            GainMethod::Mp3Reencode | GainMethod::AacReencode => "re-encode",
-            GainMethod::None => "none",
-        }
-    }
-}
-
-#[derive(Debug, Deserialize)]
-struct LoudnormOutput {
-    input_i: String,
-    input_tp: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -115,21 +430,6 @@ struct FfprobeOutput {
     format: FfprobeFormat,
 }
 
-/// Parse the overall bitrate from ffmpeg's input dump on stderr, e.g.
-/// `  Duration: 00:03:50.32, start: 0.025057, bitrate: 320 kb/s`.
-/// Returns None for "N/A" or unexpected formatting; callers fall back to ffprobe.
-fn parse_stderr_bitrate(stderr: &str) -> Option<u32> {
-    stderr
-        .lines()
-        .find(|line| line.trim_start().starts_with("Duration:"))?
-        .split("bitrate:")
-        .nth(1)?
-        .split_whitespace()
-        .next()?
-        .parse()
-        .ok()
-}
-
 fn get_bitrate(path: &Path) -> Option<u32> {
     let output = Command::new("ffprobe")
         .args([
@@ -154,7 +454,8 @@ fn get_bitrate(path: &Path) -> Option<u32> {
 }
 
 /// How the delivery True Peak ceiling is selected per file.
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum TpTargetMode {
     /// Uniform target for every file (default, post-encode delivery interpretation).
     Uniform(f64),
@@ -186,100 +487,136 @@ impl Default for TpTargetMode {
     }
 }
 
-/// Extract the first balanced `{...}` JSON object from a string slice.
-fn extract_json_object(s: &str) -> Option<&str> {
-    let start = s.find('{')?;
-    let section = &s[start..];
-    let mut depth = 0;
-    for (i, ch) in section.char_indices() {
-        match ch {
-            '{' => depth += 1,
-            '}' => {
-                depth -= 1;
-                if depth == 0 {
-                    return Some(&section[..=i]);
-                }
-            }
-            _ => {}
+/// How MP3/AAC files trade native (lossless, 1.5 dB steps) gain against
+/// re-encoding for the exact gain (`--lossy-policy`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LossyPolicy {
+    /// Re-encode when native steps would give up more than the threshold.
+    MaxLoudness,
+    /// Native steps only; files that need a re-encode are never processed.
+    NeverReencode,
+    /// Native steps whenever one fits; re-encode only below one step.
+    #[default]
+    PreferNative,
+}
+
+/// Goal of the gain decision: the True Peak ceiling, optionally combined with
+/// an integrated loudness target that the gain must not exceed either.
+/// With `attenuate`, files above the ceiling are turned down to it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct GainTarget {
+    pub tp_mode: TpTargetMode,
+    pub target_lufs: Option<f64>,
+    pub attenuate: bool,
+    pub lossy_policy: LossyPolicy,
+    /// Largest native-step loss (dB) `max-loudness` accepts.
+    pub lossy_threshold: f64,
+}
+
+impl Default for GainTarget {
+    fn default() -> Self {
+        Self {
+            tp_mode: TpTargetMode::default(),
+            target_lufs: None,
+            attenuate: false,
+            lossy_policy: LossyPolicy::default(),
+            lossy_threshold: DEFAULT_LOSSY_THRESHOLD,
         }
     }
-    None
 }
 
-/// Extract loudnorm JSON from ffmpeg stderr output.
-///
-/// Uses the `[Parsed_loudnorm_0 @` marker to locate the JSON, avoiding false
-/// matches from binary data in GEOB/PRIV ID3v2 frames.
-fn extract_loudnorm_json(stderr: &str, path: &Path) -> Result<LoudnormOutput> {
-    let marker = "[Parsed_loudnorm_0 @";
-
-    // Primary: find JSON after the loudnorm marker
-    if let Some(marker_pos) = stderr.find(marker) {
-        if let Some(json_str) = extract_json_object(&stderr[marker_pos..]) {
-            return serde_json::from_str(json_str).with_context(|| {
-                format!(
-                    "Failed to parse loudnorm JSON. Run: ffmpeg -nostdin -i \"{}\" -map 0:a:0 -af loudnorm=print_format=json -f null - 2>&1 | tail -20",
-                    path.display()
-                )
-            });
-        }
+impl GainTarget {
+    /// Whether native steps that give up `lost` dB against the exact gain
+    /// are acceptable under the lossy policy.
+    pub fn accepts_native_loss(&self, lost: f64) -> bool {
+        self.lossy_policy != LossyPolicy::MaxLoudness || lost <= self.lossy_threshold
     }
 
-    // Fallback for older ffmpeg: search backwards for a JSON block containing "input_i"
-    if let Some(input_i_pos) = stderr.rfind("\"input_i\"") {
-        if let Some(brace_pos) = stderr[..input_i_pos].rfind('{') {
-            if let Some(json_str) = extract_json_object(&stderr[brace_pos..]) {
-                if let Ok(loudnorm) = serde_json::from_str::<LoudnormOutput>(json_str) {
-                    return Ok(loudnorm);
-                }
-            }
+    /// Gain budget for a file and the constraint that sets it. Without a
+    /// LUFS target this is simply the True Peak headroom.
+    pub fn gain_budget(&self, input_i: f64, tp_headroom: f64) -> (f64, GainLimit) {
+        match self.target_lufs {
+            Some(lufs) if lufs - input_i < tp_headroom => (lufs - input_i, GainLimit::Loudness),
+            _ => (tp_headroom, GainLimit::TruePeak),
         }
     }
+}
+
+/// A file that could not be measured, kept so reports can list it.
+#[derive(Debug, Clone)]
+pub struct AnalysisFailure {
+    pub path: std::path::PathBuf,
+    pub error: String,
+}
 
-    Err(anyhow!(
-        "No loudnorm data found in ffmpeg output. \
-         This may be caused by:\n\
-         1. Problematic ID3v2 metadata (GEOB/PRIV frames from DJ software)\n\
-         2. Corrupted or unsupported audio file\n\
-         3. Very old ffmpeg version\n\n\
-         Try: ffmpeg -nostdin -i \"{}\" -map 0:a:0 -af loudnorm=print_format=json -f null - 2>&1 | tail -30\n\
-         Or remove DJ metadata: eyeD3 --remove-all-objects \"{}\"",
-        path.display(),
-        path.display()
-    ))
+/// Decode audio stream `stream` of `path` and run it through the loudness
+/// meter. Also returns the decoded duration in seconds.
+fn measure(path: &Path, stream: usize) -> Result<(LoudnessMeter, DecodeSummary, f64)> {
+    let mut decoder = Decoder::open(path, stream)?;
+    let spec = decoder.spec();
+    let mut meter = LoudnessMeter::new(spec.channels, spec.sample_rate, spec.channel_mask);
+    while let Some(block) = decoder.next_block()? {
+        meter.process(block);
+    }
+    let summary = decoder.finish()?;
+    if summary.frames == 0 {
+        return Err(anyhow!("No audio samples decoded"));
+    }
+    let duration_secs = summary.frames as f64 / spec.sample_rate as f64;
+    Ok((meter, summary, duration_secs))
 }
 
-pub fn analyze_file_with_target(path: &Path, tp_mode: TpTargetMode) -> Result<AudioAnalysis> {
-    let output = Command::new("ffmpeg")
-        .args([
-            "-nostdin",
-            "-i",
-            path.to_str().ok_or_else(|| anyhow!("Invalid path"))?,
-            "-map",
-            "0:a:0",
-            "-af",
-            "loudnorm=print_format=json",
-            "-f",
-            "null",
-            "-",
-        ])
-        .output()
-        .context("Failed to execute ffmpeg. Is ffmpeg installed?")?;
+/// Detect the format of a file, list its audio streams, read the source
+/// encoder settings and sample format, and measure audio stream `stream`.
+///
+/// The result depends only on file content, so it is what the analysis cache
+/// stores; gain decisions are derived from it by `analyze_measurement`.
+pub fn measure_source(path: &Path, stream: usize) -> Result<Measurement> {
+    let format = scanner::detect(path);
+    let streams = decoder::audio_streams(path);
+    if !streams.is_empty() && stream >= streams.len() {
+        return Err(anyhow!(
+            "No audio stream {} (the file has {})",
+            stream,
+            streams.len()
+        ));
+    }
+    if stream > 0 && format.is_some_and(AudioFormat::is_opus) {
+        // The header gain applies to the first Opus stream only.
+        return Err(anyhow!(
+            "Only the first audio stream of Opus files can be adjusted"
+        ));
+    }
 
-    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut measurement = measure_file(path, format, stream)?;
+    measurement.streams = streams;
+    // Source settings are only read from the first stream.
+    if let (Some(format), 0) = (format, stream) {
+        measurement.encoder = encoder::probe(path, format);
+    }
+    if !format.is_some_and(AudioFormat::is_lossy) {
+        measurement.sample_format = decoder::probe_pcm_format(path, stream);
+    }
+    Ok(measurement)
+}
 
-    let loudnorm: LoudnormOutput = extract_loudnorm_json(&stderr, path)?;
+/// Measure the loudness, true peak and (for lossy files) bitrate of audio
+/// stream `stream` of a file of the given format (`scanner::detect`).
+///
+/// Streams, encoder settings and sample format are left empty; re-measuring
+/// a processed file only needs the loudness.
+pub fn measure_file(
+    path: &Path,
+    format: Option<AudioFormat>,
+    stream: usize,
+) -> Result<Measurement> {
+    let (meter, summary, duration_secs) = measure(path, stream)?;
 
-    let input_i: f64 = loudnorm
-        .input_i
-        .parse()
-        .context("Failed to parse input_i")?;
-    let input_tp: f64 = loudnorm
-        .input_tp
-        .parse()
-        .context("Failed to parse input_tp")?;
+    let input_i = meter.integrated_loudness();
+    let input_tp = meter.true_peak();
 
-    // loudnorm reports "-inf" for silent audio; a non-finite value would blow up
+    // The meter reports -inf for silent audio; a non-finite value would blow up
     // the gain math (inf headroom -> i32::MAX gain steps), so reject it here.
     if !input_i.is_finite() || !input_tp.is_finite() {
         return Err(anyhow!(
@@ -289,41 +626,121 @@ pub fn analyze_file_with_target(path: &Path, tp_mode: TpTargetMode) -> Result<Au
         ));
     }
 
-    let is_mp3 = scanner::is_mp3(path);
-    let is_aac = scanner::is_aac(path);
-    let is_lossy = is_mp3 || is_aac;
-
-    // The loudnorm run's stderr already contains the bitrate in the input
-    // dump; reuse it to avoid spawning ffprobe per file (issue #47).
-    let bitrate_kbps = if is_lossy {
-        parse_stderr_bitrate(&stderr).or_else(|| get_bitrate(path))
+    // The decoder already knows the stream bitrate; ffprobe is only a fallback
+    // so we avoid spawning a process per file (issue #47).
+    let bitrate_kbps = if format.is_some_and(AudioFormat::is_lossy) {
+        summary.bitrate_kbps.or_else(|| get_bitrate(path))
     } else {
         None
     };
 
-    let target_tp = tp_mode.target_for(is_lossy, bitrate_kbps);
+    Ok(Measurement {
+        input_i,
+        input_tp,
+        bitrate_kbps,
+        duration_secs,
+        channels: meter.channel_peaks(),
+        channel_imbalance: meter.channel_imbalance(),
+        lra: meter.loudness_range(),
+        max_short_term: meter.max_short_term(),
+        max_momentary: meter.max_momentary(),
+        sample_peak: meter.sample_peak(),
+        dc_offset: meter.dc_offset(),
+        stream,
+        clipping: meter.clipping(),
+        silence: meter.silence(),
+        format,
+        streams: Vec::new(),
+        encoder: None,
+        sample_format: None,
+    })
+}
+
+/// Method for formats that take gain at any precision, or `None` for MP3
+/// and AAC, which prefer native 1.5 dB steps.
+fn precise_method(format: Option<AudioFormat>) -> Option<GainMethod> {
+    match format {
+        Some(f) if f.is_mp3() || f.is_aac() => None,
+        Some(f) if f.is_opus() => Some(GainMethod::OpusHeader),
+        Some(f) if f.is_vorbis() => Some(GainMethod::VorbisReencode),
+        Some(f) if f.is_ape() || f.is_dsd() => Some(GainMethod::TagOnly),
+        _ => Some(GainMethod::Lossless),
+    }
+}
+
+/// Derive the target ceiling and processing method for a measured file.
+pub fn analyze_measurement(
+    path: &Path,
+    measurement: &Measurement,
+    target: GainTarget,
+) -> AudioAnalysis {
+    let Measurement {
+        input_i,
+        input_tp,
+        bitrate_kbps,
+        duration_secs,
+        ref channels,
+        channel_imbalance,
+        lra,
+        max_short_term,
+        max_momentary,
+        sample_peak,
+        dc_offset,
+        stream,
+        ref clipping,
+        ref silence,
+        format,
+        ref streams,
+        ref encoder,
+        sample_format,
+    } = *measurement;
+
+    let is_aac = format.is_some_and(AudioFormat::is_aac);
+    // Native AAC gain only reaches the first track of an MP4 file.
+    let native = stream == 0;
+    let precise = precise_method(format);
+
+    let target_tp = target
+        .tp_mode
+        .target_for(format.is_some_and(AudioFormat::is_lossy), bitrate_kbps);
     let headroom = target_tp - input_tp;
+    let (budget, limited_by) = target.gain_budget(input_i, headroom);
 
-    let (gain_method, effective_gain, lossless_gain_steps) = if headroom < MIN_EFFECTIVE_GAIN {
-        (GainMethod::None, 0.0, 0)
-    } else if !is_lossy {
-        (GainMethod::FfmpegLossless, headroom, 0)
-    } else {
-        // MP3/AAC: try lossless gain in 1.5dB steps, fall back to re-encode
-        let lossless_steps = (headroom / GAIN_STEP).floor() as i32;
-        if lossless_steps >= 1 {
-            let effective = lossless_steps as f64 * GAIN_STEP;
-            if is_aac {
-                (GainMethod::AacLossless, effective, lossless_steps)
+    let (gain_method, effective_gain, lossless_gain_steps) =
+        if target.attenuate && headroom <= -MIN_EFFECTIVE_GAIN {
+            // Over the ceiling: the cut is rounded up to whole native steps for
+            // MP3/AAC so the file always lands at or below the ceiling.
+            let steps = (headroom / GAIN_STEP).floor() as i32;
+            if let Some(method) = precise {
+                (method, headroom, 0)
+            } else if is_aac && !native {
+                (GainMethod::AacReencode, headroom, 0)
+            } else if is_aac {
+                (GainMethod::AacLossless, steps as f64 * GAIN_STEP, steps)
             } else {
-                (GainMethod::Mp3Lossless, effective, lossless_steps)
+                (GainMethod::Mp3Lossless, steps as f64 * GAIN_STEP, steps)
             }
-        } else if is_aac {
-            (GainMethod::AacReencode, headroom, 0)
+        } else if budget < MIN_EFFECTIVE_GAIN {
+            (GainMethod::None, 0.0, 0)
+        } else if let Some(method) = precise {
+            (method, budget, 0)
         } else {
-            (GainMethod::Mp3Reencode, headroom, 0)
-        }
-    };
+            // MP3/AAC: try lossless gain in 1.5dB steps, fall back to re-encode
+            let lossless_steps = (budget / GAIN_STEP).floor() as i32;
+            let lost = budget - lossless_steps as f64 * GAIN_STEP;
+            if native && lossless_steps >= 1 && target.accepts_native_loss(lost) {
+                let effective = lossless_steps as f64 * GAIN_STEP;
+                if is_aac {
+                    (GainMethod::AacLossless, effective, lossless_steps)
+                } else {
+                    (GainMethod::Mp3Lossless, effective, lossless_steps)
+                }
+            } else if is_aac {
+                (GainMethod::AacReencode, budget, 0)
+            } else {
+                (GainMethod::Mp3Reencode, budget, 0)
+            }
+        };
 
     let filename = path
         .file_name()
@@ -331,129 +748,324 @@ pub fn analyze_file_with_target(path: &Path, tp_mode: TpTargetMode) -> Result<Au
         .unwrap_or("unknown")
         .to_string();
 
-    Ok(AudioAnalysis {
+    AudioAnalysis {
         filename,
         path: path.to_path_buf(),
+        format,
         input_i,
         input_tp,
         bitrate_kbps,
+        duration_secs,
+        channels: channels.clone(),
+        channel_imbalance,
+        imbalanced: false,
+        lra,
+        max_short_term,
+        max_momentary,
+        sample_peak,
+        plr: input_tp - input_i,
+        dc_offset,
+        stream,
+        stream_count: streams.len().max(1),
+        clipping: clipping.clone(),
+        silence: silence.clone(),
+        problems: Vec::new(),
         target_tp,
+        target_lufs: target.target_lufs,
         headroom,
+        // Attenuation is always set by the TP ceiling.
+        limited_by: if headroom < 0.0 {
+            GainLimit::TruePeak
+        } else {
+            limited_by
+        },
+        lost_headroom: if lossless_gain_steps != 0 {
+            // Attenuation rounds the cut up, so compare against the headroom.
+            let exact = if headroom < 0.0 { headroom } else { budget };
+            exact - effective_gain
+        } else {
+            0.0
+        },
         gain_method,
         effective_gain,
         lossless_gain_steps,
-    })
-}
-
-pub fn check_ffmpeg() -> Result<()> {
-    Command::new("ffmpeg")
-        .arg("-version")
-        .output()
-        .context("ffmpeg not found. Please install ffmpeg first.")?;
-    Ok(())
+        album: None,
+        album_budget: None,
+        encoder: encoder.clone(),
+        sample_format,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::path::PathBuf;
+    use crate::scanner;
+    use crate::silence::Dropout;
+
+    fn measurement(input_i: f64, input_tp: f64) -> Measurement {
+        Measurement {
+            input_i,
+            input_tp,
+            bitrate_kbps: Some(320),
+            duration_secs: 180.0,
+            channels: Vec::new(),
+            channel_imbalance: None,
+            lra: Some(6.0),
+            max_short_term: Some(-8.0),
+            max_momentary: Some(-6.5),
+            sample_peak: -1.5,
+            dc_offset: 0.0,
+            stream: 0,
+            clipping: Clipping::default(),
+            silence: Silence::default(),
+            format: None,
+            streams: Vec::new(),
+            encoder: None,
+            sample_format: None,
+        }
+    }
+
+    fn analyze(name: &str, input_i: f64, input_tp: f64, target: GainTarget) -> AudioAnalysis {
+        let path = Path::new(name);
+        let m = Measurement {
+            format: scanner::detect(path),
+            ..measurement(input_i, input_tp)
+        };
+        analyze_measurement(path, &m, target)
+    }
+
+    #[test]
+    fn tp_only_gain_fills_headroom() {
+        let a = analyze("a.flac", -12.0, -3.5, GainTarget::default());
+        assert_eq!(a.gain_method, GainMethod::Lossless);
+        assert!((a.effective_gain - 3.0).abs() < 1e-9);
+        assert_eq!(a.limited_by, GainLimit::TruePeak);
+    }
+
+    #[test]
+    fn max_loudness_reencodes_when_native_loses_too_much() {
+        // 4.3 dB of headroom: two steps give 3.0, leaving 1.3 dB unused.
+        let a = analyze("a.mp3", -14.0, -4.8, GainTarget::default());
+        assert_eq!(a.gain_method, GainMethod::Mp3Lossless);
+        assert!((a.lost_headroom - 1.3).abs() < 1e-9);
+
+        let target = GainTarget {
+            lossy_policy: LossyPolicy::MaxLoudness,
+            ..GainTarget::default()
+        };
+        let a = analyze("a.mp3", -14.0, -4.8, target);
+        assert_eq!(a.gain_method, GainMethod::Mp3Reencode);
+        assert!((a.effective_gain - 4.3).abs() < 1e-9);
+        assert_eq!(a.lost_headroom, 0.0);
+
+        // 3.2 dB: two steps leave only 0.2 dB, within the 0.5 dB threshold.
+        let a = analyze("a.mp3", -14.0, -3.7, target);
+        assert_eq!(a.gain_method, GainMethod::Mp3Lossless);
+        assert!((a.lost_headroom - 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn lufs_target_caps_gain_below_tp_headroom() {
+        let target = GainTarget {
+            target_lufs: Some(-9.0),
+            ..GainTarget::default()
+        };
+        let a = analyze("a.flac", -11.0, -6.5, target);
+        assert!((a.effective_gain - 2.0).abs() < 1e-9);
+        assert_eq!(a.limited_by, GainLimit::Loudness);
+
+        // A quiet but peaky file is still held at the TP ceiling.
+        let b = analyze("b.flac", -14.0, -2.5, target);
+        assert!((b.effective_gain - 2.0).abs() < 1e-9);
+        assert_eq!(b.limited_by, GainLimit::TruePeak);
+    }
 
-    /// Test JSON extraction with GEOB/PRIV frames containing '{' and '}' characters
-    /// This reproduces the issue reported in GitHub issue #10
     #[test]
-    fn test_extract_loudnorm_json_with_traktor_metadata() {
-        let stderr = r#"        encoder         : LAME3.100
-        id3v2_priv.TRAKTOR4: DMRT\xf4{\x00\x00\x02\x00\x00\x00RDH 0\x00\x00\x00\x03\x00\x00\x00SKHC\x04\x00\x00\x00\x00\x00\x00\x00i?"\x00DOMF\x04\x00\x00\x00\x00\x00\x00\x00\x14\x0a\xe8\x07NSRV\x04\x00\x00\x00\x00\x00\x00\x00\x07\x00\x00\x00ATAD\xac{\x00\x00\x17\x00\x00\x00BDNA\x04\
-        encoder         : Lavf62.3.100
-    [Parsed_loudnorm_0 @ 0xc8f448a80] N/A speed=30.3x elapsed=0:00:10.57
-    {
-    	"input_i" : "-6.83",
-    	"input_tp" : "2.55",
-    	"input_lra" : "4.40",
-    	"input_thresh" : "-16.87",
-    	"output_i" : "-23.34",
-    	"output_tp" : "-11.73",
-    	"output_lra" : "4.30",
-    	"output_thresh" : "-33.37",
-    	"normalization_type" : "dynamic",
-    	"target_offset" : "-0.66"
-    }
-    [out#0/null @ 0xc8f448300] video:0KiB audio:244683KiB"#;
-
-        let path = PathBuf::from("/test/Habstrakt - Eat Me.mp3");
-        let result = extract_loudnorm_json(stderr, &path);
-
-        assert!(result.is_ok(), "Should successfully parse loudnorm JSON");
-        let loudnorm = result.unwrap();
-        assert_eq!(loudnorm.input_i, "-6.83");
-        assert_eq!(loudnorm.input_tp, "2.55");
-    }
-
-    /// Test JSON extraction with standard output (no problematic metadata)
+    fn lufs_target_already_reached_needs_no_gain() {
+        let target = GainTarget {
+            target_lufs: Some(-9.0),
+            ..GainTarget::default()
+        };
+        let a = analyze("a.mp3", -8.0, -6.0, target);
+        assert_eq!(a.gain_method, GainMethod::None);
+    }
+
+    #[test]
+    fn lufs_budget_uses_native_steps_for_mp3() {
+        let target = GainTarget {
+            target_lufs: Some(-9.0),
+            ..GainTarget::default()
+        };
+        let a = analyze("a.mp3", -12.5, -8.0, target);
+        assert_eq!(a.gain_method, GainMethod::Mp3Lossless);
+        assert_eq!(a.lossless_gain_steps, 2);
+        assert_eq!(a.limited_by, GainLimit::Loudness);
+    }
+
     #[test]
-    fn test_extract_loudnorm_json_standard() {
-        let stderr = r#"    [Parsed_loudnorm_0 @ 0x12345678]
-    {
-    	"input_i" : "-14.00",
-    	"input_tp" : "-1.00",
-    	"input_lra" : "5.00",
-    	"input_thresh" : "-24.00",
-    	"output_i" : "-24.00",
-    	"output_tp" : "-2.00",
-    	"output_lra" : "5.00",
-    	"output_thresh" : "-34.00",
-    	"normalization_type" : "dynamic",
-    	"target_offset" : "0.00"
-    }"#;
-
-        let path = PathBuf::from("/test/normal.mp3");
-        let result = extract_loudnorm_json(stderr, &path);
-
-        assert!(result.is_ok());
-        let loudnorm = result.unwrap();
-        assert_eq!(loudnorm.input_i, "-14.00");
-        assert_eq!(loudnorm.input_tp, "-1.00");
+    fn fixed_gain_off_the_step_grid_reencodes_lossy() {
+        let mut a = analyze("a.mp3", -12.0, -8.0, GainTarget::default());
+        a.apply_fixed_gain(3.0, 3.0, GainLimit::TruePeak);
+        assert_eq!(a.gain_method, GainMethod::Mp3Lossless);
+        assert_eq!(a.lossless_gain_steps, 2);
+
+        a.apply_fixed_gain(2.0, 2.0, GainLimit::TruePeak);
+        assert_eq!(a.gain_method, GainMethod::Mp3Reencode);
+        assert_eq!(a.lossless_gain_steps, 0);
     }
 
     #[test]
-    fn test_parse_stderr_bitrate() {
-        let stderr = "Input #0, mp3, from 'track.mp3':\n  Duration: 00:03:50.32, start: 0.025057, bitrate: 320 kb/s\n  Stream #0:0: Audio: mp3";
-        assert_eq!(parse_stderr_bitrate(stderr), Some(320));
+    fn probe_results_come_from_the_measurement() {
+        let stream = AudioStream {
+            channels: Some(2),
+            sample_rate: Some(44100),
+            language: None,
+        };
+        let sample_format = PcmFormat {
+            bits: 24,
+            float: false,
+        };
+        let m = Measurement {
+            format: Some(AudioFormat::Flac),
+            streams: vec![stream.clone(), stream],
+            sample_format: Some(sample_format),
+            ..measurement(-14.0, -3.0)
+        };
+        let a = analyze_measurement(Path::new("a.flac"), &m, GainTarget::default());
+        assert_eq!(a.format, Some(AudioFormat::Flac));
+        assert_eq!(a.stream_count, 2);
+        assert_eq!(a.sample_format, Some(sample_format));
+        assert!(a.encoder.is_none());
     }
 
     #[test]
-    fn test_parse_stderr_bitrate_na_and_missing() {
+    fn later_mp4_tracks_are_reencoded() {
+        let m = Measurement {
+            stream: 1,
+            format: Some(AudioFormat::Aac),
+            ..measurement(-14.0, -4.5)
+        };
+        let mut a = analyze_measurement(Path::new("a.m4a"), &m, GainTarget::default());
+        assert_eq!(a.gain_method, GainMethod::AacReencode);
+        assert!((a.effective_gain - 4.0).abs() < 1e-9);
+
+        a.apply_fixed_gain(3.0, 3.0, GainLimit::TruePeak);
+        assert_eq!(a.gain_method, GainMethod::AacReencode);
+        assert_eq!(a.lossless_gain_steps, 0);
+    }
+
+    #[test]
+    fn problems_follow_the_thresholds() {
+        let mut a = analyze("a.flac", -14.0, -3.0, GainTarget::default());
+        let dropout = |start_secs, duration_secs| Dropout {
+            start_secs,
+            duration_secs,
+        };
+        a.silence = Silence {
+            leading_secs: 0.5,
+            trailing_secs: 4.0,
+            dropouts: vec![dropout(60.0, 0.3), dropout(90.0, 1.2)],
+            final_level: -75.0,
+        };
+        a.flag_problems(&ProblemThresholds::default());
+        assert_eq!(a.problems, [Problem::TrailingSilence, Problem::Dropouts]);
+        assert_eq!(a.silence.dropouts, [dropout(90.0, 1.2)]);
+
+        let strict = ProblemThresholds {
+            gap_secs: 0.25,
+            abrupt_end_db: -80.0,
+            ..ProblemThresholds::default()
+        };
+        a.flag_problems(&strict);
         assert_eq!(
-            parse_stderr_bitrate("  Duration: 00:03:50.32, start: 0.0, bitrate: N/A\n"),
-            None
+            a.problems,
+            [
+                Problem::LeadingSilence,
+                Problem::TrailingSilence,
+                Problem::Dropouts,
+                Problem::AbruptEnd
+            ]
         );
-        assert_eq!(parse_stderr_bitrate("no duration line here"), None);
     }
 
-    /// Test fallback when marker is not present (older ffmpeg versions)
     #[test]
-    fn test_extract_loudnorm_json_fallback() {
-        let stderr = r#"Some other output
-    {
-    	"input_i" : "-10.00",
-    	"input_tp" : "0.50",
-    	"input_lra" : "3.00",
-    	"input_thresh" : "-20.00",
-    	"output_i" : "-23.00",
-    	"output_tp" : "-10.00",
-    	"output_lra" : "3.00",
-    	"output_thresh" : "-33.00",
-    	"normalization_type" : "dynamic",
-    	"target_offset" : "-1.00"
-    }
-    More output"#;
-
-        let path = PathBuf::from("/test/old_ffmpeg.mp3");
-        let result = extract_loudnorm_json(stderr, &path);
-
-        assert!(result.is_ok());
-        let loudnorm = result.unwrap();
-        assert_eq!(loudnorm.input_i, "-10.00");
-        assert_eq!(loudnorm.input_tp, "0.50");
+    fn over_ceiling_files_are_ignored_without_attenuate() {
+        let a = analyze("a.flac", -7.0, 0.8, GainTarget::default());
+        assert_eq!(a.gain_method, GainMethod::None);
+        assert!(!a.is_attenuation());
+    }
+
+    #[test]
+    fn attenuate_lossless_to_exact_ceiling() {
+        let target = GainTarget {
+            attenuate: true,
+            ..GainTarget::default()
+        };
+        let a = analyze("a.wav", -7.0, 0.8, target);
+        assert_eq!(a.gain_method, GainMethod::Lossless);
+        assert!((a.effective_gain + 1.3).abs() < 1e-9);
+        assert!(a.is_attenuation());
+    }
+
+    #[test]
+    fn attenuate_lossy_rounds_cut_up_to_whole_steps() {
+        let target = GainTarget {
+            attenuate: true,
+            target_lufs: Some(-6.0),
+            ..GainTarget::default()
+        };
+        let a = analyze("a.m4a", -7.0, 1.2, target);
+        assert_eq!(a.gain_method, GainMethod::AacLossless);
+        assert_eq!(a.lossless_gain_steps, -2);
+        assert!((a.effective_gain + 3.0).abs() < 1e-9);
+        assert_eq!(a.limited_by, GainLimit::TruePeak);
+    }
+
+    #[test]
+    fn lossless_method_is_native_for_pcm_and_flac_only() {
+        let mut a = analyze("a.wav", -14.0, -3.0, GainTarget::default());
+        assert_eq!(a.method_label(), "ffmpeg");
+        a.sample_format = Some(PcmFormat {
+            bits: 16,
+            float: false,
+        });
+        assert!(!a.requires_ffmpeg());
+        assert_eq!(a.method_label(), "native");
+
+        a.format = Some(AudioFormat::Flac);
+        assert!(!a.requires_ffmpeg());
+        a.sample_format = Some(PcmFormat {
+            bits: 32,
+            float: false,
+        });
+        assert!(a.requires_ffmpeg());
+
+        a.format = Some(AudioFormat::Alac);
+        a.sample_format = Some(PcmFormat {
+            bits: 16,
+            float: false,
+        });
+        assert!(a.requires_ffmpeg());
+
+        // Journals written before the rename still load.
+        let old: GainMethod = serde_json::from_str("\"ffmpeg_lossless\"").unwrap();
+        assert_eq!(old, GainMethod::Lossless);
+    }
+
+    #[test]
+    fn new_formats_get_their_own_methods() {
+        let target = GainTarget::default();
+        let opus = analyze("a.opus", -14.0, -3.0, target);
+        assert_eq!(opus.gain_method, GainMethod::OpusHeader);
+        assert!((opus.effective_gain - 2.5).abs() < 1e-9);
+        assert!(!opus.requires_ffmpeg());
+        assert!(analyze("a.ogg", -14.0, -3.0, target).requires_reencode());
+        assert_eq!(
+            analyze("a.wv", -14.0, -3.0, target).gain_method,
+            GainMethod::Lossless
+        );
+        for name in ["a.ape", "a.dsf", "a.dff"] {
+            assert!(analyze(name, -14.0, -3.0, target).is_tag_only());
+        }
     }
 }
@@ -1,9 +1,16 @@
 use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
 
+use crate::album::AlbumGrouping;
 use crate::analyzer::{
-    TpTargetMode, DEFAULT_TARGET_TRUE_PEAK, SPLIT_TARGET_TRUE_PEAK_HIGH, SPLIT_TARGET_TRUE_PEAK_LOW,
+    GainTarget, LossyPolicy, ProblemThresholds, TpTargetMode, DEFAULT_ABRUPT_END_THRESHOLD,
+    DEFAULT_DROPOUT_THRESHOLD, DEFAULT_GAP_THRESHOLD, DEFAULT_IMBALANCE_THRESHOLD,
+    DEFAULT_LOSSY_THRESHOLD, DEFAULT_TARGET_TRUE_PEAK, SPLIT_TARGET_TRUE_PEAK_HIGH,
+    SPLIT_TARGET_TRUE_PEAK_LOW,
 };
+use crate::processor::ProcessOptions;
+use crate::report::ReportFormat;
+use crate::silence::MIN_DROPOUT_SECS;
 
 /// Audio loudness analyzer and gain adjustment tool.
 ///
@@ -27,6 +34,62 @@ pub struct Cli {
     #[arg(long)]
     pub tp_split_bitrate: bool,
 
+    /// Integrated loudness target in LUFS (e.g. -9). Gain is chosen to reach it
+    /// but never pushes True Peak past the ceiling.
+    #[arg(long, value_name = "LUFS", allow_hyphen_values = true)]
+    pub target_lufs: Option<f64>,
+
+    /// Album mode: give every file in a group the same gain, limited by the
+    /// group's loudest True Peak, so level differences between tracks are kept.
+    /// Groups are formed by parent directory or by album artist + album tags.
+    #[arg(long, value_name = "GROUPING", value_enum)]
+    pub album: Option<AlbumGrouping>,
+
+    /// Attenuate files whose True Peak is above the ceiling down to it
+    /// (MP3/AAC in native 1.5 dB steps, rounded to stay under the ceiling).
+    #[arg(long)]
+    pub attenuate: bool,
+
+    /// MP3/AAC trade-off between native steps and re-encoding
+    /// (default: prefer-native)
+    #[arg(long, value_enum, value_name = "POLICY")]
+    pub lossy_policy: Option<LossyPolicy>,
+
+    /// With --lossy-policy max-loudness, the most gain (dB) native steps may
+    /// give up before re-encoding instead (default: 0.5)
+    #[arg(long, value_name = "DB")]
+    pub lossy_threshold: Option<f64>,
+
+    /// Flag files whose left and right channels differ in loudness by more
+    /// than this many LU (default: 1.5)
+    #[arg(long, value_name = "LU")]
+    pub imbalance_threshold: Option<f64>,
+
+    /// Flag files with more than this many seconds of leading or trailing
+    /// silence (default: 2)
+    #[arg(long, value_name = "SECS")]
+    pub gap_threshold: Option<f64>,
+
+    /// Flag silences of at least this many seconds inside a file as
+    /// dropouts (default: 0.5, minimum 0.1)
+    #[arg(long, value_name = "SECS", value_parser = parse_dropout_threshold)]
+    pub dropout_threshold: Option<f64>,
+
+    /// Flag files whose final 10 ms are louder than this, i.e. that are cut
+    /// off instead of fading out (default: -40)
+    #[arg(long, value_name = "DBFS", allow_hyphen_values = true)]
+    pub abrupt_end_threshold: Option<f64>,
+
+    /// Audio stream to measure and adjust in files with several, counted
+    /// from 0 (default: 0); the other streams are copied unchanged
+    #[arg(long, value_name = "N")]
+    pub audio_stream: Option<usize>,
+
+    /// Write ReplayGain/R128 tags with the computed gain instead of modifying
+    /// audio (Vorbis comments, ID3v2 TXXX or iTunes freeform atoms)
+    #[arg(long, conflicts_with_all = ["reencode", "analyze_only"])]
+    pub tag_only: bool,
+
     /// Apply lossless gain adjustment (default in non-interactive mode)
     #[arg(long, conflicts_with = "no_lossless")]
     pub lossless: bool,
@@ -55,10 +118,52 @@ pub struct Cli {
     #[arg(long)]
     pub no_report: bool,
 
+    /// Report format (default: csv). Every format lists each scanned file
+    /// with its status, analysis error or processing outcome
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub format: Option<ReportFormat>,
+
+    /// Only list problem files in the report: failed analyses, clipping,
+    /// channel imbalance, long gaps, dropouts and abrupt endings
+    #[arg(long, conflicts_with = "no_report")]
+    pub problems_only: bool,
+
     /// Analyze files only, do not modify anything
     #[arg(long)]
     pub analyze_only: bool,
 
+    /// Re-measure processed files and flag any that overshoot the ceiling
+    /// or missed the planned gain
+    #[arg(long, conflicts_with_all = ["analyze_only", "tag_only"])]
+    pub verify: bool,
+
+    /// Measure each re-encode and retry with less gain until its True Peak
+    /// is within the ceiling
+    #[arg(long, requires = "reencode")]
+    pub compensate_overshoot: bool,
+
+    /// Apply TPDF dither when gain-adjusted lossless files are written back
+    /// at 16 bits or less
+    #[arg(long, conflicts_with_all = ["analyze_only", "tag_only"])]
+    pub dither: bool,
+
+    /// With --verify, restore overshooting files from their backup (or by
+    /// inverting native MP3/AAC gain)
+    #[arg(long, requires = "verify")]
+    pub rollback_overshoot: bool,
+
+    /// Ignore the analysis cache: re-measure every file and leave the cache untouched
+    #[arg(long, conflicts_with_all = ["rebuild_cache", "prune_cache"])]
+    pub no_cache: bool,
+
+    /// Re-measure every file and rewrite the analysis cache from scratch
+    #[arg(long)]
+    pub rebuild_cache: bool,
+
+    /// Drop analysis cache entries for files that no longer exist
+    #[arg(long)]
+    pub prune_cache: bool,
+
     /// Skip checking for new versions on startup
     #[arg(long)]
     pub no_update_check: bool,
@@ -75,9 +180,28 @@ impl Cli {
             || self.backup.is_some()
             || self.report.is_some()
             || self.no_report
+            || self.format.is_some()
             || self.analyze_only
+            || self.verify
+            || self.compensate_overshoot
+            || self.dither
             || self.tp_target.is_some()
             || self.tp_split_bitrate
+            || self.target_lufs.is_some()
+            || self.album.is_some()
+            || self.attenuate
+            || self.lossy_policy.is_some()
+            || self.lossy_threshold.is_some()
+            || self.imbalance_threshold.is_some()
+            || self.gap_threshold.is_some()
+            || self.dropout_threshold.is_some()
+            || self.abrupt_end_threshold.is_some()
+            || self.problems_only
+            || self.audio_stream.is_some()
+            || self.tag_only
+            || self.no_cache
+            || self.rebuild_cache
+            || self.prune_cache
     }
 
     /// Resolve the True Peak target mode from CLI flags.
@@ -95,6 +219,39 @@ impl Cli {
         }
     }
 
+    /// Combined gain goal: the TP ceiling plus the optional `--target-lufs`.
+    pub fn gain_target(&self) -> GainTarget {
+        GainTarget {
+            tp_mode: self.tp_mode(),
+            target_lufs: self.target_lufs,
+            attenuate: self.attenuate,
+            lossy_policy: self.lossy_policy.unwrap_or_default(),
+            lossy_threshold: self.lossy_threshold.unwrap_or(DEFAULT_LOSSY_THRESHOLD),
+        }
+    }
+
+    /// Limits above which files are flagged as problem files.
+    pub fn problem_thresholds(&self) -> ProblemThresholds {
+        ProblemThresholds {
+            imbalance: self.imbalance_threshold.unwrap_or(DEFAULT_IMBALANCE_THRESHOLD),
+            gap_secs: self.gap_threshold.unwrap_or(DEFAULT_GAP_THRESHOLD),
+            dropout_secs: self.dropout_threshold.unwrap_or(DEFAULT_DROPOUT_THRESHOLD),
+            abrupt_end_db: self.abrupt_end_threshold.unwrap_or(DEFAULT_ABRUPT_END_THRESHOLD),
+        }
+    }
+
+    /// Audio stream selected with `--audio-stream`.
+    pub fn audio_stream(&self) -> usize {
+        self.audio_stream.unwrap_or(0)
+    }
+
+    pub fn process_options(&self) -> ProcessOptions {
+        ProcessOptions {
+            compensate_overshoot: self.compensate_overshoot,
+            dither: self.dither,
+        }
+    }
+
     /// Whether lossless processing is enabled in non-interactive mode (default: true).
     pub fn lossless_enabled(&self) -> bool {
         !self.no_lossless
@@ -102,7 +259,12 @@ impl Cli {
 
     /// Whether re-encode processing is enabled in non-interactive mode (default: false).
     pub fn reencode_enabled(&self) -> bool {
-        self.reencode && !self.no_reencode
+        self.reencode && !self.no_reencode && self.lossy_policy != Some(LossyPolicy::NeverReencode)
+    }
+
+    /// Report format in non-interactive mode (default: CSV).
+    pub fn report_format(&self) -> ReportFormat {
+        self.format.unwrap_or_default()
     }
 
     /// Whether CSV report should be generated in non-interactive mode (default: true).
@@ -111,10 +273,43 @@ impl Cli {
     }
 }
 
+/// `--dropout-threshold`: shorter silences are never recorded as dropouts,
+/// so a lower value could not take effect.
+fn parse_dropout_threshold(value: &str) -> Result<f64, String> {
+    let secs: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    if secs >= MIN_DROPOUT_SECS {
+        Ok(secs)
+    } else {
+        Err(format!("must be at least {} seconds", MIN_DROPOUT_SECS))
+    }
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Command {
     /// Sort a Rekordbox playlist by Camelot Key then BPM, output as a new XML playlist.
     Rbsort(RbsortArgs),
+    /// Undo a processing run using its journal (.headroom-journal/).
+    Restore(RestoreArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct RestoreArgs {
+    /// Journal file, or a directory whose latest journal is used
+    /// (default: the current directory).
+    #[arg(value_name = "JOURNAL")]
+    pub journal: Option<PathBuf>,
+
+    /// Only restore these files (repeatable). Default: every file in the run.
+    #[arg(long = "file", value_name = "PATH")]
+    pub files: Vec<PathBuf>,
+
+    /// Check backups against the recorded checksums without restoring.
+    #[arg(long)]
+    pub verify: bool,
+
+    /// Restore files even if they changed after the run.
+    #[arg(long)]
+    pub force: bool,
 }
 
 #[derive(Args, Debug)]
@@ -0,0 +1,393 @@
+//! Persistent analysis cache.
+//!
+//! Measurements are stored as JSON lines next to the library, keyed by path
+//! and audio stream with size + mtime as a cheap freshness check. When that
+//! check fails the file's content hash is compared instead, so touched or
+//! renamed files are still served from the cache. Entries from another
+//! measurement engine version are ignored and dropped on save.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::analyzer::{self, Measurement, MEASUREMENT_VERSION};
+
+/// Cache file written into the scan root.
+pub const CACHE_FILE_NAME: &str = ".headroom-cache.jsonl";
+
+/// 64-bit xxh3 of the whole file, as 16 lowercase hex digits.
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Xxh3::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:016x}", hasher.digest()))
+}
+
+/// Identity of a file's current content.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    pub size: u64,
+    pub mtime_ns: u64,
+    pub hash: String,
+}
+
+/// Measurement obtained through the cache, with the fingerprint to record.
+/// `fingerprint` is `None` when the file was measured without a cache.
+pub struct CachedMeasurement {
+    pub measurement: Measurement,
+    pub fingerprint: Option<Fingerprint>,
+    pub hit: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    path: PathBuf,
+    size: u64,
+    mtime_ns: u64,
+    hash: String,
+    engine: u32,
+    #[serde(flatten)]
+    measurement: Measurement,
+}
+
+/// Relative path (see `AnalysisCache::key`) and audio stream.
+type EntryKey = (PathBuf, usize);
+
+pub struct AnalysisCache {
+    file: PathBuf,
+    root: PathBuf,
+    entries: HashMap<EntryKey, CacheEntry>,
+    by_hash: HashMap<(String, usize), EntryKey>,
+}
+
+impl AnalysisCache {
+    /// Empty cache that will be written to `<root>/CACHE_FILE_NAME`.
+    pub fn new(root: &Path) -> Self {
+        let root = std::path::absolute(root).unwrap_or_else(|_| root.to_path_buf());
+        Self {
+            file: root.join(CACHE_FILE_NAME),
+            root,
+            entries: HashMap::new(),
+            by_hash: HashMap::new(),
+        }
+    }
+
+    /// Load the cache for `root`. A missing file yields an empty cache;
+    /// unreadable lines and entries from other engine versions are skipped.
+    pub fn load(root: &Path) -> Result<Self> {
+        let mut cache = Self::new(root);
+        let file = match File::open(&cache.file) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(cache),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to open {}", cache.file.display()))
+            }
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = line.context("Failed to read analysis cache")?;
+            match serde_json::from_str::<CacheEntry>(&line) {
+                Ok(entry) if entry.engine == MEASUREMENT_VERSION => cache.insert(entry),
+                _ => {}
+            }
+        }
+        Ok(cache)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.file
+    }
+
+    /// Cache key: path relative to the cache root where possible, so a moved
+    /// library keeps its cache.
+    fn key(&self, file: &Path) -> PathBuf {
+        let abs = std::path::absolute(file).unwrap_or_else(|_| file.to_path_buf());
+        abs.strip_prefix(&self.root)
+            .map(Path::to_path_buf)
+            .unwrap_or(abs)
+    }
+
+    fn insert(&mut self, entry: CacheEntry) {
+        let stream = entry.measurement.stream;
+        let key = (entry.path.clone(), stream);
+        self.by_hash
+            .insert((entry.hash.clone(), stream), key.clone());
+        self.entries.insert(key, entry);
+    }
+
+    /// Look up `file`, returning its fingerprint and any cached measurement
+    /// of audio stream `stream`.
+    ///
+    /// The content hash is only computed when size or mtime disagree with the
+    /// entry stored under the same path and stream.
+    pub fn lookup(&self, file: &Path, stream: usize) -> Result<(Fingerprint, Option<Measurement>)> {
+        let meta =
+            fs::metadata(file).with_context(|| format!("Failed to stat {}", file.display()))?;
+        let size = meta.len();
+        let mtime_ns = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+
+        if let Some(entry) = self.entries.get(&(self.key(file), stream)) {
+            if entry.size == size && entry.mtime_ns == mtime_ns {
+                let fp = Fingerprint {
+                    size,
+                    mtime_ns,
+                    hash: entry.hash.clone(),
+                };
+                return Ok((fp, Some(entry.measurement.clone())));
+            }
+        }
+
+        let hash = hash_file(file)?;
+        let cached = self
+            .by_hash
+            .get(&(hash.clone(), stream))
+            .and_then(|key| self.entries.get(key))
+            .filter(|e| e.size == size)
+            .map(|e| e.measurement.clone());
+        Ok((
+            Fingerprint {
+                size,
+                mtime_ns,
+                hash,
+            },
+            cached,
+        ))
+    }
+
+    /// Return the cached measurement of audio stream `stream` of `file`,
+    /// measuring and probing it on a miss.
+    /// Takes `&self` so lookups can run in parallel; results are stored
+    /// afterwards with `record`.
+    pub fn measure(&self, file: &Path, stream: usize) -> Result<CachedMeasurement> {
+        let (fingerprint, cached) = self.lookup(file, stream)?;
+        let hit = cached.is_some();
+        let measurement = match cached {
+            Some(m) => m,
+            None => analyzer::measure_source(file, stream)?,
+        };
+        Ok(CachedMeasurement {
+            measurement,
+            fingerprint: Some(fingerprint),
+            hit,
+        })
+    }
+
+    /// Record the measurement for `file` under its current fingerprint.
+    pub fn record(&mut self, file: &Path, fingerprint: Fingerprint, measurement: Measurement) {
+        let key = self.key(file);
+        self.insert(CacheEntry {
+            path: key,
+            size: fingerprint.size,
+            mtime_ns: fingerprint.mtime_ns,
+            hash: fingerprint.hash,
+            engine: MEASUREMENT_VERSION,
+            measurement,
+        });
+    }
+
+    /// Drop the entries for every stream of `file`. Needed after headroom
+    /// modifies a file: replacement preserves mtime, and native gain
+    /// preserves size too.
+    pub fn forget(&mut self, file: &Path) {
+        let path = self.key(file);
+        self.entries.retain(|(p, _), _| *p != path);
+        self.by_hash.retain(|_, (p, _)| *p != path);
+    }
+
+    /// Drop entries whose file no longer exists. Returns the number removed.
+    pub fn prune(&mut self) -> usize {
+        let before = self.entries.len();
+        let root = self.root.clone();
+        self.entries
+            .retain(|(path, _), _| root.join(path).is_file());
+        let entries = &self.entries;
+        self.by_hash.retain(|_, key| entries.contains_key(key));
+        before - self.entries.len()
+    }
+
+    /// Write the cache atomically (temp file + rename).
+    pub fn save(&self) -> Result<()> {
+        let tmp = self.file.with_extension("jsonl.tmp");
+        {
+            let file =
+                File::create(&tmp).with_context(|| format!("Failed to write {}", tmp.display()))?;
+            let mut writer = BufWriter::new(file);
+            let mut entries: Vec<&CacheEntry> = self.entries.values().collect();
+            entries.sort_by(|a, b| {
+                (&a.path, a.measurement.stream).cmp(&(&b.path, b.measurement.stream))
+            });
+            for entry in entries {
+                serde_json::to_writer(&mut writer, entry)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush().context("Failed to flush analysis cache")?;
+        }
+        fs::rename(&tmp, &self.file)
+            .with_context(|| format!("Failed to write {}", self.file.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipping::Clipping;
+    use crate::scanner::AudioFormat;
+    use crate::silence::Silence;
+
+    const M: Measurement = Measurement {
+        input_i: -9.5,
+        input_tp: -1.25,
+        bitrate_kbps: Some(320),
+        duration_secs: 180.0,
+        channels: Vec::new(),
+        channel_imbalance: Some(0.25),
+        lra: Some(6.0),
+        max_short_term: Some(-8.0),
+        max_momentary: Some(-6.5),
+        sample_peak: -1.5,
+        dc_offset: 0.0,
+        stream: 0,
+        clipping: Clipping {
+            clip_events: 0,
+            clipped_samples: 0,
+            inter_sample_overs: 0,
+            events: Vec::new(),
+        },
+        silence: Silence {
+            leading_secs: 0.0,
+            trailing_secs: 0.5,
+            dropouts: Vec::new(),
+            final_level: -75.0,
+        },
+        format: Some(AudioFormat::Mp3),
+        streams: Vec::new(),
+        encoder: None,
+        sample_format: None,
+    };
+
+    fn setup() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let track = dir.path().join("track.mp3");
+        fs::write(&track, b"not really audio").unwrap();
+        (dir, track)
+    }
+
+    #[test]
+    fn miss_then_hit_after_save_and_load() {
+        let (dir, track) = setup();
+        let mut cache = AnalysisCache::load(dir.path()).unwrap();
+        let (fp, cached) = cache.lookup(&track, 0).unwrap();
+        assert!(cached.is_none());
+        cache.record(&track, fp, M);
+        cache.save().unwrap();
+
+        let cache = AnalysisCache::load(dir.path()).unwrap();
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.lookup(&track, 0).unwrap().1, Some(M));
+    }
+
+    #[test]
+    fn renamed_file_hits_by_content_hash() {
+        let (dir, track) = setup();
+        let mut cache = AnalysisCache::new(dir.path());
+        let (fp, _) = cache.lookup(&track, 0).unwrap();
+        cache.record(&track, fp, M);
+
+        let moved = dir.path().join("renamed.mp3");
+        fs::rename(&track, &moved).unwrap();
+        assert_eq!(cache.lookup(&moved, 0).unwrap().1, Some(M));
+    }
+
+    #[test]
+    fn modified_content_misses() {
+        let (dir, track) = setup();
+        let mut cache = AnalysisCache::new(dir.path());
+        let (fp, _) = cache.lookup(&track, 0).unwrap();
+        cache.record(&track, fp, M);
+
+        fs::write(&track, b"different bytes now").unwrap();
+        assert!(cache.lookup(&track, 0).unwrap().1.is_none());
+    }
+
+    #[test]
+    fn streams_are_cached_side_by_side() {
+        let (dir, track) = setup();
+        let mut cache = AnalysisCache::new(dir.path());
+        let (fp, _) = cache.lookup(&track, 0).unwrap();
+        cache.record(&track, fp, M);
+
+        let (fp, cached) = cache.lookup(&track, 1).unwrap();
+        assert!(cached.is_none());
+        let second = Measurement { stream: 1, ..M };
+        cache.record(&track, fp, second.clone());
+        assert_eq!(cache.lookup(&track, 0).unwrap().1, Some(M));
+        assert_eq!(cache.lookup(&track, 1).unwrap().1, Some(second));
+
+        cache.forget(&track);
+        assert!(cache.entries.is_empty() && cache.by_hash.is_empty());
+    }
+
+    #[test]
+    fn other_engine_versions_are_ignored() {
+        let (dir, track) = setup();
+        let entry = CacheEntry {
+            path: PathBuf::from("track.mp3"),
+            size: 16,
+            mtime_ns: 0,
+            hash: hash_file(&track).unwrap(),
+            engine: MEASUREMENT_VERSION + 1,
+            measurement: M,
+        };
+        fs::write(
+            dir.path().join(CACHE_FILE_NAME),
+            format!("{}\nnot json\n", serde_json::to_string(&entry).unwrap()),
+        )
+        .unwrap();
+
+        let cache = AnalysisCache::load(dir.path()).unwrap();
+        assert_eq!(cache.entries.len(), 0);
+    }
+
+    #[test]
+    fn prune_removes_missing_files() {
+        let (dir, track) = setup();
+        let mut cache = AnalysisCache::new(dir.path());
+        let (fp, _) = cache.lookup(&track, 0).unwrap();
+        cache.record(&track, fp, M);
+
+        fs::remove_file(&track).unwrap();
+        assert_eq!(cache.prune(), 1);
+        assert_eq!(cache.entries.len(), 0);
+    }
+
+    #[test]
+    fn forgotten_file_misses_despite_same_size_and_mtime() {
+        let (dir, track) = setup();
+        let mut cache = AnalysisCache::new(dir.path());
+        let (fp, _) = cache.lookup(&track, 0).unwrap();
+        cache.record(&track, fp, M);
+
+        cache.forget(&track);
+        assert!(cache.lookup(&track, 0).unwrap().1.is_none());
+    }
+}
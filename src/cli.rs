@@ -4,21 +4,34 @@ use console::{style, Style};
 use dialoguer::{theme::ColorfulTheme, Confirm};
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
-use crate::analyzer::{self, AudioAnalysis, TpTargetMode};
+use crate::album::{self, AlbumGrouping};
+use crate::analyzer::{
+    self, AnalysisFailure, AudioAnalysis, GainTarget, LossyPolicy, ProblemThresholds,
+    TpTargetMode,
+};
 use crate::args::{Cli, Command};
-use crate::processor;
+use crate::cache::{AnalysisCache, CachedMeasurement};
+use crate::decoder::AudioStream;
+use crate::journal::{self, Journal, JournalAction, JournalEntry};
+use crate::processor::{self, ProcessOptions};
 use crate::rbsort;
-use crate::report::{self, AnalysisSummary};
-use crate::scanner;
+use crate::report::{self, AnalysisSummary, ReportFormat, RunResults};
+use crate::restore;
+use crate::scanner::{self, AudioFormat};
+use crate::tagger;
 use crate::updater;
+use crate::verify::{self, Verification, VerifyOutcome};
 
 pub fn run() -> Result<()> {
     let cli = Cli::parse();
 
-    if let Some(Command::Rbsort(args)) = &cli.command {
-        return rbsort::run(args);
+    match &cli.command {
+        Some(Command::Rbsort(args)) => return rbsort::run(args),
+        Some(Command::Restore(args)) => return restore::run(args),
+        None => {}
     }
 
     print_banner();
@@ -27,15 +40,13 @@ pub fn run() -> Result<()> {
     // last so the network call never delays startup (issue #46).
     let update_check = (!cli.no_update_check).then(updater::spawn_check);
 
-    analyzer::check_ffmpeg()?;
-
-    let tp_mode = cli.tp_mode();
-    print_tp_target_banner(tp_mode);
+    let target = cli.gain_target();
+    print_target_banner(target);
 
     let result = if cli.is_non_interactive() {
-        run_scriptable(&cli, tp_mode)
+        run_scriptable(&cli, target)
     } else {
-        run_interactive(tp_mode)
+        run_interactive(target)
     };
 
     if let Some(handle) = update_check {
@@ -45,8 +56,8 @@ pub fn run() -> Result<()> {
     result
 }
 
-fn print_tp_target_banner(tp_mode: TpTargetMode) {
-    match tp_mode {
+fn print_target_banner(target: GainTarget) {
+    match target.tp_mode {
         TpTargetMode::Uniform(t) => {
             println!(
                 "{} TP target: {} dBTP (uniform delivery ceiling, AES TD1008 §7B)",
@@ -63,9 +74,37 @@ fn print_tp_target_banner(tp_mode: TpTargetMode) {
             );
         }
     }
+
+    if let Some(lufs) = target.target_lufs {
+        println!(
+            "{} Loudness target: {} LUFS (gain capped by the TP ceiling)",
+            style("▸").cyan(),
+            style(format!("{:+.1}", lufs)).bold(),
+        );
+    }
+
+    if target.attenuate {
+        println!(
+            "{} Attenuation: files above the ceiling are turned down to it",
+            style("▸").cyan(),
+        );
+    }
+
+    match target.lossy_policy {
+        LossyPolicy::PreferNative => {}
+        LossyPolicy::MaxLoudness => println!(
+            "{} Lossy policy: max loudness (re-encode when native steps lose > {:.1} dB)",
+            style("▸").cyan(),
+            target.lossy_threshold,
+        ),
+        LossyPolicy::NeverReencode => println!(
+            "{} Lossy policy: never re-encode (native steps only)",
+            style("▸").cyan(),
+        ),
+    }
 }
 
-fn run_interactive(tp_mode: TpTargetMode) -> Result<()> {
+fn run_interactive(target: GainTarget) -> Result<()> {
     let target_dir = std::env::current_dir().context("Failed to get current directory")?;
 
     println!(
@@ -91,43 +130,70 @@ fn run_interactive(tp_mode: TpTargetMode) -> Result<()> {
         style(files.len()).cyan()
     );
 
-    let all_analyses = analyze_files(&files, tp_mode)?;
+    let mut cache = open_cache(&target_dir, CacheOptions::default());
+    let (all_analyses, failures) = analyze_files(
+        &files,
+        target,
+        &ProblemThresholds::default(),
+        0,
+        cache.as_mut(),
+    )?;
+    report::print_imbalance_report(&all_analyses);
+    report::print_silence_report(&all_analyses);
 
     let summary = AnalysisSummary::from_analyses(&all_analyses);
 
-    if !summary.has_processable() {
+    let entries = if summary.has_processable() {
+        report::print_analysis_report(&all_analyses, target);
+        prompt_and_process(&summary, &all_analyses, &target_dir)?
+    } else {
         println!(
             "\n{} No files with enough headroom found.",
             style("ℹ").blue()
         );
         println!("  All files are already at or above the target ceiling.");
-        return Ok(());
-    }
-
-    report::print_analysis_report(&all_analyses, tp_mode);
-
-    let processable_analyses: Vec<_> = all_analyses
-        .iter()
-        .filter(|a| a.has_headroom())
-        .collect();
-
-    let csv_path = report::generate_csv(&processable_analyses, &target_dir, None)?;
+        report::print_clipping_report(&all_analyses);
+        None
+    };
+    forget_modified(cache.as_mut(), entries.as_deref().unwrap_or_default());
+
+    let results = RunResults {
+        files: &files,
+        analyses: &all_analyses,
+        failures: &failures,
+        entries: entries.as_deref().unwrap_or_default(),
+        verifications: &[],
+        target,
+        modify_requested: entries.is_some(),
+        problems_only: false,
+    };
+    let csv_path = report::generate_report(&results, ReportFormat::Csv, &target_dir, None)?;
     println!(
         "{} Report saved: {}",
         style("✓").green(),
         csv_path.display()
     );
 
+    Ok(())
+}
+
+/// Interactive prompts and processing. Returns `None` when the user declined
+/// processing, otherwise the journal entries of the files touched.
+fn prompt_and_process(
+    summary: &AnalysisSummary,
+    all_analyses: &[AudioAnalysis],
+    target_dir: &Path,
+) -> Result<Option<Vec<JournalEntry>>> {
     let has_lossless = summary.total_lossless() > 0;
     let has_reencode = summary.total_reencode() > 0;
 
-    if has_lossless && !prompt_lossless_processing(&summary)? {
+    if has_lossless && !prompt_lossless_processing(summary)? {
         println!("Done. No files were modified.");
-        return Ok(());
+        return Ok(None);
     }
 
     let allow_reencode = if has_reencode {
-        prompt_reencode_processing(&summary)?
+        prompt_reencode_processing(summary)?
     } else {
         false
     };
@@ -138,7 +204,7 @@ fn run_interactive(tp_mode: TpTargetMode) -> Result<()> {
         .interact()?;
 
     let backup_dir = if create_backup {
-        let dir = processor::create_backup_dir(&target_dir)?;
+        let dir = processor::create_backup_dir(target_dir)?;
         println!("{} Backup directory: {}", style("✓").green(), dir.display());
         Some(dir)
     } else {
@@ -147,22 +213,30 @@ fn run_interactive(tp_mode: TpTargetMode) -> Result<()> {
 
     let files_to_process: Vec<_> = all_analyses
         .iter()
-        .filter(|a| a.has_headroom() && (!a.requires_reencode() || allow_reencode))
+        .filter(|a| a.has_headroom() && !a.is_tag_only())
+        .filter(|a| !a.requires_reencode() || allow_reencode)
         .collect();
 
     if files_to_process.is_empty() {
         println!("No files to process.");
-        return Ok(());
+        return Ok(Some(Vec::new()));
     }
 
-    process_files(&files_to_process, &target_dir, backup_dir.as_deref())?;
+    ensure_ffmpeg_for(&files_to_process)?;
+    let entries = process_files(
+        &files_to_process,
+        target_dir,
+        backup_dir.as_deref(),
+        ProcessOptions::default(),
+    );
+    save_journal(target_dir, &entries);
 
     print_final_summary(&files_to_process);
 
-    Ok(())
+    Ok(Some(entries))
 }
 
-fn run_scriptable(cli: &Cli, tp_mode: TpTargetMode) -> Result<()> {
+fn run_scriptable(cli: &Cli, target: GainTarget) -> Result<()> {
     let (files, base_dir) = if cli.paths.is_empty() {
         let cwd = std::env::current_dir().context("Failed to get current directory")?;
         (scanner::scan_audio_files(&cwd), cwd)
@@ -189,41 +263,99 @@ fn run_scriptable(cli: &Cli, tp_mode: TpTargetMode) -> Result<()> {
         style(files.len()).cyan()
     );
 
-    let all_analyses = analyze_files(&files, tp_mode)?;
+    let mut cache = open_cache(
+        &base_dir,
+        CacheOptions {
+            disabled: cli.no_cache,
+            rebuild: cli.rebuild_cache,
+            prune: cli.prune_cache,
+        },
+    );
+    let (mut all_analyses, failures) = analyze_files(
+        &files,
+        target,
+        &cli.problem_thresholds(),
+        cli.audio_stream(),
+        cache.as_mut(),
+    )?;
+    report::print_imbalance_report(&all_analyses);
+    report::print_silence_report(&all_analyses);
+
+    if let Some(grouping) = cli.album {
+        apply_album_mode(&mut all_analyses, grouping, target);
+    }
 
     let summary = AnalysisSummary::from_analyses(&all_analyses);
+    let format = cli.report_format();
+    let explicit_path = cli.report.as_ref().and_then(|p| {
+        if p.as_os_str().is_empty() {
+            None
+        } else {
+            Some(p.as_path())
+        }
+    });
 
-    if !summary.has_processable() {
+    let mut entries = if summary.has_processable() {
+        report::print_analysis_report(&all_analyses, target);
+        modify_files(cli, &all_analyses, &base_dir)?
+    } else {
         println!(
             "\n{} No files with enough headroom found.",
             style("ℹ").blue()
         );
-        return Ok(());
-    }
-
-    report::print_analysis_report(&all_analyses, tp_mode);
-
-    let processable_analyses: Vec<_> = all_analyses.iter().filter(|a| a.has_headroom()).collect();
+        report::print_clipping_report(&all_analyses);
+        Vec::new()
+    };
+    let verifications = if cli.verify {
+        verify_processed(&mut entries, &all_analyses, cli.rollback_overshoot)
+    } else {
+        Vec::new()
+    };
+    save_journal(&base_dir, &entries);
+    forget_modified(cache.as_mut(), &entries);
 
+    // The report covers the whole run, so it is written last.
     if cli.report_enabled() {
-        let explicit_path = cli.report.as_ref().and_then(|p| {
-            if p.as_os_str().is_empty() {
-                None
-            } else {
-                Some(p.as_path())
-            }
-        });
-        let csv_path = report::generate_csv(&processable_analyses, &base_dir, explicit_path)?;
-        println!(
-            "{} Report saved: {}",
-            style("✓").green(),
-            csv_path.display()
-        );
+        let results = RunResults {
+            files: &files,
+            analyses: &all_analyses,
+            failures: &failures,
+            entries: &entries,
+            verifications: &verifications,
+            target,
+            modify_requested: !cli.analyze_only,
+            problems_only: cli.problems_only,
+        };
+        let path = report::generate_report(&results, format, &base_dir, explicit_path)?;
+        println!("{} Report saved: {}", style("✓").green(), path.display());
     }
 
+    Ok(())
+}
+
+/// Apply the selected action (nothing, tags, or gain) to the processable
+/// files. Returns the journal entries of the files touched; the caller
+/// saves the journal.
+fn modify_files(
+    cli: &Cli,
+    all_analyses: &[AudioAnalysis],
+    base_dir: &Path,
+) -> Result<Vec<JournalEntry>> {
     if cli.analyze_only {
         println!("{} Analyze-only mode; no files modified.", style("ℹ").blue());
-        return Ok(());
+        return Ok(Vec::new());
+    }
+
+    if cli.tag_only {
+        let files_to_tag: Vec<_> = all_analyses.iter().filter(|a| a.has_headroom()).collect();
+        let backup_dir = prepare_backup_dir(cli, base_dir)?;
+        let entries = tag_files(&files_to_tag, all_analyses, base_dir, backup_dir.as_deref());
+        println!(
+            "\n{} Done! {} files tagged; audio left untouched.",
+            style("✓").green().bold(),
+            files_to_tag.len()
+        );
+        return Ok(entries);
     }
 
     let lossless_on = cli.lossless_enabled();
@@ -232,7 +364,7 @@ fn run_scriptable(cli: &Cli, tp_mode: TpTargetMode) -> Result<()> {
     let files_to_process: Vec<_> = all_analyses
         .iter()
         .filter(|a| {
-            if !a.has_headroom() {
+            if !a.has_headroom() || a.is_tag_only() {
                 return false;
             }
             if a.requires_reencode() {
@@ -245,25 +377,142 @@ fn run_scriptable(cli: &Cli, tp_mode: TpTargetMode) -> Result<()> {
 
     if files_to_process.is_empty() {
         println!("{} No files to process with current flags.", style("ℹ").blue());
-        return Ok(());
+        return Ok(Vec::new());
     }
 
-    let backup_dir = if let Some(path) = &cli.backup {
-        let dir = if path.as_os_str().is_empty() {
-            processor::create_backup_dir(&base_dir)?
-        } else {
-            processor::ensure_backup_dir(path)?
+    let backup_dir = prepare_backup_dir(cli, base_dir)?;
+
+    ensure_ffmpeg_for(&files_to_process)?;
+    let entries = process_files(
+        &files_to_process,
+        base_dir,
+        backup_dir.as_deref(),
+        cli.process_options(),
+    );
+
+    print_final_summary(&files_to_process);
+
+    Ok(entries)
+}
+
+/// `--verify`: re-measure every successfully processed file, print the
+/// ones that missed their target and roll back overshoots if asked.
+fn verify_processed(
+    entries: &mut [JournalEntry],
+    all_analyses: &[AudioAnalysis],
+    rollback: bool,
+) -> Vec<Verification> {
+    // Journal paths are absolute.
+    let analyses: HashMap<PathBuf, &AudioAnalysis> = all_analyses
+        .iter()
+        .map(|a| (std::path::absolute(&a.path).unwrap_or_else(|_| a.path.clone()), a))
+        .collect();
+    let mut to_verify: Vec<(&mut JournalEntry, &AudioAnalysis)> = entries
+        .iter_mut()
+        .filter(|e| e.error.is_none())
+        .filter_map(|e| analyses.get(&e.path).map(|a| (e, *a)))
+        .collect();
+    if to_verify.is_empty() {
+        return Vec::new();
+    }
+
+    let pb = make_progress_bar(to_verify.len(), "Verifying...");
+    let verifications: Vec<Verification> = to_verify
+        .par_iter_mut()
+        .map(|(entry, analysis)| {
+            let verification = verify::verify_entry(entry, analysis, rollback);
+            pb.inc(1);
+            verification
+        })
+        .collect();
+    pb.finish_and_clear();
+
+    let mut flagged = 0;
+    for v in &verifications {
+        let detail = match (v.outcome, v.measured_tp, v.gain_error) {
+            (VerifyOutcome::Ok, _, _) => continue,
+            (VerifyOutcome::Error, _, _) => v.error.clone().unwrap_or_default(),
+            (outcome, Some(tp), Some(gain_error)) => format!(
+                "{} (TP {:+.2} dBTP, gain off by {:+.2} dB){}",
+                outcome.label(),
+                tp,
+                gain_error,
+                v.error.as_deref().map(|e| format!("; {}", e)).unwrap_or_default()
+            ),
+            (outcome, _, _) => outcome.label().to_string(),
         };
-        println!("{} Backup directory: {}", style("✓").green(), dir.display());
-        Some(dir)
+        flagged += 1;
+        println!(
+            "{} {}: {}",
+            style("⚠").yellow(),
+            v.path.display(),
+            detail
+        );
+    }
+
+    if flagged == 0 {
+        println!(
+            "{} Verified {} files: all within target",
+            style("✓").green(),
+            verifications.len()
+        );
     } else {
-        None
+        println!(
+            "{} Verified {} files: {} flagged",
+            style("⚠").yellow(),
+            verifications.len(),
+            flagged
+        );
+    }
+    verifications
+}
+
+fn prepare_backup_dir(cli: &Cli, base_dir: &Path) -> Result<Option<PathBuf>> {
+    let Some(path) = &cli.backup else {
+        return Ok(None);
+    };
+    let dir = if path.as_os_str().is_empty() {
+        processor::create_backup_dir(base_dir)?
+    } else {
+        processor::ensure_backup_dir(path)?
     };
+    println!("{} Backup directory: {}", style("✓").green(), dir.display());
+    Ok(Some(dir))
+}
 
-    process_files(&files_to_process, &base_dir, backup_dir.as_deref())?;
+fn apply_album_mode(analyses: &mut [AudioAnalysis], grouping: AlbumGrouping, target: GainTarget) {
+    let result = album::apply_album_gain(analyses, grouping, target);
 
-    print_final_summary(&files_to_process);
+    println!(
+        "\n{} Album mode: {} groups",
+        style("▸").cyan(),
+        style(result.groups.len()).cyan()
+    );
+    for group in &result.groups {
+        println!(
+            "  {} {} ({} tracks): {:+.1} dB [{}]",
+            style("•").dim(),
+            group.name,
+            group.tracks,
+            group.gain,
+            group.limited_by.label()
+        );
+    }
+    if result.ungrouped > 0 {
+        println!(
+            "{} {} files have no album tag; using per-track gain",
+            style("⚠").yellow(),
+            result.ungrouped
+        );
+    }
+}
 
+/// Analysis decodes in-process, so ffmpeg is only required once a selected
+/// file actually needs it for processing.
+fn ensure_ffmpeg_for(files: &[&AudioAnalysis]) -> Result<()> {
+    if files.iter().any(|a| a.requires_ffmpeg()) {
+        processor::check_ffmpeg()?;
+    }
     Ok(())
 }
 
@@ -296,16 +545,26 @@ fn print_final_summary(files_to_process: &[&AudioAnalysis]) {
     let summary = AnalysisSummary::from_iter(files_to_process.iter().copied());
 
     for (count, label) in [
-        (summary.lossless_count, "lossless files (ffmpeg)"),
+        (summary.lossless_count, "lossless files"),
         (summary.mp3_lossless_count, "MP3 files (native, lossless)"),
         (summary.aac_lossless_count, "AAC/M4A files (native, lossless)"),
         (summary.mp3_reencode_count, "MP3 files (re-encoded)"),
         (summary.aac_reencode_count, "AAC/M4A files (re-encoded)"),
+        (summary.opus_count, "Opus files (header gain, lossless)"),
+        (summary.vorbis_reencode_count, "Ogg Vorbis files (re-encoded)"),
     ] {
         if count > 0 {
             println!("  {} {} {}", style("•").dim(), count, label);
         }
     }
+
+    if summary.attenuation_count > 0 {
+        println!(
+            "  {} {} of these attenuated to the ceiling",
+            style("•").dim(),
+            summary.attenuation_count
+        );
+    }
 }
 
 fn prompt_lossless_processing(summary: &AnalysisSummary) -> Result<bool> {
@@ -326,6 +585,9 @@ fn prompt_lossless_processing(summary: &AnalysisSummary) -> Result<bool> {
             summary.aac_lossless_count
         ));
     }
+    if summary.opus_count > 0 {
+        prompt_parts.push(format!("{} Opus (header gain)", summary.opus_count));
+    }
 
     let prompt = format!(
         "Apply lossless gain adjustment to {} files?",
@@ -347,6 +609,9 @@ fn prompt_reencode_processing(summary: &AnalysisSummary) -> Result<bool> {
     if summary.aac_reencode_count > 0 {
         reencode_parts.push(format!("{} AAC/M4A", summary.aac_reencode_count));
     }
+    if summary.vorbis_reencode_count > 0 {
+        reencode_parts.push(format!("{} Ogg Vorbis", summary.vorbis_reencode_count));
+    }
 
     println!(
         "\n{} {} files have headroom but require re-encoding for precise gain.",
@@ -400,15 +665,107 @@ fn make_progress_bar(len: usize, label: &str) -> ProgressBar {
     pb
 }
 
-fn analyze_files(files: &[PathBuf], tp_mode: TpTargetMode) -> Result<Vec<AudioAnalysis>> {
+#[derive(Default)]
+struct CacheOptions {
+    disabled: bool,
+    rebuild: bool,
+    prune: bool,
+}
+
+/// Open the analysis cache stored in `root`. Cache problems never abort a
+/// run: on failure we warn and analyze without it.
+fn open_cache(root: &Path, options: CacheOptions) -> Option<AnalysisCache> {
+    if options.disabled {
+        return None;
+    }
+    if options.rebuild {
+        return Some(AnalysisCache::new(root));
+    }
+
+    let mut cache = match AnalysisCache::load(root) {
+        Ok(cache) => cache,
+        Err(e) => {
+            println!("{} Ignoring analysis cache: {:#}", style("⚠").yellow(), e);
+            return None;
+        }
+    };
+
+    if options.prune {
+        let removed = cache.prune();
+        println!(
+            "{} Pruned {} stale cache entries",
+            style("✓").green(),
+            removed
+        );
+    }
+
+    Some(cache)
+}
+
+/// Files are processed as what their content says they are; point out a
+/// file whose extension disagrees.
+fn warn_extension_mismatch(file: &Path, format: Option<AudioFormat>) {
+    if let Some(format) = scanner::extension_mismatch(file, format) {
+        println!(
+            "{} {} contains {} audio despite its extension; processing it as such",
+            style("⚠").yellow(),
+            file.display(),
+            format.label()
+        );
+    }
+}
+
+/// List the audio streams of a multi-stream file, marking the measured one.
+fn print_streams(file: &Path, streams: &[AudioStream], selected: usize) {
+    println!(
+        "{} {} has {} audio streams:",
+        style("ℹ").blue(),
+        file.display(),
+        streams.len()
+    );
+    for (i, stream) in streams.iter().enumerate() {
+        let marker = if i == selected { " (measured)" } else { "" };
+        println!("    {}: {}{}", i, stream.describe(), marker);
+    }
+}
+
+/// Measure audio stream `stream` of every file, listing the streams of files
+/// that have more than one.
+fn analyze_files(
+    files: &[PathBuf],
+    target: GainTarget,
+    thresholds: &ProblemThresholds,
+    stream: usize,
+    mut cache: Option<&mut AnalysisCache>,
+) -> Result<(Vec<AudioAnalysis>, Vec<AnalysisFailure>)> {
     let pb = make_progress_bar(files.len(), "Analyzing...");
 
+    // Lookups only need shared access; new entries are recorded after the
+    // parallel pass.
+    let cache_ref = cache.as_deref();
+
     // par_iter preserves input order in the collected Vec, so indexing is unnecessary.
-    let results: Vec<Result<AudioAnalysis, (PathBuf, anyhow::Error)>> = files
+    // Cache hits carry the probe results too, so they never reopen the file.
+    type Measured = (CachedMeasurement, AudioAnalysis);
+    let results: Vec<Result<Measured, (PathBuf, anyhow::Error)>> = files
         .par_iter()
         .map(|file| {
-            let result = analyzer::analyze_file_with_target(file, tp_mode)
-                .map_err(|e| (file.clone(), e));
+            let result = match cache_ref {
+                Some(cache) => cache.measure(file, stream),
+                None => analyzer::measure_source(file, stream).map(|measurement| {
+                    CachedMeasurement {
+                        measurement,
+                        fingerprint: None,
+                        hit: false,
+                    }
+                }),
+            }
+            .map(|cached| {
+                let mut analysis = analyzer::analyze_measurement(file, &cached.measurement, target);
+                analysis.flag_problems(thresholds);
+                (cached, analysis)
+            })
+            .map_err(|e| (file.clone(), e));
             pb.inc(1);
             result
         })
@@ -417,44 +774,202 @@ fn analyze_files(files: &[PathBuf], tp_mode: TpTargetMode) -> Result<Vec<AudioAn
     pb.finish_and_clear();
 
     let mut analyses = Vec::with_capacity(results.len());
-    for result in results {
+    let mut failures = Vec::new();
+    let mut hits = 0;
+    for (file, result) in files.iter().zip(results) {
         match result {
-            Ok(a) => analyses.push(a),
-            Err((path, e)) => println!(
-                "{} Failed to analyze {}: {}",
+            Ok((cached, analysis)) => {
+                if cached.hit {
+                    hits += 1;
+                }
+                warn_extension_mismatch(file, analysis.format);
+                let streams = &cached.measurement.streams;
+                if streams.len() > 1 {
+                    print_streams(file, streams, stream);
+                }
+                analyses.push(analysis);
+                if let (Some(cache), Some(fingerprint)) = (cache.as_deref_mut(), cached.fingerprint)
+                {
+                    cache.record(file, fingerprint, cached.measurement);
+                }
+            }
+            Err((path, e)) => {
+                println!(
+                    "{} Failed to analyze {}: {}",
+                    style("⚠").yellow(),
+                    path.display(),
+                    e
+                );
+                failures.push(AnalysisFailure {
+                    path,
+                    error: format!("{:#}", e),
+                });
+            }
+        }
+    }
+
+    if hits > 0 {
+        println!(
+            "{} Analyzed {} files ({} from cache)",
+            style("✓").green(),
+            analyses.len(),
+            hits
+        );
+    } else {
+        println!("{} Analyzed {} files", style("✓").green(), analyses.len());
+    }
+
+    if let Some(cache) = cache {
+        if let Err(e) = cache.save() {
+            println!(
+                "{} Failed to save analysis cache {}: {:#}",
                 style("⚠").yellow(),
-                path.display(),
+                cache.path().display(),
                 e
-            ),
+            );
         }
     }
 
-    println!("{} Analyzed {} files", style("✓").green(), analyses.len());
+    Ok((analyses, failures))
+}
 
-    Ok(analyses)
+/// Replaced files keep their mtime (and native gain keeps the size), so the
+/// cache's freshness check would not notice the change: drop their entries.
+fn forget_modified(cache: Option<&mut AnalysisCache>, entries: &[JournalEntry]) {
+    let Some(cache) = cache else {
+        return;
+    };
+    let mut changed = false;
+    for entry in entries.iter().filter(|e| e.checksum_after != e.checksum_before) {
+        cache.forget(&entry.path);
+        changed = true;
+    }
+    if changed {
+        if let Err(e) = cache.save() {
+            println!(
+                "{} Failed to save analysis cache {}: {:#}",
+                style("⚠").yellow(),
+                cache.path().display(),
+                e
+            );
+        }
+    }
+}
+
+/// Persist the run's journal so it can be undone with `headroom restore`.
+/// A journal failure is reported but does not fail the run: the files have
+/// already been modified at this point.
+fn save_journal(base_dir: &Path, entries: &[JournalEntry]) {
+    if entries.is_empty() {
+        return;
+    }
+    match Journal::new(base_dir, entries.to_vec()).save_new() {
+        Ok(path) => println!(
+            "{} Journal saved: {} (undo with `headroom restore`)",
+            style("✓").green(),
+            path.display()
+        ),
+        Err(e) => println!(
+            "{} Failed to write {} journal: {:#}",
+            style("⚠").yellow(),
+            journal::JOURNAL_DIR,
+            e
+        ),
+    }
+}
+
+fn tag_files(
+    files: &[&AudioAnalysis],
+    all_analyses: &[AudioAnalysis],
+    base_dir: &Path,
+    backup_dir: Option<&Path>,
+) -> Vec<JournalEntry> {
+    let tags = tagger::plan_tags(files, all_analyses);
+    let pb = make_progress_bar(files.len(), "Tagging...");
+
+    let entries = files
+        .par_iter()
+        .zip(&tags)
+        .map(|(analysis, tags)| {
+            let (entry, result) = journal::run_journaled(analysis, JournalAction::Tags, || {
+                let backup = match backup_dir {
+                    Some(dir) => Some(
+                        processor::backup_file(&analysis.path, base_dir, dir)
+                            .context("Backup failed")?,
+                    ),
+                    None => None,
+                };
+                tagger::write_gain_tags(&analysis.path, analysis.format, tags)?;
+                Ok(processor::Processed {
+                    backup,
+                    ..Default::default()
+                })
+            });
+
+            if let Err(e) = result {
+                pb.println(format!(
+                    "{} {}: {:#}",
+                    style("⚠").yellow(),
+                    analysis.filename,
+                    e
+                ));
+            }
+            pb.inc(1);
+            entry
+        })
+        .collect();
+
+    pb.finish_and_clear();
+    entries
 }
 
 fn process_files(
     analyses: &[&AudioAnalysis],
     base_dir: &std::path::Path,
     backup_dir: Option<&std::path::Path>,
-) -> Result<()> {
+    options: ProcessOptions,
+) -> Vec<JournalEntry> {
     let pb = make_progress_bar(analyses.len(), "Processing...");
 
     // Each file is processed independently; ProgressBar is thread-safe.
-    analyses.par_iter().for_each(|analysis| {
-        if let Err(e) = processor::process_file(analysis, base_dir, backup_dir) {
-            pb.println(format!(
-                "{} {}: {}",
-                style("⚠").yellow(),
-                analysis.filename,
-                e
-            ));
-        }
-        pb.inc(1);
-    });
+    let entries = analyses
+        .par_iter()
+        .map(|analysis| {
+            let (entry, result) = journal::run_journaled(analysis, JournalAction::Gain, || {
+                processor::process_file(analysis, base_dir, backup_dir, options)
+            });
+            if let Some(diff) = entry.metadata.as_ref().filter(|d| !d.is_clean()) {
+                pb.println(format!(
+                    "{} {}: metadata {}",
+                    style("⚠").yellow(),
+                    analysis.filename,
+                    diff.summary()
+                ));
+            }
+            if let Some(r) = entry.reencode.filter(|r| r.passes > 1) {
+                pb.println(format!(
+                    "{} {}: re-encoded {} times, gain reduced to {:+.2} dB (TP {:+.2} dBTP)",
+                    style("ℹ").blue(),
+                    analysis.filename,
+                    r.passes,
+                    r.gain_db,
+                    r.final_tp
+                ));
+            }
+            if let Err(e) = result {
+                pb.println(format!(
+                    "{} {}: {}",
+                    style("⚠").yellow(),
+                    analysis.filename,
+                    e
+                ));
+            }
+            pb.inc(1);
+            entry
+        })
+        .collect();
 
     pb.finish_and_clear();
 
-    Ok(())
+    entries
 }
@@ -0,0 +1,249 @@
+//! Clipping and inter-sample peak detection.
+//!
+//! Runs of consecutive full-scale samples mark a flat-topped (clipped)
+//! waveform. Reconstructed peaks above 0 dBFS between samples that are not
+//! themselves at full scale are inter-sample overs, which clip in the DAC on
+//! playback. Counts cover the whole stream; only the worst events are kept,
+//! so memory does not grow with the number of clips.
+
+use serde::{Deserialize, Serialize};
+
+use crate::loudness::amplitude_to_db;
+
+/// Sample magnitude counted as full scale: the largest positive 16-bit value.
+const FULL_SCALE: f64 = 32767.0 / 32768.0;
+
+/// Consecutive full-scale samples that make a clip event. Shorter runs occur
+/// in unclipped masters that merely peak at 0 dBFS.
+pub const MIN_CLIP_RUN: u32 = 3;
+
+/// Samples the true-peak interpolator looks back over. A reconstructed over
+/// within this distance of a full-scale sample is part of that clip, and
+/// overs closer together than this form one event.
+const OVER_GUARD_FRAMES: u64 = 12;
+
+/// Clip events kept per file.
+pub const MAX_CLIP_EVENTS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClipKind {
+    /// Run of consecutive full-scale samples.
+    FullScale,
+    /// Reconstructed waveform above 0 dBFS between samples.
+    InterSample,
+}
+
+/// One clip event on one channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClipEvent {
+    pub kind: ClipKind,
+    /// Start of the event, in seconds from the start of the stream.
+    pub time_secs: f64,
+    pub channel: String,
+    /// Clipped samples, or samples with an over, in the event.
+    pub samples: u32,
+    /// Highest sample (dBFS) or reconstructed peak (dBTP) of the event.
+    pub peak: f64,
+}
+
+impl ClipEvent {
+    /// e.g. "1:23.456 L 8 samples" or "0:04.100 R ISP +0.4 dBTP".
+    pub fn describe(&self) -> String {
+        let minutes = (self.time_secs / 60.0).floor();
+        let time = format!("{}:{:06.3}", minutes, self.time_secs - minutes * 60.0);
+        match self.kind {
+            ClipKind::FullScale => format!("{} {} {} samples", time, self.channel, self.samples),
+            ClipKind::InterSample => {
+                format!("{} {} ISP {:+.1} dBTP", time, self.channel, self.peak)
+            }
+        }
+    }
+}
+
+/// Clipping found in one stream.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Clipping {
+    /// Runs of at least `MIN_CLIP_RUN` full-scale samples.
+    pub clip_events: u64,
+    /// Samples in those runs.
+    pub clipped_samples: u64,
+    /// Samples whose reconstructed waveform exceeds 0 dBFS away from clips.
+    pub inter_sample_overs: u64,
+    /// Worst events: clips longest first, then overs highest first.
+    pub events: Vec<ClipEvent>,
+}
+
+impl Clipping {
+    pub fn is_clipped(&self) -> bool {
+        self.clip_events > 0 || self.inter_sample_overs > 0
+    }
+}
+
+/// Event in progress on one channel.
+#[derive(Debug, Clone, Copy)]
+struct Run {
+    start: u64,
+    last: u64,
+    len: u32,
+    peak: f64,
+}
+
+impl Run {
+    fn extend(run: &mut Option<Run>, frame: u64, magnitude: f64) {
+        let run = run.get_or_insert(Run {
+            start: frame,
+            last: frame,
+            len: 0,
+            peak: 0.0,
+        });
+        run.last = frame;
+        run.len += 1;
+        run.peak = run.peak.max(magnitude);
+    }
+}
+
+/// Per-channel clip and over tracking, fed by the loudness meter.
+#[derive(Debug, Clone)]
+pub struct ClipDetector {
+    sample_rate: u32,
+    clips: Vec<Option<Run>>,
+    overs: Vec<Option<Run>>,
+    last_full_scale: Vec<Option<u64>>,
+    clip_events: u64,
+    clipped_samples: u64,
+    inter_sample_overs: u64,
+    worst: Vec<(ClipKind, usize, Run)>,
+}
+
+impl ClipDetector {
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            clips: vec![None; channels],
+            overs: vec![None; channels],
+            last_full_scale: vec![None; channels],
+            clip_events: 0,
+            clipped_samples: 0,
+            inter_sample_overs: 0,
+            worst: Vec::new(),
+        }
+    }
+
+    /// Feed sample `x` of channel `ch` at `frame`, with the highest magnitude
+    /// the true-peak interpolator reconstructed after it.
+    pub fn process(&mut self, ch: usize, frame: u64, x: f64, reconstructed: f64) {
+        let magnitude = x.abs();
+        if magnitude >= FULL_SCALE {
+            self.last_full_scale[ch] = Some(frame);
+            Run::extend(&mut self.clips[ch], frame, magnitude);
+        } else if let Some(run) = self.clips[ch].take() {
+            self.end_clip(ch, run);
+        }
+
+        let near_clip = self.last_full_scale[ch].is_some_and(|f| frame - f < OVER_GUARD_FRAMES);
+        // The interpolated value lags the input by half the filter.
+        let at = frame.saturating_sub(OVER_GUARD_FRAMES / 2);
+        if reconstructed > 1.0 && !near_clip {
+            self.inter_sample_overs += 1;
+            Run::extend(&mut self.overs[ch], at, reconstructed);
+        } else if let Some(run) = self.overs[ch].filter(|run| at - run.last >= OVER_GUARD_FRAMES) {
+            self.overs[ch] = None;
+            self.keep(ClipKind::InterSample, ch, run);
+        }
+    }
+
+    fn end_clip(&mut self, ch: usize, run: Run) {
+        if run.len >= MIN_CLIP_RUN {
+            self.clip_events += 1;
+            self.clipped_samples += run.len as u64;
+            self.keep(ClipKind::FullScale, ch, run);
+        }
+    }
+
+    fn keep(&mut self, kind: ClipKind, ch: usize, run: Run) {
+        let severity = |&(kind, _, run): &(ClipKind, usize, Run)| match kind {
+            ClipKind::FullScale => (1, run.len, run.peak),
+            ClipKind::InterSample => (0, 0, run.peak),
+        };
+        self.worst.push((kind, ch, run));
+        self.worst.sort_by(|a, b| {
+            severity(b)
+                .partial_cmp(&severity(a))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        self.worst.truncate(MAX_CLIP_EVENTS);
+    }
+
+    /// Close open events and summarize, naming channels with `label`.
+    pub fn finish(mut self, label: impl Fn(usize) -> String) -> Clipping {
+        for ch in 0..self.clips.len() {
+            if let Some(run) = self.clips[ch].take() {
+                self.end_clip(ch, run);
+            }
+            if let Some(run) = self.overs[ch].take() {
+                self.keep(ClipKind::InterSample, ch, run);
+            }
+        }
+        let rate = self.sample_rate.max(1) as f64;
+        Clipping {
+            clip_events: self.clip_events,
+            clipped_samples: self.clipped_samples,
+            inter_sample_overs: self.inter_sample_overs,
+            events: self
+                .worst
+                .into_iter()
+                .map(|(kind, ch, run)| ClipEvent {
+                    kind,
+                    time_secs: run.start as f64 / rate,
+                    channel: label(ch),
+                    samples: run.len,
+                    peak: amplitude_to_db(run.peak),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(channel: &[(f64, f64)]) -> Clipping {
+        let mut detector = ClipDetector::new(1, 1000);
+        for (frame, &(x, reconstructed)) in channel.iter().enumerate() {
+            detector.process(0, frame as u64, x, reconstructed);
+        }
+        detector.finish(|_| "M".to_string())
+    }
+
+    #[test]
+    fn full_scale_runs_are_counted_longest_first() {
+        let mut samples = vec![(0.5, 0.5); 100];
+        samples[10..12].fill((1.0, 1.0)); // too short to count
+        samples[30..34].fill((-1.0, 1.0));
+        samples[60..68].fill((1.0, 1.0));
+        let clipping = detect(&samples);
+
+        assert_eq!((clipping.clip_events, clipping.clipped_samples), (2, 12));
+        assert_eq!(clipping.inter_sample_overs, 0);
+        let lengths: Vec<u32> = clipping.events.iter().map(|e| e.samples).collect();
+        assert_eq!(lengths, [8, 4]);
+        assert_eq!(clipping.events[0].describe(), "0:00.060 M 8 samples");
+    }
+
+    #[test]
+    fn overs_next_to_clips_belong_to_the_clip() {
+        let mut samples = vec![(0.5, 0.5); 100];
+        samples[20..24].fill((1.0, 1.0));
+        samples[24..30].fill((0.9, 1.1));
+        samples[70] = (0.9, 1.122);
+        let clipping = detect(&samples);
+
+        assert_eq!((clipping.clip_events, clipping.inter_sample_overs), (1, 1));
+        let over = &clipping.events[1];
+        assert_eq!((over.kind, over.samples), (ClipKind::InterSample, 1));
+        assert!((over.peak - 1.0).abs() < 0.01);
+        assert!(!Clipping::default().is_clipped());
+    }
+}
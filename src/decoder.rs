@@ -0,0 +1,1128 @@
+//! Audio decoding to interleaved f32 PCM for analysis.
+//!
+//! Supported formats are decoded in-process with symphonia, so measuring a
+//! library works on machines without ffmpeg. Files symphonia cannot open fall
+//! back to an ffmpeg subprocess that streams f32 WAV over a pipe.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::thread::JoinHandle;
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::sample::SampleFormat;
+use symphonia::core::audio::Channels;
+use symphonia::core::codecs::audio::{AudioCodecId, AudioDecoder, AudioDecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::probe::Hint;
+use symphonia::core::formats::{FormatOptions, FormatReader, Track};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTag};
+
+/// Number of frames requested per read from the ffmpeg pipe.
+const PIPE_BLOCK_FRAMES: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PcmSpec {
+    pub channels: usize,
+    pub sample_rate: u32,
+    /// WAVE channel mask naming the speaker of each channel; 0 when the
+    /// stream doesn't say.
+    pub channel_mask: u32,
+}
+
+/// Stored sample format of a lossless (PCM or FLAC) stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PcmFormat {
+    pub bits: u32,
+    pub float: bool,
+}
+
+impl PcmFormat {
+    /// e.g. "16-bit", "32-bit float".
+    pub fn label(self) -> String {
+        if self.float {
+            format!("{}-bit float", self.bits)
+        } else {
+            format!("{}-bit", self.bits)
+        }
+    }
+}
+
+/// Totals reported once a stream has been fully decoded.
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeSummary {
+    pub frames: u64,
+    /// Average bitrate of the compressed audio stream, if known.
+    pub bitrate_kbps: Option<u32>,
+}
+
+/// Pull-based decoder yielding blocks of interleaved f32 samples.
+pub struct Decoder {
+    spec: PcmSpec,
+    frames: u64,
+    backend: Backend,
+}
+
+enum Backend {
+    Native(NativeStream),
+    Ffmpeg(FfmpegStream),
+}
+
+impl Decoder {
+    /// Open audio stream `stream` (0-based, in container order) of `path`,
+    /// preferring the in-process decoder.
+    pub fn open(path: &Path, stream: usize) -> Result<Self> {
+        let (backend, spec) = match NativeStream::open(path, stream) {
+            Ok((native, spec)) => (Backend::Native(native), spec),
+            Err(native_err) => match FfmpegStream::open(path, stream) {
+                Ok((stream, spec)) => (Backend::Ffmpeg(stream), spec),
+                Err(ffmpeg_err) => {
+                    return Err(anyhow!(
+                        "Failed to decode {}: {:#} (ffmpeg fallback: {:#})",
+                        path.display(),
+                        native_err,
+                        ffmpeg_err
+                    ))
+                }
+            },
+        };
+
+        if spec.channels == 0 || spec.sample_rate == 0 {
+            return Err(anyhow!(
+                "Invalid audio stream ({} channels, {} Hz)",
+                spec.channels,
+                spec.sample_rate
+            ));
+        }
+
+        Ok(Self {
+            spec,
+            frames: 0,
+            backend,
+        })
+    }
+
+    pub fn spec(&self) -> PcmSpec {
+        self.spec
+    }
+
+    /// Next block of interleaved samples, or `None` at end of stream.
+    pub fn next_block(&mut self) -> Result<Option<&[f32]>> {
+        let block = match &mut self.backend {
+            Backend::Native(stream) => stream.next_block()?,
+            Backend::Ffmpeg(stream) => stream.next_block(self.spec.channels)?,
+        };
+        if let Some(samples) = &block {
+            self.frames += (samples.len() / self.spec.channels) as u64;
+        }
+        Ok(block)
+    }
+
+    /// Release the decoder and report stream totals.
+    pub fn finish(self) -> Result<DecodeSummary> {
+        let bitrate_kbps = match self.backend {
+            Backend::Native(stream) => average_kbps(stream.packet_bytes, self.frames, self.spec),
+            Backend::Ffmpeg(stream) => stream.finish()?,
+        };
+        Ok(DecodeSummary {
+            frames: self.frames,
+            bitrate_kbps,
+        })
+    }
+}
+
+/// Decode audio stream `stream` of `path` and return its spec and frame
+/// count.
+pub fn stream_length(path: &Path, stream: usize) -> Result<(PcmSpec, u64)> {
+    let mut decoder = Decoder::open(path, stream)?;
+    let spec = decoder.spec();
+    while decoder.next_block()?.is_some() {}
+    Ok((spec, decoder.finish()?.frames))
+}
+
+fn average_kbps(bytes: u64, frames: u64, spec: PcmSpec) -> Option<u32> {
+    if frames == 0 || bytes == 0 {
+        return None;
+    }
+    let seconds = frames as f64 / spec.sample_rate as f64;
+    Some((bytes as f64 * 8.0 / seconds / 1000.0).round() as u32)
+}
+
+/// symphonia-backed decoding of one audio track.
+struct NativeStream {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn AudioDecoder>,
+    track_id: u32,
+    samples: Vec<f32>,
+    /// The first block is decoded during `open` to learn the stream spec and
+    /// is handed out on the first `next_block` call.
+    pending: bool,
+    /// Compressed bytes of every packet read, for the average bitrate.
+    packet_bytes: u64,
+}
+
+impl NativeStream {
+    fn open(path: &Path, stream: usize) -> Result<(Self, PcmSpec)> {
+        let file = File::open(path).context("Failed to open file")?;
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+        let mut hint = Hint::new();
+        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(ext);
+        }
+
+        let format = symphonia::default::get_probe().probe(
+            &hint,
+            mss,
+            FormatOptions::default(),
+            MetadataOptions::default(),
+        )?;
+
+        let track = audio_tracks(format.as_ref())
+            .nth(stream)
+            .ok_or_else(|| anyhow!("No audio stream {}", stream))?;
+        let params = track
+            .codec_params
+            .as_ref()
+            .and_then(|p| p.audio())
+            .ok_or_else(|| anyhow!("Audio track has no codec parameters"))?;
+        let decoder = symphonia::default::get_codecs()
+            .make_audio_decoder(params, &AudioDecoderOptions::default())?;
+        let track_id = track.id;
+
+        let mut stream = Self {
+            format,
+            decoder,
+            track_id,
+            samples: Vec::new(),
+            pending: false,
+            packet_bytes: 0,
+        };
+
+        let spec = stream
+            .decode_next()?
+            .ok_or_else(|| anyhow!("No decodable audio"))?;
+        stream.pending = true;
+        Ok((stream, spec))
+    }
+
+    /// Decode packets until one yields samples; returns the block's spec.
+    fn decode_next(&mut self) -> Result<Option<PcmSpec>> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return Ok(None),
+                Err(SymphoniaError::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if packet.track_id != self.track_id {
+                continue;
+            }
+            self.packet_bytes += packet.data.len() as u64;
+
+            match self.decoder.decode(&packet) {
+                Ok(buf) => {
+                    if buf.frames() == 0 {
+                        continue;
+                    }
+                    let channels = buf.spec().channels();
+                    let spec = PcmSpec {
+                        channels: channels.count(),
+                        sample_rate: buf.spec().rate(),
+                        channel_mask: match channels {
+                            // WAVE masks only define the first 18 bits.
+                            Channels::Positioned(positions) => positions.bits() as u32,
+                            _ => 0,
+                        },
+                    };
+                    buf.copy_to_vec_interleaved(&mut self.samples);
+                    return Ok(Some(spec));
+                }
+                // Corrupt frames are skipped, matching ffmpeg's behaviour.
+                Err(SymphoniaError::DecodeError(_)) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn next_block(&mut self) -> Result<Option<&[f32]>> {
+        if self.pending {
+            self.pending = false;
+        } else if self.decode_next()?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(&self.samples))
+    }
+}
+
+/// Audio tracks of a container in file order, the order ffmpeg numbers
+/// them in (`0:a:N`).
+fn audio_tracks(format: &dyn FormatReader) -> impl Iterator<Item = &Track> {
+    format
+        .tracks()
+        .iter()
+        .filter(|t| t.codec_params.as_ref().and_then(|p| p.audio()).is_some())
+}
+
+/// One audio stream of a file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AudioStream {
+    pub channels: Option<usize>,
+    pub sample_rate: Option<u32>,
+    pub language: Option<String>,
+}
+
+impl AudioStream {
+    /// e.g. "2 ch, 48000 Hz, eng".
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(channels) = self.channels {
+            parts.push(format!("{} ch", channels));
+        }
+        if let Some(rate) = self.sample_rate {
+            parts.push(format!("{} Hz", rate));
+        }
+        parts.extend(self.language.clone());
+        if parts.is_empty() {
+            "unknown".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FfprobeStream {
+    channels: Option<usize>,
+    sample_rate: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FfprobeStreams {
+    streams: Vec<FfprobeStream>,
+}
+
+/// Every audio stream of `path`, from the container when symphonia can
+/// read it and from ffprobe otherwise. Empty when neither can.
+pub fn audio_streams(path: &Path) -> Vec<AudioStream> {
+    if let Some(format) = probe_format(path) {
+        let streams: Vec<AudioStream> = audio_tracks(format.as_ref())
+            .map(|track| {
+                let params = track.codec_params.as_ref().and_then(|p| p.audio());
+                AudioStream {
+                    channels: params.and_then(|p| p.channels.as_ref()).map(|c| c.count()),
+                    sample_rate: params.and_then(|p| p.sample_rate),
+                    language: track.language.clone(),
+                }
+            })
+            .collect();
+        if !streams.is_empty() {
+            return streams;
+        }
+    }
+    ffprobe_audio_streams(path).unwrap_or_default()
+}
+
+fn ffprobe_audio_streams(path: &Path) -> Option<Vec<AudioStream>> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "quiet",
+            "-select_streams",
+            "a",
+            "-show_entries",
+            "stream=channels,sample_rate:stream_tags=language",
+            "-print_format",
+            "json",
+            path.to_str()?,
+        ])
+        .output()
+        .ok()?;
+    let probe: FfprobeStreams = serde_json::from_slice(&output.stdout).ok()?;
+    Some(
+        probe
+            .streams
+            .into_iter()
+            .map(|mut s| AudioStream {
+                channels: s.channels,
+                sample_rate: s.sample_rate.and_then(|r| r.parse().ok()),
+                language: s.tags.remove("language"),
+            })
+            .collect(),
+    )
+}
+
+/// Album identity from the file's tags: album artist (or artist) plus album
+/// title, so identically named albums by different artists stay apart.
+/// Returns `None` when the file has no album tag or can't be probed.
+pub fn read_album_key(path: &Path) -> Option<String> {
+    let mut format = probe_format(path)?;
+
+    // Tags may be split across revisions (e.g. ID3v2 + container tags), so
+    // walk all of them until an album title turns up.
+    let mut metadata = format.metadata();
+    loop {
+        if let Some(key) = metadata.current().and_then(album_key_from_revision) {
+            return Some(key);
+        }
+        metadata.pop()?;
+    }
+}
+
+/// Sample format of audio stream `stream` of `path`, read from the
+/// container headers. `None` for unreadable files; lossy decoders report
+/// a float sample format, so callers only probe lossless files.
+pub fn probe_pcm_format(path: &Path, stream: usize) -> Option<PcmFormat> {
+    use symphonia::core::codecs::audio::well_known::{CODEC_ID_PCM_ALAW, CODEC_ID_PCM_MULAW};
+
+    let format = probe_format(path)?;
+    let params = audio_tracks(format.as_ref())
+        .nth(stream)?
+        .codec_params
+        .as_ref()?
+        .audio()?;
+
+    // A-law and µ-law store 8-bit codes for 13/14-bit samples, not linear
+    // PCM; without a sample format they are processed through ffmpeg.
+    if matches!(params.codec, CODEC_ID_PCM_ALAW | CODEC_ID_PCM_MULAW) {
+        return None;
+    }
+
+    let float = matches!(
+        params.sample_format,
+        Some(SampleFormat::F32 | SampleFormat::F64)
+    );
+    let from_sample_format = params.sample_format.map(|f| match f {
+        SampleFormat::U8 | SampleFormat::S8 => 8,
+        SampleFormat::U16 | SampleFormat::S16 => 16,
+        SampleFormat::U24 | SampleFormat::S24 => 24,
+        SampleFormat::U32 | SampleFormat::S32 | SampleFormat::F32 => 32,
+        SampleFormat::F64 => 64,
+    });
+    match (
+        params.bits_per_sample.or(from_sample_format),
+        pcm_codec_format(params.codec),
+    ) {
+        // FLAC and formats that state their depth; raw PCM codecs also say
+        // whether the samples are float.
+        (Some(bits), codec) => Some(PcmFormat {
+            bits,
+            float: float || codec.is_some_and(|c| c.float),
+        }),
+        (None, codec) => codec,
+    }
+}
+
+/// Sample format implied by a raw PCM codec id (WAV/AIFF readers do not
+/// always fill in the bit depth).
+fn pcm_codec_format(codec: AudioCodecId) -> Option<PcmFormat> {
+    use symphonia::core::codecs::audio::well_known::*;
+
+    let (bits, float) = match codec {
+        CODEC_ID_PCM_S8 | CODEC_ID_PCM_S8_PLANAR | CODEC_ID_PCM_U8 | CODEC_ID_PCM_U8_PLANAR => {
+            (8, false)
+        }
+        CODEC_ID_PCM_S16LE
+        | CODEC_ID_PCM_S16LE_PLANAR
+        | CODEC_ID_PCM_S16BE
+        | CODEC_ID_PCM_S16BE_PLANAR
+        | CODEC_ID_PCM_U16LE
+        | CODEC_ID_PCM_U16LE_PLANAR
+        | CODEC_ID_PCM_U16BE
+        | CODEC_ID_PCM_U16BE_PLANAR => (16, false),
+        CODEC_ID_PCM_S24LE
+        | CODEC_ID_PCM_S24LE_PLANAR
+        | CODEC_ID_PCM_S24BE
+        | CODEC_ID_PCM_S24BE_PLANAR
+        | CODEC_ID_PCM_U24LE
+        | CODEC_ID_PCM_U24LE_PLANAR
+        | CODEC_ID_PCM_U24BE
+        | CODEC_ID_PCM_U24BE_PLANAR => (24, false),
+        CODEC_ID_PCM_S32LE
+        | CODEC_ID_PCM_S32LE_PLANAR
+        | CODEC_ID_PCM_S32BE
+        | CODEC_ID_PCM_S32BE_PLANAR
+        | CODEC_ID_PCM_U32LE
+        | CODEC_ID_PCM_U32LE_PLANAR
+        | CODEC_ID_PCM_U32BE
+        | CODEC_ID_PCM_U32BE_PLANAR => (32, false),
+        CODEC_ID_PCM_F32LE
+        | CODEC_ID_PCM_F32LE_PLANAR
+        | CODEC_ID_PCM_F32BE
+        | CODEC_ID_PCM_F32BE_PLANAR => (32, true),
+        CODEC_ID_PCM_F64LE
+        | CODEC_ID_PCM_F64LE_PLANAR
+        | CODEC_ID_PCM_F64BE
+        | CODEC_ID_PCM_F64BE_PLANAR => (64, true),
+        _ => return None,
+    };
+    Some(PcmFormat { bits, float })
+}
+
+/// Open `path`'s container for reading metadata, without a decoder.
+pub fn probe_format(path: &Path) -> Option<Box<dyn FormatReader>> {
+    let file = File::open(path).ok()?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    symphonia::default::get_probe()
+        .probe(
+            &hint,
+            mss,
+            FormatOptions::default(),
+            MetadataOptions::default(),
+        )
+        .ok()
+}
+
+fn album_key_from_revision(revision: &MetadataRevision) -> Option<String> {
+    let mut album = None;
+    let mut album_artist = None;
+    let mut artist = None;
+    for tag in &revision.media.tags {
+        match &tag.std {
+            Some(StandardTag::Album(v)) => album = Some(v.trim().to_string()),
+            Some(StandardTag::AlbumArtist(v)) => album_artist = Some(v.trim().to_string()),
+            Some(StandardTag::Artist(v)) => artist = Some(v.trim().to_string()),
+            _ => {}
+        }
+    }
+    let album = album.filter(|a| !a.is_empty())?;
+    match album_artist.or(artist).filter(|a| !a.is_empty()) {
+        Some(by) => Some(format!("{} - {}", by, album)),
+        None => Some(album),
+    }
+}
+
+/// ffmpeg subprocess decoding one audio stream to f32 WAV on stdout.
+struct FfmpegStream {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+    stderr: JoinHandle<String>,
+    bytes: Vec<u8>,
+    filled: usize,
+    samples: Vec<f32>,
+}
+
+impl FfmpegStream {
+    fn open(path: &Path, stream: usize) -> Result<(Self, PcmSpec)> {
+        let map = format!("0:a:{}", stream);
+        let mut child = Command::new("ffmpeg")
+            .args([
+                "-nostdin",
+                "-nostats",
+                "-i",
+                path.to_str().ok_or_else(|| anyhow!("Invalid path"))?,
+                "-map",
+                &map,
+                "-c:a",
+                "pcm_f32le",
+                "-f",
+                "wav",
+                "-",
+            ])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to execute ffmpeg. Is ffmpeg installed?")?;
+
+        // Drain stderr concurrently so a chatty ffmpeg can't block on a full
+        // pipe while we are still reading samples from stdout.
+        let mut stderr_pipe = child.stderr.take().expect("stderr is piped");
+        let stderr = std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = stderr_pipe.read_to_end(&mut buf);
+            String::from_utf8_lossy(&buf).into_owned()
+        });
+
+        let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let spec = match read_wav_header(&mut stdout) {
+            Ok(spec) => spec,
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                let log = stderr.join().unwrap_or_default();
+                return Err(e.context(stderr_tail(&log)));
+            }
+        };
+
+        let frame_bytes = spec.channels * 4;
+        Ok((
+            Self {
+                child,
+                stdout,
+                stderr,
+                bytes: vec![0u8; frame_bytes * PIPE_BLOCK_FRAMES],
+                filled: 0,
+                samples: Vec::with_capacity(spec.channels * PIPE_BLOCK_FRAMES),
+            },
+            spec,
+        ))
+    }
+
+    fn next_block(&mut self, channels: usize) -> Result<Option<&[f32]>> {
+        let frame_bytes = channels * 4;
+        loop {
+            let n = self
+                .stdout
+                .read(&mut self.bytes[self.filled..])
+                .context("Failed to read decoded audio from ffmpeg")?;
+            self.filled += n;
+
+            let usable = self.filled - self.filled % frame_bytes;
+            if usable == self.bytes.len() || (n == 0 && usable > 0) {
+                self.samples.clear();
+                self.samples.extend(
+                    self.bytes[..usable]
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+                );
+                self.bytes.copy_within(usable..self.filled, 0);
+                self.filled -= usable;
+                return Ok(Some(&self.samples));
+            }
+
+            if n == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Wait for ffmpeg to exit; returns the bitrate from its input dump.
+    fn finish(mut self) -> Result<Option<u32>> {
+        drop(self.stdout);
+        let status = self.child.wait().context("Failed to wait for ffmpeg")?;
+        let stderr = self.stderr.join().unwrap_or_default();
+
+        if !status.success() {
+            return Err(anyhow!("ffmpeg failed to decode: {}", stderr_tail(&stderr)));
+        }
+
+        Ok(parse_stderr_bitrate(&stderr))
+    }
+}
+
+fn stderr_tail(stderr: &str) -> String {
+    let lines: Vec<&str> = stderr.lines().collect();
+    lines[lines.len().saturating_sub(5)..].join("\n")
+}
+
+/// Parse the overall bitrate from ffmpeg's input dump on stderr, e.g.
+/// `  Duration: 00:03:50.32, start: 0.025057, bitrate: 320 kb/s`.
+/// Returns None for "N/A" or unexpected formatting; callers fall back to ffprobe.
+fn parse_stderr_bitrate(stderr: &str) -> Option<u32> {
+    stderr
+        .lines()
+        .find(|line| line.trim_start().starts_with("Duration:"))?
+        .split("bitrate:")
+        .nth(1)?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Parse a streamed RIFF/WAVE header up to the start of the `data` chunk.
+///
+/// ffmpeg writes placeholder sizes when the output is a pipe, so chunk sizes
+/// are only trusted for skipping the chunks that precede `data`.
+fn read_wav_header<R: Read>(reader: &mut R) -> Result<PcmSpec> {
+    let mut riff = [0u8; 12];
+    reader
+        .read_exact(&mut riff)
+        .context("ffmpeg produced no audio output")?;
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        return Err(anyhow!("Unexpected ffmpeg output (not a WAV stream)"));
+    }
+
+    let mut spec = None;
+    loop {
+        let mut header = [0u8; 8];
+        reader
+            .read_exact(&mut header)
+            .context("Truncated WAV header from ffmpeg")?;
+        let id = &header[0..4];
+        let size = read_u32(&header[4..8]) as usize;
+
+        if id == b"data" {
+            return spec.ok_or_else(|| anyhow!("WAV stream has no fmt chunk"));
+        }
+
+        let mut body = vec![0u8; size + (size & 1)];
+        reader
+            .read_exact(&mut body)
+            .context("Truncated WAV header from ffmpeg")?;
+        if id == b"fmt " {
+            if body.len() < 16 {
+                return Err(anyhow!("Malformed WAV fmt chunk"));
+            }
+            let bits = read_u16(&body[14..16]);
+            if bits != 32 {
+                return Err(anyhow!("Expected 32-bit float PCM, got {} bits", bits));
+            }
+            // ffmpeg writes WAVE_FORMAT_EXTENSIBLE, with a channel mask, for
+            // more than two channels.
+            let channel_mask = if read_u16(&body[0..2]) == 0xfffe && body.len() >= 24 {
+                read_u32(&body[20..24])
+            } else {
+                0
+            };
+            spec = Some(PcmSpec {
+                channels: read_u16(&body[2..4]) as usize,
+                sample_rate: read_u32(&body[4..8]),
+                channel_mask,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav_bytes(channels: u16, rate: u32, extra_chunk: bool, samples: &[f32]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(b"RIFF");
+        out.extend_from_slice(&u32::MAX.to_le_bytes());
+        out.extend_from_slice(b"WAVE");
+        out.extend_from_slice(b"fmt ");
+        out.extend_from_slice(&16u32.to_le_bytes());
+        out.extend_from_slice(&3u16.to_le_bytes());
+        out.extend_from_slice(&channels.to_le_bytes());
+        out.extend_from_slice(&rate.to_le_bytes());
+        out.extend_from_slice(&(rate * channels as u32 * 4).to_le_bytes());
+        out.extend_from_slice(&(channels * 4).to_le_bytes());
+        out.extend_from_slice(&32u16.to_le_bytes());
+        if extra_chunk {
+            // Odd-sized LIST chunk exercises the RIFF pad byte.
+            out.extend_from_slice(b"LIST");
+            out.extend_from_slice(&5u32.to_le_bytes());
+            out.extend_from_slice(b"INFO\0\0");
+        }
+        out.extend_from_slice(b"data");
+        let data_len = (samples.len() * 4) as u32;
+        out.extend_from_slice(&data_len.to_le_bytes());
+        for s in samples {
+            out.extend_from_slice(&s.to_le_bytes());
+        }
+        out
+    }
+
+    #[test]
+    fn probes_float_and_integer_wav_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let float = dir.path().join("f.wav");
+        std::fs::write(&float, wav_bytes(2, 44_100, false, &[0.0; 8])).unwrap();
+        assert_eq!(
+            probe_pcm_format(&float, 0),
+            Some(PcmFormat {
+                bits: 32,
+                float: true
+            })
+        );
+
+        // Same layout as 16-bit integer PCM.
+        let mut bytes = wav_bytes(2, 44_100, false, &[0.0; 4]);
+        bytes[20..22].copy_from_slice(&1u16.to_le_bytes());
+        bytes[28..32].copy_from_slice(&(44_100u32 * 4).to_le_bytes());
+        bytes[32..34].copy_from_slice(&4u16.to_le_bytes());
+        bytes[34..36].copy_from_slice(&16u16.to_le_bytes());
+        let int = dir.path().join("i.wav");
+        std::fs::write(&int, bytes).unwrap();
+        assert_eq!(
+            probe_pcm_format(&int, 0).map(|f| f.label()).as_deref(),
+            Some("16-bit")
+        );
+    }
+
+    /// Mono 8 kHz µ-law WAV (format tag 7, with the `cbSize` field).
+    fn mulaw_wav(samples: &[u8]) -> Vec<u8> {
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&((38 + samples.len()) as u32).to_le_bytes());
+        out.extend_from_slice(b"WAVEfmt ");
+        out.extend_from_slice(&18u32.to_le_bytes());
+        for v in [7u16, 1] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        for v in [8_000u32, 8_000] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        for v in [1u16, 8, 0] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+        out.extend_from_slice(samples);
+        out
+    }
+
+    #[test]
+    fn companded_wav_has_no_linear_sample_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mulaw.wav");
+        std::fs::write(&path, mulaw_wav(&[0xff, 0x80, 0x00, 0x7f])).unwrap();
+        assert!(probe_format(&path).is_some());
+        assert_eq!(probe_pcm_format(&path, 0), None);
+    }
+
+    #[test]
+    fn test_read_wav_header_skips_metadata_chunks() {
+        let bytes = wav_bytes(2, 44_100, true, &[]);
+        let spec = read_wav_header(&mut bytes.as_slice()).unwrap();
+        assert_eq!(
+            spec,
+            PcmSpec {
+                channels: 2,
+                sample_rate: 44_100,
+                channel_mask: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_wav_header_rejects_non_wav() {
+        assert!(read_wav_header(&mut &b"ID3\x04\0\0\0\0\0\0\0\0"[..]).is_err());
+    }
+
+    #[test]
+    fn test_parse_stderr_bitrate() {
+        let stderr = "Input #0, mp3, from 'track.mp3':\n  Duration: 00:03:50.32, start: 0.025057, bitrate: 320 kb/s\n  Stream #0:0: Audio: mp3";
+        assert_eq!(parse_stderr_bitrate(stderr), Some(320));
+    }
+
+    #[test]
+    fn test_parse_stderr_bitrate_na_and_missing() {
+        assert_eq!(
+            parse_stderr_bitrate("  Duration: 00:03:50.32, start: 0.0, bitrate: N/A\n"),
+            None
+        );
+        assert_eq!(parse_stderr_bitrate("no duration line here"), None);
+    }
+
+    #[test]
+    fn test_average_kbps() {
+        let spec = PcmSpec {
+            channels: 2,
+            sample_rate: 44_100,
+            channel_mask: 0,
+        };
+        // One second of audio carried in 40 000 bytes is 320 kbps.
+        assert_eq!(average_kbps(40_000, 44_100, spec), Some(320));
+        assert_eq!(average_kbps(0, 44_100, spec), None);
+    }
+
+    #[test]
+    fn test_native_decodes_float_wav() {
+        let samples: Vec<f32> = (0..1000).map(|n| (n as f32 / 1000.0) - 0.5).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("float.wav");
+        std::fs::write(&path, wav_bytes(2, 48_000, false, &samples)).unwrap();
+
+        let mut decoder = Decoder::open(&path, 0).unwrap();
+        assert_eq!(
+            decoder.spec(),
+            PcmSpec {
+                channels: 2,
+                sample_rate: 48_000,
+                channel_mask: 0x3
+            }
+        );
+        let mut decoded = Vec::new();
+        while let Some(block) = decoder.next_block().unwrap() {
+            decoded.extend_from_slice(block);
+        }
+        let summary = decoder.finish().unwrap();
+
+        assert_eq!(summary.frames, 500);
+        assert_eq!(decoded, samples);
+    }
+
+    /// Pack `(value, bits)` fields, most significant bit first.
+    fn pack_msb(fields: &[(u32, u32)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut used = 0;
+        for &(value, bits) in fields {
+            for bit in (0..bits).rev() {
+                if used % 8 == 0 {
+                    out.push(0);
+                }
+                *out.last_mut().unwrap() |= (((value >> bit) & 1) as u8) << (7 - used % 8);
+                used += 1;
+            }
+        }
+        out
+    }
+
+    /// Pack `(value, bits)` fields, least significant bit first (Vorbis).
+    fn pack_lsb(fields: &[(u32, u32)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut used = 0;
+        for &(value, bits) in fields {
+            for bit in 0..bits {
+                if used % 8 == 0 {
+                    out.push(0);
+                }
+                *out.last_mut().unwrap() |= (((value >> bit) & 1) as u8) << (used % 8);
+                used += 1;
+            }
+        }
+        out
+    }
+
+    fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    /// Mono 16-bit ALAC in an MP4 container, one uncompressed packet per
+    /// 256-sample frame.
+    fn alac_m4a(samples: &[i16]) -> Vec<u8> {
+        const FRAME: usize = 256;
+        let packets: Vec<Vec<u8>> = samples
+            .chunks(FRAME)
+            .map(|frame| {
+                // SCE header: partial frame with its own length, uncompressed.
+                let mut fields = vec![(0, 3), (0, 4), (0, 12), (1, 1), (0, 2), (1, 1)];
+                fields.push((frame.len() as u32, 32));
+                fields.extend(frame.iter().map(|&s| (s as u16 as u32, 16)));
+                fields.push((7, 3));
+                pack_msb(&fields)
+            })
+            .collect();
+
+        let mut cookie = (FRAME as u32).to_be_bytes().to_vec();
+        cookie.extend_from_slice(&[0, 16, 40, 10, 14, 1]);
+        cookie.extend_from_slice(&255u16.to_be_bytes());
+        cookie.extend_from_slice(&[0; 8]);
+        cookie.extend_from_slice(&44_100u32.to_be_bytes());
+        let mut alac_config = vec![0; 4];
+        alac_config.extend_from_slice(&cookie);
+
+        let mut entry = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        entry.extend_from_slice(&[0; 8]);
+        entry.extend_from_slice(&1u16.to_be_bytes());
+        entry.extend_from_slice(&16u16.to_be_bytes());
+        entry.extend_from_slice(&[0; 4]);
+        entry.extend_from_slice(&(44_100u32 << 16).to_be_bytes());
+        entry.extend(mp4_box(b"alac", &alac_config));
+        let mut stsd = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stsd.extend(mp4_box(b"alac", &entry));
+
+        let count = packets.len() as u32;
+        let full_box = |words: &[u32]| {
+            let mut out = vec![0; 4];
+            for w in words {
+                out.extend_from_slice(&w.to_be_bytes());
+            }
+            out
+        };
+        let stbl = |chunk_offset: u32| {
+            let mut sizes = vec![0, count];
+            sizes.extend(packets.iter().map(|p| p.len() as u32));
+            [
+                mp4_box(b"stsd", &stsd),
+                mp4_box(b"stts", &full_box(&[1, count, FRAME as u32])),
+                mp4_box(b"stsc", &full_box(&[1, 1, count, 1])),
+                mp4_box(b"stsz", &full_box(&sizes)),
+                mp4_box(b"stco", &full_box(&[1, chunk_offset])),
+            ]
+            .concat()
+        };
+        let duration = samples.len() as u32;
+        let moov = |chunk_offset: u32| {
+            let mut mvhd = full_box(&[0, 0, 44_100, duration, 0x0001_0000]);
+            mvhd.extend_from_slice(&[1, 0]);
+            mvhd.extend_from_slice(&[0; 70]);
+            mvhd.extend_from_slice(&2u32.to_be_bytes());
+            let mut tkhd = full_box(&[0, 0, 1, 0, duration, 0, 0, 0]);
+            tkhd.extend_from_slice(&[0; 48]);
+            tkhd[3] = 7;
+            let mut hdlr = full_box(&[0]);
+            hdlr.extend_from_slice(b"soun");
+            hdlr.extend_from_slice(&[0; 13]);
+            let minf = [
+                mp4_box(b"smhd", &full_box(&[0])),
+                mp4_box(b"stbl", &stbl(chunk_offset)),
+            ]
+            .concat();
+            let mdia = [
+                mp4_box(b"mdhd", &full_box(&[0, 0, 44_100, duration, 0])),
+                mp4_box(b"hdlr", &hdlr),
+                mp4_box(b"minf", &minf),
+            ]
+            .concat();
+            let trak = [mp4_box(b"tkhd", &tkhd), mp4_box(b"mdia", &mdia)].concat();
+            mp4_box(
+                b"moov",
+                &[mp4_box(b"mvhd", &mvhd), mp4_box(b"trak", &trak)].concat(),
+            )
+        };
+
+        let ftyp = mp4_box(b"ftyp", b"M4A \0\0\0\0M4A isom");
+        let chunk_offset = (ftyp.len() + moov(0).len() + 8) as u32;
+        [
+            ftyp,
+            moov(chunk_offset),
+            mp4_box(b"mdat", &packets.concat()),
+        ]
+        .concat()
+    }
+
+    fn ogg_page(header_type: u8, granule: u64, sequence: u32, packets: &[&[u8]]) -> Vec<u8> {
+        let mut lacing = Vec::new();
+        for packet in packets {
+            lacing.extend(std::iter::repeat_n(255, packet.len() / 255));
+            lacing.push((packet.len() % 255) as u8);
+        }
+        let mut out = b"OggS\0".to_vec();
+        out.push(header_type);
+        out.extend_from_slice(&granule.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes());
+        out.extend_from_slice(&sequence.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.push(lacing.len() as u8);
+        out.extend(lacing);
+        for packet in packets {
+            out.extend_from_slice(packet);
+        }
+        let crc = crate::ogg::crc32(&out);
+        out[22..26].copy_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    /// Mono Ogg Vorbis whose packets all mark the floor unused, so every
+    /// 256-sample block decodes to silence.
+    fn silent_vorbis(packets: usize) -> Vec<u8> {
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.extend_from_slice(&0u32.to_le_bytes());
+        ident.push(1);
+        ident.extend_from_slice(&44_100u32.to_le_bytes());
+        ident.extend_from_slice(&[0; 12]);
+        ident.extend_from_slice(&[0x88, 1]);
+
+        let mut comment = b"\x03vorbis".to_vec();
+        comment.extend_from_slice(&0u32.to_le_bytes());
+        comment.extend_from_slice(&0u32.to_le_bytes());
+        comment.push(1);
+
+        let mut setup = b"\x05vorbis".to_vec();
+        setup.extend(pack_lsb(&[
+            // One codebook: 1 dimension, 2 entries of length 1, no lookup.
+            (0, 8),
+            (0x56_4342, 24),
+            (1, 16),
+            (2, 24),
+            (0, 1),
+            (0, 1),
+            (0, 5),
+            (0, 5),
+            (0, 4),
+            // One placeholder time domain transform.
+            (0, 6),
+            (0, 16),
+            // One type 1 floor without partitions.
+            (0, 6),
+            (1, 16),
+            (0, 5),
+            (1, 2),
+            (8, 4),
+            // One type 0 residue over the first 128 bins.
+            (0, 6),
+            (0, 16),
+            (0, 24),
+            (128, 24),
+            (31, 24),
+            (0, 6),
+            (0, 8),
+            (0, 3),
+            (0, 1),
+            // One mapping: single submap, no coupling.
+            (0, 6),
+            (0, 16),
+            (0, 1),
+            (0, 1),
+            (0, 2),
+            (0, 8),
+            (0, 8),
+            (0, 8),
+            // One short-block mode.
+            (0, 6),
+            (0, 1),
+            (0, 16),
+            (0, 16),
+            (0, 8),
+            // Framing bit.
+            (1, 1),
+        ]));
+
+        // Overlapping 256-sample blocks yield 128 samples each after the first.
+        let audio = vec![vec![0u8]; packets];
+        let audio: Vec<&[u8]> = audio.iter().map(Vec::as_slice).collect();
+        let granule = (packets as u64 - 1) * 128;
+        [
+            ogg_page(0x02, 0, 0, &[&ident]),
+            ogg_page(0, 0, 1, &[&comment, &setup]),
+            ogg_page(0x04, granule, 2, &audio),
+        ]
+        .concat()
+    }
+
+    fn decode_natively(path: &Path) -> (PcmSpec, Vec<f32>) {
+        let mut decoder = Decoder::open(path, 0).unwrap();
+        assert!(matches!(decoder.backend, Backend::Native(_)));
+        let spec = decoder.spec();
+        let mut decoded = Vec::new();
+        while let Some(block) = decoder.next_block().unwrap() {
+            decoded.extend_from_slice(block);
+        }
+        (spec, decoded)
+    }
+
+    #[test]
+    fn test_native_decodes_alac() {
+        let samples: Vec<i16> = (0..600).map(|n| (n * 50 - 15_000) as i16).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("alac.m4a");
+        std::fs::write(&path, alac_m4a(&samples)).unwrap();
+
+        let (spec, decoded) = decode_natively(&path);
+        assert_eq!((spec.channels, spec.sample_rate), (1, 44_100));
+        let expected: Vec<f32> = samples.iter().map(|&s| s as f32 / 32768.0).collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn test_native_decodes_ogg_vorbis() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("silence.ogg");
+        std::fs::write(&path, silent_vorbis(11)).unwrap();
+
+        let (spec, decoded) = decode_natively(&path);
+        assert_eq!((spec.channels, spec.sample_rate), (1, 44_100));
+        assert_eq!(decoded.len(), 1280);
+        assert!(decoded.iter().all(|&s| s == 0.0));
+    }
+}
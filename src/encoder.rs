@@ -0,0 +1,677 @@
+//! Source encoder settings of MP3/AAC files.
+//!
+//! Re-encoding should reproduce the original encode as closely as ffmpeg
+//! allows, so the analyzer reads the parameters the encoder left in the
+//! stream: the MP3 frame header plus the Xing/Info and LAME tags (bitrate
+//! mode, `-V` quality, preset), or the AAC AudioSpecificConfig from the MP4
+//! `esds` box or the ADTS header (profile, sample rate, channels).
+
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::ogg;
+use crate::scanner::AudioFormat;
+
+/// How much of an MP3 is searched for the first frame after the ID3 tag.
+const MP3_SYNC_SEARCH: usize = 64 * 1024;
+
+/// Nested MP4 boxes leading to the sample description.
+const STSD_PATH: &[&[u8; 4]] = &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BitrateMode {
+    Cbr,
+    Abr,
+    Vbr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AacProfile {
+    Main,
+    Lc,
+    /// HE-AAC (AAC LC + SBR).
+    He,
+    /// HE-AAC v2 (AAC LC + SBR + Parametric Stereo).
+    HeV2,
+    Other,
+}
+
+impl AacProfile {
+    fn from_object_type(aot: u8) -> Self {
+        match aot {
+            1 => AacProfile::Main,
+            2 => AacProfile::Lc,
+            5 => AacProfile::He,
+            29 => AacProfile::HeV2,
+            _ => AacProfile::Other,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            AacProfile::Main => "Main",
+            AacProfile::Lc => "LC",
+            AacProfile::He => "HE-AAC",
+            AacProfile::HeV2 => "HE-AACv2",
+            AacProfile::Other => "other",
+        }
+    }
+}
+
+/// Encoder parameters read from a lossy file. Fields the stream does not
+/// carry are `None`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncoderSettings {
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub bitrate_mode: Option<BitrateMode>,
+    /// CBR bitrate or ABR target.
+    pub bitrate_kbps: Option<u32>,
+    /// LAME VBR quality (`-V`).
+    pub vbr_quality: Option<u8>,
+    /// LAME preset, e.g. "V0", "extreme", "ABR 192".
+    pub lame_preset: Option<String>,
+    /// Encoder version string, e.g. "LAME3.100".
+    pub encoder: Option<String>,
+    /// MP3 joint stereo (vs. plain stereo).
+    pub joint_stereo: Option<bool>,
+    pub aac_profile: Option<AacProfile>,
+}
+
+impl EncoderSettings {
+    pub fn new(sample_rate: u32, channels: u8) -> Self {
+        Self {
+            sample_rate,
+            channels,
+            bitrate_mode: None,
+            bitrate_kbps: None,
+            vbr_quality: None,
+            lame_preset: None,
+            encoder: None,
+            joint_stereo: None,
+            aac_profile: None,
+        }
+    }
+
+    /// Short human-readable summary, e.g. "LAME3.100 VBR V0, 44.1 kHz, joint stereo".
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        let mut codec = Vec::new();
+        if let Some(encoder) = &self.encoder {
+            codec.push(encoder.clone());
+        }
+        if let Some(profile) = self.aac_profile {
+            codec.push(profile.label().to_string());
+        }
+        match (self.bitrate_mode, self.vbr_quality, self.bitrate_kbps) {
+            (Some(BitrateMode::Vbr), Some(q), _) => codec.push(format!("VBR V{}", q)),
+            (Some(BitrateMode::Vbr), None, Some(k)) => codec.push(format!("VBR ~{}k", k)),
+            (Some(BitrateMode::Vbr), None, None) => codec.push("VBR".into()),
+            (Some(BitrateMode::Abr), _, Some(k)) => codec.push(format!("ABR {}k", k)),
+            (Some(BitrateMode::Cbr), _, Some(k)) => codec.push(format!("CBR {}k", k)),
+            _ => {}
+        }
+        if !codec.is_empty() {
+            parts.push(codec.join(" "));
+        }
+        if let Some(preset) = &self.lame_preset {
+            parts.push(format!("preset {}", preset));
+        }
+        parts.push(format!("{} Hz", self.sample_rate));
+        parts.push(match (self.channels, self.joint_stereo) {
+            (1, _) => "mono".to_string(),
+            (2, Some(true)) => "joint stereo".to_string(),
+            (2, _) => "stereo".to_string(),
+            (n, _) => format!("{} ch", n),
+        });
+        parts.join(", ")
+    }
+}
+
+/// Read the encoder settings of an MP3, AAC or Ogg Vorbis file of the given
+/// format. `None` for other formats or when the stream headers cannot be
+/// parsed.
+pub fn probe(path: &Path, format: AudioFormat) -> Option<EncoderSettings> {
+    if format.is_mp3() {
+        let mut data = Vec::new();
+        File::open(path)
+            .ok()?
+            .take((MP3_SYNC_SEARCH + 16 * 1024) as u64 + 10)
+            .read_to_end(&mut data)
+            .ok()?;
+        let start = id3v2_len(&data);
+        parse_mp3(data.get(start..)?)
+    } else if format.is_aac() {
+        let mut file = File::open(path).ok()?;
+        let mut magic = [0u8; 2];
+        file.read_exact(&mut magic).ok()?;
+        if magic[0] == 0xff && magic[1] & 0xf6 == 0xf0 {
+            let mut header = [0u8; 7];
+            file.seek(SeekFrom::Start(0)).ok()?;
+            file.read_exact(&mut header).ok()?;
+            return parse_adts(&header);
+        }
+        let stsd = find_mp4_box(&mut file, STSD_PATH)?;
+        parse_stsd(&stsd)
+    } else if format.is_vorbis() {
+        parse_vorbis_ident(&ogg::first_packet(path).ok()?)
+    } else {
+        None
+    }
+}
+
+/// Type of the first `stsd` sample entry of an MPEG-4 file, e.g. `mp4a` or
+/// `alac`.
+pub fn mp4_sample_entry(path: &Path) -> Option<[u8; 4]> {
+    let mut file = File::open(path).ok()?;
+    let stsd = find_mp4_box(&mut file, STSD_PATH)?;
+    stsd.get(12..16)?.try_into().ok()
+}
+
+/// Vorbis identification header. Vorbis is VBR in practice; the nominal
+/// bitrate is the encoder's target.
+fn parse_vorbis_ident(packet: &[u8]) -> Option<EncoderSettings> {
+    if packet.get(..7)? != b"\x01vorbis" {
+        return None;
+    }
+    let channels = *packet.get(11)?;
+    let sample_rate = u32::from_le_bytes(packet.get(12..16)?.try_into().ok()?);
+    let nominal = i32::from_le_bytes(packet.get(20..24)?.try_into().ok()?);
+    let mut settings = EncoderSettings::new(sample_rate, channels);
+    settings.encoder = Some("Vorbis".into());
+    settings.bitrate_mode = Some(BitrateMode::Vbr);
+    settings.bitrate_kbps = (nominal > 0).then_some(nominal as u32 / 1000);
+    Some(settings)
+}
+
+/// Length of a leading ID3v2 tag, or 0.
+pub fn id3v2_len(data: &[u8]) -> usize {
+    if data.len() < 10 || &data[..3] != b"ID3" {
+        return 0;
+    }
+    let size = data[6..10]
+        .iter()
+        .fold(0usize, |acc, &b| (acc << 7) | (b & 0x7f) as usize);
+    let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+    10 + size + footer
+}
+
+struct Mp3Header {
+    mpeg1: bool,
+    crc: bool,
+    bitrate_kbps: u32,
+    sample_rate: u32,
+    channel_mode: u8,
+}
+
+fn parse_mp3_header(h: &[u8]) -> Option<Mp3Header> {
+    if h.len() < 4 || h[0] != 0xff || h[1] & 0xe0 != 0xe0 {
+        return None;
+    }
+    let version = (h[1] >> 3) & 3;
+    let layer = (h[1] >> 1) & 3;
+    let bitrate_idx = (h[2] >> 4) as usize;
+    let sr_idx = ((h[2] >> 2) & 3) as usize;
+    if version == 1 || layer != 1 || bitrate_idx == 0 || bitrate_idx == 15 || sr_idx == 3 {
+        return None;
+    }
+
+    const MPEG1_KBPS: [u32; 15] = [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ];
+    const MPEG2_KBPS: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    let (bitrate_kbps, base_rate) = match version {
+        3 => (MPEG1_KBPS[bitrate_idx], [44100, 48000, 32000][sr_idx]),
+        2 => (MPEG2_KBPS[bitrate_idx], [22050, 24000, 16000][sr_idx]),
+        _ => (MPEG2_KBPS[bitrate_idx], [11025, 12000, 8000][sr_idx]),
+    };
+
+    Some(Mp3Header {
+        mpeg1: version == 3,
+        crc: h[1] & 1 == 0,
+        bitrate_kbps,
+        sample_rate: base_rate,
+        channel_mode: h[3] >> 6,
+    })
+}
+
+fn parse_mp3(data: &[u8]) -> Option<EncoderSettings> {
+    let search = data.len().min(MP3_SYNC_SEARCH);
+    let (pos, header) = (0..search).find_map(|i| parse_mp3_header(&data[i..]).map(|h| (i, h)))?;
+
+    let channels = if header.channel_mode == 3 { 1 } else { 2 };
+    let mut settings = EncoderSettings::new(header.sample_rate, channels);
+    if channels == 2 {
+        settings.joint_stereo = Some(header.channel_mode == 1);
+    }
+
+    let side_info = match (header.mpeg1, channels) {
+        (true, 1) => 17,
+        (true, _) => 32,
+        (false, 1) => 9,
+        (false, _) => 17,
+    };
+    let xing_at = pos + 4 + if header.crc { 2 } else { 0 } + side_info;
+    let xing = data.get(xing_at..).unwrap_or_default();
+
+    if xing.starts_with(b"Xing") || xing.starts_with(b"Info") {
+        settings.bitrate_mode = Some(if xing.starts_with(b"Xing") {
+            BitrateMode::Vbr
+        } else {
+            BitrateMode::Cbr
+        });
+        parse_xing(xing, &mut settings);
+    } else if data.get(pos + 36..pos + 40) == Some(b"VBRI") {
+        settings.bitrate_mode = Some(BitrateMode::Vbr);
+    } else {
+        // No VBR header: a plain CBR stream is the common case.
+        settings.bitrate_mode = Some(BitrateMode::Cbr);
+    }
+    if settings.bitrate_mode == Some(BitrateMode::Cbr) && settings.bitrate_kbps.is_none() {
+        settings.bitrate_kbps = Some(header.bitrate_kbps);
+    }
+    Some(settings)
+}
+
+/// Xing/Info header and the LAME extension that follows it.
+fn parse_xing(xing: &[u8], settings: &mut EncoderSettings) {
+    let Some(flags) = xing.get(4..8) else {
+        return;
+    };
+    let flags = u32::from_be_bytes([flags[0], flags[1], flags[2], flags[3]]);
+    let mut at = 8;
+    for (bit, len) in [(0x1, 4), (0x2, 4), (0x4, 100)] {
+        if flags & bit != 0 {
+            at += len;
+        }
+    }
+    let quality = if flags & 0x8 != 0 {
+        let q = xing.get(at..at + 4).map(|b| b[3]);
+        at += 4;
+        q
+    } else {
+        None
+    };
+
+    let Some(lame) = xing.get(at..at + 28) else {
+        return;
+    };
+    let version = &lame[..9];
+    if !(version.starts_with(b"LAME")
+        || version.starts_with(b"Lavc")
+        || version.starts_with(b"Lavf"))
+    {
+        return;
+    }
+    settings.encoder = Some(
+        String::from_utf8_lossy(version)
+            .trim_end_matches(['\0', ' '])
+            .to_string(),
+    );
+
+    match lame[9] & 0x0f {
+        1 | 8 => settings.bitrate_mode = Some(BitrateMode::Cbr),
+        2 | 9 => {
+            settings.bitrate_mode = Some(BitrateMode::Abr);
+            settings.bitrate_kbps = Some(lame[20] as u32);
+        }
+        3..=6 => settings.bitrate_mode = Some(BitrateMode::Vbr),
+        _ => {}
+    }
+    if settings.bitrate_mode == Some(BitrateMode::Vbr) {
+        settings.vbr_quality = quality.filter(|&q| q <= 100).map(|q| (100 - q) / 10);
+    }
+
+    let preset = u16::from_be_bytes([lame[26], lame[27]]) & 0x07ff;
+    settings.lame_preset = match preset {
+        8..=320 => Some(format!("ABR {}", preset)),
+        410..=500 if preset % 10 == 0 => Some(format!("V{}", (500 - preset) / 10)),
+        1000 => Some("r3mix".into()),
+        1001 => Some("standard".into()),
+        1002 => Some("extreme".into()),
+        1003 => Some("insane".into()),
+        1004 => Some("fast standard".into()),
+        1005 => Some("fast extreme".into()),
+        1006 => Some("medium".into()),
+        1007 => Some("fast medium".into()),
+        _ => None,
+    };
+}
+
+/// ADTS frame header of a raw `.aac` stream. A buffer fullness of 0x7ff
+/// marks a VBR stream.
+fn parse_adts(h: &[u8; 7]) -> Option<EncoderSettings> {
+    let aot = ((h[2] >> 6) & 3) + 1;
+    let sample_rate = sampling_frequency(((h[2] >> 2) & 0x0f) as usize)?;
+    let channels = ((h[2] & 1) << 2) | (h[3] >> 6);
+    let fullness = (u16::from(h[5] & 0x1f) << 6) | u16::from(h[6] >> 2);
+    let mut settings = EncoderSettings::new(sample_rate, channels);
+    settings.aac_profile = Some(AacProfile::from_object_type(aot));
+    settings.bitrate_mode = Some(if fullness == 0x7ff {
+        BitrateMode::Vbr
+    } else {
+        BitrateMode::Cbr
+    });
+    Some(settings)
+}
+
+fn sampling_frequency(index: usize) -> Option<u32> {
+    const RATES: [u32; 13] = [
+        96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+    ];
+    RATES.get(index).copied()
+}
+
+/// Body of the box at `path`, walking from the top level of `file`.
+fn find_mp4_box(file: &mut File, path: &[&[u8; 4]]) -> Option<Vec<u8>> {
+    let file_len = file.metadata().ok()?.len();
+    let (mut start, mut end) = (0u64, file_len);
+    for name in path {
+        let mut pos = start;
+        let mut found = None;
+        while pos + 8 <= end {
+            file.seek(SeekFrom::Start(pos)).ok()?;
+            let mut header = [0u8; 16];
+            file.read_exact(&mut header[..8]).ok()?;
+            let mut size = u32::from_be_bytes(header[..4].try_into().ok()?) as u64;
+            let mut header_len = 8;
+            if size == 1 {
+                file.read_exact(&mut header[8..16]).ok()?;
+                size = u64::from_be_bytes(header[8..16].try_into().ok()?);
+                header_len = 16;
+            } else if size == 0 {
+                size = end - pos;
+            }
+            if size < header_len {
+                return None;
+            }
+            if &header[4..8] == *name {
+                found = Some((pos + header_len, pos + size));
+                break;
+            }
+            pos += size;
+        }
+        (start, end) = found?;
+    }
+    let len = usize::try_from(end.checked_sub(start)?).ok()?;
+    let mut body = vec![0u8; len.min(1 << 20)];
+    file.seek(SeekFrom::Start(start)).ok()?;
+    file.read_exact(&mut body).ok()?;
+    Some(body)
+}
+
+/// First `mp4a` sample entry of an `stsd` box body.
+fn parse_stsd(stsd: &[u8]) -> Option<EncoderSettings> {
+    // version/flags (4) + entry count (4), then the sample entry box.
+    let entry = stsd.get(8..)?;
+    let size = u32::from_be_bytes(entry.get(..4)?.try_into().ok()?) as usize;
+    if entry.get(4..8)? != b"mp4a" {
+        return None;
+    }
+    let entry = entry.get(8..size.min(entry.len()))?;
+
+    // SampleEntry (8) + AudioSampleEntry (20); QuickTime v1/v2 extend it.
+    let version = u16::from_be_bytes(entry.get(8..10)?.try_into().ok()?);
+    let channels = u16::from_be_bytes(entry.get(16..18)?.try_into().ok()?) as u8;
+    let sample_rate = u32::from(u16::from_be_bytes(entry.get(24..26)?.try_into().ok()?));
+    let children = match version {
+        1 => 28 + 16,
+        2 => 28 + 36,
+        _ => 28,
+    };
+
+    let mut settings = EncoderSettings::new(sample_rate, channels);
+    let mut rest = entry.get(children..)?;
+    while rest.len() >= 8 {
+        let size = u32::from_be_bytes(rest[..4].try_into().ok()?) as usize;
+        if size < 8 || size > rest.len() {
+            break;
+        }
+        if &rest[4..8] == b"esds" {
+            // Skip the full-box version/flags; a box too short for them is ignored.
+            if let Some(config) = rest.get(12..size).and_then(esds_decoder_config) {
+                apply_decoder_config(config, &mut settings);
+            }
+            break;
+        }
+        rest = &rest[size..];
+    }
+    Some(settings)
+}
+
+/// Read an MPEG-4 descriptor header; returns (tag, body, rest).
+fn descriptor(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let mut len = 0usize;
+    let mut i = 1;
+    loop {
+        let b = *data.get(i)?;
+        len = (len << 7) | (b & 0x7f) as usize;
+        i += 1;
+        if b & 0x80 == 0 || i > 4 {
+            break;
+        }
+    }
+    let body = data.get(i..i + len)?;
+    Some((tag, body, &data[i + len..]))
+}
+
+/// DecoderConfigDescriptor body inside an `esds` body.
+fn esds_decoder_config(data: &[u8]) -> Option<&[u8]> {
+    let (tag, es, _) = descriptor(data)?;
+    if tag != 0x03 {
+        return None;
+    }
+    let flags = *es.get(2)?;
+    let mut at = 3;
+    if flags & 0x80 != 0 {
+        at += 2;
+    }
+    if flags & 0x40 != 0 {
+        at += 1 + *es.get(at)? as usize;
+    }
+    if flags & 0x20 != 0 {
+        at += 2;
+    }
+    let (tag, config, _) = descriptor(es.get(at..)?)?;
+    (tag == 0x04).then_some(config)
+}
+
+/// DecoderConfigDescriptor: a peak bitrate above the average marks VBR
+/// (average 0 means unknown); the DecoderSpecificInfo that follows is the
+/// AudioSpecificConfig.
+fn apply_decoder_config(config: &[u8], settings: &mut EncoderSettings) {
+    if let (Some(max), Some(avg)) = (config.get(5..9), config.get(9..13)) {
+        let max = u32::from_be_bytes([max[0], max[1], max[2], max[3]]);
+        let avg = u32::from_be_bytes([avg[0], avg[1], avg[2], avg[3]]);
+        if avg > 0 {
+            settings.bitrate_mode = Some(if max > avg {
+                BitrateMode::Vbr
+            } else {
+                BitrateMode::Cbr
+            });
+            settings.bitrate_kbps = Some((avg + 500) / 1000);
+        }
+    }
+    if let Some((0x05, asc, _)) = config.get(13..).and_then(descriptor) {
+        apply_audio_specific_config(asc, settings);
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, bits: usize) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = *self.data.get(self.pos / 8)?;
+            value = (value << 1) | u32::from((byte >> (7 - self.pos % 8)) & 1);
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    fn object_type(&mut self) -> Option<u8> {
+        match self.read(5)? {
+            31 => Some(32 + self.read(6)? as u8),
+            aot => Some(aot as u8),
+        }
+    }
+
+    fn sample_rate(&mut self) -> Option<u32> {
+        match self.read(4)? {
+            15 => self.read(24),
+            index => sampling_frequency(index as usize),
+        }
+    }
+}
+
+/// AudioSpecificConfig: object type (profile), sample rate and channels.
+/// Explicitly signalled SBR reports the output (doubled) sample rate.
+fn apply_audio_specific_config(asc: &[u8], settings: &mut EncoderSettings) {
+    let mut bits = BitReader { data: asc, pos: 0 };
+    let parsed = (|| {
+        let aot = bits.object_type()?;
+        let core_rate = bits.sample_rate()?;
+        let channels = bits.read(4)? as u8;
+        let rate = if aot == 5 || aot == 29 {
+            bits.sample_rate()?
+        } else {
+            core_rate
+        };
+        Some((aot, rate, channels))
+    })();
+
+    if let Some((aot, rate, channels)) = parsed {
+        settings.aac_profile = Some(AacProfile::from_object_type(aot));
+        settings.sample_rate = rate;
+        settings.channels = match (aot, channels) {
+            (_, 0) => settings.channels,
+            // Parametric Stereo carries a mono core.
+            (29, 1) => 2,
+            (_, n) => n,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// MPEG-1 Layer III, 128 kbps, 44.1 kHz joint stereo frame carrying a
+    /// Xing header with quality and a LAME tag.
+    fn lame_vbr_frame(quality: u8, vbr_method: u8, preset: u16) -> Vec<u8> {
+        let mut frame = vec![0xff, 0xfb, 0x90, 0x40];
+        frame.extend_from_slice(&[0u8; 32]);
+        frame.extend_from_slice(b"Xing");
+        frame.extend_from_slice(&0x0000_000fu32.to_be_bytes());
+        frame.extend_from_slice(&[0u8; 4 + 4 + 100]);
+        frame.extend_from_slice(&[0, 0, 0, quality]);
+        let mut lame = [0u8; 36];
+        lame[..9].copy_from_slice(b"LAME3.100");
+        lame[9] = vbr_method;
+        lame[26..28].copy_from_slice(&preset.to_be_bytes());
+        frame.extend_from_slice(&lame);
+        frame.resize(417, 0);
+        frame
+    }
+
+    #[test]
+    fn lame_v0_is_detected() {
+        let settings = parse_mp3(&lame_vbr_frame(100, 4, 500)).unwrap();
+        assert_eq!(settings.bitrate_mode, Some(BitrateMode::Vbr));
+        assert_eq!(settings.vbr_quality, Some(0));
+        assert_eq!(settings.lame_preset.as_deref(), Some("V0"));
+        assert_eq!(settings.encoder.as_deref(), Some("LAME3.100"));
+        assert_eq!(settings.sample_rate, 44100);
+        assert_eq!(settings.joint_stereo, Some(true));
+        assert_eq!(
+            settings.describe(),
+            "LAME3.100 VBR V0, preset V0, 44100 Hz, joint stereo"
+        );
+    }
+
+    #[test]
+    fn plain_frames_are_cbr_at_header_bitrate() {
+        let mut data = vec![0u8; 20];
+        data.extend_from_slice(&[0xff, 0xfb, 0x90, 0x40]);
+        data.resize(600, 0);
+        let settings = parse_mp3(&data).unwrap();
+        assert_eq!(settings.bitrate_mode, Some(BitrateMode::Cbr));
+        assert_eq!(settings.bitrate_kbps, Some(128));
+    }
+
+    #[test]
+    fn he_aac_config_reports_output_rate() {
+        // AOT 5 (SBR), core 24 kHz (index 6), stereo, extension 48 kHz (index 3).
+        let asc = [0b0010_1011, 0b0001_0001, 0b1000_0000];
+        let mut settings = EncoderSettings::new(24000, 2);
+        apply_audio_specific_config(&asc, &mut settings);
+        assert_eq!(settings.aac_profile, Some(AacProfile::He));
+        assert_eq!(settings.sample_rate, 48000);
+        assert_eq!(settings.channels, 2);
+    }
+
+    #[test]
+    fn aac_bitrate_mode_is_read_from_esds_and_adts() {
+        let config = |max: u32, avg: u32| {
+            let mut config = vec![0x40, 0x15, 0, 0, 0];
+            config.extend_from_slice(&max.to_be_bytes());
+            config.extend_from_slice(&avg.to_be_bytes());
+            config.extend_from_slice(&[0x05, 2, 0x12, 0x10]);
+            config
+        };
+        let mut vbr = EncoderSettings::new(44100, 2);
+        apply_decoder_config(&config(320_000, 256_000), &mut vbr);
+        assert_eq!(vbr.bitrate_mode, Some(BitrateMode::Vbr));
+        assert_eq!(vbr.aac_profile, Some(AacProfile::Lc));
+        assert_eq!(vbr.describe(), "LC VBR ~256k, 44100 Hz, stereo");
+        let mut cbr = EncoderSettings::new(44100, 2);
+        apply_decoder_config(&config(256_000, 256_000), &mut cbr);
+        assert_eq!(cbr.bitrate_mode, Some(BitrateMode::Cbr));
+        assert_eq!(cbr.bitrate_kbps, Some(256));
+
+        // LC, 44.1 kHz, stereo; buffer fullness 0x7ff.
+        let adts = [0xff, 0xf1, 0x50, 0x80, 0x00, 0x1f, 0xfc];
+        let settings = parse_adts(&adts).unwrap();
+        assert_eq!(settings.bitrate_mode, Some(BitrateMode::Vbr));
+        assert_eq!((settings.sample_rate, settings.channels), (44100, 2));
+    }
+
+    #[test]
+    fn truncated_esds_is_skipped() {
+        let mut stsd = vec![0u8; 8];
+        stsd.extend_from_slice(&(8u32 + 28 + 10).to_be_bytes());
+        stsd.extend_from_slice(b"mp4a");
+        let mut entry = vec![0u8; 28];
+        entry[16..18].copy_from_slice(&2u16.to_be_bytes());
+        entry[24..26].copy_from_slice(&44100u16.to_be_bytes());
+        stsd.extend_from_slice(&entry);
+        stsd.extend_from_slice(&10u32.to_be_bytes());
+        stsd.extend_from_slice(b"esds\0\0");
+        let settings = parse_stsd(&stsd).unwrap();
+        assert_eq!((settings.sample_rate, settings.channels), (44100, 2));
+        assert_eq!(settings.aac_profile, None);
+    }
+
+    #[test]
+    fn vorbis_nominal_bitrate_is_read() {
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.extend_from_slice(&0u32.to_le_bytes());
+        ident.push(2);
+        ident.extend_from_slice(&48000u32.to_le_bytes());
+        ident.extend_from_slice(&0i32.to_le_bytes());
+        ident.extend_from_slice(&192_000i32.to_le_bytes());
+        ident.extend_from_slice(&0i32.to_le_bytes());
+        let settings = parse_vorbis_ident(&ident).unwrap();
+        assert_eq!(settings.bitrate_kbps, Some(192));
+        assert_eq!(settings.describe(), "Vorbis VBR ~192k, 48000 Hz, stereo");
+    }
+}
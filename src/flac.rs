@@ -0,0 +1,980 @@
+//! FLAC metadata block I/O and frame re-encoding.
+//!
+//! A FLAC stream is the `fLaC` marker, a chain of metadata blocks (each with
+//! a last-block flag, a 7-bit type and a 24-bit length) and then the audio
+//! frames. Tag writing rewrites the block chain and copies the frames through
+//! untouched. Gain decodes the frames, scales the samples and encodes new
+//! frames (fixed predictors, Rice-coded residuals) behind the original
+//! blocks, so no ffmpeg is needed.
+
+use anyhow::{bail, Context, Result};
+use md5::{Digest, Md5};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::decoder::{Decoder, PcmFormat};
+use crate::pcm::Tpdf;
+use crate::replace::{self, FrameMatch};
+
+pub const MAGIC: &[u8; 4] = b"fLaC";
+pub const BLOCK_STREAMINFO: u8 = 0;
+pub const BLOCK_SEEKTABLE: u8 = 3;
+pub const BLOCK_VORBIS_COMMENT: u8 = 4;
+
+/// One metadata block: type and body.
+pub type Block = (u8, Vec<u8>);
+
+/// Bytes moved per read/write when the block chain changes length.
+const SHIFT_CHUNK: usize = 1 << 20;
+
+/// Samples per channel in each encoded frame.
+const BLOCK_SIZE: usize = 4096;
+/// Spacing of the rebuilt seek table.
+const SEEK_INTERVAL_SECS: u64 = 10;
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_PARTITION_ORDER: u32 = 8;
+/// Largest Rice parameter with the 4-bit parameter encoding.
+const MAX_RICE4_PARAM: u32 = 14;
+const MAX_RICE5_PARAM: u32 = 30;
+
+/// Read the marker and all metadata blocks, leaving `reader` at the first
+/// audio frame.
+pub fn read_blocks(reader: &mut impl Read) -> Result<Vec<Block>> {
+    let mut magic = [0u8; 4];
+    reader
+        .read_exact(&mut magic)
+        .context("Failed to read FLAC header")?;
+    if &magic != MAGIC {
+        bail!("Not a FLAC stream (missing fLaC marker)");
+    }
+
+    let mut blocks = Vec::new();
+    loop {
+        let mut header = [0u8; 4];
+        reader
+            .read_exact(&mut header)
+            .context("Failed to read FLAC metadata block header")?;
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let mut body = vec![0u8; len];
+        reader
+            .read_exact(&mut body)
+            .context("Truncated FLAC metadata block")?;
+        blocks.push((block_type, body));
+        if is_last {
+            return Ok(blocks);
+        }
+    }
+}
+
+/// Write the marker and `blocks`, flagging the final one as last.
+pub fn write_blocks(writer: &mut impl Write, blocks: &[Block]) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    let last = blocks.len().saturating_sub(1);
+    for (i, (block_type, body)) in blocks.iter().enumerate() {
+        if body.len() >= 1 << 24 {
+            bail!("FLAC metadata block too large");
+        }
+        let flag = if i == last { 0x80 } else { 0 };
+        let len = (body.len() as u32).to_be_bytes();
+        writer.write_all(&[flag | block_type, len[1], len[2], len[3]])?;
+        writer.write_all(body)?;
+    }
+    Ok(())
+}
+
+/// Rewrite the FLAC file at `path` with the block chain `edit` produces; the
+/// audio frames are copied unchanged. The edit is made on a staged copy that
+/// then replaces the original.
+pub fn rewrite_blocks(path: &Path, edit: impl FnOnce(&mut Vec<Block>) -> Result<()>) -> Result<()> {
+    replace::modify_copy(path, FrameMatch::Verbatim, |staged| {
+        edit_blocks(staged, edit)
+    })
+    .context("Failed to write FLAC metadata")
+}
+
+/// Replace the block chain of the FLAC file at `path` in place. The frames
+/// only move when the new chain differs in length from the old one.
+fn edit_blocks(path: &Path, edit: impl FnOnce(&mut Vec<Block>) -> Result<()>) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut blocks = read_blocks(&mut BufReader::new(&file))?;
+    let old_len = 4 + blocks
+        .iter()
+        .map(|(_, body)| 4 + body.len() as u64)
+        .sum::<u64>();
+    edit(&mut blocks)?;
+    let mut header = Vec::new();
+    write_blocks(&mut header, &blocks)?;
+    let new_len = header.len() as u64;
+
+    let file_len = file.metadata()?.len();
+    let mut buf = vec![0u8; SHIFT_CHUNK];
+    if new_len < old_len {
+        // Move the frames forward front to back, then drop the tail.
+        let mut pos = old_len;
+        while pos < file_len {
+            let len = SHIFT_CHUNK.min((file_len - pos) as usize);
+            file.seek(SeekFrom::Start(pos))?;
+            file.read_exact(&mut buf[..len])?;
+            file.seek(SeekFrom::Start(pos - (old_len - new_len)))?;
+            file.write_all(&buf[..len])?;
+            pos += len as u64;
+        }
+        file.set_len(file_len - (old_len - new_len))?;
+    } else if new_len > old_len {
+        // Move the frames back, back to front so nothing is overwritten
+        // before it is read.
+        let mut end = file_len;
+        while end > old_len {
+            let len = SHIFT_CHUNK.min((end - old_len) as usize);
+            let pos = end - len as u64;
+            file.seek(SeekFrom::Start(pos))?;
+            file.read_exact(&mut buf[..len])?;
+            file.seek(SeekFrom::Start(pos + (new_len - old_len)))?;
+            file.write_all(&buf[..len])?;
+            end = pos;
+        }
+    }
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Give the freshly encoded FLAC at `encoded` every metadata block of
+/// `source` (tags, pictures, cue sheet, application blocks, padding) in
+/// their original order. STREAMINFO and SEEKTABLE describe the new frames,
+/// so those come from the encoder.
+pub fn carry_blocks(source: &Path, encoded: &Path) -> Result<()> {
+    let file =
+        File::open(source).with_context(|| format!("Failed to open {}", source.display()))?;
+    let source_blocks = read_blocks(&mut BufReader::new(file))?;
+
+    // `encoded` is itself a staged file.
+    edit_blocks(encoded, |blocks| {
+        let mut merged: Vec<Block> = blocks
+            .drain(..)
+            .filter(|(t, _)| matches!(*t, BLOCK_STREAMINFO | BLOCK_SEEKTABLE))
+            .collect();
+        if merged.first().map(|(t, _)| *t) != Some(BLOCK_STREAMINFO) {
+            bail!("Encoded FLAC has no STREAMINFO block");
+        }
+        merged.extend(
+            source_blocks
+                .into_iter()
+                .filter(|(t, _)| !matches!(*t, BLOCK_STREAMINFO | BLOCK_SEEKTABLE)),
+        );
+        *blocks = merged;
+        Ok(())
+    })
+}
+
+/// Whether `apply_gain` can encode samples of `format`.
+pub fn can_encode(format: PcmFormat) -> bool {
+    !format.float && (4..=24).contains(&format.bits)
+}
+
+/// Whether the FLAC file at `path` has a STREAMINFO `apply_gain` can encode
+/// for.
+pub fn supports(path: &Path) -> bool {
+    read_source(path).is_ok_and(|(_, info)| can_encode(info.format()))
+}
+
+/// Write the FLAC file at `source`, scaled by `gain_db`, to `output`. Every
+/// metadata block is kept in order except STREAMINFO and SEEKTABLE, which
+/// are rebuilt for the new frames. With `dither`, output of 16 bits or less
+/// gets TPDF dither.
+pub fn apply_gain(source: &Path, output: &Path, gain_db: f64, dither: bool) -> Result<()> {
+    let (blocks, info) = read_source(source)?;
+    if !can_encode(info.format()) {
+        bail!("Cannot encode {} FLAC", info.format().label());
+    }
+    let mut decoder = Decoder::open(source, 0)?;
+    if decoder.spec().channels != info.channels {
+        bail!("Decoded channel count does not match STREAMINFO");
+    }
+
+    let gain = 10f64.powf(gain_db / 20.0);
+    let scale = gain * (1u64 << (info.bits - 1)) as f64;
+    let (min, max) = (-(1i64 << (info.bits - 1)), (1i64 << (info.bits - 1)) - 1);
+    let mut tpdf = (dither && info.bits <= 16).then(Tpdf::new);
+
+    let file =
+        File::create(output).with_context(|| format!("Failed to create {}", output.display()))?;
+    let kept = blocks
+        .into_iter()
+        .filter(|(t, _)| !matches!(*t, BLOCK_STREAMINFO | BLOCK_SEEKTABLE))
+        .collect();
+    let mut encoder = Encoder::new(BufWriter::new(file), info, kept)?;
+    while let Some(block) = decoder.next_block()? {
+        encoder.push(block.iter().map(|&sample| {
+            let mut scaled = sample as f64 * scale;
+            if let Some(tpdf) = &mut tpdf {
+                scaled += tpdf.sample();
+            }
+            (scaled.round() as i64).clamp(min, max)
+        }))?;
+    }
+    decoder.finish()?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Metadata blocks and parsed STREAMINFO of the FLAC file at `path`.
+fn read_source(path: &Path) -> Result<(Vec<Block>, StreamInfo)> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let blocks = read_blocks(&mut BufReader::new(file))?;
+    let info = match blocks.first() {
+        Some((BLOCK_STREAMINFO, body)) => StreamInfo::parse(body)?,
+        _ => bail!("FLAC stream has no STREAMINFO block"),
+    };
+    Ok((blocks, info))
+}
+
+/// The STREAMINFO fields the encoder needs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct StreamInfo {
+    sample_rate: u32,
+    channels: usize,
+    bits: u32,
+    /// Samples per channel; 0 when unknown.
+    total_samples: u64,
+}
+
+impl StreamInfo {
+    fn parse(body: &[u8]) -> Result<Self> {
+        if body.len() < 34 {
+            bail!("Truncated FLAC STREAMINFO block");
+        }
+        let packed = u64::from_be_bytes(body[10..18].try_into()?);
+        Ok(Self {
+            sample_rate: (packed >> 44) as u32,
+            channels: ((packed >> 41) & 0x7) as usize + 1,
+            bits: ((packed >> 36) & 0x1f) as u32 + 1,
+            total_samples: packed & 0xf_ffff_ffff,
+        })
+    }
+
+    fn format(self) -> PcmFormat {
+        PcmFormat {
+            bits: self.bits,
+            float: false,
+        }
+    }
+
+    /// STREAMINFO body for frames of `BLOCK_SIZE` samples.
+    fn to_body(self, frame_sizes: (u32, u32), md5: &[u8]) -> Vec<u8> {
+        let mut body = Vec::with_capacity(34);
+        body.extend_from_slice(&(BLOCK_SIZE as u16).to_be_bytes());
+        body.extend_from_slice(&(BLOCK_SIZE as u16).to_be_bytes());
+        body.extend_from_slice(&frame_sizes.0.to_be_bytes()[1..]);
+        body.extend_from_slice(&frame_sizes.1.to_be_bytes()[1..]);
+        let packed = (self.sample_rate as u64) << 44
+            | ((self.channels - 1) as u64) << 41
+            | ((self.bits - 1) as u64) << 36
+            | (self.total_samples & 0xf_ffff_ffff);
+        body.extend_from_slice(&packed.to_be_bytes());
+        body.extend_from_slice(md5);
+        body
+    }
+}
+
+/// Streams interleaved samples into FLAC frames. STREAMINFO and SEEKTABLE
+/// are written as placeholders first and filled in by `finish`.
+struct Encoder<W: Write + Seek> {
+    writer: W,
+    info: StreamInfo,
+    /// Interleaved samples not yet encoded.
+    pending: Vec<i64>,
+    frames: u64,
+    samples: u64,
+    /// Bytes of frames written so far; seek offsets count from the first.
+    audio_bytes: u64,
+    frame_sizes: Option<(u32, u32)>,
+    seek_points: u64,
+    seektable: Vec<u8>,
+    md5: Md5,
+}
+
+impl<W: Write + Seek> Encoder<W> {
+    /// Write the marker and block chain: STREAMINFO, SEEKTABLE (sized from
+    /// the expected length), then `blocks`.
+    fn new(mut writer: W, info: StreamInfo, blocks: Vec<Block>) -> Result<Self> {
+        let interval = SEEK_INTERVAL_SECS * info.sample_rate as u64;
+        let seek_points = if interval == 0 {
+            0
+        } else {
+            info.total_samples.div_ceil(interval)
+        };
+        let mut chain = vec![(BLOCK_STREAMINFO, vec![0; 34])];
+        if seek_points > 0 {
+            chain.push((BLOCK_SEEKTABLE, vec![0; 18 * seek_points as usize]));
+        }
+        chain.extend(blocks);
+
+        write_blocks(&mut writer, &chain)?;
+        Ok(Self {
+            writer,
+            info,
+            pending: Vec::with_capacity(BLOCK_SIZE * info.channels),
+            frames: 0,
+            samples: 0,
+            audio_bytes: 0,
+            frame_sizes: None,
+            seek_points,
+            seektable: Vec::new(),
+            md5: Md5::new(),
+        })
+    }
+
+    fn push(&mut self, samples: impl Iterator<Item = i64>) -> Result<()> {
+        let frame_len = BLOCK_SIZE * self.info.channels;
+        for sample in samples {
+            self.pending.push(sample);
+            if self.pending.len() == frame_len {
+                self.write_frame()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self) -> Result<()> {
+        let len = (self.pending.len() / self.info.channels) as u64;
+        let frame = encode_frame(self.info, self.frames, &self.pending);
+
+        // Point at the first frame reaching the next target. Any frame start
+        // is a valid point, so a frame that overshoots a target is fine.
+        let interval = SEEK_INTERVAL_SECS * self.info.sample_rate as u64;
+        let recorded = (self.seektable.len() / 18) as u64;
+        if recorded < self.seek_points && recorded * interval < self.samples + len {
+            self.seektable
+                .extend_from_slice(&self.samples.to_be_bytes());
+            self.seektable
+                .extend_from_slice(&self.audio_bytes.to_be_bytes());
+            self.seektable
+                .extend_from_slice(&(len as u16).to_be_bytes());
+        }
+
+        let bytes = (self.info.bits as usize).div_ceil(8);
+        let raw: Vec<u8> = self
+            .pending
+            .iter()
+            .flat_map(|s| s.to_le_bytes().into_iter().take(bytes))
+            .collect();
+        self.md5.update(&raw);
+
+        self.writer.write_all(&frame)?;
+        let size = frame.len() as u32;
+        self.frame_sizes = Some(match self.frame_sizes {
+            Some((min, max)) => (min.min(size), max.max(size)),
+            None => (size, size),
+        });
+        self.frames += 1;
+        self.samples += len;
+        self.audio_bytes += frame.len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Encode the final partial frame, fill in STREAMINFO and SEEKTABLE, and
+    /// hand back the writer.
+    fn finish(mut self) -> Result<W> {
+        if !self.pending.is_empty() {
+            self.write_frame()?;
+        }
+        let info = StreamInfo {
+            total_samples: self.samples,
+            ..self.info
+        };
+        let md5 = self.md5.finalize();
+        let streaminfo = info.to_body(self.frame_sizes.unwrap_or((0, 0)), &md5);
+        // Marker plus STREAMINFO header, then the SEEKTABLE header.
+        self.writer.seek(SeekFrom::Start(8))?;
+        self.writer.write_all(&streaminfo)?;
+        if self.seek_points > 0 {
+            // Points left over (the stream came up short) are placeholders.
+            while self.seektable.len() < 18 * self.seek_points as usize {
+                self.seektable.extend_from_slice(&[0xff; 8]);
+                self.seektable.extend_from_slice(&[0; 10]);
+            }
+            self.writer.seek(SeekFrom::Start(8 + 34 + 4))?;
+            self.writer.write_all(&self.seektable)?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Encode one frame of interleaved `samples`.
+fn encode_frame(info: StreamInfo, number: u64, samples: &[i64]) -> Vec<u8> {
+    let len = samples.len() / info.channels;
+    let channel = |c: usize| -> Vec<i64> {
+        samples
+            .iter()
+            .skip(c)
+            .step_by(info.channels)
+            .copied()
+            .collect()
+    };
+
+    // Stereo can also be coded as side (one bit wider) with left, right or
+    // mid; keep whichever pair is cheapest.
+    let (assignment, subframes) = if info.channels == 2 {
+        let (left, right) = (channel(0), channel(1));
+        let side: Vec<i64> = left.iter().zip(&right).map(|(l, r)| l - r).collect();
+        let mid: Vec<i64> = left.iter().zip(&right).map(|(l, r)| (l + r) >> 1).collect();
+        let candidates = [
+            (left, info.bits),
+            (right, info.bits),
+            (side, info.bits + 1),
+            (mid, info.bits),
+        ]
+        .map(|(samples, bits)| {
+            let plan = plan_subframe(&samples, bits);
+            (samples, bits, plan)
+        });
+        // Independent, left/side, right/side (side first), mid/side.
+        let (assignment, pair) = [
+            (0b0001, [0, 1]),
+            (0b1000, [0, 2]),
+            (0b1001, [2, 1]),
+            (0b1010, [3, 2]),
+        ]
+        .into_iter()
+        .min_by_key(|(_, pair)| pair.iter().map(|&i| candidates[i].2 .1).sum::<u64>())
+        .unwrap();
+        (assignment, pair.map(|i| candidates[i].clone()).to_vec())
+    } else {
+        let subframes = (0..info.channels)
+            .map(|c| {
+                let samples = channel(c);
+                let plan = plan_subframe(&samples, info.bits);
+                (samples, info.bits, plan)
+            })
+            .collect();
+        (info.channels as u64 - 1, subframes)
+    };
+
+    let mut w = BitWriter::default();
+    w.write(0xfff8, 16);
+    // Block size as a 16-bit value at the end of the header; sample rate
+    // from STREAMINFO.
+    w.write(0b0111, 4);
+    w.write(0, 4);
+    w.write(assignment, 4);
+    let size_code = match info.bits {
+        8 => 1,
+        12 => 2,
+        16 => 4,
+        20 => 5,
+        24 => 6,
+        _ => 0,
+    };
+    w.write(size_code, 3);
+    w.write(0, 1);
+    write_utf8(&mut w, number);
+    w.write(len as u64 - 1, 16);
+    let crc = crc8(&w.bytes);
+    w.write(crc as u64, 8);
+
+    for (samples, bits, (plan, _)) in &subframes {
+        write_subframe(&mut w, samples, *bits, plan);
+    }
+    w.align();
+    let crc = crc16(&w.bytes);
+    w.write(crc as u64, 16);
+    w.bytes
+}
+
+/// How one channel of a frame is coded.
+#[derive(Debug, Clone, PartialEq)]
+enum Subframe {
+    Constant,
+    Verbatim,
+    /// Fixed polynomial predictor of `order`, residual Rice-coded with one
+    /// parameter per partition.
+    Fixed {
+        order: usize,
+        partition_order: u32,
+        params: Vec<u32>,
+    },
+}
+
+/// The cheapest subframe for `samples` and its size in bits.
+fn plan_subframe(samples: &[i64], bits: u32) -> (Subframe, u64) {
+    let len = samples.len();
+    if samples.iter().all(|&s| s == samples[0]) {
+        return (Subframe::Constant, 8 + bits as u64);
+    }
+    let mut best = (Subframe::Verbatim, 8 + len as u64 * bits as u64);
+
+    // Order-n residuals are the n-th differences; build them in place.
+    let mut residual = samples.to_vec();
+    for order in 0..=MAX_FIXED_ORDER.min(len - 1) {
+        if order > 0 {
+            for i in (order..len).rev() {
+                residual[i] -= residual[i - 1];
+            }
+        }
+        let (partition_order, params, rice_bits) = plan_rice(&residual[order..], order, len);
+        let cost = 8 + (order as u64) * bits as u64 + rice_bits;
+        if cost < best.1 {
+            best = (
+                Subframe::Fixed {
+                    order,
+                    partition_order,
+                    params,
+                },
+                cost,
+            );
+        }
+    }
+    best
+}
+
+/// Partition order, per-partition Rice parameters and estimated size in
+/// bits of the residual section coding `residual` (the last
+/// `block_len - order` samples of a block).
+fn plan_rice(residual: &[i64], order: usize, block_len: usize) -> (u32, Vec<u32>, u64) {
+    let max_order = (1..=MAX_PARTITION_ORDER)
+        .take_while(|&p| block_len.is_multiple_of(1 << p) && (block_len >> p) > order)
+        .last()
+        .unwrap_or(0);
+
+    // Sums of the folded residuals over the finest partitions, merged
+    // pairwise for each coarser order.
+    let finest = block_len >> max_order;
+    let mut sums = vec![0u64; 1 << max_order];
+    for (i, &r) in residual.iter().enumerate() {
+        sums[(i + order) / finest] += fold(r);
+    }
+
+    let mut best: Option<(u32, Vec<u32>, u64)> = None;
+    for partition_order in (0..=max_order).rev() {
+        let size = (block_len >> partition_order) as u64;
+        let (params, bits): (Vec<u32>, Vec<u64>) = sums
+            .iter()
+            .enumerate()
+            .map(|(i, &sum)| {
+                let count = if i == 0 { size - order as u64 } else { size };
+                rice_param(sum, count)
+            })
+            .unzip();
+        let param_bits = if params.iter().all(|&k| k <= MAX_RICE4_PARAM) {
+            4
+        } else {
+            5
+        };
+        let cost = 6 + param_bits * params.len() as u64 + bits.iter().sum::<u64>();
+        if best.as_ref().is_none_or(|b| cost < b.2) {
+            best = Some((partition_order, params, cost));
+        }
+        sums = sums.chunks(2).map(|pair| pair.iter().sum()).collect();
+    }
+    best.unwrap()
+}
+
+/// Rice parameter for `count` folded values summing to `sum`, and the
+/// approximate bits they take.
+fn rice_param(sum: u64, count: u64) -> (u32, u64) {
+    if count == 0 {
+        return (0, 0);
+    }
+    let mean = sum / count;
+    let guess = if mean == 0 {
+        0
+    } else {
+        63 - mean.leading_zeros()
+    };
+    let guess = guess.min(MAX_RICE5_PARAM);
+    (guess.saturating_sub(1)..=(guess + 1).min(MAX_RICE5_PARAM))
+        .map(|k| (k, count * (k as u64 + 1) + (sum >> k)))
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap()
+}
+
+/// Map signed residuals to unsigned: 0, -1, 1, -2, ... -> 0, 1, 2, 3, ...
+fn fold(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn write_subframe(w: &mut BitWriter, samples: &[i64], bits: u32, plan: &Subframe) {
+    // Zero padding bit, 6-bit type, no wasted bits.
+    match plan {
+        Subframe::Constant => {
+            w.write(0b000000 << 1, 8);
+            w.write_signed(samples[0], bits);
+        }
+        Subframe::Verbatim => {
+            w.write(0b000001 << 1, 8);
+            for &sample in samples {
+                w.write_signed(sample, bits);
+            }
+        }
+        Subframe::Fixed {
+            order,
+            partition_order,
+            params,
+        } => {
+            w.write((0b001000 | *order as u64) << 1, 8);
+            for &sample in &samples[..*order] {
+                w.write_signed(sample, bits);
+            }
+            let mut residual = samples.to_vec();
+            for o in 1..=*order {
+                for i in (o..residual.len()).rev() {
+                    residual[i] -= residual[i - 1];
+                }
+            }
+
+            let param_bits = if params.iter().all(|&k| k <= MAX_RICE4_PARAM) {
+                w.write(0, 2);
+                4
+            } else {
+                w.write(1, 2);
+                5
+            };
+            w.write(*partition_order as u64, 4);
+            let size = samples.len() >> partition_order;
+            for (i, &k) in params.iter().enumerate() {
+                w.write(k as u64, param_bits);
+                let start = if i == 0 { *order } else { i * size };
+                for &r in &residual[start..(i + 1) * size] {
+                    let folded = fold(r);
+                    w.write_unary(folded >> k);
+                    w.write(folded, k);
+                }
+            }
+        }
+    }
+}
+
+/// Frame numbers use the extended UTF-8 scheme (up to 36 bits).
+fn write_utf8(w: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        w.write(value, 8);
+        return;
+    }
+    // With n continuation bytes the lead byte keeps 6 - n value bits.
+    let continuation = (1..=6u32).find(|&n| value < 1 << (5 * n + 6)).unwrap();
+    w.write(
+        ((0xff << (7 - continuation)) as u8) as u64 | (value >> (6 * continuation)),
+        8,
+    );
+    for n in (0..continuation).rev() {
+        w.write(0x80 | ((value >> (6 * n)) & 0x3f), 8);
+    }
+}
+
+/// MSB-first bit packing.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    /// Append the low `bits` (at most 32) bits of `value`.
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        self.acc = (self.acc << bits) | (value & ((1 << bits) - 1));
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    /// Two's complement in `bits` bits.
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    /// `zeros` zero bits, then a one.
+    fn write_unary(&mut self, mut zeros: u64) {
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+/// Frame header CRC-8: polynomial 0x07, zero init.
+fn crc8(data: &[u8]) -> u8 {
+    const TABLE: [u8; 256] = {
+        let mut table = [0u8; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u8;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 0x80 != 0 {
+                    (crc << 1) ^ 0x07
+                } else {
+                    crc << 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    data.iter().fold(0, |crc, &b| TABLE[(crc ^ b) as usize])
+}
+
+/// Frame CRC-16: polynomial 0x8005, zero init.
+fn crc16(data: &[u8]) -> u16 {
+    const TABLE: [u16; 256] = {
+        let mut table = [0u16; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = (i as u16) << 8;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 0x8000 != 0 {
+                    (crc << 1) ^ 0x8005
+                } else {
+                    crc << 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    data.iter().fold(0, |crc, &b| {
+        (crc << 8) ^ TABLE[((crc >> 8) as u8 ^ b) as usize]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn flac(blocks: &[Block], frames: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_blocks(&mut data, blocks).unwrap();
+        data.extend_from_slice(frames);
+        data
+    }
+
+    /// Encode interleaved `samples` to `path` behind `blocks`.
+    fn encode(path: &Path, info: StreamInfo, blocks: Vec<Block>, samples: &[i64]) {
+        let file = File::create(path).unwrap();
+        let mut encoder = Encoder::new(BufWriter::new(file), info, blocks).unwrap();
+        encoder.push(samples.iter().copied()).unwrap();
+        encoder.finish().unwrap();
+    }
+
+    /// Decode `path` back to integers of `bits`.
+    fn decode(path: &Path, bits: u32) -> Vec<i64> {
+        let mut decoder = Decoder::open(path, 0).unwrap();
+        let scale = (1u64 << (bits - 1)) as f64;
+        let mut samples = Vec::new();
+        while let Some(block) = decoder.next_block().unwrap() {
+            samples.extend(block.iter().map(|&s| (s as f64 * scale) as i64));
+        }
+        samples
+    }
+
+    /// Silence, a ramp, loud noise and a correlated stereo tone in turn, so
+    /// every subframe type and stereo mode gets used.
+    fn test_signal(frames: usize, channels: usize, bits: u32) -> Vec<i64> {
+        let max = (1i64 << (bits - 1)) - 1;
+        let mut rng = 0x2545_f491_4f6c_dd1du64;
+        let mut samples = Vec::with_capacity(frames * channels);
+        for i in 0..frames {
+            let tone = ((i as f64 * 0.01).sin() * max as f64 * 0.8) as i64;
+            for c in 0..channels {
+                rng ^= rng << 13;
+                rng ^= rng >> 7;
+                rng ^= rng << 17;
+                let noise = (rng % (2 * max as u64 + 1)) as i64 - max;
+                samples.push(match i / BLOCK_SIZE {
+                    0 => 0,
+                    1 => (i as i64 * 37 - c as i64 * 1000) % max,
+                    2 => noise,
+                    _ => tone + c as i64 * (noise >> 8),
+                });
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn encoded_frames_decode_to_the_same_samples() {
+        let dir = tempfile::tempdir().unwrap();
+        for (channels, bits) in [(1, 24), (2, 16), (2, 24), (3, 8)] {
+            // Not a whole number of frames, so the last one is short.
+            let samples = test_signal(4 * BLOCK_SIZE + 1000, channels, bits);
+            let info = StreamInfo {
+                sample_rate: 44100,
+                channels,
+                bits,
+                total_samples: 0,
+            };
+            let path = dir.path().join(format!("{channels}x{bits}.flac"));
+            encode(&path, info, Vec::new(), &samples);
+
+            assert_eq!(decode(&path, bits), samples, "{channels}ch {bits}-bit");
+            let (_, written) = read_source(&path).unwrap();
+            assert_eq!(written.total_samples, 4 * BLOCK_SIZE as u64 + 1000);
+        }
+    }
+
+    #[test]
+    fn gain_rewrites_frames_and_keeps_other_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("src.flac");
+        let output = dir.path().join("out.flac");
+        let frames = 30 * 8000;
+        let info = StreamInfo {
+            sample_rate: 8000,
+            channels: 2,
+            bits: 16,
+            total_samples: frames as u64,
+        };
+        let samples = test_signal(frames, 2, 16);
+        // Vendor string, then one field; all lengths little-endian.
+        let comment = [
+            &6u32.to_le_bytes()[..],
+            b"vendor",
+            &1u32.to_le_bytes(),
+            &14u32.to_le_bytes(),
+            b"TITLE=original",
+        ]
+        .concat();
+        let others = vec![
+            (2, b"APPLdata".to_vec()),
+            (BLOCK_VORBIS_COMMENT, comment),
+            (1, vec![0; 16]),
+        ];
+        encode(&source, info, others.clone(), &samples);
+
+        let gain_db = -6.0;
+        apply_gain(&source, &output, gain_db, false).unwrap();
+
+        let scale = 10f64.powf(gain_db / 20.0) * 32768.0;
+        let expected: Vec<i64> = samples
+            .iter()
+            .map(|&s| (s as f64 / 32768.0 * scale).round() as i64)
+            .collect();
+        assert_eq!(decode(&output, 16), expected);
+
+        let blocks = read_blocks(&mut BufReader::new(File::open(&output).unwrap())).unwrap();
+        assert_eq!(blocks[0].0, BLOCK_STREAMINFO);
+        assert_eq!(StreamInfo::parse(&blocks[0].1).unwrap(), info);
+        // One seek point every 10 s.
+        assert_eq!(blocks[1].0, BLOCK_SEEKTABLE);
+        assert_eq!(blocks[1].1.len(), 3 * 18);
+        assert_eq!(&blocks[2..], &others[..]);
+    }
+
+    #[test]
+    fn only_integer_depths_up_to_24_bits_are_encoded() {
+        let format = |bits, float| PcmFormat { bits, float };
+        assert!(can_encode(format(16, false)));
+        assert!(can_encode(format(24, false)));
+        assert!(!can_encode(format(32, false)));
+        assert!(!can_encode(format(32, true)));
+    }
+
+    #[test]
+    fn rewritten_blocks_move_the_frames_intact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.flac");
+        // Longer than one shift chunk, and not a multiple of it.
+        let frames: Vec<u8> = (0..5 * SHIFT_CHUNK / 2).map(|i| (i % 251) as u8).collect();
+        let original = [
+            (BLOCK_STREAMINFO, vec![1; 34]),
+            (BLOCK_VORBIS_COMMENT, b"old tags".to_vec()),
+        ];
+        fs::write(&path, flac(&original, &frames)).unwrap();
+
+        for tags in [&b"much longer tags than before"[..], b"short", b"equal"] {
+            rewrite_blocks(&path, |blocks| {
+                blocks[1].1 = tags.to_vec();
+                Ok(())
+            })
+            .unwrap();
+            let expected = flac(
+                &[
+                    (BLOCK_STREAMINFO, vec![1; 34]),
+                    (BLOCK_VORBIS_COMMENT, tags.to_vec()),
+                ],
+                &frames,
+            );
+            assert!(fs::read(&path).unwrap() == expected);
+        }
+        assert!(!replace::staging_path(&path).exists());
+    }
+
+    #[test]
+    fn carried_blocks_keep_order_and_encoder_streaminfo() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("src.flac");
+        let encoded = dir.path().join("enc.flac");
+        fs::write(
+            &source,
+            flac(
+                &[
+                    (BLOCK_STREAMINFO, vec![1; 34]),
+                    (2, b"APPLdata".to_vec()),
+                    (BLOCK_SEEKTABLE, vec![1; 18]),
+                    (BLOCK_VORBIS_COMMENT, b"original tags".to_vec()),
+                    (5, b"cuesheet".to_vec()),
+                    (6, b"picture".to_vec()),
+                ],
+                b"\xff\xf8old frames",
+            ),
+        )
+        .unwrap();
+        fs::write(
+            &encoded,
+            flac(
+                &[
+                    (BLOCK_STREAMINFO, vec![2; 34]),
+                    (BLOCK_SEEKTABLE, vec![2; 18]),
+                    (BLOCK_VORBIS_COMMENT, b"ffmpeg tags".to_vec()),
+                    (1, vec![0; 16]),
+                ],
+                b"\xff\xf8new frames",
+            ),
+        )
+        .unwrap();
+
+        carry_blocks(&source, &encoded).unwrap();
+
+        let expected = flac(
+            &[
+                (BLOCK_STREAMINFO, vec![2; 34]),
+                (BLOCK_SEEKTABLE, vec![2; 18]),
+                (2, b"APPLdata".to_vec()),
+                (BLOCK_VORBIS_COMMENT, b"original tags".to_vec()),
+                (5, b"cuesheet".to_vec()),
+                (6, b"picture".to_vec()),
+            ],
+            b"\xff\xf8new frames",
+        );
+        assert_eq!(fs::read(&encoded).unwrap(), expected);
+    }
+}
@@ -0,0 +1,235 @@
+//! Processing journal.
+//!
+//! Every run that modifies files writes `<base>/.headroom-journal/run_<ts>.json`
+//! recording, per file, the backup location, the gain applied and content
+//! checksums before and after. `headroom restore` reads it to undo the run.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::analyzer::{AudioAnalysis, GainMethod};
+use crate::cache;
+use crate::metadata::{MetadataDiff, MetadataSnapshot};
+use crate::processor::{Processed, ReencodePasses};
+
+/// Journal directory created inside the run's base directory.
+pub const JOURNAL_DIR: &str = ".headroom-journal";
+
+/// Bump when the journal layout changes incompatibly.
+pub const JOURNAL_VERSION: u32 = 1;
+
+/// What a run did to a file.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalAction {
+    /// Audio gain applied by `processor::process_file`.
+    Gain,
+    /// ReplayGain/R128 tags written (`--tag-only`).
+    Tags,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub path: PathBuf,
+    pub backup: Option<PathBuf>,
+    pub action: JournalAction,
+    pub method: GainMethod,
+    pub gain_db: f64,
+    /// Native MP3/AAC global_gain steps; invertible without a backup.
+    pub native_steps: i32,
+    pub checksum_before: Option<String>,
+    pub checksum_after: Option<String>,
+    pub error: Option<String>,
+    pub restored_at: Option<String>,
+    /// Overshoot-compensated re-encode; `gain_db` is then the kept pass's gain.
+    #[serde(default)]
+    pub reencode: Option<ReencodePasses>,
+    /// Encoder and settings a re-encode used.
+    #[serde(default)]
+    pub reencode_settings: Option<String>,
+    /// Tags, pictures and chapters lost or altered by processing; `None`
+    /// when the file could not be audited.
+    #[serde(default)]
+    pub metadata: Option<MetadataDiff>,
+}
+
+impl JournalEntry {
+    /// Whether the change can be undone by applying the opposite native steps.
+    pub fn is_native_invertible(&self) -> bool {
+        self.action == JournalAction::Gain
+            && self.native_steps != 0
+            && matches!(
+                self.method,
+                GainMethod::Mp3Lossless | GainMethod::AacLossless
+            )
+    }
+}
+
+/// Run `op` on `analysis`'s file and record the outcome.
+pub fn run_journaled(
+    analysis: &AudioAnalysis,
+    action: JournalAction,
+    op: impl FnOnce() -> Result<Processed>,
+) -> (JournalEntry, Result<()>) {
+    let path = &analysis.path;
+    let checksum_before = cache::hash_file(path).ok();
+    let metadata_before = MetadataSnapshot::read(path);
+    let result = op();
+    let checksum_after = cache::hash_file(path).ok();
+    let metadata = match (&metadata_before, &result) {
+        (Some(before), Ok(_)) => {
+            Some(before.diff(&MetadataSnapshot::read(path).unwrap_or_default()))
+        }
+        _ => None,
+    };
+
+    let (processed, error, result) = match result {
+        Ok(processed) => (processed, None, Ok(())),
+        Err(e) => (Processed::default(), Some(format!("{:#}", e)), Err(e)),
+    };
+
+    let entry = JournalEntry {
+        path: std::path::absolute(path).unwrap_or_else(|_| path.clone()),
+        backup: processed
+            .backup
+            .map(|b| std::path::absolute(&b).unwrap_or(b)),
+        action,
+        method: analysis.gain_method.clone(),
+        gain_db: processed
+            .reencode
+            .map_or(analysis.effective_gain, |r| r.gain_db),
+        native_steps: analysis.lossless_gain_steps,
+        checksum_before,
+        checksum_after,
+        error,
+        restored_at: None,
+        reencode: processed.reencode,
+        reencode_settings: processed.reencode_settings,
+        metadata,
+    };
+    (entry, result)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Journal {
+    pub version: u32,
+    pub created: String,
+    pub base_dir: PathBuf,
+    pub entries: Vec<JournalEntry>,
+}
+
+impl Journal {
+    pub fn new(base_dir: &Path, entries: Vec<JournalEntry>) -> Self {
+        Self {
+            version: JOURNAL_VERSION,
+            created: Local::now().to_rfc3339(),
+            base_dir: std::path::absolute(base_dir).unwrap_or_else(|_| base_dir.to_path_buf()),
+            entries,
+        }
+    }
+
+    /// Write the journal as a new file in `<base_dir>/JOURNAL_DIR`.
+    pub fn save_new(&self) -> Result<PathBuf> {
+        let dir = self.base_dir.join(JOURNAL_DIR);
+        fs::create_dir_all(&dir).context("Failed to create journal directory")?;
+
+        let stamp = Local::now().format("%Y%m%d_%H%M%S");
+        let mut path = dir.join(format!("run_{}.json", stamp));
+        let mut n = 1;
+        while path.exists() {
+            n += 1;
+            path = dir.join(format!("run_{}_{}.json", stamp, n));
+        }
+        self.save(&path)?;
+        Ok(path)
+    }
+
+    /// Write the journal to `path` (temp file + rename).
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("json.tmp");
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(&tmp, json).with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let journal: Journal = serde_json::from_str(&data)
+            .with_context(|| format!("Invalid journal {}", path.display()))?;
+        if journal.version != JOURNAL_VERSION {
+            return Err(anyhow!(
+                "Unsupported journal version {} in {}",
+                journal.version,
+                path.display()
+            ));
+        }
+        Ok(journal)
+    }
+}
+
+/// Most recent journal in `dir` (a base directory or its journal directory).
+pub fn latest(dir: &Path) -> Result<PathBuf> {
+    let journal_dir = if dir.file_name().is_some_and(|n| n == JOURNAL_DIR) {
+        dir.to_path_buf()
+    } else {
+        dir.join(JOURNAL_DIR)
+    };
+
+    // Names embed a sortable timestamp, so the last one is the newest.
+    let mut journals: Vec<PathBuf> = fs::read_dir(&journal_dir)
+        .with_context(|| format!("No journal found in {}", journal_dir.display()))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "json"))
+        .collect();
+    journals.sort();
+    journals
+        .pop()
+        .ok_or_else(|| anyhow!("No journal found in {}", journal_dir.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &Path) -> JournalEntry {
+        JournalEntry {
+            path: path.to_path_buf(),
+            backup: None,
+            action: JournalAction::Gain,
+            method: GainMethod::Mp3Lossless,
+            gain_db: 3.0,
+            native_steps: 2,
+            checksum_before: Some("00".into()),
+            checksum_after: Some("11".into()),
+            error: None,
+            restored_at: None,
+            reencode: None,
+            reencode_settings: None,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn save_load_roundtrip_and_latest() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path(), vec![entry(&dir.path().join("a.mp3"))]);
+        let first = journal.save_new().unwrap();
+        let second = journal.save_new().unwrap();
+        assert_ne!(first, second);
+
+        assert_eq!(latest(dir.path()).unwrap(), second);
+        let loaded = Journal::load(&second).unwrap();
+        assert_eq!(loaded.entries, journal.entries);
+        assert!(loaded.entries[0].is_native_invertible());
+    }
+
+    #[test]
+    fn latest_without_journal_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(latest(dir.path()).is_err());
+    }
+}
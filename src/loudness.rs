@@ -0,0 +1,775 @@
+//! In-process loudness and true-peak measurement (ITU-R BS.1770-4 / EBU R128).
+//!
+//! The meter is fed interleaved f32 PCM and keeps only per-block energies, so
+//! memory stays bounded by track length in 100 ms steps rather than samples.
+
+use serde::{Deserialize, Serialize};
+
+use crate::clipping::{ClipDetector, Clipping};
+use crate::silence::{Silence, SilenceDetector};
+
+/// Absolute gating threshold (EBU R128 / BS.1770-4 §2.8).
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+
+/// Relative gate offset below the abs-gated loudness.
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// Gating blocks are 400 ms long with 75% overlap, i.e. built from four
+/// consecutive 100 ms sub-blocks.
+const SUB_BLOCKS_PER_BLOCK: usize = 4;
+
+/// Short-term loudness uses a 3 s window, advanced in 100 ms steps.
+const SUB_BLOCKS_PER_SHORT_TERM: usize = 30;
+
+/// Loudness range relative gate (EBU Tech 3342).
+const LRA_RELATIVE_GATE_LU: f64 = -20.0;
+
+/// Loudness range spans the 10th to the 95th percentile of the gated
+/// short-term distribution (EBU Tech 3342).
+const LRA_PERCENTILES: (f64, f64) = (0.10, 0.95);
+
+/// 4x oversampling interpolation filter from BS.1770-4 Annex 2 (48 taps,
+/// 12 per polyphase branch).
+const TRUE_PEAK_PHASES: [[f64; 12]; 4] = [
+    [
+        0.0017089843750,
+        0.0109863281250,
+        -0.0196533203125,
+        0.0332031250000,
+        -0.0594482421875,
+        0.1373291015625,
+        0.9721679687500,
+        -0.1022949218750,
+        0.0476074218750,
+        -0.0266113281250,
+        0.0148925781250,
+        -0.0083007812500,
+    ],
+    [
+        -0.0291748046875,
+        0.0292968750000,
+        -0.0517578125000,
+        0.0891113281250,
+        -0.1665039062500,
+        0.4650878906250,
+        0.7797851562500,
+        -0.2003173828125,
+        0.1015625000000,
+        -0.0582275390625,
+        0.0330810546875,
+        -0.0189208984375,
+    ],
+    [
+        -0.0189208984375,
+        0.0330810546875,
+        -0.0582275390625,
+        0.1015625000000,
+        -0.2003173828125,
+        0.7797851562500,
+        0.4650878906250,
+        -0.1665039062500,
+        0.0891113281250,
+        -0.0517578125000,
+        0.0292968750000,
+        -0.0291748046875,
+    ],
+    [
+        -0.0083007812500,
+        0.0148925781250,
+        -0.0266113281250,
+        0.0476074218750,
+        -0.1022949218750,
+        0.9721679687500,
+        0.1373291015625,
+        -0.0594482421875,
+        0.0332031250000,
+        -0.0196533203125,
+        0.0109863281250,
+        0.0017089843750,
+    ],
+];
+
+const TRUE_PEAK_TAPS: usize = 12;
+
+/// Label and BS.1770 weight of each WAVE channel mask bit. Surround and back
+/// speakers get +1.5 dB (1.41) and the LFE is left out, as in ffmpeg's
+/// ebur128 filter.
+const SPEAKERS: [(&str, f64); 18] = [
+    ("L", 1.0),
+    ("R", 1.0),
+    ("C", 1.0),
+    ("LFE", 0.0),
+    ("Lb", 1.41),
+    ("Rb", 1.41),
+    ("Lc", 1.0),
+    ("Rc", 1.0),
+    ("Cb", 1.41),
+    ("Ls", 1.41),
+    ("Rs", 1.41),
+    ("Tc", 1.0),
+    ("Tfl", 1.0),
+    ("Tfc", 1.0),
+    ("Tfr", 1.0),
+    ("Tbl", 1.41),
+    ("Tbc", 1.41),
+    ("Tbr", 1.41),
+];
+
+/// Left/right speaker pairs (mask bits) compared for channel imbalance.
+const SPEAKER_PAIRS: [(u32, u32); 6] = [(0, 1), (4, 5), (6, 7), (9, 10), (12, 14), (15, 17)];
+
+/// Per-channel loudness floor for the imbalance check, so a silent side
+/// gives a large but finite difference.
+const CHANNEL_LOUDNESS_FLOOR: f64 = ABSOLUTE_GATE_LUFS;
+
+/// Lowest level reported for a single channel or window. Digital silence
+/// would be `-inf`, which JSON (reports, the analysis cache) can't hold.
+pub const LEVEL_FLOOR_DB: f64 = -120.0;
+
+/// WAVE default channel mask for streams that don't declare their layout:
+/// mono, stereo, 3.0, quad, 5.0, 5.1, 6.1 and 7.1. 0 for other counts.
+fn default_channel_mask(channels: usize) -> u32 {
+    match channels {
+        1 => 0x4,
+        2 => 0x3,
+        3 => 0x7,
+        4 => 0x33,
+        5 => 0x37,
+        6 => 0x3f,
+        7 => 0x13f,
+        8 => 0x63f,
+        _ => 0,
+    }
+}
+
+/// Speaker (mask bit) of each channel in stream order. Falls back to the
+/// default layout when `mask` doesn't describe `channels` speakers.
+fn speaker_bits(channels: usize, mask: u32) -> Vec<Option<u32>> {
+    let mask = if mask.count_ones() as usize == channels {
+        mask
+    } else {
+        default_channel_mask(channels)
+    };
+    if mask.count_ones() as usize != channels {
+        return vec![None; channels];
+    }
+    (0..32)
+        .filter(|bit| mask & (1 << bit) != 0)
+        .map(Some)
+        .collect()
+}
+
+/// Peak levels of one channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelPeak {
+    /// Speaker label, e.g. "L", "Rs", "LFE"; "M" for mono.
+    pub channel: String,
+    /// dBTP.
+    pub true_peak: f64,
+    /// dBFS.
+    pub sample_peak: f64,
+}
+
+fn power_to_lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+fn lufs_to_power(lufs: f64) -> f64 {
+    10f64.powf((lufs + 0.691) / 10.0)
+}
+
+/// Convert a linear amplitude to dB (full scale = 0 dB).
+pub fn amplitude_to_db(amplitude: f64) -> f64 {
+    20.0 * amplitude.log10()
+}
+
+/// Second-order IIR section, transposed direct form II.
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b0: b[0],
+            b1: b[1],
+            b2: b[2],
+            a1: a[1],
+            a2: a[2],
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// K-weighting pre-filter (high shelf) and RLB high-pass for `sample_rate`.
+///
+/// BS.1770 only tabulates coefficients for 48 kHz; these are re-derived from
+/// the analog prototype so 44.1/88.2/96 kHz material is weighted identically.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let fs = sample_rate as f64;
+
+    let f0 = 1681.974450955533;
+    let gain_db = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (std::f64::consts::PI * f0 / fs).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (std::f64::consts::PI * f0 / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, highpass]
+}
+
+/// Per-channel 4x oversampling peak detector.
+#[derive(Debug, Clone)]
+struct TruePeakDetector {
+    history: [f64; TRUE_PEAK_TAPS],
+    pos: usize,
+    peak: f64,
+}
+
+impl TruePeakDetector {
+    fn new() -> Self {
+        Self {
+            history: [0.0; TRUE_PEAK_TAPS],
+            pos: 0,
+            peak: 0.0,
+        }
+    }
+
+    /// Feed one sample; returns the highest magnitude interpolated after it.
+    #[inline]
+    fn process(&mut self, x: f64) -> f64 {
+        self.history[self.pos] = x;
+        self.pos = (self.pos + 1) % TRUE_PEAK_TAPS;
+
+        let mut reconstructed: f64 = 0.0;
+        for phase in &TRUE_PEAK_PHASES {
+            // Oldest sample pairs with the first coefficient. The phase set is
+            // time-symmetric (phase 3 mirrors phase 0), so this is equivalent
+            // to a forward convolution.
+            let mut acc = 0.0;
+            for (tap, coeff) in phase.iter().enumerate() {
+                acc += coeff * self.history[(self.pos + tap) % TRUE_PEAK_TAPS];
+            }
+            reconstructed = reconstructed.max(acc.abs());
+        }
+        self.peak = self.peak.max(x.abs()).max(reconstructed);
+        reconstructed
+    }
+}
+
+/// Integrated loudness + true-peak meter for one audio stream.
+#[derive(Debug, Clone)]
+pub struct LoudnessMeter {
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    weights: Vec<f64>,
+    peaks: Vec<TruePeakDetector>,
+    /// Speaker of each channel, as a WAVE channel mask bit.
+    speakers: Vec<Option<u32>>,
+    /// Largest absolute sample value per channel.
+    sample_peaks: Vec<f64>,
+    /// Per-channel K-weighted sum of squares over the whole stream.
+    totals: Vec<f64>,
+    /// Per-channel sum of raw samples, for the DC offset.
+    sums: Vec<f64>,
+    frames: u64,
+    sub_block_len: usize,
+    sub_block_pos: usize,
+    /// Per-channel sum of squares for the sub-block in progress.
+    current: Vec<f64>,
+    /// Weighted energy of every completed 100 ms sub-block.
+    sub_blocks: Vec<f64>,
+    /// Mean-square power of every completed 400 ms gating block.
+    blocks: Vec<f64>,
+    clips: ClipDetector,
+    silence: SilenceDetector,
+}
+
+impl LoudnessMeter {
+    /// Meter for a stream whose speakers are given by a WAVE channel mask,
+    /// which sets the BS.1770 weight of each channel. A mask of 0 selects
+    /// the default layout for `channels`.
+    pub fn new(channels: usize, sample_rate: u32, mask: u32) -> Self {
+        let filter = k_weighting(sample_rate);
+        let speakers = speaker_bits(channels, mask);
+        let weights = speakers
+            .iter()
+            .map(|bit| {
+                bit.and_then(|b| SPEAKERS.get(b as usize))
+                    .map_or(1.0, |s| s.1)
+            })
+            .collect();
+        Self {
+            channels,
+            filters: vec![filter; channels],
+            weights,
+            peaks: vec![TruePeakDetector::new(); channels],
+            speakers,
+            sample_peaks: vec![0.0; channels],
+            totals: vec![0.0; channels],
+            sums: vec![0.0; channels],
+            frames: 0,
+            sub_block_len: ((sample_rate as usize) / 10).max(1),
+            sub_block_pos: 0,
+            current: vec![0.0; channels],
+            sub_blocks: Vec::new(),
+            blocks: Vec::new(),
+            clips: ClipDetector::new(channels, sample_rate),
+            silence: SilenceDetector::new(sample_rate),
+        }
+    }
+
+    /// Feed interleaved samples. A trailing partial frame is ignored.
+    pub fn process(&mut self, interleaved: &[f32]) {
+        if self.channels == 0 {
+            return;
+        }
+        for frame in interleaved.chunks_exact(self.channels) {
+            let (mut frame_peak, mut frame_power) = (0.0f64, 0.0);
+            for (ch, &sample) in frame.iter().enumerate() {
+                let x = sample as f64;
+                frame_peak = frame_peak.max(x.abs());
+                frame_power += x * x;
+                let reconstructed = self.peaks[ch].process(x);
+                self.clips.process(ch, self.frames, x, reconstructed);
+                self.sample_peaks[ch] = self.sample_peaks[ch].max(x.abs());
+                self.sums[ch] += x;
+                let [shelf, highpass] = &mut self.filters[ch];
+                let y = highpass.process(shelf.process(x));
+                self.current[ch] += y * y;
+            }
+            self.silence
+                .process(frame_peak, frame_power / self.channels as f64);
+            self.frames += 1;
+            self.sub_block_pos += 1;
+            if self.sub_block_pos == self.sub_block_len {
+                self.finish_sub_block();
+            }
+        }
+    }
+
+    fn finish_sub_block(&mut self) {
+        let energy: f64 = self
+            .current
+            .iter()
+            .zip(&self.weights)
+            .map(|(sum, w)| sum * w)
+            .sum();
+        for (total, sum) in self.totals.iter_mut().zip(&self.current) {
+            *total += sum;
+        }
+        self.current.iter_mut().for_each(|s| *s = 0.0);
+        self.sub_block_pos = 0;
+
+        self.sub_blocks.push(energy);
+        if let Some(power) = self.window_power(SUB_BLOCKS_PER_BLOCK, self.sub_blocks.len()) {
+            self.blocks.push(power);
+        }
+    }
+
+    /// Mean-square power of the `len` sub-blocks ending before `end`.
+    fn window_power(&self, len: usize, end: usize) -> Option<f64> {
+        let window = self.sub_blocks.get(end.checked_sub(len)?..end)?;
+        Some(window.iter().sum::<f64>() / (len * self.sub_block_len) as f64)
+    }
+
+    /// Power of every 3 s short-term window, one per 100 ms.
+    fn short_term_powers(&self) -> Vec<f64> {
+        (SUB_BLOCKS_PER_SHORT_TERM..=self.sub_blocks.len())
+            .filter_map(|end| self.window_power(SUB_BLOCKS_PER_SHORT_TERM, end))
+            .collect()
+    }
+
+    /// Gated integrated loudness in LUFS; `-inf` when no block passes the
+    /// absolute gate (silence or input shorter than 400 ms).
+    pub fn integrated_loudness(&self) -> f64 {
+        let abs_gate = lufs_to_power(ABSOLUTE_GATE_LUFS);
+        let above_abs: Vec<f64> = self
+            .blocks
+            .iter()
+            .copied()
+            .filter(|&p| p > abs_gate)
+            .collect();
+        if above_abs.is_empty() {
+            return f64::NEG_INFINITY;
+        }
+        let abs_mean = above_abs.iter().sum::<f64>() / above_abs.len() as f64;
+        let rel_gate = lufs_to_power(power_to_lufs(abs_mean) + RELATIVE_GATE_LU);
+
+        let (sum, count) = above_abs
+            .iter()
+            .filter(|&&p| p > rel_gate)
+            .fold((0.0, 0usize), |(s, n), p| (s + p, n + 1));
+        if count == 0 {
+            return f64::NEG_INFINITY;
+        }
+        power_to_lufs(sum / count as f64)
+    }
+
+    /// Highest momentary (400 ms) loudness in LUFS; `None` for input
+    /// shorter than one block.
+    pub fn max_momentary(&self) -> Option<f64> {
+        self.blocks
+            .iter()
+            .copied()
+            .reduce(f64::max)
+            .map(power_to_lufs)
+    }
+
+    /// Highest short-term (3 s) loudness in LUFS; `None` for input shorter
+    /// than 3 s.
+    pub fn max_short_term(&self) -> Option<f64> {
+        self.short_term_powers()
+            .into_iter()
+            .reduce(f64::max)
+            .map(power_to_lufs)
+    }
+
+    /// Loudness range in LU (EBU Tech 3342): the spread between the 10th
+    /// and 95th percentile of gated short-term loudness. `None` when no
+    /// short-term window passes the gates.
+    pub fn loudness_range(&self) -> Option<f64> {
+        let abs_gate = lufs_to_power(ABSOLUTE_GATE_LUFS);
+        let above_abs: Vec<f64> = self
+            .short_term_powers()
+            .into_iter()
+            .filter(|&p| p > abs_gate)
+            .collect();
+        if above_abs.is_empty() {
+            return None;
+        }
+        let mean = above_abs.iter().sum::<f64>() / above_abs.len() as f64;
+        let rel_gate = lufs_to_power(power_to_lufs(mean) + LRA_RELATIVE_GATE_LU);
+
+        let mut gated: Vec<f64> = above_abs
+            .into_iter()
+            .filter(|&p| p > rel_gate)
+            .map(power_to_lufs)
+            .collect();
+        if gated.is_empty() {
+            return None;
+        }
+        gated.sort_by(f64::total_cmp);
+        let percentile = |q: f64| gated[((gated.len() - 1) as f64 * q).round() as usize];
+        Some(percentile(LRA_PERCENTILES.1) - percentile(LRA_PERCENTILES.0))
+    }
+
+    /// Highest sample value over all channels in dBFS.
+    pub fn sample_peak(&self) -> f64 {
+        amplitude_to_db(self.sample_peaks.iter().copied().fold(0.0, f64::max))
+    }
+
+    /// Largest per-channel mean sample value, as a fraction of full scale
+    /// (signed, so the direction of the offset is kept).
+    pub fn dc_offset(&self) -> f64 {
+        let frames = self.frames.max(1) as f64;
+        self.sums
+            .iter()
+            .map(|sum| sum / frames)
+            .fold(
+                0.0,
+                |max: f64, dc| if dc.abs() > max.abs() { dc } else { max },
+            )
+    }
+
+    /// Maximum true peak over all channels in dBTP.
+    pub fn true_peak(&self) -> f64 {
+        let peak = self.peaks.iter().map(|p| p.peak).fold(0.0, f64::max);
+        amplitude_to_db(peak)
+    }
+
+    /// True and sample peak of every channel, in stream order.
+    pub fn channel_peaks(&self) -> Vec<ChannelPeak> {
+        (0..self.channels)
+            .map(|ch| ChannelPeak {
+                channel: self.channel_label(ch),
+                true_peak: amplitude_to_db(self.peaks[ch].peak).max(LEVEL_FLOOR_DB),
+                sample_peak: amplitude_to_db(self.sample_peaks[ch]).max(LEVEL_FLOOR_DB),
+            })
+            .collect()
+    }
+
+    /// Full-scale clips and inter-sample overs, with the worst events.
+    pub fn clipping(&self) -> Clipping {
+        self.clips.clone().finish(|ch| self.channel_label(ch))
+    }
+
+    /// Leading and trailing silence, dropouts and the final level.
+    pub fn silence(&self) -> Silence {
+        self.silence.clone().finish()
+    }
+
+    fn channel_label(&self, ch: usize) -> String {
+        if self.channels == 1 {
+            return "M".to_string();
+        }
+        match self.speakers[ch].and_then(|b| SPEAKERS.get(b as usize)) {
+            Some((label, _)) => label.to_string(),
+            None => format!("Ch{}", ch + 1),
+        }
+    }
+
+    /// Largest loudness difference (LU) between the two sides of a
+    /// left/right speaker pair, from ungated K-weighted channel energy.
+    /// `None` when the layout has no such pair (mono).
+    pub fn channel_imbalance(&self) -> Option<f64> {
+        let loudness = |bit: u32| {
+            let ch = self.speakers.iter().position(|&b| b == Some(bit))?;
+            let power = self.totals[ch] / self.frames.max(1) as f64;
+            Some(power_to_lufs(power).max(CHANNEL_LOUDNESS_FLOOR))
+        };
+        SPEAKER_PAIRS
+            .iter()
+            .filter_map(|&(left, right)| Some((loudness(left)? - loudness(right)?).abs()))
+            .reduce(f64::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stereo_sine(freq: f64, amplitude_db: f64, seconds: f64, rate: u32, phase: f64) -> Vec<f32> {
+        let amp = 10f64.powf(amplitude_db / 20.0);
+        let frames = (seconds * rate as f64) as usize;
+        (0..frames)
+            .flat_map(|n| {
+                let s = (amp
+                    * (2.0 * std::f64::consts::PI * freq * n as f64 / rate as f64 + phase).sin())
+                    as f32;
+                [s, s]
+            })
+            .collect()
+    }
+
+    fn measure(segments: &[(f64, f64)], rate: u32) -> LoudnessMeter {
+        let mut meter = LoudnessMeter::new(2, rate, 0);
+        for &(level, seconds) in segments {
+            meter.process(&stereo_sine(1000.0, level, seconds, rate, 0.0));
+        }
+        meter
+    }
+
+    /// The Annex 2 table is quantised to 2^-13; its half-sample phases sit
+    /// about 0.25 dB low at DC, which is within the spec's tolerance.
+    #[test]
+    fn interpolation_phases_have_near_unity_dc_gain() {
+        for phase in &TRUE_PEAK_PHASES {
+            let sum: f64 = phase.iter().sum();
+            assert!((sum - 1.0).abs() < 0.03, "phase sum {sum}");
+        }
+    }
+
+    /// EBU Tech 3341 test cases 1 and 2: stereo 1 kHz sine (shortened from
+    /// 20 s; a steady tone gates identically at any length).
+    #[test]
+    fn tech3341_steady_sine() {
+        for rate in [44_100, 48_000] {
+            let i = measure(&[(-23.0, 5.0)], rate).integrated_loudness();
+            assert!((i + 23.0).abs() < 0.1, "{rate} Hz: {i}");
+            let i = measure(&[(-33.0, 5.0)], rate).integrated_loudness();
+            assert!((i + 33.0).abs() < 0.1, "{rate} Hz: {i}");
+        }
+    }
+
+    /// EBU Tech 3341 test cases 3 and 4: quiet passages removed by the gates
+    /// (segment lengths scaled down 5x, preserving their ratios).
+    #[test]
+    fn tech3341_gating() {
+        let i = measure(&[(-36.0, 2.0), (-23.0, 12.0), (-36.0, 2.0)], 48_000).integrated_loudness();
+        assert!((i + 23.0).abs() < 0.1, "{i}");
+
+        let i = measure(
+            &[
+                (-72.0, 2.0),
+                (-36.0, 2.0),
+                (-23.0, 12.0),
+                (-36.0, 2.0),
+                (-72.0, 2.0),
+            ],
+            48_000,
+        )
+        .integrated_loudness();
+        assert!((i + 23.0).abs() < 0.1, "{i}");
+    }
+
+    #[test]
+    fn silence_is_negative_infinity() {
+        let mut meter = LoudnessMeter::new(2, 48_000, 0);
+        meter.process(&vec![0.0; 48_000 * 2 * 2]);
+        assert_eq!(meter.integrated_loudness(), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn surround_channels_are_weighted_and_lfe_ignored() {
+        // 5.1 with the same tone in L and Ls only: Ls counts 1.41x, so the
+        // sum is 10*log10(2.41) above a single front channel.
+        let tone = stereo_sine(1000.0, -23.0, 5.0, 48_000, 0.0);
+        let frames: Vec<f32> = tone
+            .chunks_exact(2)
+            .flat_map(|f| [f[0], 0.0, 0.0, f[0], f[0], 0.0])
+            .collect();
+
+        let mut meter = LoudnessMeter::new(6, 48_000, 0x60f);
+        meter.process(&frames);
+        let single = measure(&[(-23.0, 5.0)], 48_000).integrated_loudness() - 10.0 * 2f64.log10();
+        let expected = single + 10.0 * 2.41f64.log10();
+        let i = meter.integrated_loudness();
+        assert!((i - expected).abs() < 0.05, "{i} vs {expected}");
+
+        let labels: Vec<String> = meter
+            .channel_peaks()
+            .into_iter()
+            .map(|p| p.channel)
+            .collect();
+        assert_eq!(labels, ["L", "R", "C", "LFE", "Ls", "Rs"]);
+    }
+
+    #[test]
+    fn per_channel_peaks_and_imbalance() {
+        let tone = stereo_sine(1000.0, -6.0, 2.0, 48_000, 0.0);
+        let frames: Vec<f32> = tone
+            .chunks_exact(2)
+            .flat_map(|f| [f[0], f[1] * 0.5])
+            .collect();
+
+        let mut meter = LoudnessMeter::new(2, 48_000, 0);
+        meter.process(&frames);
+        let peaks = meter.channel_peaks();
+        assert!((peaks[0].sample_peak + 6.0).abs() < 0.01, "{:?}", peaks[0]);
+        assert!(
+            (peaks[1].sample_peak + 12.02).abs() < 0.01,
+            "{:?}",
+            peaks[1]
+        );
+        assert!(peaks[1].true_peak >= peaks[1].sample_peak);
+
+        let imbalance = meter.channel_imbalance().unwrap();
+        assert!((imbalance - 6.02).abs() < 0.05, "{imbalance}");
+        assert_eq!(LoudnessMeter::new(1, 48_000, 0).channel_imbalance(), None);
+    }
+
+    #[test]
+    fn silent_channel_peaks_stay_finite() {
+        let mut meter = LoudnessMeter::new(2, 48_000, 0);
+        let left_only: Vec<f32> = stereo_sine(1000.0, -20.0, 1.0, 48_000, 0.0)
+            .chunks(2)
+            .flat_map(|frame| [frame[0], 0.0])
+            .collect();
+        meter.process(&left_only);
+        let peaks = meter.channel_peaks();
+        assert_eq!(peaks[1].true_peak, LEVEL_FLOOR_DB);
+        assert!(
+            serde_json::from_str::<Vec<ChannelPeak>>(&serde_json::to_string(&peaks).unwrap())
+                .is_ok()
+        );
+    }
+
+    /// EBU Tech 3342 case 1: 20 s at -20 LUFS then 20 s at -30 LUFS has a
+    /// loudness range of 10 LU (segments shortened to 10 s).
+    #[test]
+    fn tech3342_loudness_range() {
+        let meter = measure(&[(-20.0, 10.0), (-30.0, 10.0)], 48_000);
+        let lra = meter.loudness_range().unwrap();
+        assert!((lra - 10.0).abs() < 0.5, "{lra}");
+
+        let max_st = meter.max_short_term().unwrap();
+        let max_m = meter.max_momentary().unwrap();
+        assert!((max_st + 20.0).abs() < 0.1, "{max_st}");
+        assert!((max_m + 20.0).abs() < 0.1, "{max_m}");
+
+        assert_eq!(measure(&[(-20.0, 2.0)], 48_000).max_short_term(), None);
+    }
+
+    #[test]
+    fn sample_peak_and_dc_offset() {
+        let tone = stereo_sine(1000.0, -6.0, 1.0, 48_000, 0.0);
+        let shifted: Vec<f32> = tone
+            .chunks_exact(2)
+            .flat_map(|f| [f[0] + 0.1, f[1]])
+            .collect();
+        let mut meter = LoudnessMeter::new(2, 48_000, 0);
+        meter.process(&shifted);
+
+        assert!(
+            (meter.dc_offset() - 0.1).abs() < 1e-4,
+            "{}",
+            meter.dc_offset()
+        );
+        let expected = amplitude_to_db(10f64.powf(-6.0 / 20.0) + 0.1);
+        assert!((meter.sample_peak() - expected).abs() < 0.01);
+    }
+
+    /// EBU Tech 3341 true-peak case: fs/4 sine at 0 dBFS with a 45° phase
+    /// offset has sample peaks at -3.01 dBFS but a true peak of 0 dBTP.
+    #[test]
+    fn tech3341_inter_sample_peak() {
+        let mut meter = LoudnessMeter::new(2, 48_000, 0);
+        meter.process(&stereo_sine(
+            12_000.0,
+            0.0,
+            1.0,
+            48_000,
+            std::f64::consts::FRAC_PI_4,
+        ));
+        let tp = meter.true_peak();
+        assert!((-0.4..=0.2).contains(&tp), "{tp}");
+    }
+
+    /// The same sine 1 dB hotter never reaches full scale in its samples but
+    /// overshoots between every one of them.
+    #[test]
+    fn inter_sample_overs_without_clipped_samples() {
+        let mut meter = LoudnessMeter::new(2, 48_000, 0);
+        meter.process(&stereo_sine(
+            12_000.0,
+            1.0,
+            1.0,
+            48_000,
+            std::f64::consts::FRAC_PI_4,
+        ));
+        let clipping = meter.clipping();
+        assert_eq!(clipping.clip_events, 0);
+        assert!(clipping.inter_sample_overs > 40_000, "{clipping:?}");
+        assert_eq!(clipping.events.len(), 2);
+        assert_eq!(
+            clipping.events[0].kind,
+            crate::clipping::ClipKind::InterSample
+        );
+        assert!((clipping.events[0].peak - 1.0).abs() < 0.3);
+    }
+}
@@ -1,11 +1,27 @@
+mod album;
 mod analyzer;
 mod args;
+mod cache;
 mod cli;
+mod clipping;
+mod decoder;
+mod encoder;
+mod flac;
+mod journal;
+mod loudness;
+mod metadata;
+mod ogg;
+mod pcm;
 mod processor;
 mod rbsort;
+mod replace;
 mod report;
+mod restore;
 mod scanner;
+mod silence;
+mod tagger;
 mod updater;
+mod verify;
 
 use anyhow::Result;
 
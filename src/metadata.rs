@@ -0,0 +1,350 @@
+//! Metadata preservation audit.
+//!
+//! Every tag, embedded picture and chapter of a file is fingerprinted before
+//! and after processing, so a rewrite that drops artwork, chapters or private
+//! frames (Serato/Traktor `GEOB`, `PRIV`) is reported instead of noticed
+//! later in the DJ software. Tags the processing itself is expected to
+//! rewrite (encoder, ReplayGain, MP3Gain undo) are left out of the audit.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use xxhash_rust::xxh3::Xxh3;
+
+use id3::frame::Content;
+use symphonia::core::meta::well_known::METADATA_ID_ID3V2;
+use symphonia::core::meta::{Chapter, ChapterGroup, ChapterGroupItem, MetadataContainer};
+use symphonia::core::meta::{StandardTag, Tag};
+
+use crate::decoder;
+
+/// Fingerprint of each metadata item, keyed by a readable identity such as
+/// `TXXX:CATALOGNUMBER`, `GEOB:Serato Markers2` or `picture:FrontCover`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetadataSnapshot {
+    items: BTreeMap<String, u64>,
+}
+
+/// Metadata items that disappeared or changed value during processing.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetadataDiff {
+    pub missing: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl MetadataDiff {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.changed.is_empty()
+    }
+
+    /// One-line summary, e.g. "missing: APIC, GEOB:Serato Markers2; changed: TIT2".
+    pub fn summary(&self) -> String {
+        if self.is_clean() {
+            return "kept".to_string();
+        }
+        let mut parts = Vec::new();
+        if !self.missing.is_empty() {
+            parts.push(format!("missing: {}", self.missing.join(", ")));
+        }
+        if !self.changed.is_empty() {
+            parts.push(format!("changed: {}", self.changed.join(", ")));
+        }
+        parts.join("; ")
+    }
+}
+
+impl MetadataSnapshot {
+    /// Read every metadata revision, picture and chapter of `path`. `None`
+    /// when the container cannot be probed.
+    pub fn read(path: &Path) -> Option<Self> {
+        let mut format = decoder::probe_format(path)?;
+        let mut snapshot = Self::default();
+
+        // symphonia skips unknown ID3 frames and WAV/AIFF ID3 chunks, so ID3
+        // tags are read frame by frame with the id3 crate instead.
+        let id3 = id3::Tag::read_from_path(path).ok();
+        if let Some(tag) = &id3 {
+            snapshot.add_id3(tag);
+        }
+
+        if let Some(chapters) = format.chapters() {
+            let mut n = 0;
+            snapshot.add_chapters(chapters, &mut n);
+        }
+
+        let mut metadata = format.metadata();
+        loop {
+            let revision = metadata
+                .current()
+                .filter(|r| id3.is_none() || r.info.metadata != METADATA_ID_ID3V2);
+            if let Some(revision) = revision {
+                snapshot.add_container("", &revision.media);
+                for track in &revision.per_track {
+                    let prefix = format!("track {}/", track.track_id);
+                    snapshot.add_container(&prefix, &track.metadata);
+                }
+            }
+            if metadata.pop().is_none() {
+                break;
+            }
+        }
+        Some(snapshot)
+    }
+
+    /// Items of `self` that are missing from or differ in `after`. Added
+    /// items are not reported.
+    pub fn diff(&self, after: &MetadataSnapshot) -> MetadataDiff {
+        let mut diff = MetadataDiff::default();
+        for (key, hash) in &self.items {
+            match after.items.get(key) {
+                None => diff.missing.push(key.clone()),
+                Some(h) if h != hash => diff.changed.push(key.clone()),
+                Some(_) => {}
+            }
+        }
+        diff
+    }
+
+    /// Insert under `key`, numbering repeats (`COMM`, `COMM #2`, ...).
+    fn insert(&mut self, key: String, hash: u64) {
+        let mut unique = key.clone();
+        let mut n = 1;
+        while self.items.contains_key(&unique) {
+            n += 1;
+            unique = format!("{} #{}", key, n);
+        }
+        self.items.insert(unique, hash);
+    }
+
+    fn add_id3(&mut self, tag: &id3::Tag) {
+        for frame in tag.frames() {
+            let detail = match frame.content() {
+                Content::ExtendedText(t) => Some(t.description.as_str()),
+                Content::ExtendedLink(l) => Some(l.description.as_str()),
+                Content::Comment(c) => Some(c.description.as_str()),
+                Content::Lyrics(l) => Some(l.description.as_str()),
+                Content::EncapsulatedObject(o) => Some(o.description.as_str()),
+                Content::Private(p) => Some(p.owner_identifier.as_str()),
+                Content::Popularimeter(p) => Some(p.user.as_str()),
+                Content::Chapter(c) => Some(c.element_id.as_str()),
+                _ => None,
+            };
+            let key = match detail.filter(|d| !d.is_empty()) {
+                Some(detail) => format!("{}:{}", frame.id(), detail),
+                None => frame.id().to_string(),
+            };
+            if frame.id() == "TSSE" || is_rewritten_key(&key) {
+                continue;
+            }
+            let mut hasher = Xxh3::new();
+            hasher.update(format!("{:?}", frame.content()).as_bytes());
+            self.insert(key, hasher.digest());
+        }
+    }
+
+    fn add_container(&mut self, prefix: &str, container: &MetadataContainer) {
+        for tag in container.tags.iter().filter(|t| !is_rewritten(t)) {
+            let (key, hash) = tag_identity(tag);
+            self.insert(format!("{}{}", prefix, key), hash);
+        }
+        for visual in &container.visuals {
+            let usage = visual
+                .usage
+                .map(|u| format!("{:?}", u))
+                .unwrap_or_else(|| "Other".to_string());
+            let mut hasher = Xxh3::new();
+            hasher.update(&visual.data);
+            self.insert(format!("{}picture:{}", prefix, usage), hasher.digest());
+        }
+    }
+
+    fn add_chapters(&mut self, group: &ChapterGroup, n: &mut usize) {
+        for item in &group.items {
+            match item {
+                ChapterGroupItem::Group(group) => self.add_chapters(group, n),
+                ChapterGroupItem::Chapter(chapter) => {
+                    *n += 1;
+                    self.insert(format!("chapter {}", n), chapter_hash(chapter));
+                }
+            }
+        }
+    }
+}
+
+/// Readable identity of a tag (raw key plus any descriptive sub-fields) and
+/// a hash of its value.
+fn tag_identity(tag: &Tag) -> (String, u64) {
+    let mut key = tag.raw.key.clone();
+    let mut hasher = Xxh3::new();
+    hasher.update(tag.raw.value.to_string().as_bytes());
+    for sub in tag.raw.sub_fields.iter().flatten() {
+        let value = sub.value.to_string();
+        if matches!(sub.field.as_str(), "DESCRIPTION" | "OWNER" | "FILE_NAME") {
+            if !value.is_empty() {
+                key = format!("{}:{}", key, value);
+            }
+        } else {
+            hasher.update(sub.field.as_bytes());
+            hasher.update(value.as_bytes());
+        }
+    }
+    (key, hasher.digest())
+}
+
+fn chapter_hash(chapter: &Chapter) -> u64 {
+    let mut hasher = Xxh3::new();
+    hasher.update(format!("{:?}", chapter.start_time).as_bytes());
+    for tag in &chapter.tags {
+        hasher.update(tag.raw.key.as_bytes());
+        hasher.update(tag.raw.value.to_string().as_bytes());
+    }
+    hasher.digest()
+}
+
+/// Tags that gain processing or tagging legitimately rewrites.
+fn is_rewritten(tag: &Tag) -> bool {
+    if matches!(
+        tag.std,
+        Some(
+            StandardTag::Encoder(_)
+                | StandardTag::EncoderSettings(_)
+                | StandardTag::Mp3GainAlbumMinMax(_)
+                | StandardTag::Mp3GainMinMax(_)
+                | StandardTag::Mp3GainUndo(_)
+                | StandardTag::ReplayGainAlbumGain(_)
+                | StandardTag::ReplayGainAlbumPeak(_)
+                | StandardTag::ReplayGainTrackGain(_)
+                | StandardTag::ReplayGainTrackPeak(_)
+        )
+    ) {
+        return true;
+    }
+    is_rewritten_key(&tag_identity(tag).0)
+}
+
+fn is_rewritten_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    ["replaygain", "r128_", "mp3gain", "itunsmpb"]
+        .iter()
+        .any(|k| key.contains(k))
+}
+
+/// Copy the source's ID3v2 tag (MP3 header, WAV `id3 ` or AIFF `ID3 `
+/// chunk) verbatim onto a file ffmpeg wrote. ffmpeg drops frames it does not
+/// understand, such as `GEOB` and `PRIV`. No-op when the source has no tag.
+pub fn carry_id3(src: &Path, dest: &Path) -> Result<()> {
+    let tag = match id3::Tag::read_from_path(src) {
+        Ok(tag) => tag,
+        Err(id3::Error {
+            kind: id3::ErrorKind::NoTag,
+            ..
+        }) => return Ok(()),
+        Err(e) => return Err(e).context("Failed to read source ID3v2 tag"),
+    };
+    tag.write_to_path(dest, tag.version())
+        .context("Failed to copy ID3v2 tag")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use id3::TagLike;
+
+    fn snapshot(items: &[(&str, u64)]) -> MetadataSnapshot {
+        let mut s = MetadataSnapshot::default();
+        for (key, hash) in items {
+            s.insert(key.to_string(), *hash);
+        }
+        s
+    }
+
+    #[test]
+    fn diff_reports_missing_and_changed_but_not_added() {
+        let before = snapshot(&[("TIT2", 1), ("GEOB:Serato Markers2", 2), ("COMM", 3)]);
+        let after = snapshot(&[("TIT2", 1), ("COMM", 4), ("TXXX:NEW", 5)]);
+        let diff = before.diff(&after);
+        assert_eq!(diff.missing, ["GEOB:Serato Markers2"]);
+        assert_eq!(diff.changed, ["COMM"]);
+        assert_eq!(
+            diff.summary(),
+            "missing: GEOB:Serato Markers2; changed: COMM"
+        );
+        assert!(before.diff(&before).is_clean());
+    }
+
+    #[test]
+    fn repeated_keys_are_numbered() {
+        let s = snapshot(&[("COMM", 1), ("COMM", 2)]);
+        assert_eq!(s.items.len(), 2);
+        assert!(s.items.contains_key("COMM #2"));
+    }
+
+    /// Mono 16-bit WAV with `frames` silent samples.
+    fn wav(frames: u32) -> Vec<u8> {
+        let data_len = frames * 2;
+        let mut b = Vec::new();
+        b.extend_from_slice(b"RIFF");
+        b.extend_from_slice(&(36 + data_len).to_le_bytes());
+        b.extend_from_slice(b"WAVEfmt ");
+        b.extend_from_slice(&16u32.to_le_bytes());
+        b.extend_from_slice(&1u16.to_le_bytes());
+        b.extend_from_slice(&1u16.to_le_bytes());
+        b.extend_from_slice(&44100u32.to_le_bytes());
+        b.extend_from_slice(&88200u32.to_le_bytes());
+        b.extend_from_slice(&2u16.to_le_bytes());
+        b.extend_from_slice(&16u16.to_le_bytes());
+        b.extend_from_slice(b"data");
+        b.extend_from_slice(&data_len.to_le_bytes());
+        b.resize(b.len() + data_len as usize, 0);
+        b
+    }
+
+    #[test]
+    fn dropped_id3_chunk_is_reported_and_carried_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = dir.path().join("a.wav");
+        let rewritten = dir.path().join("b.wav");
+        std::fs::write(&original, wav(4410)).unwrap();
+        std::fs::write(&rewritten, wav(4410)).unwrap();
+        let mut tag = id3::Tag::new();
+        tag.set_title("Track");
+        tag.add_frame(id3::frame::Private {
+            owner_identifier: "traktor".into(),
+            private_data: vec![9, 9],
+        });
+        tag.write_to_path(&original, id3::Version::Id3v24).unwrap();
+
+        let before = MetadataSnapshot::read(&original).unwrap();
+        let diff = before.diff(&MetadataSnapshot::read(&rewritten).unwrap());
+        assert_eq!(diff.missing, ["PRIV:traktor", "TIT2"]);
+
+        carry_id3(&original, &rewritten).unwrap();
+        let diff = before.diff(&MetadataSnapshot::read(&rewritten).unwrap());
+        assert!(diff.is_clean(), "{:?}", diff);
+    }
+
+    #[test]
+    fn carry_id3_copies_private_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.mp3");
+        let dest = dir.path().join("dest.mp3");
+        std::fs::write(&src, [0u8; 16]).unwrap();
+        std::fs::write(&dest, [0u8; 16]).unwrap();
+
+        let mut tag = id3::Tag::new();
+        tag.set_title("Track");
+        tag.add_frame(id3::frame::EncapsulatedObject {
+            mime_type: "application/octet-stream".into(),
+            filename: String::new(),
+            description: "Serato Markers2".into(),
+            data: vec![1, 2, 3],
+        });
+        tag.write_to_path(&src, id3::Version::Id3v24).unwrap();
+
+        carry_id3(&src, &dest).unwrap();
+        let copied = id3::Tag::read_from_path(&dest).unwrap();
+        assert_eq!(copied.title(), Some("Track"));
+        assert_eq!(copied.encapsulated_objects().count(), 1);
+    }
+}
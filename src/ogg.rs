@@ -0,0 +1,369 @@
+//! Ogg page I/O for Vorbis and Opus streams.
+//!
+//! Two edits are supported, both without touching the audio packets:
+//! - the Opus output gain (RFC 7845 §5.1), a Q7.8 dB field in the `OpusHead`
+//!   packet that every decoder applies; rewritten in place since the page
+//!   size does not change
+//! - the comment header packet, whose new size means the header pages are
+//!   re-paginated and every later page renumbered (and re-checksummed)
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::replace::{self, FrameMatch};
+
+const CAPTURE: &[u8; 4] = b"OggS";
+const FLAG_CONTINUED: u8 = 0x01;
+/// Granule position of a page on which no packet ends.
+const NO_GRANULE: u64 = u64::MAX;
+
+const OPUS_HEAD: &[u8; 8] = b"OpusHead";
+const OPUS_TAGS: &[u8; 8] = b"OpusTags";
+const VORBIS_IDENT: &[u8; 7] = b"\x01vorbis";
+const VORBIS_COMMENT: &[u8; 7] = b"\x03vorbis";
+
+/// One Ogg page.
+#[derive(Debug, Clone, PartialEq)]
+struct Page {
+    header_type: u8,
+    granule: u64,
+    serial: u32,
+    sequence: u32,
+    lacing: Vec<u8>,
+    body: Vec<u8>,
+}
+
+impl Page {
+    /// Read the next page; `None` at end of file.
+    fn read(reader: &mut impl Read) -> Result<Option<Self>> {
+        let mut header = [0u8; 27];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e).context("Failed to read Ogg page"),
+        }
+        if &header[..4] != CAPTURE {
+            bail!("Not an Ogg stream (missing OggS capture pattern)");
+        }
+        let mut lacing = vec![0u8; header[26] as usize];
+        reader
+            .read_exact(&mut lacing)
+            .context("Truncated Ogg page")?;
+        let mut body = vec![0u8; lacing.iter().map(|&l| l as usize).sum()];
+        reader.read_exact(&mut body).context("Truncated Ogg page")?;
+        Ok(Some(Self {
+            header_type: header[5],
+            granule: u64::from_le_bytes(header[6..14].try_into()?),
+            serial: u32::from_le_bytes(header[14..18].try_into()?),
+            sequence: u32::from_le_bytes(header[18..22].try_into()?),
+            lacing,
+            body,
+        }))
+    }
+
+    /// Serialized page with a fresh checksum.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(27 + self.lacing.len() + self.body.len());
+        out.extend_from_slice(CAPTURE);
+        out.push(0);
+        out.push(self.header_type);
+        out.extend_from_slice(&self.granule.to_le_bytes());
+        out.extend_from_slice(&self.serial.to_le_bytes());
+        out.extend_from_slice(&self.sequence.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.push(self.lacing.len() as u8);
+        out.extend_from_slice(&self.lacing);
+        out.extend_from_slice(&self.body);
+        let crc = crc32(&out);
+        out[22..26].copy_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    /// Whether the page's last segment completes a packet.
+    fn ends_packet(&self) -> bool {
+        self.lacing.last().is_some_and(|&l| l < 255)
+    }
+}
+
+/// Ogg CRC-32: polynomial 0x04c11db7, no reflection, zero init.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = (i as u32) << 24;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ 0x04c1_1db7
+                } else {
+                    crc << 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    data.iter().fold(0u32, |crc, &b| {
+        (crc << 8) ^ TABLE[((crc >> 24) as u8 ^ b) as usize]
+    })
+}
+
+/// First packet of the stream (the codec identification header).
+pub fn first_packet(path: &Path) -> Result<Vec<u8>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let page = Page::read(&mut BufReader::new(file))?.ok_or_else(|| anyhow!("Empty Ogg stream"))?;
+    let len = page
+        .lacing
+        .iter()
+        .position(|&l| l < 255)
+        .map(|i| page.lacing[..=i].iter().map(|&l| l as usize).sum())
+        .ok_or_else(|| anyhow!("Ogg identification header spans pages"))?;
+    Ok(page.body[..len].to_vec())
+}
+
+/// Add `gain_db` to the Opus output gain of the file at `path`, in place.
+/// The field has 1/256 dB resolution; the gain is rounded down so a boost
+/// never exceeds the requested value. Returns the gain actually applied.
+pub fn apply_opus_gain(path: &Path, gain_db: f64) -> Result<f64> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut page =
+        Page::read(&mut BufReader::new(&mut file))?.ok_or_else(|| anyhow!("Empty Ogg stream"))?;
+    if page.body.len() < 19 || &page.body[..8] != OPUS_HEAD {
+        bail!("Not an Opus stream (missing OpusHead)");
+    }
+
+    let steps = (gain_db * 256.0).floor() as i32;
+    let current = i16::from_le_bytes([page.body[16], page.body[17]]) as i32;
+    let gain =
+        i16::try_from(current + steps).map_err(|_| anyhow!("Opus output gain out of range"))?;
+    page.body[16..18].copy_from_slice(&gain.to_le_bytes());
+
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&page.to_bytes())
+        .context("Failed to write Opus header")?;
+    file.flush()?;
+    Ok(steps as f64 / 256.0)
+}
+
+/// Rewrite the comment header of the Vorbis or Opus file at `path`. `edit`
+/// receives the comment packet without its codec prefix (Vorbis comment
+/// layout, plus any trailing bytes) and returns the replacement. Audio pages
+/// are copied unchanged apart from their sequence numbers; the result is
+/// written to a staged file that then replaces the original.
+pub fn rewrite_comments(path: &Path, edit: impl FnOnce(&[u8]) -> Result<Vec<u8>>) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+
+    let first = Page::read(&mut reader)?.ok_or_else(|| anyhow!("Empty Ogg stream"))?;
+    let (header_packets, prefix): (usize, &[u8]) = if first.body.starts_with(VORBIS_IDENT) {
+        (3, VORBIS_COMMENT)
+    } else if first.body.starts_with(OPUS_HEAD) {
+        (2, OPUS_TAGS)
+    } else {
+        bail!("Unsupported Ogg codec (only Vorbis and Opus are tagged)");
+    };
+    if first.lacing.iter().filter(|&&l| l < 255).count() != 1 || !first.ends_packet() {
+        bail!("Unexpected Ogg identification page layout");
+    }
+
+    // Collect the comment (and setup) packets; the last one ends its page.
+    let wanted = header_packets - 1;
+    let mut packets: Vec<Vec<u8>> = vec![Vec::new()];
+    let mut old_pages = 0u32;
+    while packets.len() <= wanted {
+        let page = Page::read(&mut reader)?
+            .ok_or_else(|| anyhow!("Ogg stream ends inside its headers"))?;
+        if page.serial != first.serial {
+            bail!("Multiplexed Ogg streams are not supported");
+        }
+        old_pages += 1;
+        let mut pos = 0;
+        for &len in &page.lacing {
+            if packets.len() > wanted {
+                bail!("Ogg audio data shares a page with the headers");
+            }
+            let current = packets.last_mut().unwrap_or_else(|| unreachable!());
+            current.extend_from_slice(&page.body[pos..pos + len as usize]);
+            pos += len as usize;
+            if len < 255 {
+                packets.push(Vec::new());
+            }
+        }
+    }
+    packets.truncate(wanted);
+
+    let comment = packets[0]
+        .strip_prefix(prefix)
+        .ok_or_else(|| anyhow!("Missing Ogg comment header"))?;
+    let mut new_comment = prefix.to_vec();
+    new_comment.extend(edit(comment)?);
+    packets[0] = new_comment;
+
+    let header_pages = paginate(&packets, first.serial, first.sequence + 1);
+    let delta = header_pages.len() as i64 - old_pages as i64;
+
+    replace::replace_with(path, FrameMatch::Verbatim, |staged| {
+        let mut writer = BufWriter::new(File::create(staged)?);
+        writer.write_all(&first.to_bytes())?;
+        for page in &header_pages {
+            writer.write_all(&page.to_bytes())?;
+        }
+        while let Some(mut page) = Page::read(&mut reader)? {
+            if page.serial == first.serial {
+                page.sequence = (page.sequence as i64 + delta) as u32;
+            }
+            writer.write_all(&page.to_bytes())?;
+        }
+        writer.flush()?;
+        Ok(())
+    })
+    .context("Failed to write Ogg comments")
+}
+
+/// Lay `packets` out on pages of up to 255 segments, starting at `sequence`.
+fn paginate(packets: &[Vec<u8>], serial: u32, sequence: u32) -> Vec<Page> {
+    let mut pages = Vec::new();
+    let mut page = Page {
+        header_type: 0,
+        granule: NO_GRANULE,
+        serial,
+        sequence,
+        lacing: Vec::new(),
+        body: Vec::new(),
+    };
+    for packet in packets {
+        let mut rest = packet.as_slice();
+        loop {
+            if page.lacing.len() == 255 {
+                let next = Page {
+                    header_type: FLAG_CONTINUED,
+                    granule: NO_GRANULE,
+                    sequence: page.sequence + 1,
+                    lacing: Vec::new(),
+                    body: Vec::new(),
+                    ..page
+                };
+                pages.push(std::mem::replace(&mut page, next));
+            }
+            let len = rest.len().min(255);
+            page.lacing.push(len as u8);
+            page.body.extend_from_slice(&rest[..len]);
+            rest = &rest[len..];
+            // A packet ends on its first segment shorter than 255 bytes;
+            // header pages on which one ends carry granule 0.
+            if len < 255 {
+                page.granule = 0;
+                break;
+            }
+        }
+    }
+    if !page.lacing.is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn page(header_type: u8, sequence: u32, packets: &[&[u8]]) -> Vec<u8> {
+        let mut p = Page {
+            header_type,
+            granule: 0,
+            serial: 7,
+            sequence,
+            lacing: Vec::new(),
+            body: Vec::new(),
+        };
+        for packet in packets {
+            let mut len = packet.len();
+            while len >= 255 {
+                p.lacing.push(255);
+                len -= 255;
+            }
+            p.lacing.push(len as u8);
+            p.body.extend_from_slice(packet);
+        }
+        p.to_bytes()
+    }
+
+    fn opus_file(comment: &[u8]) -> Vec<u8> {
+        let mut head = OPUS_HEAD.to_vec();
+        head.extend_from_slice(&[1, 2, 0x38, 0x01, 0x80, 0xbb, 0, 0, 0x00, 0x01, 0]);
+        let mut tags = OPUS_TAGS.to_vec();
+        tags.extend_from_slice(comment);
+        let mut data = page(0x02, 0, &[&head]);
+        data.extend(page(0, 1, &[&tags]));
+        data.extend(page(0, 2, &[b"audio packet"]));
+        data.extend(page(0x04, 3, &[b"last packet"]));
+        data
+    }
+
+    #[test]
+    fn crc_matches_reference_value() {
+        // Check value of the non-reflected CRC-32/MPEG-2 variant with zero
+        // init and no final xor (CRC-32/BZIP2 without its xors).
+        assert_eq!(crc32(b"123456789"), 0x89a1_897f);
+    }
+
+    #[test]
+    fn opus_output_gain_is_added_to_the_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.opus");
+        fs::write(&path, opus_file(b"")).unwrap();
+
+        let applied = apply_opus_gain(&path, 1.5).unwrap();
+        assert_eq!(applied, 1.5);
+        let head = first_packet(&path).unwrap();
+        // Existing +1.0 dB (256) plus 1.5 dB (384).
+        assert_eq!(i16::from_le_bytes([head[16], head[17]]), 640);
+        let pages = fs::read(&path).unwrap();
+        let mut reader = pages.as_slice();
+        let first = Page::read(&mut reader).unwrap().unwrap();
+        assert_eq!(first.to_bytes(), pages[..first.to_bytes().len()]);
+    }
+
+    #[test]
+    fn comment_rewrite_repaginates_and_renumbers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.opus");
+        fs::write(&path, opus_file(b"short")).unwrap();
+
+        let big = vec![b'x'; 70_000];
+        rewrite_comments(&path, |old| {
+            assert_eq!(old, b"short");
+            Ok(big.clone())
+        })
+        .unwrap();
+
+        let data = fs::read(&path).unwrap();
+        let mut reader = data.as_slice();
+        let mut pages = Vec::new();
+        while let Some(p) = Page::read(&mut reader).unwrap() {
+            pages.push(p);
+        }
+        // 8 + 70 000 bytes need 275 segments: two header pages.
+        assert_eq!(pages.len(), 5);
+        assert_eq!(
+            pages.iter().map(|p| p.sequence).collect::<Vec<_>>(),
+            [0, 1, 2, 3, 4]
+        );
+        assert_eq!(pages[2].header_type, FLAG_CONTINUED);
+        assert_eq!(pages[1].granule, NO_GRANULE);
+        let comment: Vec<u8> = [pages[1].body.as_slice(), &pages[2].body].concat();
+        assert_eq!(&comment[8..], big.as_slice());
+        assert_eq!(pages[3].body, b"audio packet");
+        assert_eq!(pages[4].header_type, 0x04);
+    }
+}
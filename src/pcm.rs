@@ -0,0 +1,574 @@
+//! Native sample-level gain for WAV (RIFF/RF64/BWF) and AIFF/AIFF-C.
+//!
+//! Gain does not change the size of a PCM sample, so the file is modified in
+//! place (on a staged copy): only the bytes of the sample data are rewritten
+//! and every other chunk — `bext`, `iXML`, `id3 `, cue points, markers —
+//! stays byte-for-byte identical. Compressed encodings (ADPCM, µ-law, ...)
+//! are rejected so the caller can fall back to ffmpeg.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::scanner::AudioFormat;
+
+/// Samples scaled per read/write round trip.
+const BLOCK_SAMPLES: usize = 64 * 1024;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// How samples are stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    /// Integer PCM. `valid_bits` may be less than the container width
+    /// (e.g. 20-bit audio in 3-byte samples); the low bits are then zero.
+    Int {
+        bytes: usize,
+        valid_bits: u32,
+        little_endian: bool,
+        /// 8-bit WAV is offset binary.
+        unsigned: bool,
+    },
+    Float {
+        bytes: usize,
+        little_endian: bool,
+    },
+}
+
+impl Encoding {
+    fn bytes(self) -> usize {
+        match self {
+            Encoding::Int { bytes, .. } | Encoding::Float { bytes, .. } => bytes,
+        }
+    }
+}
+
+/// Where the sample data lives and how it is encoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PcmLayout {
+    data_offset: u64,
+    data_len: u64,
+    encoding: Encoding,
+}
+
+/// Whether `format` is a container this module can process.
+pub fn is_native(format: AudioFormat) -> bool {
+    matches!(format, AudioFormat::Wav | AudioFormat::Aiff)
+}
+
+/// Whether the samples of the WAV/AIFF file at `path` are stored in an
+/// encoding `apply_gain` can scale.
+pub fn supports(path: &Path) -> bool {
+    File::open(path).is_ok_and(|mut file| read_layout(&mut file).is_ok())
+}
+
+/// Scale every sample of the WAV/AIFF file at `path` by `gain_db`, in place.
+/// With `dither`, integer output of 16 bits or less gets TPDF dither.
+pub fn apply_gain(path: &Path, gain_db: f64, dither: bool) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let layout = read_layout(&mut file)?;
+
+    let gain = 10f64.powf(gain_db / 20.0);
+    let mut scaler = Scaler::new(layout.encoding, gain, dither);
+    let block_bytes = BLOCK_SAMPLES * layout.encoding.bytes();
+    let mut buf = vec![0u8; block_bytes];
+    let mut pos = layout.data_offset;
+    let end = layout.data_offset + layout.data_len;
+
+    while pos < end {
+        let len = block_bytes.min((end - pos) as usize);
+        // Drop a trailing partial sample rather than misread it.
+        let len = len - len % layout.encoding.bytes();
+        if len == 0 {
+            break;
+        }
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut buf[..len])
+            .context("Failed to read sample data")?;
+        scaler.scale(&mut buf[..len]);
+        file.seek(SeekFrom::Start(pos))?;
+        file.write_all(&buf[..len])
+            .context("Failed to write sample data")?;
+        pos += len as u64;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+fn read_layout(file: &mut File) -> Result<PcmLayout> {
+    let file_len = file.metadata()?.len();
+    let mut header = [0u8; 12];
+    file.read_exact(&mut header)
+        .context("Failed to read file header")?;
+
+    let layout = match (&header[..4], &header[8..12]) {
+        (b"RIFF" | b"RF64" | b"BW64", b"WAVE") => read_wav_layout(file, file_len)?,
+        (b"FORM", b"AIFF") => read_aiff_layout(file, file_len, false)?,
+        (b"FORM", b"AIFC") => read_aiff_layout(file, file_len, true)?,
+        _ => bail!("Not a WAV or AIFF file"),
+    };
+    Ok(PcmLayout {
+        // Streaming writers may leave a size larger than the file.
+        data_len: layout
+            .data_len
+            .min(file_len.saturating_sub(layout.data_offset)),
+        ..layout
+    })
+}
+
+/// Iterate the chunks after the 12-byte container header, calling `visit`
+/// with each chunk id, body offset and declared body size. Stops when
+/// `visit` returns `true`.
+fn walk_chunks(
+    file: &mut File,
+    file_len: u64,
+    little_endian: bool,
+    mut visit: impl FnMut(&mut File, [u8; 4], u64, u64) -> Result<bool>,
+) -> Result<()> {
+    let mut pos = 12u64;
+    while pos + 8 <= file_len {
+        let mut header = [0u8; 8];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header)?;
+        let id = [header[0], header[1], header[2], header[3]];
+        let size_bytes = [header[4], header[5], header[6], header[7]];
+        let size = if little_endian {
+            u32::from_le_bytes(size_bytes)
+        } else {
+            u32::from_be_bytes(size_bytes)
+        } as u64;
+        if visit(file, id, pos + 8, size)? {
+            return Ok(());
+        }
+        // Chunks are padded to an even length.
+        pos += 8 + size + (size & 1);
+    }
+    Ok(())
+}
+
+fn read_body(file: &mut File, offset: u64, size: u64, max: usize) -> Result<Vec<u8>> {
+    let mut body = vec![0u8; (size as usize).min(max)];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut body).context("Truncated chunk")?;
+    Ok(body)
+}
+
+fn read_wav_layout(file: &mut File, file_len: u64) -> Result<PcmLayout> {
+    let mut encoding = None;
+    let mut data = None;
+    let mut ds64_data_len = None;
+
+    walk_chunks(file, file_len, true, |file, id, offset, size| {
+        match &id {
+            // RF64: the real data size, when the data chunk says 0xFFFFFFFF.
+            b"ds64" => {
+                let body = read_body(file, offset, size, 28)?;
+                if body.len() >= 16 {
+                    ds64_data_len = Some(u64::from_le_bytes(body[8..16].try_into()?));
+                }
+            }
+            b"fmt " => encoding = Some(wav_encoding(&read_body(file, offset, size, 40)?)?),
+            b"data" => {
+                let len = match (size, ds64_data_len) {
+                    (0xffff_ffff, Some(len)) => len,
+                    _ => size,
+                };
+                data = Some((offset, len));
+                return Ok(true);
+            }
+            _ => {}
+        }
+        Ok(false)
+    })?;
+
+    let encoding = encoding.ok_or_else(|| anyhow!("WAV file has no fmt chunk"))?;
+    let (data_offset, data_len) = data.ok_or_else(|| anyhow!("WAV file has no data chunk"))?;
+    Ok(PcmLayout {
+        data_offset,
+        data_len,
+        encoding,
+    })
+}
+
+fn wav_encoding(fmt: &[u8]) -> Result<Encoding> {
+    if fmt.len() < 16 {
+        bail!("WAV fmt chunk too short");
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([fmt[i], fmt[i + 1]]);
+    let mut tag = u16_at(0);
+    let channels = u16_at(2).max(1) as usize;
+    let block_align = u16_at(12) as usize;
+    let bits = u16_at(14) as u32;
+    let mut valid_bits = bits;
+    if tag == WAVE_FORMAT_EXTENSIBLE && fmt.len() >= 26 {
+        valid_bits = match u16_at(18) as u32 {
+            0 => bits,
+            v => v.min(bits),
+        };
+        // The sub-format GUID starts with the plain format tag.
+        tag = u16_at(24);
+    }
+
+    let bytes = block_align / channels;
+    if bytes == 0 || bytes * 8 < bits as usize {
+        bail!("Inconsistent WAV block alignment");
+    }
+    match (tag, bytes) {
+        (WAVE_FORMAT_PCM, 1..=4) => Ok(Encoding::Int {
+            bytes,
+            valid_bits,
+            little_endian: true,
+            unsigned: bytes == 1,
+        }),
+        (WAVE_FORMAT_IEEE_FLOAT, 4 | 8) => Ok(Encoding::Float {
+            bytes,
+            little_endian: true,
+        }),
+        _ => bail!("Unsupported WAV encoding (format tag {:#06x})", tag),
+    }
+}
+
+fn read_aiff_layout(file: &mut File, file_len: u64, aifc: bool) -> Result<PcmLayout> {
+    let mut encoding = None;
+    let mut data = None;
+
+    walk_chunks(file, file_len, false, |file, id, offset, size| {
+        match &id {
+            b"COMM" => encoding = Some(aiff_encoding(&read_body(file, offset, size, 64)?, aifc)?),
+            b"SSND" => {
+                let body = read_body(file, offset, size, 8)?;
+                if body.len() < 8 {
+                    bail!("AIFF SSND chunk too short");
+                }
+                let skip = u32::from_be_bytes(body[..4].try_into()?) as u64;
+                data = Some((offset + 8 + skip, size.saturating_sub(8 + skip)));
+            }
+            _ => {}
+        }
+        // COMM may follow SSND, so read to the end.
+        Ok(false)
+    })?;
+
+    let encoding = encoding.ok_or_else(|| anyhow!("AIFF file has no COMM chunk"))?;
+    let (data_offset, data_len) = data.ok_or_else(|| anyhow!("AIFF file has no SSND chunk"))?;
+    Ok(PcmLayout {
+        data_offset,
+        data_len,
+        encoding,
+    })
+}
+
+fn aiff_encoding(comm: &[u8], aifc: bool) -> Result<Encoding> {
+    if comm.len() < 18 {
+        bail!("AIFF COMM chunk too short");
+    }
+    let bits = u16::from_be_bytes([comm[6], comm[7]]) as u32;
+    let compression: [u8; 4] = if aifc {
+        comm.get(18..22)
+            .ok_or_else(|| anyhow!("AIFF-C COMM chunk has no compression type"))?
+            .try_into()?
+    } else {
+        *b"NONE"
+    };
+
+    let int = |little_endian| {
+        let bytes = bits.div_ceil(8) as usize;
+        if !(1..=4).contains(&bytes) {
+            bail!("Unsupported AIFF sample size {}", bits);
+        }
+        Ok(Encoding::Int {
+            bytes,
+            valid_bits: bits,
+            little_endian,
+            unsigned: false,
+        })
+    };
+    match &compression {
+        b"NONE" | b"twos" => int(false),
+        b"sowt" => int(true),
+        b"fl32" | b"FL32" => Ok(Encoding::Float {
+            bytes: 4,
+            little_endian: false,
+        }),
+        b"fl64" | b"FL64" => Ok(Encoding::Float {
+            bytes: 8,
+            little_endian: false,
+        }),
+        other => bail!(
+            "Unsupported AIFF-C compression '{}'",
+            String::from_utf8_lossy(other)
+        ),
+    }
+}
+
+/// TPDF dither: the sum of two uniform variables, ±1 LSB peak.
+pub struct Tpdf {
+    rng: u64,
+}
+
+impl Tpdf {
+    pub fn new() -> Self {
+        Self {
+            rng: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    /// Noise to add before rounding, in LSBs.
+    pub fn sample(&mut self) -> f64 {
+        self.uniform() - self.uniform()
+    }
+
+    /// Uniform in [0, 1) (xorshift64*).
+    fn uniform(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        (self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Applies a linear gain to raw sample bytes.
+struct Scaler {
+    encoding: Encoding,
+    gain: f64,
+    dither: Option<Tpdf>,
+}
+
+impl Scaler {
+    fn new(encoding: Encoding, gain: f64, dither: bool) -> Self {
+        let dither =
+            dither && matches!(encoding, Encoding::Int { valid_bits, .. } if valid_bits <= 16);
+        Self {
+            encoding,
+            gain,
+            dither: dither.then(Tpdf::new),
+        }
+    }
+
+    fn scale(&mut self, buf: &mut [u8]) {
+        match self.encoding {
+            Encoding::Int {
+                bytes,
+                valid_bits,
+                little_endian,
+                unsigned,
+            } => {
+                let container_bits = bytes as u32 * 8;
+                // Quantization step of the valid bits, in container units.
+                let step = (1i64 << (container_bits - valid_bits.min(container_bits))) as f64;
+                let max = ((1i64 << (container_bits - 1)) as f64) - step;
+                let min = -((1i64 << (container_bits - 1)) as f64);
+                for sample in buf.chunks_exact_mut(bytes) {
+                    let value = read_int(sample, little_endian, unsigned) as f64;
+                    let mut scaled = value * self.gain / step;
+                    if let Some(dither) = &mut self.dither {
+                        scaled += dither.sample();
+                    }
+                    let quantized = (scaled.round() * step).clamp(min, max);
+                    write_int(sample, quantized as i64, little_endian, unsigned);
+                }
+            }
+            Encoding::Float {
+                bytes: 4,
+                little_endian,
+            } => {
+                for sample in buf.chunks_exact_mut(4) {
+                    let raw: [u8; 4] = sample.try_into().unwrap_or_default();
+                    let value = if little_endian {
+                        f32::from_le_bytes(raw)
+                    } else {
+                        f32::from_be_bytes(raw)
+                    };
+                    let scaled = (value as f64 * self.gain) as f32;
+                    sample.copy_from_slice(&if little_endian {
+                        scaled.to_le_bytes()
+                    } else {
+                        scaled.to_be_bytes()
+                    });
+                }
+            }
+            Encoding::Float { little_endian, .. } => {
+                for sample in buf.chunks_exact_mut(8) {
+                    let raw: [u8; 8] = sample.try_into().unwrap_or_default();
+                    let value = if little_endian {
+                        f64::from_le_bytes(raw)
+                    } else {
+                        f64::from_be_bytes(raw)
+                    };
+                    let scaled = value * self.gain;
+                    sample.copy_from_slice(&if little_endian {
+                        scaled.to_le_bytes()
+                    } else {
+                        scaled.to_be_bytes()
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Sign-extended integer sample, left-aligned to the container width.
+fn read_int(sample: &[u8], little_endian: bool, unsigned: bool) -> i64 {
+    let mut value: i64 = 0;
+    if little_endian {
+        for &b in sample.iter().rev() {
+            value = (value << 8) | b as i64;
+        }
+    } else {
+        for &b in sample {
+            value = (value << 8) | b as i64;
+        }
+    }
+    let bits = sample.len() as u32 * 8;
+    if unsigned {
+        value - (1 << (bits - 1))
+    } else {
+        // Sign-extend from the container width.
+        (value << (64 - bits)) >> (64 - bits)
+    }
+}
+
+fn write_int(sample: &mut [u8], value: i64, little_endian: bool, unsigned: bool) {
+    let bits = sample.len() as u32 * 8;
+    let raw = if unsigned {
+        value + (1 << (bits - 1))
+    } else {
+        value
+    };
+    let n = sample.len();
+    for (i, byte) in sample.iter_mut().enumerate() {
+        let shift = if little_endian { i } else { n - 1 - i } * 8;
+        *byte = (raw >> shift) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav_16(samples: &[i16], trailing_chunk: bool) -> Vec<u8> {
+        let data_len = (samples.len() * 2) as u32;
+        let mut b = Vec::new();
+        b.extend_from_slice(b"RIFF");
+        b.extend_from_slice(&0u32.to_le_bytes());
+        b.extend_from_slice(b"WAVEfmt ");
+        b.extend_from_slice(&16u32.to_le_bytes());
+        for v in [1u16, 1] {
+            b.extend_from_slice(&v.to_le_bytes());
+        }
+        b.extend_from_slice(&44100u32.to_le_bytes());
+        b.extend_from_slice(&88200u32.to_le_bytes());
+        for v in [2u16, 16] {
+            b.extend_from_slice(&v.to_le_bytes());
+        }
+        b.extend_from_slice(b"bext");
+        b.extend_from_slice(&3u32.to_le_bytes());
+        b.extend_from_slice(b"abc\0");
+        b.extend_from_slice(b"data");
+        b.extend_from_slice(&data_len.to_le_bytes());
+        for s in samples {
+            b.extend_from_slice(&s.to_le_bytes());
+        }
+        if trailing_chunk {
+            b.extend_from_slice(b"id3 ");
+            b.extend_from_slice(&4u32.to_le_bytes());
+            b.extend_from_slice(b"TAG!");
+        }
+        let riff_len = (b.len() - 8) as u32;
+        b[4..8].copy_from_slice(&riff_len.to_le_bytes());
+        b
+    }
+
+    #[test]
+    fn companded_wav_is_not_supported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.wav");
+        let mut bytes = wav_16(&[0; 4], false);
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(supports(&path));
+
+        // Format tag 7: µ-law.
+        bytes[20..22].copy_from_slice(&7u16.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(!supports(&path));
+    }
+
+    #[test]
+    fn wav_samples_scale_and_other_chunks_are_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.wav");
+        let before = wav_16(&[1000, -1000, 20000, i16::MIN], true);
+        std::fs::write(&path, &before).unwrap();
+
+        apply_gain(&path, 20.0 * 2f64.log10(), false).unwrap();
+        let after = std::fs::read(&path).unwrap();
+        assert_eq!(after.len(), before.len());
+
+        let expected = wav_16(&[2000, -2000, i16::MAX, i16::MIN], true);
+        assert_eq!(after, expected);
+    }
+
+    #[test]
+    fn aiff_24_bit_big_endian_is_scaled() {
+        let mut comm = Vec::new();
+        comm.extend_from_slice(&1u16.to_be_bytes());
+        comm.extend_from_slice(&2u32.to_be_bytes());
+        comm.extend_from_slice(&24u16.to_be_bytes());
+        comm.extend_from_slice(&[0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0]);
+        let mut b = b"FORM\0\0\0\0AIFF".to_vec();
+        b.extend_from_slice(b"COMM");
+        b.extend_from_slice(&(comm.len() as u32).to_be_bytes());
+        b.extend_from_slice(&comm);
+        b.extend_from_slice(b"SSND");
+        b.extend_from_slice(&14u32.to_be_bytes());
+        b.extend_from_slice(&[0u8; 8]);
+        b.extend_from_slice(&[0x00, 0x10, 0x00, 0xff, 0xf0, 0x00]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.aiff");
+        std::fs::write(&path, &b).unwrap();
+        apply_gain(&path, -20.0 * 2f64.log10(), false).unwrap();
+        let after = std::fs::read(&path).unwrap();
+        assert_eq!(
+            &after[after.len() - 6..],
+            &[0x00, 0x08, 0x00, 0xff, 0xf8, 0x00]
+        );
+        assert_eq!(&after[..after.len() - 6], &b[..b.len() - 6]);
+    }
+
+    #[test]
+    fn dither_stays_within_one_lsb() {
+        let enc = Encoding::Int {
+            bytes: 2,
+            valid_bits: 16,
+            little_endian: true,
+            unsigned: false,
+        };
+        let mut scaler = Scaler::new(enc, 1.0, true);
+        let mut buf: Vec<u8> = [100i16; 256].iter().flat_map(|s| s.to_le_bytes()).collect();
+        scaler.scale(&mut buf);
+        let values: Vec<i16> = buf
+            .chunks_exact(2)
+            .map(|c| i16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        assert!(values.iter().all(|v| (99..=101).contains(v)));
+        assert!(values.iter().any(|&v| v != 100));
+    }
+
+    #[test]
+    fn compressed_wav_is_rejected() {
+        let mut fmt = vec![0u8; 16];
+        fmt[0..2].copy_from_slice(&2u16.to_le_bytes());
+        fmt[2..4].copy_from_slice(&1u16.to_le_bytes());
+        fmt[12..14].copy_from_slice(&256u16.to_le_bytes());
+        fmt[14..16].copy_from_slice(&4u16.to_le_bytes());
+        assert!(wav_encoding(&fmt).is_err());
+    }
+}
@@ -1,9 +1,68 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
-use crate::analyzer::{AudioAnalysis, GainMethod};
+use crate::analyzer::{self, AudioAnalysis, GainMethod};
+use crate::decoder::PcmFormat;
+use crate::encoder::{AacProfile, BitrateMode, EncoderSettings};
+use crate::flac;
+use crate::metadata;
+use crate::ogg;
+use crate::pcm;
+use crate::replace::{self, FrameMatch, REENCODE_FRAME_TOLERANCE};
+use crate::scanner::{self, AudioFormat};
+use crate::verify::TP_TOLERANCE_DB;
+
+/// Explicit stream and metadata mapping for every ffmpeg rewrite: all audio
+/// streams, global tags and chapters. Cover art (`0:v?`) is added only for
+/// containers that can hold it. Audio streams other than the adjusted one
+/// are stream-copied (see `audio_options`).
+const METADATA_ARGS: [&str; 6] = ["-map", "0:a", "-map_metadata", "0", "-map_chapters", "0"];
+
+/// Re-encode attempts before the overshoot loop gives up.
+pub const MAX_REENCODE_PASSES: u32 = 4;
+
+/// Extra reduction per retry so the next pass lands under the ceiling rather
+/// than on it.
+const COMPENSATION_MARGIN_DB: f64 = 0.05;
+
+/// Settings that change how files are processed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessOptions {
+    /// Measure re-encodes and retry with less gain while they overshoot.
+    pub compensate_overshoot: bool,
+    /// TPDF dither when lossless output is 16-bit or less.
+    pub dither: bool,
+}
+
+/// Outcome of a re-encode measured by the overshoot loop.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReencodePasses {
+    pub passes: u32,
+    /// Gain of the pass that was kept.
+    pub gain_db: f64,
+    /// True Peak of the kept output.
+    pub final_tp: f64,
+}
+
+/// What `process_file` did.
+#[derive(Debug, Default)]
+pub struct Processed {
+    pub backup: Option<PathBuf>,
+    pub reencode: Option<ReencodePasses>,
+    /// Encoder and settings a re-encode used, e.g. "libmp3lame VBR V0, 44100 Hz, 2 ch".
+    pub reencode_settings: Option<String>,
+}
+
+pub fn check_ffmpeg() -> Result<()> {
+    Command::new("ffmpeg")
+        .arg("-version")
+        .output()
+        .context("ffmpeg not found. Please install ffmpeg first.")?;
+    Ok(())
+}
 
 pub fn create_backup_dir(base_dir: &Path) -> Result<PathBuf> {
     ensure_backup_dir(&base_dir.join("backup"))
@@ -14,7 +73,7 @@ pub fn create_backup_dir(base_dir: &Path) -> Result<PathBuf> {
 /// on magic directory names.
 pub fn ensure_backup_dir(backup_dir: &Path) -> Result<PathBuf> {
     fs::create_dir_all(backup_dir).context("Failed to create backup directory")?;
-    let marker = backup_dir.join(crate::scanner::BACKUP_MARKER);
+    let marker = backup_dir.join(scanner::BACKUP_MARKER);
     if !marker.exists() {
         fs::write(&marker, "Created by headroom; this directory is skipped when scanning.\n")
             .context("Failed to write backup marker file")?;
@@ -22,7 +81,7 @@ pub fn ensure_backup_dir(backup_dir: &Path) -> Result<PathBuf> {
     Ok(backup_dir.to_path_buf())
 }
 
-fn backup_file(file_path: &Path, base_dir: &Path, backup_dir: &Path) -> Result<PathBuf> {
+pub fn backup_file(file_path: &Path, base_dir: &Path, backup_dir: &Path) -> Result<PathBuf> {
     // Preserve directory structure relative to base_dir so sibling files with
     // the same name in different folders don't collide in the backup.
     // base_dir can be empty (mixed-root inputs), making strip_prefix return the
@@ -49,27 +108,123 @@ fn path_str(path: &Path) -> Result<&str> {
     path.to_str().ok_or_else(|| anyhow!("Invalid path: {}", path.display()))
 }
 
-/// Apply gain to lossless files using ffmpeg volume filter
-fn apply_gain_ffmpeg(file_path: &Path, gain_db: f64) -> Result<()> {
-    let extension = file_path
-        .extension()
-        .and_then(|e| e.to_str())
-        .unwrap_or("wav");
-    let temp_path = file_path.with_extension(format!("tmp.{}", extension));
+/// Apply precise gain to lossless files: natively to PCM WAV/AIFF samples
+/// and FLAC frames, with the ffmpeg volume filter for everything else
+/// (including encodings `pcm` and `flac` reject).
+fn apply_gain_lossless(analysis: &AudioAnalysis, options: ProcessOptions) -> Result<()> {
+    let file_path = analysis.path.as_path();
+    let native = analysis.is_native_lossless();
+    if native && flac::supports(file_path) {
+        // New frames behind the original metadata blocks.
+        return replace::replace_with(file_path, FrameMatch::Exact, |staged| {
+            flac::apply_gain(file_path, staged, analysis.effective_gain, options.dither)
+        });
+    }
+    if native && pcm::supports(file_path) {
+        // Only the sample bytes change; every other chunk is kept as is.
+        return replace::modify_copy(file_path, FrameMatch::Exact, |staged| {
+            pcm::apply_gain(staged, analysis.effective_gain, options.dither)
+        });
+    }
+    replace::replace_with(file_path, FrameMatch::Exact, |temp_path| {
+        ffmpeg_gain(analysis, temp_path, options.dither)
+    })
+}
 
-    let input = path_str(file_path)?;
-    let temp = path_str(&temp_path)?;
-    let volume_arg = format!("volume={}dB", gain_db);
+/// PCM codec ffmpeg should write for `format` in a WAV (little-endian) or
+/// AIFF (big-endian) container. Unknown formats keep the 24-bit default.
+fn pcm_codec(format: Option<PcmFormat>, big_endian: bool) -> &'static str {
+    let (bits, float) = format.map_or((24, false), |f| (f.bits, f.float));
+    match (bits, float, big_endian) {
+        (64, true, false) => "pcm_f64le",
+        (64, true, true) => "pcm_f64be",
+        (_, true, false) => "pcm_f32le",
+        (_, true, true) => "pcm_f32be",
+        (..=8, _, false) => "pcm_u8",
+        (..=8, _, true) => "pcm_s8",
+        (..=16, _, false) => "pcm_s16le",
+        (..=16, _, true) => "pcm_s16be",
+        (..=24, _, false) => "pcm_s24le",
+        (..=24, _, true) => "pcm_s24be",
+        (_, _, false) => "pcm_s32le",
+        (_, _, true) => "pcm_s32be",
+    }
+}
+
+/// Gain filter, requantized to the source depth with TPDF dither when
+/// `dither` is set and the output is 16-bit integer or less.
+fn gain_filter(gain_db: f64, format: Option<PcmFormat>, dither: bool) -> String {
+    let volume = format!("volume={}dB", gain_db);
+    match format {
+        Some(f) if dither && !f.float && f.bits <= 16 => {
+            let sample_fmt = if f.bits <= 8 { "u8" } else { "s16" };
+            format!(
+                "{},aresample=osf={}:dither_method=triangular",
+                volume, sample_fmt
+            )
+        }
+        _ => volume,
+    }
+}
 
-    let mut args: Vec<&str> = vec!["-y", "-i", input, "-af", &volume_arg];
-    match extension.to_ascii_lowercase().as_str() {
-        "flac" => args.extend(["-c:a", "flac"]),
+fn ffmpeg_gain(analysis: &AudioAnalysis, temp_path: &Path, dither: bool) -> Result<()> {
+    let file_path = analysis.path.as_path();
+    let format = analysis.sample_format;
+    let container = analysis.format;
+
+    let input = path_str(file_path)?;
+    let temp = path_str(temp_path)?;
+    let filter = gain_filter(analysis.effective_gain, format, dither);
+    let raw_bits = format.map_or(24, |f| f.bits).to_string();
+
+    let mut args: Vec<&str> = vec!["-y", "-i", input];
+    args.extend(METADATA_ARGS);
+    let mut audio: Vec<&str> = vec!["-filter:a", &filter];
+    match container {
+        // Without an explicit sample format the encoder picks 16 bits.
+        Some(AudioFormat::Flac) => {
+            args.extend(["-map", "0:v?", "-c:v", "copy"]);
+            audio.extend(["-c:a", "flac"]);
+            match format {
+                Some(f) if f.bits <= 16 => audio.extend(["-sample_fmt", "s16"]),
+                _ => audio.extend(["-sample_fmt", "s32", "-bits_per_raw_sample", &raw_bits]),
+            }
+        }
+        // Apple Lossless; the ALAC encoder only takes planar input.
+        Some(AudioFormat::Alac) => {
+            args.extend(["-map", "0:v?", "-c:v", "copy"]);
+            audio.extend(["-c:a", "alac"]);
+            match format {
+                Some(f) if f.bits <= 16 => audio.extend(["-sample_fmt", "s16p"]),
+                _ => audio.extend(["-sample_fmt", "s32p", "-bits_per_raw_sample", &raw_bits]),
+            }
+        }
+        // WavPack is re-encoded losslessly; its APEv2 tags come from -map_metadata.
+        // The encoder only takes planar input and stores float as float.
+        Some(AudioFormat::WavPack) => {
+            audio.extend(["-c:a", "wavpack"]);
+            match format {
+                Some(f) if f.float => audio.extend(["-sample_fmt", "fltp"]),
+                Some(f) if f.bits <= 8 => audio.extend(["-sample_fmt", "u8p"]),
+                Some(f) if f.bits <= 16 => audio.extend(["-sample_fmt", "s16p"]),
+                _ => audio.extend(["-sample_fmt", "s32p", "-bits_per_raw_sample", &raw_bits]),
+            }
+        }
         // ffmpeg's AIFF muxer drops ID3v2 chunks unless -write_id3v2 is set.
-        "aiff" | "aif" => args.extend(["-c:a", "pcm_s24be", "-write_id3v2", "1"]),
+        Some(AudioFormat::Aiff) => {
+            audio.extend(["-c:a", pcm_codec(format, true)]);
+            args.extend(["-write_id3v2", "1"]);
+        }
         // -write_bext preserves Broadcast Wave Format chunks (time_reference, umid).
-        "wav" => args.extend(["-c:a", "pcm_s24le", "-write_bext", "1"]),
+        Some(AudioFormat::Wav) => {
+            audio.extend(["-c:a", pcm_codec(format, false)]);
+            args.extend(["-write_bext", "1"]);
+        }
         _ => {}
     }
+    let audio = audio_options(&audio, analysis.stream, analysis.stream_count);
+    args.extend(audio.iter().map(String::as_str));
+    args.extend(muxer_for(analysis));
     args.push(temp);
 
     let output = Command::new("ffmpeg")
@@ -78,32 +233,60 @@ fn apply_gain_ffmpeg(file_path: &Path, gain_db: f64) -> Result<()> {
         .context("Failed to execute ffmpeg for gain adjustment")?;
 
     if !output.status.success() {
-        let _ = fs::remove_file(&temp_path);
         let stderr = String::from_utf8_lossy(&output.stderr);
         return Err(anyhow!("ffmpeg failed: {}", stderr));
     }
+    // ffmpeg rewrites the block chain; restore the original one.
+    if container == Some(AudioFormat::Flac) {
+        flac::carry_blocks(file_path, temp_path)?;
+    }
+    metadata::carry_id3(file_path, temp_path)
+}
 
-    fs::rename(&temp_path, file_path).context("Failed to rename processed file")
+/// Audio options (name/value pairs) for a file with `stream_count` audio
+/// streams. With more than one, the other streams are stream-copied and each
+/// option is narrowed to audio stream `stream` (`-c:a` -> `-c:a:1`,
+/// `-ar` -> `-ar:a:1`).
+fn audio_options<S: AsRef<str>>(options: &[S], stream: usize, stream_count: usize) -> Vec<String> {
+    let options = options.iter().map(|o| o.as_ref().to_string());
+    if stream_count <= 1 {
+        return options.collect();
+    }
+    let mut narrowed = vec!["-c:a".to_string(), "copy".to_string()];
+    for (i, option) in options.enumerate() {
+        narrowed.push(match i % 2 {
+            0 if option.ends_with(":a") => format!("{}:{}", option, stream),
+            0 => format!("{}:a:{}", option, stream),
+            _ => option,
+        });
+    }
+    narrowed
+}
+
+/// `-f` arguments forcing the muxer of the real format when the extension
+/// says otherwise; ffmpeg would pick the muxer from the extension.
+fn muxer_for(analysis: &AudioAnalysis) -> Vec<&'static str> {
+    match scanner::extension_mismatch(&analysis.path, analysis.format)
+        .and_then(|format| format.muxer())
+    {
+        Some(muxer) => vec!["-f", muxer],
+        None => Vec::new(),
+    }
 }
 
 #[derive(Clone, Copy)]
 enum LossyFormat {
     Mp3,
     Aac,
+    Vorbis,
 }
 
 impl LossyFormat {
-    fn temp_ext(self) -> &'static str {
-        match self {
-            LossyFormat::Mp3 => "tmp.mp3",
-            LossyFormat::Aac => "tmp.m4a",
-        }
-    }
-
     fn default_bitrate(self) -> &'static str {
         match self {
             LossyFormat::Mp3 => "320k",
             LossyFormat::Aac => "256k",
+            LossyFormat::Vorbis => "192k",
         }
     }
 
@@ -112,6 +295,7 @@ impl LossyFormat {
             LossyFormat::Mp3 => &["libmp3lame"],
             // Tries libfdk_aac first (higher quality), falls back to built-in aac.
             LossyFormat::Aac => &["libfdk_aac", "aac"],
+            LossyFormat::Vorbis => &["libvorbis"],
         }
     }
 
@@ -119,58 +303,282 @@ impl LossyFormat {
         match self {
             LossyFormat::Mp3 => "MP3",
             LossyFormat::Aac => "AAC",
+            LossyFormat::Vorbis => "Vorbis",
         }
     }
+
+    /// Whether cover art is a separate video stream. Ogg keeps it in a
+    /// `METADATA_BLOCK_PICTURE` comment, carried with the other tags.
+    fn has_cover_stream(self) -> bool {
+        !matches!(self, LossyFormat::Vorbis)
+    }
 }
 
-/// Apply lossless gain to MP3/AAC files using mp3rgain library (1.5dB steps)
+/// Apply lossless gain to MP3/AAC files using mp3rgain library (1.5dB steps).
+/// mp3rgain edits in place, so it works on a staged copy.
 fn apply_gain_native(file_path: &Path, gain_steps: i32, format: LossyFormat) -> Result<()> {
     if gain_steps == 0 {
         return Ok(());
     }
-    match format {
-        LossyFormat::Mp3 => mp3rgain::apply_gain(file_path, gain_steps)
+    replace::modify_copy(file_path, FrameMatch::Exact, |copy| match format {
+        LossyFormat::Mp3 => mp3rgain::apply_gain(copy, gain_steps)
             .map(|_| ())
             .context("mp3rgain failed to apply MP3 gain"),
-        LossyFormat::Aac => mp3rgain::aac::apply_aac_gain(file_path, gain_steps)
+        LossyFormat::Aac => mp3rgain::aac::apply_aac_gain(copy, gain_steps)
             .map(|_| ())
             .context("mp3rgain failed to apply AAC gain"),
+        LossyFormat::Vorbis => bail!("Vorbis has no native gain"),
+    })
+}
+
+/// Add the gain to the Opus header output gain, on a staged copy.
+fn apply_gain_opus(analysis: &AudioAnalysis) -> Result<()> {
+    replace::modify_copy(&analysis.path, FrameMatch::Exact, |copy| {
+        ogg::apply_opus_gain(copy, analysis.effective_gain).map(|_| ())
+    })
+}
+
+/// Re-encode with `effective_gain`; returns the settings used.
+fn apply_gain_reencode(analysis: &AudioAnalysis, format: LossyFormat) -> Result<String> {
+    let mut settings = String::new();
+    replace::replace_with(
+        &analysis.path,
+        FrameMatch::Within(REENCODE_FRAME_TOLERANCE),
+        |temp_path| {
+            settings = reencode(analysis, temp_path, analysis.effective_gain, format)?;
+            Ok(())
+        },
+    )?;
+    Ok(settings)
+}
+
+/// Gain for the next re-encode pass, or `None` when `final_tp` is already
+/// within tolerance of the ceiling.
+fn next_pass_gain(gain_db: f64, final_tp: f64, target_tp: f64) -> Option<f64> {
+    let excess = final_tp - target_tp;
+    (excess > TP_TOLERANCE_DB).then_some(gain_db - excess - COMPENSATION_MARGIN_DB)
+}
+
+/// Re-encode, measure the output and retry with the overshoot taken off the
+/// gain until the True Peak is within tolerance of `target_tp`. Fails (and
+/// leaves the original untouched) if that takes more than
+/// `MAX_REENCODE_PASSES` attempts.
+fn apply_gain_reencode_compensated(
+    analysis: &AudioAnalysis,
+    format: LossyFormat,
+) -> Result<(ReencodePasses, String)> {
+    let file_path = analysis.path.as_path();
+    let mut result = None;
+
+    replace::replace_with(
+        file_path,
+        FrameMatch::Within(REENCODE_FRAME_TOLERANCE),
+        |temp_path| {
+            let mut gain_db = analysis.effective_gain;
+            for passes in 1..=MAX_REENCODE_PASSES {
+                let settings = reencode(analysis, temp_path, gain_db, format)?;
+                let final_tp = analyzer::measure_file(temp_path, analysis.format, analysis.stream)
+                    .context("Failed to measure re-encoded output")?
+                    .input_tp;
+
+                match next_pass_gain(gain_db, final_tp, analysis.target_tp) {
+                    Some(reduced) => gain_db = reduced,
+                    None => {
+                        let passes = ReencodePasses {
+                            passes,
+                            gain_db,
+                            final_tp,
+                        };
+                        result = Some((passes, settings));
+                        return Ok(());
+                    }
+                }
+            }
+            bail!(
+                "re-encode still over the ceiling after {} passes",
+                MAX_REENCODE_PASSES
+            )
+        },
+    )?;
+
+    result.ok_or_else(|| anyhow!("re-encode produced no result"))
+}
+
+/// LAME `-V` level closest to an average VBR bitrate, for VBR sources
+/// without a quality field.
+fn vbr_quality_for(kbps: u32) -> u8 {
+    match kbps {
+        220.. => 0,
+        190..=219 => 1,
+        170..=189 => 2,
+        150..=169 => 3,
+        130..=149 => 4,
+        _ => 5,
     }
 }
 
-fn apply_gain_reencode(
-    file_path: &Path,
-    gain_db: f64,
-    bitrate_kbps: Option<u32>,
+/// libfdk_aac `-vbr` mode for a per-channel bitrate of `kbps`.
+fn fdk_vbr_mode_for(kbps: u32) -> u8 {
+    match kbps {
+        96.. => 5,
+        64..=95 => 4,
+        48..=63 => 3,
+        32..=47 => 2,
+        _ => 1,
+    }
+}
+
+/// libvorbis `-q` level whose nominal bitrate is closest to `kbps`.
+fn vorbis_quality_for(kbps: u32) -> u8 {
+    match kbps {
+        400.. => 10,
+        288..=399 => 9,
+        240..=287 => 8,
+        208..=239 => 7,
+        176..=207 => 6,
+        144..=175 => 5,
+        120..=143 => 4,
+        104..=119 => 3,
+        88..=103 => 2,
+        72..=87 => 1,
+        _ => 0,
+    }
+}
+
+/// ffmpeg output options reproducing the source encode with `encoder`, and
+/// a summary of them for the report. Without source settings this is CBR at
+/// the measured (or default) bitrate.
+fn encoder_args(
     format: LossyFormat,
-) -> Result<()> {
-    let temp_path = file_path.with_extension(format.temp_ext());
+    encoder: &str,
+    source: Option<&EncoderSettings>,
+    bitrate_kbps: Option<u32>,
+) -> (Vec<String>, String) {
     let bitrate = bitrate_kbps
         .map(|kbps| format!("{}k", kbps))
         .unwrap_or_else(|| format.default_bitrate().to_string());
+    let mut args = vec!["-c:a".to_string(), encoder.to_string()];
+    let mut summary = vec![encoder.to_string()];
+
+    let mode = source.and_then(|s| s.bitrate_mode);
+    match format {
+        // -q:a switches libmp3lame to VBR, so it is only passed for VBR sources.
+        LossyFormat::Mp3 => match mode {
+            Some(BitrateMode::Vbr) => {
+                let quality = source
+                    .and_then(|s| s.vbr_quality)
+                    .unwrap_or_else(|| vbr_quality_for(bitrate_kbps.unwrap_or(320)));
+                args.extend(["-q:a".to_string(), quality.to_string()]);
+                summary.push(format!("VBR V{}", quality));
+            }
+            Some(BitrateMode::Abr) => {
+                let kbps = source.and_then(|s| s.bitrate_kbps).or(bitrate_kbps);
+                let target = kbps.map(|k| format!("{}k", k)).unwrap_or(bitrate);
+                summary.push(format!("ABR {}", target));
+                args.extend(["-b:a".to_string(), target, "-abr".into(), "1".into()]);
+            }
+            _ => {
+                summary.push(format!("CBR {}", bitrate));
+                args.extend(["-b:a".to_string(), bitrate]);
+            }
+        },
+        LossyFormat::Aac => {
+            let profile = source.and_then(|s| s.aac_profile);
+            let fdk_profile = match profile {
+                Some(AacProfile::Lc) => Some("aac_low"),
+                Some(AacProfile::He) => Some("aac_he"),
+                Some(AacProfile::HeV2) => Some("aac_he_v2"),
+                _ => None,
+            };
+            // The built-in encoder only produces AAC LC.
+            match fdk_profile {
+                Some(name) if encoder == "libfdk_aac" => {
+                    args.extend(["-profile:a".to_string(), name.to_string()]);
+                    summary.push(profile.map(AacProfile::label).unwrap_or_default().into());
+                }
+                _ => summary.push(AacProfile::Lc.label().into()),
+            }
+            // The built-in encoder's quality mode is experimental, so VBR
+            // sources get its average-bitrate mode at the measured rate.
+            match mode {
+                Some(BitrateMode::Vbr) if encoder == "libfdk_aac" => {
+                    let channels = source.map_or(2, |s| u32::from(s.channels.max(1)));
+                    let kbps = bitrate_kbps.or(source.and_then(|s| s.bitrate_kbps));
+                    let level = fdk_vbr_mode_for(kbps.unwrap_or(256) / channels);
+                    args.extend(["-vbr".to_string(), level.to_string()]);
+                    summary.push(format!("VBR {}", level));
+                }
+                _ => {
+                    summary.push(bitrate.clone());
+                    args.extend(["-b:a".to_string(), bitrate]);
+                }
+            }
+        }
+        // Vorbis sources are quality-mode VBR; aim for the same nominal rate.
+        LossyFormat::Vorbis => {
+            let kbps = source.and_then(|s| s.bitrate_kbps).or(bitrate_kbps);
+            let quality = vorbis_quality_for(kbps.unwrap_or(192));
+            args.extend(["-q:a".to_string(), quality.to_string()]);
+            summary.push(format!("q{}", quality));
+        }
+    }
+    let mut summary = vec![summary.join(" ")];
+
+    if let Some(source) = source {
+        args.extend(["-ar".to_string(), source.sample_rate.to_string()]);
+        args.extend(["-ac".to_string(), source.channels.to_string()]);
+        summary.push(format!("{} Hz", source.sample_rate));
+        summary.push(format!("{} ch", source.channels));
+        if let (LossyFormat::Mp3, Some(joint)) = (format, source.joint_stereo) {
+            args.extend(["-joint_stereo".to_string(), u8::from(joint).to_string()]);
+            if joint {
+                summary.push("joint stereo".into());
+            }
+        }
+    }
+    (args, summary.join(", "))
+}
+
+/// Re-encode `analysis.path` into `temp_path` with `gain_db`, trying each
+/// encoder of `format` in turn. Returns the settings that succeeded.
+fn reencode(
+    analysis: &AudioAnalysis,
+    temp_path: &Path,
+    gain_db: f64,
+    format: LossyFormat,
+) -> Result<String> {
     let volume_arg = format!("volume={}dB", gain_db);
 
-    let input = path_str(file_path)?;
-    let temp = path_str(&temp_path)?;
+    let input = path_str(&analysis.path)?;
+    let temp = path_str(temp_path)?;
     let label = format.label();
 
     for encoder in format.encoders() {
-        // CBR-only: adding -q:a would force libmp3lame to VBR and override -b:a.
-        let args = [
-            "-y", "-i", input, "-af", &volume_arg, "-c:a", encoder, "-b:a", &bitrate, temp,
-        ];
-
-        let output = Command::new("ffmpeg")
-            .args(args)
+        let (codec_args, summary) =
+            encoder_args(format, encoder, analysis.encoder.as_ref(), analysis.bitrate_kbps);
+        let mut audio = vec!["-filter:a".to_string(), volume_arg.clone()];
+        audio.extend(codec_args);
+
+        let mut command = Command::new("ffmpeg");
+        command.args(["-y", "-i", input]).args(METADATA_ARGS);
+        if format.has_cover_stream() {
+            command.args(["-map", "0:v?", "-c:v", "copy"]);
+        }
+        let output = command
+            .args(audio_options(&audio, analysis.stream, analysis.stream_count))
+            .args(muxer_for(analysis))
+            .arg(temp)
             .output()
             .with_context(|| format!("Failed to execute ffmpeg for {} re-encode", label))?;
 
         if output.status.success() {
-            return fs::rename(&temp_path, file_path)
-                .context("Failed to rename processed file");
+            if let LossyFormat::Mp3 = format {
+                metadata::carry_id3(&analysis.path, temp_path)?;
+            }
+            return Ok(summary);
         }
 
-        let _ = fs::remove_file(&temp_path);
+        let _ = fs::remove_file(temp_path);
     }
 
     Err(anyhow!(
@@ -179,41 +587,175 @@ fn apply_gain_reencode(
     ))
 }
 
+/// Apply the analysed gain to one file.
 pub fn process_file(
     analysis: &AudioAnalysis,
     base_dir: &Path,
     backup_dir: Option<&Path>,
-) -> Result<()> {
+    options: ProcessOptions,
+) -> Result<Processed> {
     if !analysis.has_headroom() {
-        return Ok(());
+        return Ok(Processed::default());
     }
 
     let file_path = analysis.path.as_path();
 
-    if let Some(backup) = backup_dir {
-        backup_file(file_path, base_dir, backup).context("Backup failed")?;
-    }
+    let backup_path = match backup_dir {
+        Some(backup) => Some(backup_file(file_path, base_dir, backup).context("Backup failed")?),
+        None => None,
+    };
 
-    match analysis.gain_method {
-        GainMethod::FfmpegLossless => apply_gain_ffmpeg(file_path, analysis.effective_gain),
+    let result = match analysis.gain_method {
+        GainMethod::Lossless => apply_gain_lossless(analysis, options).map(|_| None),
         GainMethod::Mp3Lossless => {
             apply_gain_native(file_path, analysis.lossless_gain_steps, LossyFormat::Mp3)
+                .map(|_| None)
         }
         GainMethod::AacLossless => {
             apply_gain_native(file_path, analysis.lossless_gain_steps, LossyFormat::Aac)
+                .map(|_| None)
+        }
+        GainMethod::OpusHeader => apply_gain_opus(analysis).map(|_| None),
+        GainMethod::Mp3Reencode | GainMethod::AacReencode | GainMethod::VorbisReencode
+            if options.compensate_overshoot =>
+        {
+            let format = match analysis.gain_method {
+                GainMethod::Mp3Reencode => LossyFormat::Mp3,
+                GainMethod::AacReencode => LossyFormat::Aac,
+                _ => LossyFormat::Vorbis,
+            };
+            let (passes, settings) = apply_gain_reencode_compensated(analysis, format)?;
+            return Ok(Processed {
+                backup: backup_path,
+                reencode: Some(passes),
+                reencode_settings: Some(settings),
+            });
         }
-        GainMethod::Mp3Reencode => apply_gain_reencode(
-            file_path,
-            analysis.effective_gain,
-            analysis.bitrate_kbps,
-            LossyFormat::Mp3,
-        ),
-        GainMethod::AacReencode => apply_gain_reencode(
-            file_path,
-            analysis.effective_gain,
-            analysis.bitrate_kbps,
-            LossyFormat::Aac,
-        ),
-        GainMethod::None => Ok(()),
+        GainMethod::Mp3Reencode => apply_gain_reencode(analysis, LossyFormat::Mp3).map(Some),
+        GainMethod::AacReencode => apply_gain_reencode(analysis, LossyFormat::Aac).map(Some),
+        GainMethod::VorbisReencode => {
+            apply_gain_reencode(analysis, LossyFormat::Vorbis).map(Some)
+        }
+        GainMethod::TagOnly => Err(anyhow!("No encoder can rewrite this format; use --tag-only")),
+        GainMethod::None => Ok(None),
+    };
+    result.map(|reencode_settings| Processed {
+        backup: backup_path,
+        reencode: None,
+        reencode_settings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overshoot_is_taken_off_the_next_pass() {
+        assert_eq!(next_pass_gain(1.0, -0.45, -0.5), None);
+        let reduced = next_pass_gain(1.0, 0.1, -0.5).unwrap();
+        assert!((reduced - (1.0 - 0.6 - COMPENSATION_MARGIN_DB)).abs() < 1e-9);
+    }
+
+    fn source(mode: BitrateMode) -> EncoderSettings {
+        let mut settings = EncoderSettings::new(44100, 2);
+        settings.bitrate_mode = Some(mode);
+        settings.joint_stereo = Some(true);
+        settings
+    }
+
+    #[test]
+    fn vbr_mp3_is_reencoded_as_vbr_at_the_same_quality() {
+        let mut vbr = source(BitrateMode::Vbr);
+        vbr.vbr_quality = Some(2);
+        let (args, summary) = encoder_args(LossyFormat::Mp3, "libmp3lame", Some(&vbr), Some(190));
+        assert!(args.windows(2).any(|w| w == ["-q:a", "2"]));
+        assert!(!args.iter().any(|a| a == "-b:a"));
+        assert!(args.windows(2).any(|w| w == ["-ar", "44100"]));
+        assert_eq!(summary, "libmp3lame VBR V2, 44100 Hz, 2 ch, joint stereo");
+    }
+
+    #[test]
+    fn he_aac_keeps_its_profile_only_with_fdk() {
+        let mut he = source(BitrateMode::Cbr);
+        he.joint_stereo = None;
+        he.aac_profile = Some(AacProfile::He);
+        let (fdk, _) = encoder_args(LossyFormat::Aac, "libfdk_aac", Some(&he), Some(64));
+        assert!(fdk.windows(2).any(|w| w == ["-profile:a", "aac_he"]));
+        let (native, summary) = encoder_args(LossyFormat::Aac, "aac", Some(&he), Some(64));
+        assert!(!native.iter().any(|a| a == "-profile:a"));
+        assert!(summary.starts_with("aac LC 64k"));
+    }
+
+    #[test]
+    fn vbr_aac_is_reencoded_as_vbr_with_fdk() {
+        let mut vbr = source(BitrateMode::Vbr);
+        vbr.joint_stereo = None;
+        vbr.aac_profile = Some(AacProfile::Lc);
+        let (fdk, summary) = encoder_args(LossyFormat::Aac, "libfdk_aac", Some(&vbr), Some(256));
+        assert!(fdk.windows(2).any(|w| w == ["-vbr", "5"]));
+        assert!(!fdk.iter().any(|a| a == "-b:a"));
+        assert_eq!(summary, "libfdk_aac LC VBR 5, 44100 Hz, 2 ch");
+        let (native, summary) = encoder_args(LossyFormat::Aac, "aac", Some(&vbr), Some(256));
+        assert!(native.windows(2).any(|w| w == ["-b:a", "256k"]));
+        assert!(summary.starts_with("aac LC 256k"));
+    }
+
+    #[test]
+    fn vorbis_is_reencoded_at_the_nominal_quality() {
+        let mut vorbis = source(BitrateMode::Vbr);
+        vorbis.joint_stereo = None;
+        vorbis.bitrate_kbps = Some(192);
+        let (args, summary) = encoder_args(LossyFormat::Vorbis, "libvorbis", Some(&vorbis), None);
+        assert!(args.windows(2).any(|w| w == ["-q:a", "6"]));
+        assert_eq!(summary, "libvorbis q6, 44100 Hz, 2 ch");
+    }
+
+    #[test]
+    fn lossless_output_keeps_the_source_sample_format() {
+        let f = |bits, float| Some(PcmFormat { bits, float });
+        assert_eq!(pcm_codec(f(16, false), false), "pcm_s16le");
+        assert_eq!(pcm_codec(f(24, false), true), "pcm_s24be");
+        assert_eq!(pcm_codec(f(32, true), false), "pcm_f32le");
+        assert_eq!(pcm_codec(f(64, true), true), "pcm_f64be");
+        assert_eq!(pcm_codec(None, false), "pcm_s24le");
+    }
+
+    #[test]
+    fn dither_only_applies_to_16_bit_integer_output() {
+        let s16 = Some(PcmFormat { bits: 16, float: false });
+        let s24 = Some(PcmFormat { bits: 24, float: false });
+        assert_eq!(
+            gain_filter(1.5, s16, true),
+            "volume=1.5dB,aresample=osf=s16:dither_method=triangular"
+        );
+        assert_eq!(gain_filter(1.5, s16, false), "volume=1.5dB");
+        assert_eq!(gain_filter(1.5, s24, true), "volume=1.5dB");
+    }
+
+    #[test]
+    fn other_audio_streams_are_copied() {
+        let options = ["-filter:a", "volume=2dB", "-c:a", "flac", "-sample_fmt", "s16"];
+        assert_eq!(audio_options(&options, 0, 1), options);
+        assert_eq!(
+            audio_options(&options, 1, 3),
+            [
+                "-c:a",
+                "copy",
+                "-filter:a:1",
+                "volume=2dB",
+                "-c:a:1",
+                "flac",
+                "-sample_fmt:a:1",
+                "s16"
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_source_falls_back_to_cbr() {
+        let (args, summary) = encoder_args(LossyFormat::Mp3, "libmp3lame", None, None);
+        assert_eq!(args, ["-c:a", "libmp3lame", "-b:a", "320k"]);
+        assert_eq!(summary, "libmp3lame CBR 320k");
     }
 }
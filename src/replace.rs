@@ -0,0 +1,280 @@
+//! Crash-safe replacement of audio files.
+//!
+//! Changes are never made to the original file. The new content is written
+//! to a staged sibling (`<stem>.headroom-tmp.<ext>`), fsynced, decoded to
+//! confirm every audio stream survived (unless only tags changed), given the
+//! original's permissions, timestamps and extended attributes, and only then
+//! renamed over the original. A killed run therefore leaves either the old
+//! or the new file, plus at most a stray staged file that the scanner
+//! ignores.
+
+use anyhow::{bail, Context, Result};
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use crate::decoder;
+
+/// Marker in the name of staged files.
+pub const STAGING_MARKER: &str = "headroom-tmp";
+
+/// Frame-count slack for re-encodes, which may add or drop encoder
+/// delay/padding (an MP3 frame is 1152 samples, AAC priming is 2112).
+pub const REENCODE_FRAME_TOLERANCE: u64 = 4096;
+
+/// How closely the staged audio must match the original.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameMatch {
+    /// Same sample rate, channels and frame count.
+    Exact,
+    /// Same sample rate and channels; frame count within the given slack.
+    Within(u64),
+    /// Audio data copied byte for byte, as by tag writers; nothing is decoded.
+    Verbatim,
+}
+
+/// Staged sibling of `path`. The extension is kept last so ffmpeg and the
+/// decoder still pick the right container.
+pub fn staging_path(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, STAGING_MARKER, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, STAGING_MARKER),
+    };
+    path.with_file_name(name)
+}
+
+/// Whether `path` is a staged file left behind by an interrupted run.
+pub fn is_staging_file(path: &Path) -> bool {
+    path.file_stem()
+        .and_then(|s| s.to_str())
+        .is_some_and(|s| s.ends_with(&format!(".{}", STAGING_MARKER)))
+}
+
+/// Replace `path` with the content `write` produces at the staged path.
+pub fn replace_with(
+    path: &Path,
+    check: FrameMatch,
+    write: impl FnOnce(&Path) -> Result<()>,
+) -> Result<()> {
+    replace_checked(path, path, check, write)
+}
+
+/// Replace `path` with a byte copy of `source` (a backup). The staged copy is
+/// checked against `source` rather than `path`, which may have been edited
+/// or deleted since.
+pub fn copy_from(path: &Path, source: &Path) -> Result<()> {
+    replace_checked(path, source, FrameMatch::Exact, |staged| {
+        fs::copy(source, staged).with_context(|| format!("Failed to copy {}", source.display()))?;
+        Ok(())
+    })
+}
+
+fn replace_checked(
+    path: &Path,
+    reference: &Path,
+    check: FrameMatch,
+    write: impl FnOnce(&Path) -> Result<()>,
+) -> Result<()> {
+    let staged = staging_path(path);
+    let result = write(&staged).and_then(|()| commit(path, reference, &staged, check));
+    if result.is_err() {
+        let _ = fs::remove_file(&staged);
+    }
+    result
+}
+
+/// Copy `path` to the staged path, let `modify` edit the copy in place, then
+/// replace the original with it. Used for tools that only work in place.
+pub fn modify_copy(
+    path: &Path,
+    check: FrameMatch,
+    modify: impl FnOnce(&Path) -> Result<()>,
+) -> Result<()> {
+    replace_with(path, check, |staged| {
+        fs::copy(path, staged)
+            .with_context(|| format!("Failed to stage a copy of {}", path.display()))?;
+        modify(staged)
+    })
+}
+
+fn commit(path: &Path, reference: &Path, staged: &Path, check: FrameMatch) -> Result<()> {
+    // Write access is needed for fsync on Windows.
+    OpenOptions::new()
+        .write(true)
+        .open(staged)
+        .and_then(|f| f.sync_all())
+        .context("Failed to flush staged file to disk")?;
+
+    if check != FrameMatch::Verbatim {
+        verify_streams(reference, staged, check)?;
+    }
+    copy_metadata(if path.exists() { path } else { reference }, staged)?;
+
+    fs::rename(staged, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    sync_parent(path);
+    Ok(())
+}
+
+/// Decode both files and compare every audio stream.
+fn verify_streams(original: &Path, staged: &Path, check: FrameMatch) -> Result<()> {
+    let count = decoder::audio_streams(original).len();
+    let new_count = decoder::audio_streams(staged).len();
+    if new_count != count {
+        bail!(
+            "Processed file has {} audio streams instead of {}",
+            new_count,
+            count
+        );
+    }
+    for stream in 0..count.max(1) {
+        verify_stream(original, staged, stream, check)
+            .with_context(|| format!("Audio stream {}", stream))?;
+    }
+    Ok(())
+}
+
+fn verify_stream(original: &Path, staged: &Path, stream: usize, check: FrameMatch) -> Result<()> {
+    let (spec, frames) =
+        decoder::stream_length(original, stream).context("Failed to decode the original")?;
+    let (new_spec, new_frames) =
+        decoder::stream_length(staged, stream).context("Processed file does not decode")?;
+
+    // Speaker layouts are labelled differently by different encoders; only
+    // the channel count and rate must survive.
+    if (new_spec.channels, new_spec.sample_rate) != (spec.channels, spec.sample_rate) {
+        bail!(
+            "Processed file changed format: {} ch {} Hz -> {} ch {} Hz",
+            spec.channels,
+            spec.sample_rate,
+            new_spec.channels,
+            new_spec.sample_rate
+        );
+    }
+    let slack = match check {
+        FrameMatch::Exact | FrameMatch::Verbatim => 0,
+        FrameMatch::Within(n) => n,
+    };
+    if frames.abs_diff(new_frames) > slack {
+        bail!(
+            "Processed file length changed: {} -> {} samples",
+            frames,
+            new_frames
+        );
+    }
+    Ok(())
+}
+
+/// Give `staged` the permissions, timestamps and extended attributes of
+/// `original`. Ownership is left alone (changing it needs privileges).
+fn copy_metadata(original: &Path, staged: &Path) -> Result<()> {
+    let meta = fs::metadata(original)
+        .with_context(|| format!("Failed to read metadata of {}", original.display()))?;
+    fs::set_permissions(staged, meta.permissions()).context("Failed to copy permissions")?;
+
+    #[cfg(unix)]
+    if let Ok(names) = xattr::list(original) {
+        // Best effort: some attributes (e.g. security labels) are not ours
+        // to set, and the filesystem of the staged file is the same anyway.
+        for name in names {
+            if let Ok(Some(value)) = xattr::get(original, &name) {
+                let _ = xattr::set(staged, &name, &value);
+            }
+        }
+    }
+
+    let atime = filetime::FileTime::from_last_access_time(&meta);
+    let mtime = filetime::FileTime::from_last_modification_time(&meta);
+    filetime::set_file_times(staged, atime, mtime).context("Failed to copy timestamps")
+}
+
+/// Persist the rename itself. Directories cannot be fsynced on Windows.
+fn sync_parent(path: &Path) {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        if let Ok(dir) = fs::File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mono 16-bit 44.1 kHz WAV.
+    fn wav(samples: &[i16]) -> Vec<u8> {
+        let data_len = (samples.len() * 2) as u32;
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&(36 + data_len).to_le_bytes());
+        out.extend_from_slice(b"WAVEfmt \x10\x00\x00\x00\x01\x00\x01\x00");
+        out.extend_from_slice(&44100u32.to_le_bytes());
+        out.extend_from_slice(&88200u32.to_le_bytes());
+        out.extend_from_slice(&[2, 0, 16, 0]);
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&data_len.to_le_bytes());
+        for s in samples {
+            out.extend_from_slice(&s.to_le_bytes());
+        }
+        out
+    }
+
+    fn setup() -> (tempfile::TempDir, PathBuf, filetime::FileTime) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.wav");
+        fs::write(&path, wav(&[100; 4410])).unwrap();
+        let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 0);
+        filetime::set_file_mtime(&path, mtime).unwrap();
+        (dir, path, mtime)
+    }
+
+    #[test]
+    fn staged_files_are_recognized() {
+        let staged = staging_path(Path::new("dir/track.flac"));
+        assert_eq!(staged, Path::new("dir/track.headroom-tmp.flac"));
+        assert!(is_staging_file(&staged));
+        assert!(!is_staging_file(Path::new("dir/track.flac")));
+    }
+
+    #[test]
+    fn replacement_keeps_timestamps_and_leaves_no_staged_file() {
+        let (_dir, path, mtime) = setup();
+
+        replace_with(&path, FrameMatch::Exact, |staged| {
+            fs::write(staged, wav(&[200; 4410]))?;
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), wav(&[200; 4410]));
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&meta),
+            mtime
+        );
+        assert!(!staging_path(&path).exists());
+    }
+
+    #[test]
+    fn length_change_is_rejected_and_original_kept() {
+        let (_dir, path, _) = setup();
+
+        let result = modify_copy(&path, FrameMatch::Within(100), |copy| {
+            fs::write(copy, wav(&[200; 4000]))?;
+            Ok(())
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read(&path).unwrap(), wav(&[100; 4410]));
+        assert!(!staging_path(&path).exists());
+    }
+}
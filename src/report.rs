@@ -1,29 +1,260 @@
 use anyhow::{Context, Result};
 use chrono::Local;
+use clap::ValueEnum;
 use console::Style;
-use std::path::Path;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 
-use crate::analyzer::{AudioAnalysis, GainMethod, TpTargetMode, GAIN_STEP};
+use crate::analyzer::{
+    AnalysisFailure, AudioAnalysis, GainMethod, GainTarget, Problem, TpTargetMode, GAIN_STEP,
+};
+use crate::journal::{JournalAction, JournalEntry};
+use crate::loudness::ChannelPeak;
+use crate::metadata::MetadataDiff;
+use crate::processor::ReencodePasses;
+use crate::verify::Verification;
 
-pub fn generate_csv(
-    analyses: &[&AudioAnalysis],
-    output_dir: &Path,
-    explicit_path: Option<&Path>,
-) -> Result<std::path::PathBuf> {
-    let output_path = if let Some(p) = explicit_path {
+/// Version of the JSON / NDJSON report schema. Bump on any change that is
+/// not a pure addition of fields.
+pub const REPORT_SCHEMA_VERSION: u32 = 2;
+
+/// Report file format (`--format`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ReportFormat {
+    /// One row per scanned file, with a `Status` column.
+    #[default]
+    Csv,
+    /// One JSON document with run metadata and a `files` array.
+    Json,
+    /// Newline-delimited JSON: a `run` header line, then one `file` line each.
+    Ndjson,
+}
+
+impl ReportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ReportFormat::Csv => "csv",
+            ReportFormat::Json => "json",
+            ReportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// Explicit report path (creating its parent), or a timestamped file in
+/// `output_dir`.
+fn report_path(output_dir: &Path, explicit_path: Option<&Path>, ext: &str) -> Result<PathBuf> {
+    if let Some(p) = explicit_path {
         if let Some(parent) = p.parent() {
             if !parent.as_os_str().is_empty() {
                 std::fs::create_dir_all(parent).context("Failed to create report directory")?;
             }
         }
-        p.to_path_buf()
+        Ok(p.to_path_buf())
     } else {
         let timestamp = Local::now().format("%Y%m%d_%H%M%S");
-        let filename = format!("headroom_report_{}.csv", timestamp);
-        output_dir.join(&filename)
-    };
+        let filename = format!("headroom_report_{}.{}", timestamp, ext);
+        Ok(output_dir.join(&filename))
+    }
+}
+
+/// Outcome of one scanned file over the whole run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileStatus {
+    /// Has headroom; the run did not try to modify files.
+    Processable,
+    /// At or above the ceiling (or loudness target); nothing to do.
+    NoHeadroom,
+    /// Analysis failed.
+    Failed,
+    /// Has headroom but was excluded by flags or prompts (e.g. re-encode off).
+    SkippedByFlag,
+    Processed,
+    ProcessingError,
+}
+
+impl FileStatus {
+    pub fn label(self) -> &'static str {
+        match self {
+            FileStatus::Processable => "processable",
+            FileStatus::NoHeadroom => "no-headroom",
+            FileStatus::Failed => "failed",
+            FileStatus::SkippedByFlag => "skipped-by-flag",
+            FileStatus::Processed => "processed",
+            FileStatus::ProcessingError => "processing-error",
+        }
+    }
+}
+
+/// Result of modifying one file, taken from its journal entry.
+#[derive(Serialize)]
+struct ProcessingRecord<'a> {
+    action: JournalAction,
+    ok: bool,
+    error: Option<&'a str>,
+    /// Gain applied; differs from the analysis after overshoot compensation.
+    gain_db: f64,
+    backup: Option<&'a Path>,
+    checksum_before: Option<&'a str>,
+    checksum_after: Option<&'a str>,
+    reencode: Option<ReencodePasses>,
+    reencode_settings: Option<&'a str>,
+    metadata: Option<&'a MetadataDiff>,
+}
+
+impl<'a> From<&'a JournalEntry> for ProcessingRecord<'a> {
+    fn from(entry: &'a JournalEntry) -> Self {
+        Self {
+            action: entry.action,
+            ok: entry.error.is_none(),
+            error: entry.error.as_deref(),
+            gain_db: entry.gain_db,
+            backup: entry.backup.as_deref(),
+            checksum_before: entry.checksum_before.as_deref(),
+            checksum_after: entry.checksum_after.as_deref(),
+            reencode: entry.reencode,
+            reencode_settings: entry.reencode_settings.as_deref(),
+            metadata: entry.metadata.as_ref(),
+        }
+    }
+}
 
-    let mut writer = csv::Writer::from_path(&output_path).context("Failed to create CSV file")?;
+/// One scanned file: its analysis or the analysis error, and what
+/// processing did to it (`null` when it was not touched).
+#[derive(Serialize)]
+struct FileRecord<'a> {
+    path: &'a Path,
+    status: FileStatus,
+    analysis: Option<&'a AudioAnalysis>,
+    error: Option<&'a str>,
+    processing: Option<ProcessingRecord<'a>>,
+    /// `--verify` re-measurement; `null` when not verified.
+    verification: Option<&'a Verification>,
+}
+
+impl FileRecord<'_> {
+    /// Whether the file failed or was flagged with a problem.
+    fn is_problem(&self) -> bool {
+        matches!(self.status, FileStatus::Failed | FileStatus::ProcessingError)
+            || self.analysis.is_some_and(|a| !a.problems.is_empty())
+    }
+
+    /// Analysis or processing error, whichever applies.
+    fn error_message(&self) -> Option<&str> {
+        self.error
+            .or_else(|| self.processing.as_ref().and_then(|p| p.error))
+    }
+}
+
+#[derive(Serialize)]
+struct RunRecord {
+    schema_version: u32,
+    headroom_version: &'static str,
+    generated: String,
+    target: GainTarget,
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    #[serde(flatten)]
+    run: RunRecord,
+    files: Vec<FileRecord<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum NdjsonLine<'a> {
+    Run(RunRecord),
+    File(FileRecord<'a>),
+}
+
+/// Everything a report covers, in scan order.
+pub struct RunResults<'a> {
+    pub files: &'a [PathBuf],
+    pub analyses: &'a [AudioAnalysis],
+    pub failures: &'a [AnalysisFailure],
+    pub entries: &'a [JournalEntry],
+    pub verifications: &'a [Verification],
+    pub target: GainTarget,
+    /// Whether the run went on to modify files. Processable files without a
+    /// journal entry are then reported as skipped rather than processable.
+    pub modify_requested: bool,
+    /// Leave out files that were analyzed and processed without a problem
+    /// (`--problems-only`).
+    pub problems_only: bool,
+}
+
+impl<'a> RunResults<'a> {
+    fn records(&self) -> Vec<FileRecord<'a>> {
+        let analyses: HashMap<&Path, &AudioAnalysis> =
+            self.analyses.iter().map(|a| (a.path.as_path(), a)).collect();
+        let failures: HashMap<&Path, &str> = self
+            .failures
+            .iter()
+            .map(|f| (f.path.as_path(), f.error.as_str()))
+            .collect();
+        // Journal paths are absolute.
+        let entries: HashMap<&Path, &JournalEntry> =
+            self.entries.iter().map(|e| (e.path.as_path(), e)).collect();
+        let verifications: HashMap<&Path, &Verification> = self
+            .verifications
+            .iter()
+            .map(|v| (v.path.as_path(), v))
+            .collect();
+
+        self.files
+            .iter()
+            .map(|path| {
+                let abs = std::path::absolute(path).unwrap_or_else(|_| path.clone());
+                let analysis = analyses.get(path.as_path()).copied();
+                let error = failures.get(path.as_path()).copied();
+                let entry = entries.get(abs.as_path()).copied();
+
+                let status = match (analysis, entry) {
+                    (_, Some(e)) if e.error.is_some() => FileStatus::ProcessingError,
+                    (_, Some(_)) => FileStatus::Processed,
+                    (None, None) => FileStatus::Failed,
+                    (Some(a), None) if !a.has_headroom() => FileStatus::NoHeadroom,
+                    (Some(_), None) if self.modify_requested => FileStatus::SkippedByFlag,
+                    (Some(_), None) => FileStatus::Processable,
+                };
+
+                FileRecord {
+                    path,
+                    status,
+                    analysis,
+                    error,
+                    processing: entry.map(Into::into),
+                    verification: verifications.get(abs.as_path()).copied(),
+                }
+            })
+            .filter(|record| !self.problems_only || record.is_problem())
+            .collect()
+    }
+}
+
+/// Write a report covering every scanned file in `format`.
+pub fn generate_report(
+    results: &RunResults,
+    format: ReportFormat,
+    output_dir: &Path,
+    explicit_path: Option<&Path>,
+) -> Result<PathBuf> {
+    let output_path = report_path(output_dir, explicit_path, format.extension())?;
+    let records = results.records();
+
+    match format {
+        ReportFormat::Csv => write_csv(&records, &output_path)?,
+        _ => write_json(results, records, format, &output_path)?,
+    }
+    Ok(output_path)
+}
+
+fn write_csv(records: &[FileRecord], output_path: &Path) -> Result<()> {
+    let mut writer = csv::Writer::from_path(output_path).context("Failed to create CSV file")?;
 
     writer
         .write_record([
@@ -36,14 +267,136 @@ pub fn generate_csv(
             "Headroom (dB)",
             "Method",
             "Effective Gain (dB)",
+            "Target (LUFS)",
+            "Limited By",
+            "Album",
+            "Status",
+            "Error",
+            "Verify",
+            "Verified TP (dBTP)",
+            "Re-encode Passes",
+            "Re-encode TP (dBTP)",
+            "Lost Headroom (dB)",
+            "Source Encoder",
+            "Re-encode Settings",
+            "Metadata",
+            "Sample Format",
+            "Channel TP (dBTP)",
+            "Channel Sample Peak (dBFS)",
+            "Channel Imbalance (LU)",
+            "Imbalanced",
+            "LRA (LU)",
+            "Max Short-term (LUFS)",
+            "Max Momentary (LUFS)",
+            "Sample Peak (dBFS)",
+            "PLR (dB)",
+            "DC Offset (%)",
+            "Audio Stream",
+            "Audio Streams",
+            "Clip Events",
+            "Clipped Samples",
+            "Inter-sample Overs",
+            "Worst Clips",
+            "Leading Silence (s)",
+            "Trailing Silence (s)",
+            "Dropouts",
+            "Final Level (dBFS)",
+            "Problems",
         ])
         .context("Failed to write CSV header")?;
 
-    for analysis in analyses {
+    for record in records {
+        let status = record.status.label();
+        let error = record.error_message().unwrap_or("");
+        let verify = record.verification.map_or("-", |v| v.outcome.label());
+        let verified_tp = record
+            .verification
+            .and_then(|v| v.measured_tp)
+            .map(|tp| format!("{:.1}", tp))
+            .unwrap_or_else(|| "-".to_string());
+        let reencode = record.processing.as_ref().and_then(|p| p.reencode);
+        let (passes, reencode_tp) = match reencode {
+            Some(r) => (r.passes.to_string(), format!("{:.1}", r.final_tp)),
+            None => ("-".to_string(), "-".to_string()),
+        };
+        let reencode_settings = record
+            .processing
+            .as_ref()
+            .and_then(|p| p.reencode_settings)
+            .unwrap_or("-");
+        let metadata = record
+            .processing
+            .as_ref()
+            .and_then(|p| p.metadata)
+            .map(|m| m.summary())
+            .unwrap_or_else(|| "-".to_string());
+
+        let Some(analysis) = record.analysis else {
+            let filename = record
+                .path
+                .file_name()
+                .map(|n| n.to_string_lossy())
+                .unwrap_or_else(|| record.path.to_string_lossy());
+            let mut row = vec!["-"; 11];
+            row.insert(0, &filename);
+            row.extend([status, error]);
+            row.extend(["-"; 30]);
+            writer.write_record(&row).context("Failed to write CSV record")?;
+            continue;
+        };
+
         let bitrate = analysis
             .bitrate_kbps
             .map(|b| b.to_string())
             .unwrap_or_else(|| "-".to_string());
+        let target_lufs = analysis
+            .target_lufs
+            .map(|l| format!("{:.1}", l))
+            .unwrap_or_else(|| "-".to_string());
+        let lost = if analysis.lossless_gain_steps != 0 {
+            format!("{:.2}", analysis.lost_headroom)
+        } else {
+            "-".to_string()
+        };
+        let sample_format = analysis
+            .sample_format
+            .map(|f| f.label())
+            .unwrap_or_else(|| "-".to_string());
+        let source_encoder = analysis
+            .encoder
+            .as_ref()
+            .map(|e| e.describe())
+            .unwrap_or_else(|| "-".to_string());
+        let imbalanced = match analysis.channel_imbalance {
+            Some(_) if analysis.imbalanced => "yes",
+            Some(_) => "no",
+            None => "-",
+        };
+        let clipping = &analysis.clipping;
+        let worst_clips = if clipping.events.is_empty() {
+            "-".to_string()
+        } else {
+            let events: Vec<String> = clipping.events.iter().map(|e| e.describe()).collect();
+            events.join(" / ")
+        };
+        let silence = &analysis.silence;
+        let dropouts = if silence.dropouts.is_empty() {
+            "-".to_string()
+        } else {
+            let dropouts: Vec<String> = silence.dropouts.iter().map(|d| d.describe()).collect();
+            dropouts.join(" / ")
+        };
+        let problems = if analysis.problems.is_empty() {
+            "-".to_string()
+        } else {
+            let problems: Vec<&str> = analysis.problems.iter().map(|p| p.label()).collect();
+            problems.join("; ")
+        };
+        let optional = |value: Option<f64>| {
+            value
+                .map(|v| format!("{:.1}", v))
+                .unwrap_or_else(|| "-".to_string())
+        };
 
         writer
             .write_record([
@@ -54,22 +407,100 @@ pub fn generate_csv(
                 &format!("{:.1}", analysis.input_tp),
                 &format!("{:.1}", analysis.target_tp),
                 &format!("{:+.1}", analysis.headroom),
-                analysis.gain_method.method_label(),
-                &format!("{:+.1}", analysis.effective_gain),
+                analysis.method_label(),
+                &format!("{:+.1}", reencode.map_or(analysis.effective_gain, |r| r.gain_db)),
+                &target_lufs,
+                analysis.limited_by.label(),
+                analysis.album.as_deref().unwrap_or("-"),
+                status,
+                error,
+                verify,
+                &verified_tp,
+                &passes,
+                &reencode_tp,
+                &lost,
+                &source_encoder,
+                reencode_settings,
+                &metadata,
+                &sample_format,
+                &channel_list(analysis, |c| c.true_peak),
+                &channel_list(analysis, |c| c.sample_peak),
+                &optional(analysis.channel_imbalance),
+                imbalanced,
+                &optional(analysis.lra),
+                &optional(analysis.max_short_term),
+                &optional(analysis.max_momentary),
+                &format!("{:.1}", analysis.sample_peak),
+                &format!("{:.1}", analysis.plr),
+                &format!("{:.3}", analysis.dc_offset * 100.0),
+                &analysis.stream.to_string(),
+                &analysis.stream_count.to_string(),
+                &clipping.clip_events.to_string(),
+                &clipping.clipped_samples.to_string(),
+                &clipping.inter_sample_overs.to_string(),
+                &worst_clips,
+                &format!("{:.2}", silence.leading_secs),
+                &format!("{:.2}", silence.trailing_secs),
+                &dropouts,
+                &format!("{:.1}", silence.final_level),
+                &problems,
             ])
             .context("Failed to write CSV record")?;
     }
 
-    writer.flush().context("Failed to flush CSV")?;
+    writer.flush().context("Failed to flush CSV")
+}
 
-    Ok(output_path)
+/// Per-channel values as "L -1.2 / R -3.4"; "-" when not measured.
+fn channel_list(analysis: &AudioAnalysis, value: impl Fn(&ChannelPeak) -> f64) -> String {
+    if analysis.channels.is_empty() {
+        return "-".to_string();
+    }
+    analysis
+        .channels
+        .iter()
+        .map(|c| format!("{} {:.1}", c.channel, value(c)))
+        .collect::<Vec<_>>()
+        .join(" / ")
 }
 
-pub fn print_analysis_report(analyses: &[AudioAnalysis], tp_mode: TpTargetMode) {
+fn write_json(
+    results: &RunResults,
+    files: Vec<FileRecord>,
+    format: ReportFormat,
+    output_path: &Path,
+) -> Result<()> {
+    let file = File::create(output_path).context("Failed to create report file")?;
+    let mut writer = BufWriter::new(file);
+
+    let run = RunRecord {
+        schema_version: REPORT_SCHEMA_VERSION,
+        headroom_version: env!("CARGO_PKG_VERSION"),
+        generated: Local::now().to_rfc3339(),
+        target: results.target,
+    };
+
+    if format == ReportFormat::Ndjson {
+        serde_json::to_writer(&mut writer, &NdjsonLine::Run(run))?;
+        writer.write_all(b"\n")?;
+        for record in files {
+            serde_json::to_writer(&mut writer, &NdjsonLine::File(record))?;
+            writer.write_all(b"\n")?;
+        }
+    } else {
+        serde_json::to_writer_pretty(&mut writer, &JsonReport { run, files })?;
+        writer.write_all(b"\n")?;
+    }
+
+    writer.flush().context("Failed to write report")
+}
+
+pub fn print_analysis_report(analyses: &[AudioAnalysis], target: GainTarget) {
     let header_style = Style::new().bold().cyan();
     let lossless_style = Style::new().green();
     let mp3_lossless_style = Style::new().yellow();
     let reencode_style = Style::new().magenta();
+    let attenuate_style = Style::new().red();
     let dim_style = Style::new().dim();
 
     // Calculate column width (use character count, not byte count)
@@ -83,19 +514,26 @@ pub fn print_analysis_report(analyses: &[AudioAnalysis], tp_mode: TpTargetMode)
 
     println!();
 
-    let mp3_label = native_lossless_label("MP3", tp_mode);
-    let aac_label = native_lossless_label("AAC/M4A", tp_mode);
+    let mp3_label = native_lossless_label("MP3", target.tp_mode);
+    let aac_label = native_lossless_label("AAC/M4A", target.tp_mode);
+    let show_limit = target.target_lufs.is_some();
     let sections: &[(GainMethod, &str, &Style)] = &[
-        (GainMethod::FfmpegLossless, "lossless files (ffmpeg, precise gain)", &lossless_style),
+        (GainMethod::Lossless, "lossless files (precise gain)", &lossless_style),
         (GainMethod::Mp3Lossless, mp3_label.as_str(), &mp3_lossless_style),
         (GainMethod::AacLossless, aac_label.as_str(), &mp3_lossless_style),
         (GainMethod::Mp3Reencode, "MP3 files (re-encode required for precise gain)", &reencode_style),
         (GainMethod::AacReencode, "AAC/M4A files (re-encode required)", &reencode_style),
+        (GainMethod::OpusHeader, "Opus files (header output gain, lossless)", &lossless_style),
+        (GainMethod::VorbisReencode, "Ogg Vorbis files (re-encode required)", &reencode_style),
+        (GainMethod::TagOnly, "APE/DSD files (no encoder; tags only with --tag-only)", &dim_style),
     ];
 
     let mut total = 0;
     for (method, label, accent_style) in sections {
-        let files: Vec<_> = analyses.iter().filter(|a| a.gain_method == *method).collect();
+        let files: Vec<_> = analyses
+            .iter()
+            .filter(|a| a.gain_method == *method && !a.is_attenuation())
+            .collect();
         if !files.is_empty() {
             total += files.len();
             println!(
@@ -104,11 +542,28 @@ pub fn print_analysis_report(analyses: &[AudioAnalysis], tp_mode: TpTargetMode)
                 header_style.apply_to(format!("{}", files.len())),
                 label,
             );
-            print_file_table(&files, filename_width, accent_style);
+            let show_lost = matches!(method, GainMethod::Mp3Lossless | GainMethod::AacLossless);
+            print_file_table(&files, filename_width, accent_style, show_limit, show_lost);
             println!();
         }
     }
 
+    // Over-ceiling files get their own section regardless of method, so
+    // attenuations are never mistaken for gain increases.
+    let attenuated: Vec<_> = analyses.iter().filter(|a| a.is_attenuation()).collect();
+    if !attenuated.is_empty() {
+        total += attenuated.len();
+        println!(
+            "{} {} files above the ceiling (attenuated to target)",
+            attenuate_style.apply_to("●"),
+            header_style.apply_to(format!("{}", attenuated.len())),
+        );
+        print_file_table(&attenuated, filename_width, &attenuate_style, show_limit, false);
+        println!();
+    }
+
+    print_clipping_section(analyses);
+
     if total == 0 {
         println!(
             "{} No files with available headroom found.",
@@ -117,6 +572,178 @@ pub fn print_analysis_report(analyses: &[AudioAnalysis], tp_mode: TpTargetMode)
     }
 }
 
+/// The clipping section on its own, for runs where no file has headroom
+/// and `print_analysis_report` is skipped.
+pub fn print_clipping_report(analyses: &[AudioAnalysis]) {
+    if analyses.iter().any(|a| a.clipping.is_clipped()) {
+        println!();
+        print_clipping_section(analyses);
+    }
+}
+
+/// List files with full-scale clips or inter-sample overs, with the worst
+/// event of each. Prints nothing when there are none.
+fn print_clipping_section(analyses: &[AudioAnalysis]) {
+    let clipped: Vec<_> = analyses.iter().filter(|a| a.clipping.is_clipped()).collect();
+    if clipped.is_empty() {
+        return;
+    }
+    let dim_style = Style::new().dim();
+    let filename_width = clipped
+        .iter()
+        .map(|a| a.filename.chars().count())
+        .max()
+        .unwrap_or(8)
+        .clamp(8, 40);
+
+    println!(
+        "{} {} files with clipping",
+        Style::new().red().apply_to("●"),
+        Style::new().bold().cyan().apply_to(clipped.len()),
+    );
+    let header = format!(
+        "{:<width$} {:>7} {:>9} {:>10}  {}",
+        "Filename",
+        "Clips",
+        "Clipped",
+        "ISP Overs",
+        "Worst",
+        width = filename_width,
+    );
+    println!("  {}", dim_style.apply_to(header));
+    for analysis in clipped {
+        let clipping = &analysis.clipping;
+        let worst = clipping.events.first().map(|e| e.describe()).unwrap_or_default();
+        println!(
+            "  {:<width$} {:>7} {:>9} {:>10}  {}",
+            truncate_name(&analysis.filename, filename_width),
+            clipping.clip_events,
+            clipping.clipped_samples,
+            clipping.inter_sample_overs,
+            dim_style.apply_to(worst),
+            width = filename_width,
+        );
+    }
+    println!();
+}
+
+/// List files whose channels differ in loudness beyond the threshold, with
+/// the True Peak of each channel. Prints nothing when there are none.
+pub fn print_imbalance_report(analyses: &[AudioAnalysis]) {
+    let imbalanced: Vec<_> = analyses.iter().filter(|a| a.imbalanced).collect();
+    if imbalanced.is_empty() {
+        return;
+    }
+    let dim_style = Style::new().dim();
+    let filename_width = imbalanced
+        .iter()
+        .map(|a| a.filename.chars().count())
+        .max()
+        .unwrap_or(8)
+        .clamp(8, 40);
+
+    println!();
+    println!(
+        "{} {} files with unbalanced channels",
+        Style::new().yellow().apply_to("⚠"),
+        Style::new().bold().cyan().apply_to(imbalanced.len()),
+    );
+    let header = format!(
+        "{:<width$} {:>10}  {}",
+        "Filename",
+        "Imbalance",
+        "Channel True Peak (dBTP)",
+        width = filename_width,
+    );
+    println!("  {}", dim_style.apply_to(header));
+    for analysis in imbalanced {
+        println!(
+            "  {:<width$} {:>10}  {}",
+            truncate_name(&analysis.filename, filename_width),
+            format!("{:.1} LU", analysis.channel_imbalance.unwrap_or(0.0)),
+            dim_style.apply_to(channel_list(analysis, |c| c.true_peak)),
+            width = filename_width,
+        );
+    }
+}
+
+/// List files with long leading or trailing silence, dropouts or an abrupt
+/// ending. Prints nothing when there are none.
+pub fn print_silence_report(analyses: &[AudioAnalysis]) {
+    let silence_problems = [
+        Problem::LeadingSilence,
+        Problem::TrailingSilence,
+        Problem::Dropouts,
+        Problem::AbruptEnd,
+    ];
+    let flagged: Vec<_> = analyses
+        .iter()
+        .filter(|a| a.problems.iter().any(|p| silence_problems.contains(p)))
+        .collect();
+    if flagged.is_empty() {
+        return;
+    }
+    let dim_style = Style::new().dim();
+    let filename_width = flagged
+        .iter()
+        .map(|a| a.filename.chars().count())
+        .max()
+        .unwrap_or(8)
+        .clamp(8, 40);
+
+    println!();
+    println!(
+        "{} {} files with gaps, dropouts or abrupt endings",
+        Style::new().yellow().apply_to("⚠"),
+        Style::new().bold().cyan().apply_to(flagged.len()),
+    );
+    let header = format!(
+        "{:<width$} {:>8} {:>8} {:>8} {:>11}",
+        "Filename",
+        "Leading",
+        "Trailing",
+        "Dropouts",
+        "Final Level",
+        width = filename_width,
+    );
+    println!("  {}", dim_style.apply_to(header));
+    for analysis in flagged {
+        let silence = &analysis.silence;
+        let final_level = format!("{:.1} dBFS", silence.final_level);
+        let longest = silence
+            .dropouts
+            .iter()
+            .max_by(|a, b| a.duration_secs.total_cmp(&b.duration_secs))
+            .map(|d| format!("longest {}", d.describe()))
+            .unwrap_or_default();
+        println!(
+            "  {:<width$} {:>8} {:>8} {:>8} {:>11}  {}",
+            truncate_name(&analysis.filename, filename_width),
+            format!("{:.1} s", silence.leading_secs),
+            format!("{:.1} s", silence.trailing_secs),
+            silence.dropouts.len(),
+            if analysis.problems.contains(&Problem::AbruptEnd) {
+                Style::new().yellow().apply_to(final_level)
+            } else {
+                Style::new().apply_to(final_level)
+            },
+            dim_style.apply_to(longest),
+            width = filename_width,
+        );
+    }
+}
+
+/// `name` cut to `width` characters with an ellipsis.
+fn truncate_name(name: &str, width: usize) -> String {
+    // Use character count instead of byte count to handle multi-byte UTF-8 characters
+    if name.chars().count() > width {
+        let truncated: String = name.chars().take(width - 1).collect();
+        format!("{}…", truncated)
+    } else {
+        name.to_string()
+    }
+}
+
 fn native_lossless_label(format: &str, tp_mode: TpTargetMode) -> String {
     match tp_mode {
         TpTargetMode::Uniform(t) => format!(
@@ -135,44 +762,63 @@ fn native_lossless_label(format: &str, tp_mode: TpTargetMode) -> String {
     }
 }
 
-fn print_file_table(files: &[&AudioAnalysis], filename_width: usize, accent_style: &Style) {
+/// Print one section's rows. `show_limit` adds a column naming the binding
+/// constraint (TP or LUFS) when a loudness target is active; `show_lost`
+/// adds the gain native steps give up.
+fn print_file_table(
+    files: &[&AudioAnalysis],
+    filename_width: usize,
+    accent_style: &Style,
+    show_limit: bool,
+    show_lost: bool,
+) {
     let dim_style = Style::new().dim();
 
     // Pad before styling: fmt width counts ANSI escape bytes, so applying a
     // width specifier to an already-styled value breaks column alignment.
-    println!(
-        "  {}",
-        dim_style.apply_to(format!(
-            "{:<width$} {:>8} {:>12} {:>10} {:>12}",
-            "Filename",
-            "LUFS",
-            "True Peak",
-            "Target",
-            "Gain",
-            width = filename_width,
-        ))
+    let mut header = format!(
+        "{:<width$} {:>8} {:>12} {:>10} {:>12}",
+        "Filename",
+        "LUFS",
+        "True Peak",
+        "Target",
+        "Gain",
+        width = filename_width,
     );
+    if show_limit {
+        header.push_str(&format!(" {:>7}", "Limit"));
+    }
+    if show_lost {
+        header.push_str(&format!(" {:>10}", "Lost"));
+    }
+    println!("  {}", dim_style.apply_to(header));
 
     for analysis in files {
-        // Use character count instead of byte count to handle multi-byte UTF-8 characters
-        let char_count = analysis.filename.chars().count();
-        let display_name: String = if char_count > filename_width {
-            let truncated: String = analysis.filename.chars().take(filename_width - 1).collect();
-            format!("{}…", truncated)
-        } else {
-            analysis.filename.clone()
-        };
+        let display_name = truncate_name(&analysis.filename, filename_width);
 
         let gain_str = format!("{:>12}", format!("{:+.1} dB", analysis.effective_gain));
         let target_str = format!("{:>8.1}", analysis.target_tp);
 
+        let limit_str = if show_limit {
+            format!(" {:>7}", analysis.limited_by.label())
+        } else {
+            String::new()
+        };
+        let lost_str = if show_lost {
+            format!(" {:>10}", format!("{:.2} dB", analysis.lost_headroom))
+        } else {
+            String::new()
+        };
+
         println!(
-            "  {:<width$} {:>8.1} {:>10.1} dBTP {} dBTP {}",
+            "  {:<width$} {:>8.1} {:>10.1} dBTP {} dBTP {}{}{}",
             display_name,
             analysis.input_i,
             analysis.input_tp,
             dim_style.apply_to(target_str),
             accent_style.apply_to(gain_str),
+            dim_style.apply_to(limit_str),
+            dim_style.apply_to(lost_str),
             width = filename_width,
         );
     }
@@ -185,6 +831,12 @@ pub struct AnalysisSummary {
     pub aac_lossless_count: usize,
     pub mp3_reencode_count: usize,
     pub aac_reencode_count: usize,
+    pub opus_count: usize,
+    pub vorbis_reencode_count: usize,
+    /// APE/DSD files whose gain can only be written as tags.
+    pub tag_only_count: usize,
+    /// Files turned down to the ceiling; also counted under their method.
+    pub attenuation_count: usize,
 }
 
 impl AnalysisSummary {
@@ -199,14 +851,24 @@ impl AnalysisSummary {
             aac_lossless_count: 0,
             mp3_reencode_count: 0,
             aac_reencode_count: 0,
+            opus_count: 0,
+            vorbis_reencode_count: 0,
+            tag_only_count: 0,
+            attenuation_count: 0,
         };
         for a in analyses {
+            if a.is_attenuation() {
+                summary.attenuation_count += 1;
+            }
             match a.gain_method {
-                GainMethod::FfmpegLossless => summary.lossless_count += 1,
+                GainMethod::Lossless => summary.lossless_count += 1,
                 GainMethod::Mp3Lossless => summary.mp3_lossless_count += 1,
                 GainMethod::AacLossless => summary.aac_lossless_count += 1,
                 GainMethod::Mp3Reencode => summary.mp3_reencode_count += 1,
                 GainMethod::AacReencode => summary.aac_reencode_count += 1,
+                GainMethod::OpusHeader => summary.opus_count += 1,
+                GainMethod::VorbisReencode => summary.vorbis_reencode_count += 1,
+                GainMethod::TagOnly => summary.tag_only_count += 1,
                 GainMethod::None => {}
             }
         }
@@ -214,14 +876,144 @@ impl AnalysisSummary {
     }
 
     pub fn total_lossless(&self) -> usize {
-        self.lossless_count + self.mp3_lossless_count + self.aac_lossless_count
+        self.lossless_count + self.mp3_lossless_count + self.aac_lossless_count + self.opus_count
     }
 
     pub fn total_reencode(&self) -> usize {
-        self.mp3_reencode_count + self.aac_reencode_count
+        self.mp3_reencode_count + self.aac_reencode_count + self.vorbis_reencode_count
     }
 
+    /// Tag-only files count: `--tag-only` can still act on them.
     pub fn has_processable(&self) -> bool {
-        self.total_lossless() + self.total_reencode() > 0
+        self.total_lossless() + self.total_reencode() + self.tag_only_count > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::{self, Measurement};
+    use crate::clipping::Clipping;
+    use crate::scanner::AudioFormat;
+    use crate::silence::Silence;
+
+    fn fixture() -> (Vec<PathBuf>, Vec<AudioAnalysis>, Vec<AnalysisFailure>) {
+        let files = vec![
+            PathBuf::from("a.flac"),
+            PathBuf::from("b.flac"),
+            PathBuf::from("c.flac"),
+        ];
+        let measure = |input_tp| Measurement {
+            input_i: -14.0,
+            input_tp,
+            bitrate_kbps: None,
+            duration_secs: 60.0,
+            channels: Vec::new(),
+            channel_imbalance: None,
+            lra: Some(6.0),
+            max_short_term: Some(-8.0),
+            max_momentary: Some(-6.5),
+            sample_peak: -1.5,
+            dc_offset: 0.0,
+            stream: 0,
+            clipping: Clipping::default(),
+            silence: Silence::default(),
+            format: Some(AudioFormat::Flac),
+            streams: Vec::new(),
+            encoder: None,
+            sample_format: None,
+        };
+        let analyses = vec![
+            analyzer::analyze_measurement(&files[0], &measure(-6.0), GainTarget::default()),
+            analyzer::analyze_measurement(&files[2], &measure(-0.2), GainTarget::default()),
+        ];
+        let failures = vec![AnalysisFailure {
+            path: files[1].clone(),
+            error: "decode error".into(),
+        }];
+        (files, analyses, failures)
+    }
+
+    #[test]
+    fn problems_only_keeps_failed_and_flagged_files() {
+        let (files, mut analyses, failures) = fixture();
+        analyses[1].problems = vec![Problem::AbruptEnd];
+        let results = RunResults {
+            files: &files,
+            analyses: &analyses,
+            failures: &failures,
+            entries: &[],
+            verifications: &[],
+            target: GainTarget::default(),
+            modify_requested: false,
+            problems_only: true,
+        };
+
+        let paths: Vec<&Path> = results.records().iter().map(|r| r.path).collect();
+        assert_eq!(paths, [Path::new("b.flac"), Path::new("c.flac")]);
+    }
+
+    #[test]
+    fn ndjson_covers_every_scanned_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (files, analyses, failures) = fixture();
+        let results = RunResults {
+            files: &files,
+            analyses: &analyses,
+            failures: &failures,
+            entries: &[],
+            verifications: &[],
+            target: GainTarget::default(),
+            modify_requested: false,
+            problems_only: false,
+        };
+
+        let path = generate_report(&results, ReportFormat::Ndjson, dir.path(), None).unwrap();
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["type"], "run");
+        assert_eq!(lines[0]["schema_version"], REPORT_SCHEMA_VERSION);
+        assert_eq!(lines[1]["analysis"]["gain_method"], "lossless");
+        assert_eq!(lines[1]["status"], "processable");
+        assert!(lines[1]["processing"].is_null());
+        assert_eq!(lines[2]["error"], "decode error");
+        assert_eq!(lines[2]["status"], "failed");
+        assert!(lines[2]["analysis"].is_null());
+        assert_eq!(lines[3]["status"], "no-headroom");
+    }
+
+    #[test]
+    fn csv_lists_skipped_and_failed_files_with_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let (files, analyses, failures) = fixture();
+        let results = RunResults {
+            files: &files,
+            analyses: &analyses,
+            failures: &failures,
+            entries: &[],
+            verifications: &[],
+            target: GainTarget::default(),
+            modify_requested: true,
+            problems_only: false,
+        };
+
+        let path = generate_report(&results, ReportFormat::Csv, dir.path(), None).unwrap();
+        let mut reader = csv::Reader::from_path(path).unwrap();
+        let headers = reader.headers().unwrap().clone();
+        let column = |name| headers.iter().position(|h| h == name).unwrap();
+        let (plr, clips) = (column("PLR (dB)"), column("Clip Events"));
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!((&rows[0][plr], &rows[1][plr]), ("8.0", "-"));
+        assert_eq!((&rows[0][clips], &rows[1][clips]), ("0", "-"));
+        assert_eq!(&rows[0][12], "skipped-by-flag");
+        assert_eq!((&rows[1][0], &rows[1][12], &rows[1][13]), ("b.flac", "failed", "decode error"));
+        assert_eq!(&rows[2][12], "no-headroom");
     }
 }
@@ -0,0 +1,298 @@
+//! `headroom restore`: undo a processing run from its journal.
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Local;
+use console::style;
+use std::path::PathBuf;
+
+use crate::analyzer::GainMethod;
+use crate::args::RestoreArgs;
+use crate::cache::{self, AnalysisCache};
+use crate::journal::{self, Journal, JournalEntry};
+use crate::replace::{self, FrameMatch};
+
+pub fn run(args: &RestoreArgs) -> Result<()> {
+    let journal_path = match &args.journal {
+        Some(p) if p.is_file() => p.clone(),
+        Some(dir) => journal::latest(dir)?,
+        None => {
+            journal::latest(&std::env::current_dir().context("Failed to get current directory")?)?
+        }
+    };
+    let mut journal = Journal::load(&journal_path)?;
+
+    println!(
+        "{} Journal: {} ({} files, {})",
+        style("▸").cyan(),
+        style(journal_path.display()).bold(),
+        journal.entries.len(),
+        journal.created
+    );
+
+    let selected = select_entries(&journal, &args.files)?;
+
+    if args.verify {
+        return verify(&journal, &selected);
+    }
+
+    let mut restored = 0;
+    let mut failed = 0;
+    for &i in &selected {
+        let entry = &journal.entries[i];
+        if entry.restored_at.is_some() {
+            println!(
+                "  {} {}: already restored",
+                style("•").dim(),
+                entry.path.display()
+            );
+            continue;
+        }
+        if entry.error.is_some() && entry.checksum_after == entry.checksum_before {
+            // Processing failed before touching the file.
+            continue;
+        }
+
+        match restore_entry(entry, args.force) {
+            Ok(how) => {
+                restored += 1;
+                println!("{} {} ({})", style("✓").green(), entry.path.display(), how);
+                journal.entries[i].restored_at = Some(Local::now().to_rfc3339());
+            }
+            Err(e) => {
+                failed += 1;
+                println!("{} {}: {:#}", style("⚠").yellow(), entry.path.display(), e);
+            }
+        }
+    }
+
+    journal.save(&journal_path)?;
+    if restored > 0 {
+        forget_restored(&journal, &selected);
+    }
+
+    println!(
+        "\n{} Restored {} files{}",
+        style("✓").green().bold(),
+        restored,
+        if failed > 0 {
+            format!(", {} failed", failed)
+        } else {
+            String::new()
+        }
+    );
+    if failed > 0 {
+        bail!("{} files could not be restored", failed);
+    }
+    Ok(())
+}
+
+/// Restored files may keep size and mtime, so drop them from the analysis
+/// cache of the run's base directory, if there is one.
+fn forget_restored(journal: &Journal, selected: &[usize]) {
+    let Ok(mut cache) = AnalysisCache::load(&journal.base_dir) else {
+        return;
+    };
+    if !cache.path().is_file() {
+        return;
+    }
+    for &i in selected {
+        cache.forget(&journal.entries[i].path);
+    }
+    let _ = cache.save();
+}
+
+/// Indices of the entries to act on: all, or those matching `files`.
+fn select_entries(journal: &Journal, files: &[PathBuf]) -> Result<Vec<usize>> {
+    if files.is_empty() {
+        return Ok((0..journal.entries.len()).collect());
+    }
+
+    let mut selected = Vec::new();
+    for file in files {
+        let abs = std::path::absolute(file).unwrap_or_else(|_| file.clone());
+        match journal.entries.iter().position(|e| e.path == abs) {
+            Some(i) => selected.push(i),
+            None => bail!("{} is not part of this journal", file.display()),
+        }
+    }
+    Ok(selected)
+}
+
+/// Check each backup against the pre-processing checksum and report whether
+/// the file has changed since the run. Modifies nothing.
+fn verify(journal: &Journal, selected: &[usize]) -> Result<()> {
+    let mut bad_backups = 0;
+    for &i in selected {
+        let entry = &journal.entries[i];
+        let backup = match &entry.backup {
+            None => "no backup".to_string(),
+            Some(b) => match cache::hash_file(b) {
+                Ok(h) if Some(&h) == entry.checksum_before.as_ref() => "backup OK".to_string(),
+                Ok(_) => {
+                    bad_backups += 1;
+                    "backup MODIFIED".to_string()
+                }
+                Err(_) => {
+                    bad_backups += 1;
+                    "backup MISSING".to_string()
+                }
+            },
+        };
+        let current = match cache::hash_file(&entry.path) {
+            Ok(h) if Some(&h) == entry.checksum_after.as_ref() => "unchanged since run",
+            Ok(h) if Some(&h) == entry.checksum_before.as_ref() => "original content",
+            Ok(_) => "modified since run",
+            Err(_) => "missing",
+        };
+        let icon = if backup.starts_with("backup M") {
+            style("⚠").yellow()
+        } else {
+            style("✓").green()
+        };
+        println!(
+            "{} {}: {}, file {}",
+            icon,
+            entry.path.display(),
+            backup,
+            current
+        );
+    }
+
+    if bad_backups > 0 {
+        bail!("{} backups failed verification", bad_backups);
+    }
+    println!("\n{} All backups verified", style("✓").green().bold());
+    Ok(())
+}
+
+/// Undo one entry. Prefers a verified backup; native MP3/AAC gain is
+/// inverted in place when no usable backup exists. Returns how the file was
+/// restored.
+pub fn restore_entry(entry: &JournalEntry, force: bool) -> Result<&'static str> {
+    let current = cache::hash_file(&entry.path).ok();
+    if current.is_some() && current == entry.checksum_before {
+        return Ok("already original");
+    }
+    if !force && current.is_some() && current != entry.checksum_after {
+        bail!("modified since the run; use --force to restore anyway");
+    }
+
+    if let Some(backup) = &entry.backup {
+        match cache::hash_file(backup) {
+            Ok(h) if Some(&h) == entry.checksum_before.as_ref() => {
+                replace::copy_from(&entry.path, backup)?;
+                return Ok("from backup");
+            }
+            Ok(_) if !entry.is_native_invertible() => {
+                bail!("backup {} does not match the original", backup.display())
+            }
+            Err(_) if !entry.is_native_invertible() => {
+                bail!("backup {} is missing", backup.display())
+            }
+            _ => {}
+        }
+    }
+
+    if entry.is_native_invertible() {
+        if current.is_none() {
+            bail!("file is missing and no usable backup exists");
+        }
+        let steps = -entry.native_steps;
+        replace::modify_copy(&entry.path, FrameMatch::Exact, |copy| match entry.method {
+            GainMethod::AacLossless => mp3rgain::aac::apply_aac_gain(copy, steps)
+                .map(|_| ())
+                .context("mp3rgain failed to invert AAC gain"),
+            _ => mp3rgain::apply_gain(copy, steps)
+                .map(|_| ())
+                .context("mp3rgain failed to invert MP3 gain"),
+        })?;
+        let after = cache::hash_file(&entry.path).ok();
+        return Ok(if after.is_some() && after == entry.checksum_before {
+            "native gain inverted"
+        } else {
+            "native gain inverted; bytes differ from original"
+        });
+    }
+
+    Err(anyhow!("no backup recorded for this file"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::JournalAction;
+    use std::fs;
+
+    /// Mono 16-bit 44.1 kHz WAV.
+    fn wav(samples: &[i16]) -> Vec<u8> {
+        let data_len = (samples.len() * 2) as u32;
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&(36 + data_len).to_le_bytes());
+        out.extend_from_slice(b"WAVEfmt \x10\x00\x00\x00\x01\x00\x01\x00");
+        out.extend_from_slice(&44100u32.to_le_bytes());
+        out.extend_from_slice(&88200u32.to_le_bytes());
+        out.extend_from_slice(&[2, 0, 16, 0]);
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&data_len.to_le_bytes());
+        for s in samples {
+            out.extend_from_slice(&s.to_le_bytes());
+        }
+        out
+    }
+
+    fn setup() -> (tempfile::TempDir, JournalEntry) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.wav");
+        let backup = dir.path().join("a.backup.wav");
+        fs::write(&backup, wav(&[100; 4410])).unwrap();
+        fs::write(&path, wav(&[200; 4410])).unwrap();
+        let entry = JournalEntry {
+            path: path.clone(),
+            backup: Some(backup.clone()),
+            action: JournalAction::Gain,
+            method: GainMethod::Lossless,
+            gain_db: 1.2,
+            native_steps: 0,
+            checksum_before: Some(cache::hash_file(&backup).unwrap()),
+            checksum_after: Some(cache::hash_file(&path).unwrap()),
+            error: None,
+            restored_at: None,
+            reencode: None,
+            reencode_settings: None,
+            metadata: None,
+        };
+        (dir, entry)
+    }
+
+    #[test]
+    fn restores_from_verified_backup() {
+        let (_dir, entry) = setup();
+        assert_eq!(restore_entry(&entry, false).unwrap(), "from backup");
+        assert_eq!(fs::read(&entry.path).unwrap(), wav(&[100; 4410]));
+        assert!(!replace::staging_path(&entry.path).exists());
+    }
+
+    #[test]
+    fn restores_deleted_file_from_backup() {
+        let (_dir, entry) = setup();
+        fs::remove_file(&entry.path).unwrap();
+        assert_eq!(restore_entry(&entry, false).unwrap(), "from backup");
+        assert_eq!(fs::read(&entry.path).unwrap(), wav(&[100; 4410]));
+    }
+
+    #[test]
+    fn refuses_files_modified_since_the_run_without_force() {
+        let (_dir, entry) = setup();
+        fs::write(&entry.path, b"edited later").unwrap();
+        assert!(restore_entry(&entry, false).is_err());
+        assert!(restore_entry(&entry, true).is_ok());
+    }
+
+    #[test]
+    fn rejects_tampered_backup() {
+        let (_dir, entry) = setup();
+        fs::write(entry.backup.as_ref().unwrap(), b"tampered").unwrap();
+        assert!(restore_entry(&entry, false).is_err());
+        assert_eq!(fs::read(&entry.path).unwrap(), wav(&[200; 4410]));
+    }
+}
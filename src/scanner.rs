@@ -1,11 +1,20 @@
 use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 use walkdir::{DirEntry, WalkDir};
 
-const LOSSLESS_EXTENSIONS: &[&str] = &["flac", "aiff", "aif", "wav"];
+use crate::{encoder, ogg};
+
+const LOSSLESS_EXTENSIONS: &[&str] = &["flac", "aiff", "aif", "wav", "wv", "ape"];
 const MP3_EXTENSIONS: &[&str] = &["mp3"];
+/// MPEG-4 containers and raw ADTS; `.m4a` may also hold ALAC.
 const AAC_EXTENSIONS: &[&str] = &["m4a", "aac", "mp4"];
+const VORBIS_EXTENSIONS: &[&str] = &["ogg", "oga"];
+const OPUS_EXTENSIONS: &[&str] = &["opus"];
+const DSD_EXTENSIONS: &[&str] = &["dsf", "dff"];
 
 /// Marker file written into backup directories created by headroom.
 /// Directories containing it are skipped during recursive scans so backup
@@ -80,22 +89,25 @@ pub fn resolve_inputs(inputs: &[String]) -> Result<Vec<PathBuf>> {
 
 fn is_audio_candidate(path: &Path) -> bool {
     let filename = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
-    if filename.starts_with("._") {
+    if filename.starts_with("._") || crate::replace::is_staging_file(path) {
         return false;
     }
     is_supported_audio_file(path)
 }
 
 fn is_supported_audio_file(path: &Path) -> bool {
-    has_extension(path, LOSSLESS_EXTENSIONS)
-        || has_extension(path, MP3_EXTENSIONS)
-        || has_extension(path, AAC_EXTENSIONS)
+    get_supported_extensions()
+        .iter()
+        .any(|ext| has_extension(path, &[ext]))
 }
 
 pub fn get_supported_extensions() -> Vec<&'static str> {
     let mut exts: Vec<&str> = LOSSLESS_EXTENSIONS.to_vec();
     exts.extend(MP3_EXTENSIONS);
     exts.extend(AAC_EXTENSIONS);
+    exts.extend(VORBIS_EXTENSIONS);
+    exts.extend(OPUS_EXTENSIONS);
+    exts.extend(DSD_EXTENSIONS);
     exts
 }
 
@@ -106,10 +118,274 @@ fn has_extension(path: &Path, extensions: &[&str]) -> bool {
         .unwrap_or(false)
 }
 
-pub fn is_mp3(path: &Path) -> bool {
-    has_extension(path, MP3_EXTENSIONS)
+/// Audio format identified from file content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AudioFormat {
+    Flac,
+    Wav,
+    Aiff,
+    /// Apple Lossless in an MPEG-4 container.
+    Alac,
+    WavPack,
+    Ape,
+    Dsf,
+    Dff,
+    Mp3,
+    /// AAC in an MPEG-4 container.
+    Aac,
+    /// Raw ADTS AAC stream.
+    Adts,
+    Vorbis,
+    Opus,
+}
+
+impl AudioFormat {
+    pub fn label(self) -> &'static str {
+        match self {
+            AudioFormat::Flac => "FLAC",
+            AudioFormat::Wav => "WAV",
+            AudioFormat::Aiff => "AIFF",
+            AudioFormat::Alac => "ALAC",
+            AudioFormat::WavPack => "WavPack",
+            AudioFormat::Ape => "APE",
+            AudioFormat::Dsf => "DSF",
+            AudioFormat::Dff => "DSDIFF",
+            AudioFormat::Mp3 => "MP3",
+            AudioFormat::Aac | AudioFormat::Adts => "AAC",
+            AudioFormat::Vorbis => "Ogg Vorbis",
+            AudioFormat::Opus => "Opus",
+        }
+    }
+
+    /// Extensions a file of this format may legitimately carry.
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            AudioFormat::Flac => &["flac"],
+            AudioFormat::Wav => &["wav"],
+            AudioFormat::Aiff => &["aiff", "aif"],
+            AudioFormat::Alac | AudioFormat::Aac => &["m4a", "mp4"],
+            AudioFormat::WavPack => &["wv"],
+            AudioFormat::Ape => &["ape"],
+            AudioFormat::Dsf => &["dsf"],
+            AudioFormat::Dff => &["dff"],
+            AudioFormat::Mp3 => MP3_EXTENSIONS,
+            AudioFormat::Adts => &["aac"],
+            AudioFormat::Vorbis => VORBIS_EXTENSIONS,
+            AudioFormat::Opus => &["opus", "ogg", "oga"],
+        }
+    }
+
+    /// ffmpeg muxer that writes this format; `None` for formats headroom
+    /// never encodes.
+    pub fn muxer(self) -> Option<&'static str> {
+        match self {
+            AudioFormat::Flac => Some("flac"),
+            AudioFormat::Wav => Some("wav"),
+            AudioFormat::Aiff => Some("aiff"),
+            AudioFormat::Alac | AudioFormat::Aac => Some("ipod"),
+            AudioFormat::WavPack => Some("wv"),
+            AudioFormat::Mp3 => Some("mp3"),
+            AudioFormat::Adts => Some("adts"),
+            AudioFormat::Vorbis | AudioFormat::Opus => Some("ogg"),
+            AudioFormat::Ape | AudioFormat::Dsf | AudioFormat::Dff => None,
+        }
+    }
+
+    pub fn is_mp3(self) -> bool {
+        self == AudioFormat::Mp3
+    }
+
+    /// AAC in an MPEG-4 container or ADTS stream. ALAC in `.m4a` is not AAC.
+    pub fn is_aac(self) -> bool {
+        matches!(self, AudioFormat::Aac | AudioFormat::Adts)
+    }
+
+    pub fn is_vorbis(self) -> bool {
+        self == AudioFormat::Vorbis
+    }
+
+    pub fn is_opus(self) -> bool {
+        self == AudioFormat::Opus
+    }
+
+    /// Lossy formats: MP3, AAC, Ogg Vorbis and Opus.
+    pub fn is_lossy(self) -> bool {
+        self.is_mp3() || self.is_aac() || self.is_vorbis() || self.is_opus()
+    }
+
+    pub fn is_ape(self) -> bool {
+        self == AudioFormat::Ape
+    }
+
+    /// DSD Stream File (`.dsf`) or DSDIFF (`.dff`).
+    pub fn is_dsd(self) -> bool {
+        matches!(self, AudioFormat::Dsf | AudioFormat::Dff)
+    }
+
+    /// Format implied by the extension alone. `.m4a`/`.mp4` are taken as AAC.
+    fn from_extension(path: &Path) -> Option<Self> {
+        const BY_EXTENSION: &[AudioFormat] = &[
+            AudioFormat::Flac,
+            AudioFormat::Wav,
+            AudioFormat::Aiff,
+            AudioFormat::Aac,
+            AudioFormat::WavPack,
+            AudioFormat::Ape,
+            AudioFormat::Dsf,
+            AudioFormat::Dff,
+            AudioFormat::Mp3,
+            AudioFormat::Adts,
+            AudioFormat::Vorbis,
+            AudioFormat::Opus,
+        ];
+        BY_EXTENSION
+            .iter()
+            .copied()
+            .find(|f| has_extension(path, f.extensions()))
+    }
+}
+
+/// Bytes read from the start of a file for sniffing (after any ID3v2 tag).
+const SNIFF_LEN: usize = 64;
+
+/// Identify the format of `path` from its content: magic bytes, the first
+/// Ogg packet or the MPEG-4 sample entry. `None` when the file cannot be
+/// read or is not recognized.
+pub fn sniff(path: &Path) -> Option<AudioFormat> {
+    let mut file = File::open(path).ok()?;
+    let mut head = [0u8; 10];
+    let n = file.read(&mut head).ok()?;
+    // MP3, and occasionally FLAC, APE or ADTS, may start with an ID3v2 tag.
+    let tagged = n == 10 && &head[..3] == b"ID3";
+    let skip = if tagged {
+        encoder::id3v2_len(&head) as u64
+    } else {
+        0
+    };
+    file.seek(SeekFrom::Start(skip)).ok()?;
+    let mut data = Vec::with_capacity(SNIFF_LEN);
+    file.take(SNIFF_LEN as u64).read_to_end(&mut data).ok()?;
+
+    let format = sniff_bytes(&data).or(tagged.then_some(AudioFormat::Mp3))?;
+    match format {
+        AudioFormat::Aac => match encoder::mp4_sample_entry(path) {
+            Some(entry) if &entry == b"alac" => Some(AudioFormat::Alac),
+            _ => Some(AudioFormat::Aac),
+        },
+        AudioFormat::Vorbis => {
+            let packet = ogg::first_packet(path).ok()?;
+            if packet.starts_with(b"OpusHead") {
+                Some(AudioFormat::Opus)
+            } else if packet.starts_with(b"\x01vorbis") {
+                Some(AudioFormat::Vorbis)
+            } else {
+                None
+            }
+        }
+        other => Some(other),
+    }
+}
+
+/// Classify the leading bytes of a stream. MPEG-4 files come back as
+/// [`AudioFormat::Aac`] and Ogg streams as [`AudioFormat::Vorbis`]; the codec
+/// inside is resolved by [`sniff`].
+fn sniff_bytes(data: &[u8]) -> Option<AudioFormat> {
+    let tag = |range: std::ops::Range<usize>| data.get(range);
+    match tag(0..4)? {
+        b"fLaC" => return Some(AudioFormat::Flac),
+        b"RIFF" | b"RF64" | b"BW64" if tag(8..12) == Some(b"WAVE") => {
+            return Some(AudioFormat::Wav)
+        }
+        b"FORM" if matches!(tag(8..12), Some(b"AIFF" | b"AIFC")) => return Some(AudioFormat::Aiff),
+        b"FRM8" if tag(12..16) == Some(b"DSD ") => return Some(AudioFormat::Dff),
+        b"OggS" => return Some(AudioFormat::Vorbis),
+        b"wvpk" => return Some(AudioFormat::WavPack),
+        b"MAC " => return Some(AudioFormat::Ape),
+        b"DSD " => return Some(AudioFormat::Dsf),
+        _ => {}
+    }
+    if tag(4..8) == Some(b"ftyp") {
+        return Some(AudioFormat::Aac);
+    }
+    // Frame sync: the layer bits tell ADTS (00) from MPEG Layer III (01).
+    if data[0] == 0xff && data[1] & 0xe0 == 0xe0 {
+        return match (data[1] >> 1) & 0x03 {
+            0 if data[1] & 0xf0 == 0xf0 => Some(AudioFormat::Adts),
+            1 => Some(AudioFormat::Mp3),
+            _ => None,
+        };
+    }
+    None
 }
 
-pub fn is_aac(path: &Path) -> bool {
-    has_extension(path, AAC_EXTENSIONS)
+/// Format of `path`, from its content when readable and its extension
+/// otherwise. This opens and parses the file: call it once per file and keep
+/// the result (`AudioAnalysis::format`).
+pub fn detect(path: &Path) -> Option<AudioFormat> {
+    sniff(path).or_else(|| AudioFormat::from_extension(path))
+}
+
+/// `format`, the detected format of `path`, when it contradicts the
+/// extension, e.g. WAV data in a file named `.mp3`.
+pub fn extension_mismatch(path: &Path, format: Option<AudioFormat>) -> Option<AudioFormat> {
+    format.filter(|format| !has_extension(path, format.extensions()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn sniffed(name: &str, data: &[u8]) -> (Option<AudioFormat>, Option<AudioFormat>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        fs::write(&path, data).unwrap();
+        (sniff(&path), extension_mismatch(&path, detect(&path)))
+    }
+
+    #[test]
+    fn wav_named_mp3_is_detected_as_wav() {
+        let mut wav = b"RIFF\x24\x00\x00\x00WAVEfmt ".to_vec();
+        wav.resize(44, 0);
+        assert_eq!(
+            sniffed("song.mp3", &wav),
+            (Some(AudioFormat::Wav), Some(AudioFormat::Wav))
+        );
+        assert_eq!(sniffed("song.wav", &wav), (Some(AudioFormat::Wav), None));
+    }
+
+    #[test]
+    fn frame_sync_tells_mp3_from_adts() {
+        let mp3 = [0xff, 0xfb, 0x90, 0x64, 0, 0, 0, 0];
+        let adts = [0xff, 0xf1, 0x50, 0x80, 0x02, 0x1f, 0xfc];
+        assert_eq!(sniffed("a.mp3", &mp3), (Some(AudioFormat::Mp3), None));
+        assert_eq!(
+            sniffed("a.mp3", &adts),
+            (Some(AudioFormat::Adts), Some(AudioFormat::Adts))
+        );
+    }
+
+    #[test]
+    fn id3_tag_is_skipped_before_sniffing() {
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x04".to_vec();
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(b"fLaC\x80\x00\x00\x22");
+        assert_eq!(
+            sniffed("a.mp3", &data),
+            (Some(AudioFormat::Flac), Some(AudioFormat::Flac))
+        );
+        // A tag followed by nothing recognizable is still an MP3.
+        let mut tagged = data[..14].to_vec();
+        tagged.extend_from_slice(&[0; 8]);
+        assert_eq!(sniffed("a.mp3", &tagged), (Some(AudioFormat::Mp3), None));
+    }
+
+    #[test]
+    fn unreadable_files_fall_back_to_the_extension() {
+        let path = Path::new("/nonexistent/track.m4a");
+        assert_eq!(sniff(path), None);
+        assert_eq!(detect(path), Some(AudioFormat::Aac));
+        assert!(detect(path).is_some_and(|f| f.is_aac() && f.is_lossy()));
+        assert_eq!(extension_mismatch(path, detect(path)), None);
+    }
 }
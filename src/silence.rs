@@ -0,0 +1,218 @@
+//! Silence, gap and truncation detection.
+//!
+//! The stream is cut into 10 ms windows; a window is silent when no sample
+//! in any channel reaches `SILENCE_LEVEL_DB`. Silent runs at the start and
+//! end are the leading and trailing gaps, runs in between are dropouts. The
+//! level of the last 10 ms tells a fade or a gap from an abrupt ending.
+
+use serde::{Deserialize, Serialize};
+
+use crate::loudness::{amplitude_to_db, LEVEL_FLOOR_DB};
+
+/// Peak level (dBFS) below which a window counts as silent.
+pub const SILENCE_LEVEL_DB: f64 = -60.0;
+
+/// Shortest internal silence recorded as a dropout.
+pub const MIN_DROPOUT_SECS: f64 = 0.1;
+
+/// Dropouts kept per file (the longest ones).
+pub const MAX_DROPOUTS: usize = 20;
+
+/// Internal silence between two sounding passages.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Dropout {
+    pub start_secs: f64,
+    pub duration_secs: f64,
+}
+
+impl Dropout {
+    /// e.g. "1:23.450 (1.2 s)".
+    pub fn describe(&self) -> String {
+        let minutes = (self.start_secs / 60.0).floor();
+        format!(
+            "{}:{:06.3} ({:.1} s)",
+            minutes,
+            self.start_secs - minutes * 60.0,
+            self.duration_secs
+        )
+    }
+}
+
+/// Silence found in one stream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Silence {
+    /// Silence before the first sound (seconds).
+    pub leading_secs: f64,
+    /// Silence after the last sound (seconds).
+    pub trailing_secs: f64,
+    /// Internal silences of at least `MIN_DROPOUT_SECS`; the longest
+    /// `MAX_DROPOUTS`, in time order.
+    pub dropouts: Vec<Dropout>,
+    /// RMS level of the final 10 ms (dBFS).
+    pub final_level: f64,
+}
+
+impl Default for Silence {
+    fn default() -> Self {
+        Self {
+            leading_secs: 0.0,
+            trailing_secs: 0.0,
+            dropouts: Vec::new(),
+            final_level: LEVEL_FLOOR_DB,
+        }
+    }
+}
+
+/// Windowed silence tracking, fed one frame at a time by the loudness meter.
+#[derive(Debug, Clone)]
+pub struct SilenceDetector {
+    sample_rate: u32,
+    window_len: usize,
+    window_pos: usize,
+    window_peak: f64,
+    frames: u64,
+    /// Start frame of the silent run in progress.
+    silent_since: Option<u64>,
+    leading: Option<u64>,
+    dropouts: Vec<(u64, u64)>,
+    /// Mean-square power of the most recent frames, as a ring.
+    tail: Vec<f64>,
+}
+
+impl SilenceDetector {
+    pub fn new(sample_rate: u32) -> Self {
+        let window_len = (sample_rate as usize / 100).max(1);
+        Self {
+            sample_rate,
+            window_len,
+            window_pos: 0,
+            window_peak: 0.0,
+            frames: 0,
+            silent_since: None,
+            leading: None,
+            dropouts: Vec::new(),
+            tail: Vec::with_capacity(window_len),
+        }
+    }
+
+    /// Feed one frame: its highest absolute sample and mean-square power
+    /// over all channels.
+    pub fn process(&mut self, peak: f64, power: f64) {
+        if self.tail.len() < self.window_len {
+            self.tail.push(power);
+        } else {
+            self.tail[self.frames as usize % self.window_len] = power;
+        }
+        self.frames += 1;
+        self.window_peak = self.window_peak.max(peak);
+        self.window_pos += 1;
+        if self.window_pos == self.window_len {
+            self.finish_window();
+        }
+    }
+
+    fn finish_window(&mut self) {
+        let start = self.frames - self.window_pos as u64;
+        if amplitude_to_db(self.window_peak) < SILENCE_LEVEL_DB {
+            self.silent_since.get_or_insert(start);
+        } else if let Some(since) = self.silent_since.take() {
+            match self.leading {
+                None => self.leading = Some(start - since),
+                Some(_) => self.add_dropout(since, start - since),
+            }
+        } else {
+            self.leading.get_or_insert(0);
+        }
+        self.window_pos = 0;
+        self.window_peak = 0.0;
+    }
+
+    fn add_dropout(&mut self, start: u64, len: u64) {
+        if (len as f64) < MIN_DROPOUT_SECS * self.sample_rate as f64 {
+            return;
+        }
+        self.dropouts.push((start, len));
+        if self.dropouts.len() > MAX_DROPOUTS {
+            let shortest = (0..self.dropouts.len())
+                .min_by_key(|&i| self.dropouts[i].1)
+                .unwrap_or(0);
+            self.dropouts.remove(shortest);
+        }
+    }
+
+    /// Close the last window and summarize.
+    pub fn finish(mut self) -> Silence {
+        if self.window_pos > 0 {
+            self.finish_window();
+        }
+        let rate = self.sample_rate.max(1) as f64;
+        let secs = |frames: u64| frames as f64 / rate;
+        let trailing = self.silent_since.map_or(0, |since| self.frames - since);
+        let (leading, trailing) = match self.leading {
+            Some(leading) => (leading, trailing),
+            // Never left silence: the whole stream is one leading gap.
+            None => (self.frames, 0),
+        };
+        let power = self.tail.iter().sum::<f64>() / self.tail.len().max(1) as f64;
+        Silence {
+            leading_secs: secs(leading),
+            trailing_secs: secs(trailing),
+            dropouts: self
+                .dropouts
+                .iter()
+                .map(|&(start, len)| Dropout {
+                    start_secs: secs(start),
+                    duration_secs: secs(len),
+                })
+                .collect(),
+            final_level: (10.0 * power.log10()).max(LEVEL_FLOOR_DB),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(segments: &[(f64, f64)]) -> Silence {
+        let rate = 1000;
+        let mut detector = SilenceDetector::new(rate);
+        for &(amplitude, secs) in segments {
+            for _ in 0..(secs * rate as f64) as usize {
+                detector.process(amplitude, amplitude * amplitude);
+            }
+        }
+        detector.finish()
+    }
+
+    #[test]
+    fn gaps_and_dropouts_are_timed() {
+        let silence = detect(&[
+            (0.0, 1.5),
+            (0.5, 2.0),
+            (0.0, 0.05), // too short for a dropout
+            (0.5, 1.0),
+            (0.0, 0.75),
+            (0.5, 1.0),
+            (0.0, 3.0),
+        ]);
+        assert!((silence.leading_secs - 1.5).abs() < 1e-9);
+        assert!((silence.trailing_secs - 3.0).abs() < 1e-9);
+        assert_eq!(
+            silence.dropouts,
+            [Dropout {
+                start_secs: 4.55,
+                duration_secs: 0.75
+            }]
+        );
+        assert_eq!(silence.final_level, LEVEL_FLOOR_DB);
+        assert_eq!(silence.dropouts[0].describe(), "0:04.550 (0.8 s)");
+    }
+
+    #[test]
+    fn cut_off_ending_keeps_its_level() {
+        let silence = detect(&[(0.5, 2.0)]);
+        assert_eq!((silence.leading_secs, silence.trailing_secs), (0.0, 0.0));
+        assert!((silence.final_level - amplitude_to_db(0.5)).abs() < 1e-9);
+    }
+}
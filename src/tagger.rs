@@ -0,0 +1,741 @@
+//! ReplayGain / R128 tag writing (`--tag-only`).
+//!
+//! Instead of rewriting audio, the gain headroom would have applied is stored
+//! as loudness metadata for players that honour it:
+//! - FLAC: Vorbis comments, plus `R128_TRACK_GAIN` for Opus-style readers
+//! - Ogg Vorbis: Vorbis comments; Opus: `R128_TRACK_GAIN` only (RFC 7845)
+//! - MP3, AIFF, WAV, DSF, DFF: ID3v2 `TXXX` frames
+//! - WavPack, APE: APEv2 items
+//! - M4A (AAC and ALAC): iTunes freeform (`----:com.apple.iTunes:`) atoms
+//!
+//! The gain values are headroom's own decision (TP ceiling / LUFS target), not
+//! a normalization to the ReplayGain reference level.
+
+use anyhow::{anyhow, bail, Context, Result};
+use id3::TagLike;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::analyzer::AudioAnalysis;
+use crate::flac;
+use crate::ogg;
+use crate::replace::{self, FrameMatch};
+use crate::scanner::AudioFormat;
+
+const TRACK_GAIN: &str = "REPLAYGAIN_TRACK_GAIN";
+const TRACK_PEAK: &str = "REPLAYGAIN_TRACK_PEAK";
+const ALBUM_GAIN: &str = "REPLAYGAIN_ALBUM_GAIN";
+const ALBUM_PEAK: &str = "REPLAYGAIN_ALBUM_PEAK";
+const R128_TRACK_GAIN: &str = "R128_TRACK_GAIN";
+const R128_ALBUM_GAIN: &str = "R128_ALBUM_GAIN";
+
+/// Gain (dB) and linear peak written for one file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GainTags {
+    pub track_gain: f64,
+    pub track_peak: f64,
+    /// Shared album gain and album peak, in album mode.
+    pub album: Option<(f64, f64)>,
+}
+
+impl GainTags {
+    /// Tag values as `(KEY, value)` pairs in ReplayGain text format.
+    fn replaygain_fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            (TRACK_GAIN, format_gain(self.track_gain)),
+            (TRACK_PEAK, format_peak(self.track_peak)),
+        ];
+        if let Some((gain, peak)) = self.album {
+            fields.push((ALBUM_GAIN, format_gain(gain)));
+            fields.push((ALBUM_PEAK, format_peak(peak)));
+        }
+        fields
+    }
+
+    fn r128_fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![(R128_TRACK_GAIN, format_r128(self.track_gain))];
+        if let Some((gain, _)) = self.album {
+            fields.push((R128_ALBUM_GAIN, format_r128(gain)));
+        }
+        fields
+    }
+}
+
+fn format_gain(db: f64) -> String {
+    format!("{:+.2} dB", db)
+}
+
+fn format_peak(linear: f64) -> String {
+    format!("{:.6}", linear)
+}
+
+/// R128 gains are Q7.8 fixed point integers (RFC 7845 §5.2.1).
+fn format_r128(db: f64) -> String {
+    let q78 = (db * 256.0).round().clamp(i16::MIN as f64, i16::MAX as f64);
+    format!("{}", q78 as i16)
+}
+
+fn linear_peak(dbtp: f64) -> f64 {
+    10f64.powf(dbtp / 20.0)
+}
+
+/// Tag values for each of `files`. Album peaks are taken over every analysis
+/// in the same album group, including members that need no gain themselves.
+pub fn plan_tags(files: &[&AudioAnalysis], all: &[AudioAnalysis]) -> Vec<GainTags> {
+    let mut album_peaks: HashMap<&str, f64> = HashMap::new();
+    for a in all {
+        if let Some(album) = a.album.as_deref() {
+            let peak = album_peaks.entry(album).or_insert(f64::NEG_INFINITY);
+            *peak = peak.max(a.input_tp);
+        }
+    }
+
+    files
+        .iter()
+        .map(|a| GainTags {
+            track_gain: a.track_gain(),
+            track_peak: linear_peak(a.input_tp),
+            album: a
+                .album
+                .as_deref()
+                .zip(a.album_budget)
+                .map(|(album, gain)| (gain, linear_peak(album_peaks[album]))),
+        })
+        .collect()
+}
+
+/// Write gain tags into `path`, choosing the tag format from its detected
+/// `format`.
+pub fn write_gain_tags(path: &Path, format: Option<AudioFormat>, tags: &GainTags) -> Result<()> {
+    match format {
+        Some(AudioFormat::Flac) => {
+            let mut fields = tags.replaygain_fields();
+            fields.extend(tags.r128_fields());
+            write_flac_comments(path, &fields)
+        }
+        Some(AudioFormat::Vorbis) => write_ogg_comments(path, &tags.replaygain_fields()),
+        Some(AudioFormat::Opus) => write_ogg_comments(path, &tags.r128_fields()),
+        // mp3rgain, id3, mp4meta, DSF and APEv2 tags all rewrite the file in
+        // place, so they work on a staged copy.
+        Some(AudioFormat::Mp3) => replace::modify_copy(path, FrameMatch::Verbatim, |copy| {
+            write_mp3_tags(copy, tags)
+        }),
+        Some(AudioFormat::Aiff | AudioFormat::Wav) => {
+            replace::modify_copy(path, FrameMatch::Verbatim, |copy| {
+                write_id3_chunk(copy, tags)
+            })
+        }
+        Some(AudioFormat::Dsf) => {
+            replace::modify_copy(path, FrameMatch::Verbatim, |copy| write_dsf_id3(copy, tags))
+        }
+        Some(AudioFormat::Dff) => write_dff_id3(path, tags),
+        Some(AudioFormat::WavPack | AudioFormat::Ape) => {
+            replace::modify_copy(path, FrameMatch::Verbatim, |copy| {
+                write_ape_tags(copy, &tags.replaygain_fields())
+            })
+        }
+        Some(AudioFormat::Aac | AudioFormat::Alac) => {
+            replace::modify_copy(path, FrameMatch::Verbatim, |copy| {
+                write_mp4_tags(copy, tags)
+            })
+        }
+        Some(format) => bail!("Tagging is not supported for {} files", format.label()),
+        None => bail!("Tagging is not supported for {}", path.display()),
+    }
+}
+
+fn write_mp3_tags(path: &Path, tags: &GainTags) -> Result<()> {
+    let mut rg = mp3rgain::Id3v2ReplayGain {
+        track_gain: Some(format_gain(tags.track_gain)),
+        track_peak: Some(format_peak(tags.track_peak)),
+        ..Default::default()
+    };
+    if let Some((gain, peak)) = tags.album {
+        rg.album_gain = Some(format_gain(gain));
+        rg.album_peak = Some(format_peak(peak));
+    }
+    mp3rgain::write_id3v2_replaygain(path, &rg).context("Failed to write ID3v2 ReplayGain tags")
+}
+
+/// ID3v2 inside an AIFF `ID3 ` chunk or a WAV `id3 ` chunk; the id3 crate
+/// picks the container from the file's header.
+fn write_id3_chunk(path: &Path, tags: &GainTags) -> Result<()> {
+    let mut tag = match id3::Tag::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(id3::Error {
+            kind: id3::ErrorKind::NoTag,
+            ..
+        }) => id3::Tag::new(),
+        Err(e) => return Err(e).context("Failed to read ID3v2 chunk"),
+    };
+    set_replaygain_frames(&mut tag, tags);
+    tag.write_to_path(path, id3::Version::Id3v24)
+        .context("Failed to write ID3v2 chunk")
+}
+
+/// ID3v2 tag parsed from `data`, or an empty one when there is none.
+fn id3_or_new(data: &[u8]) -> Result<id3::Tag> {
+    if data.is_empty() {
+        return Ok(id3::Tag::new());
+    }
+    id3::Tag::read_from2(io::Cursor::new(data)).context("Failed to read ID3v2 tag")
+}
+
+fn encode_id3(tag: &id3::Tag) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    tag.write_to(&mut out, id3::Version::Id3v24)
+        .context("Failed to encode ID3v2 tag")?;
+    Ok(out)
+}
+
+/// DSF keeps its ID3v2 tag at the end of the file, at the offset stored in
+/// the `DSD ` header (0 when there is none), after the audio data.
+fn write_dsf_id3(path: &Path, tags: &GainTags) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut header = [0u8; 28];
+    file.read_exact(&mut header)
+        .context("Failed to read DSF header")?;
+    if &header[..4] != b"DSD " {
+        bail!("Not a DSF file (missing DSD header)");
+    }
+    let len = file.metadata()?.len();
+    let pointer = u64::from_le_bytes(header[20..28].try_into()?);
+    let tag_start = if pointer == 0 || pointer > len {
+        len
+    } else {
+        pointer
+    };
+
+    let mut existing = Vec::new();
+    file.seek(SeekFrom::Start(tag_start))?;
+    file.read_to_end(&mut existing)?;
+    let mut tag = id3_or_new(&existing)?;
+    set_replaygain_frames(&mut tag, tags);
+    let encoded = encode_id3(&tag)?;
+
+    file.set_len(tag_start)?;
+    file.seek(SeekFrom::Start(tag_start))?;
+    file.write_all(&encoded)?;
+    let total = tag_start + encoded.len() as u64;
+    file.seek(SeekFrom::Start(12))?;
+    file.write_all(&total.to_le_bytes())?;
+    file.write_all(&tag_start.to_le_bytes())?;
+    file.flush().context("Failed to write DSF ID3v2 tag")
+}
+
+/// DSDIFF has no standard tag chunk; like other taggers, an ID3v2 tag goes
+/// into a top-level `ID3 ` chunk, replacing any existing one. The file is
+/// rebuilt at the staged path and then replaces the original.
+fn write_dff_id3(path: &Path, tags: &GainTags) -> Result<()> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    );
+    let mut header = [0u8; 16];
+    reader
+        .read_exact(&mut header)
+        .context("Failed to read DSDIFF header")?;
+    if &header[..4] != b"FRM8" || &header[12..16] != b"DSD " {
+        bail!("Not a DSDIFF file (missing FRM8/DSD header)");
+    }
+    let end = 12 + u64::from_be_bytes(header[4..12].try_into()?);
+
+    // Top-level chunks as (id, offset of header, padded total length).
+    let mut chunks = Vec::new();
+    let mut pos = 16u64;
+    while pos + 12 <= end {
+        let mut chunk = [0u8; 12];
+        reader.seek(SeekFrom::Start(pos))?;
+        reader.read_exact(&mut chunk)?;
+        let size = u64::from_be_bytes(chunk[4..12].try_into()?);
+        let total = 12 + size + (size & 1);
+        chunks.push(([chunk[0], chunk[1], chunk[2], chunk[3]], pos, total));
+        pos += total;
+    }
+
+    let mut tag = id3::Tag::new();
+    if let Some(&(_, offset, total)) = chunks.iter().find(|(id, ..)| id == b"ID3 ") {
+        let mut body = vec![0u8; (total - 12) as usize];
+        reader.seek(SeekFrom::Start(offset + 12))?;
+        reader.read_exact(&mut body)?;
+        tag = id3_or_new(&body)?;
+    }
+    set_replaygain_frames(&mut tag, tags);
+    let mut encoded = encode_id3(&tag)?;
+    let size = encoded.len() as u64;
+    if size & 1 == 1 {
+        encoded.push(0);
+    }
+
+    replace::replace_with(path, FrameMatch::Verbatim, |staged| {
+        let mut writer = BufWriter::new(File::create(staged)?);
+        let kept: u64 = chunks
+            .iter()
+            .filter(|(id, ..)| id != b"ID3 ")
+            .map(|&(_, _, total)| total)
+            .sum();
+        let form_size = 4 + kept + 12 + encoded.len() as u64;
+        writer.write_all(b"FRM8")?;
+        writer.write_all(&form_size.to_be_bytes())?;
+        writer.write_all(b"DSD ")?;
+        for &(id, offset, total) in &chunks {
+            if &id == b"ID3 " {
+                continue;
+            }
+            reader.seek(SeekFrom::Start(offset))?;
+            io::copy(&mut (&mut reader).take(total), &mut writer)?;
+        }
+        writer.write_all(b"ID3 ")?;
+        writer.write_all(&size.to_be_bytes())?;
+        writer.write_all(&encoded)?;
+        writer.flush().context("Failed to write DSDIFF ID3 chunk")
+    })
+}
+
+/// Replace the ReplayGain `TXXX` frames of `tag`.
+fn set_replaygain_frames(tag: &mut id3::Tag, tags: &GainTags) {
+    for (key, value) in tags.replaygain_fields() {
+        // Other taggers use lowercase descriptions; drop those too so no
+        // stale duplicate survives next to ours.
+        let stale: Vec<String> = tag
+            .extended_texts()
+            .filter(|t| t.description.eq_ignore_ascii_case(key))
+            .map(|t| t.description.clone())
+            .collect();
+        for desc in stale {
+            tag.remove_extended_text(Some(&desc), None);
+        }
+        tag.add_frame(id3::frame::ExtendedText {
+            description: key.to_string(),
+            value,
+        });
+    }
+}
+
+/// Rewrite the comment header of an Ogg Vorbis or Opus file.
+fn write_ogg_comments(path: &Path, fields: &[(&str, String)]) -> Result<()> {
+    ogg::rewrite_comments(path, |data| {
+        let (mut comments, len) = VorbisComments::parse_prefix(data)?;
+        comments.set(fields);
+        // Keep the Vorbis framing bit and any Opus binary extension data.
+        let mut out = comments.to_bytes();
+        out.extend_from_slice(&data[len..]);
+        Ok(out)
+    })
+    .context("Failed to write Ogg comments")
+}
+
+const APE_PREAMBLE: &[u8; 8] = b"APETAGEX";
+const APE_VERSION: u32 = 2000;
+const APE_HAS_HEADER: u32 = 1 << 31;
+const APE_IS_HEADER: u32 = 1 << 29;
+const ID3V1_LEN: u64 = 128;
+
+/// APEv2 item: key and raw value; flags 0 is UTF-8 text.
+type ApeItem = (String, u32, Vec<u8>);
+
+/// Rewrite the APEv2 tag at the end of a WavPack or Monkey's Audio file,
+/// replacing items case-insensitively. An ID3v1 tag after it is kept.
+fn write_ape_tags(path: &Path, fields: &[(&str, String)]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let len = file.metadata()?.len();
+
+    let mut id3v1 = Vec::new();
+    let mut end = len;
+    if len >= ID3V1_LEN {
+        let mut tail = vec![0u8; ID3V1_LEN as usize];
+        file.seek(SeekFrom::Start(len - ID3V1_LEN))?;
+        file.read_exact(&mut tail)?;
+        if tail.starts_with(b"TAG") {
+            id3v1 = tail;
+            end -= ID3V1_LEN;
+        }
+    }
+
+    let mut items = Vec::new();
+    let mut tag_start = end;
+    if end >= 32 {
+        let mut footer = [0u8; 32];
+        file.seek(SeekFrom::Start(end - 32))?;
+        file.read_exact(&mut footer)?;
+        if &footer[..8] == APE_PREAMBLE {
+            let size = u32::from_le_bytes(footer[12..16].try_into()?) as u64;
+            let count = u32::from_le_bytes(footer[16..20].try_into()?);
+            let flags = u32::from_le_bytes(footer[20..24].try_into()?);
+            let header = if flags & APE_HAS_HEADER != 0 { 32 } else { 0 };
+            if size < 32 || size + header > end {
+                bail!("Corrupt APEv2 tag");
+            }
+            let mut body = vec![0u8; (size - 32) as usize];
+            file.seek(SeekFrom::Start(end - size))?;
+            file.read_exact(&mut body)?;
+            items = parse_ape_items(&body, count)?;
+            tag_start = end - size - header;
+        }
+    }
+
+    items.retain(|(key, ..)| !fields.iter().any(|(k, _)| k.eq_ignore_ascii_case(key)));
+    for (key, value) in fields {
+        items.push((key.to_string(), 0, value.as_bytes().to_vec()));
+    }
+
+    file.set_len(tag_start)?;
+    file.seek(SeekFrom::Start(tag_start))?;
+    file.write_all(&ape_tag(&items))?;
+    file.write_all(&id3v1)?;
+    file.flush().context("Failed to write APEv2 tag")
+}
+
+fn parse_ape_items(mut body: &[u8], count: u32) -> Result<Vec<ApeItem>> {
+    let mut items = Vec::new();
+    for _ in 0..count {
+        if body.len() < 8 {
+            bail!("Truncated APEv2 item");
+        }
+        let value_len = u32::from_le_bytes(body[..4].try_into()?) as usize;
+        let flags = u32::from_le_bytes(body[4..8].try_into()?);
+        let key_len = body[8..]
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| anyhow!("Unterminated APEv2 item key"))?;
+        let key = String::from_utf8_lossy(&body[8..8 + key_len]).into_owned();
+        let value = body
+            .get(9 + key_len..9 + key_len + value_len)
+            .ok_or_else(|| anyhow!("Truncated APEv2 item"))?
+            .to_vec();
+        body = &body[9 + key_len + value_len..];
+        items.push((key, flags, value));
+    }
+    Ok(items)
+}
+
+/// APEv2 tag with header and footer.
+fn ape_tag(items: &[ApeItem]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (key, flags, value) in items {
+        body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        body.extend_from_slice(&flags.to_le_bytes());
+        body.extend_from_slice(key.as_bytes());
+        body.push(0);
+        body.extend_from_slice(value);
+    }
+    let frame = |flags: u32| {
+        let mut out = APE_PREAMBLE.to_vec();
+        out.extend_from_slice(&APE_VERSION.to_le_bytes());
+        out.extend_from_slice(&(body.len() as u32 + 32).to_le_bytes());
+        out.extend_from_slice(&(items.len() as u32).to_le_bytes());
+        out.extend_from_slice(&flags.to_le_bytes());
+        out.extend_from_slice(&[0u8; 8]);
+        out
+    };
+    let mut tag = frame(APE_HAS_HEADER | APE_IS_HEADER);
+    tag.extend_from_slice(&body);
+    tag.extend(frame(APE_HAS_HEADER));
+    tag
+}
+
+fn write_mp4_tags(path: &Path, tags: &GainTags) -> Result<()> {
+    use mp3rgain::mp4meta;
+
+    // The writer replaces every ReplayGain atom, so carry over existing album
+    // values when this run has none to write.
+    let existing = mp4meta::read_replaygain_tags(path).unwrap_or_default();
+    let mut rg = mp4meta::ReplayGainTags::default();
+    rg.set_track(tags.track_gain, tags.track_peak);
+    match tags.album {
+        Some((gain, peak)) => rg.set_album(gain, peak),
+        None => {
+            let parsed = existing
+                .album_gain()
+                .and_then(parse_gain)
+                .zip(existing.album_peak().and_then(|p| p.trim().parse().ok()));
+            if let Some((gain, peak)) = parsed {
+                rg.set_album(gain, peak);
+            }
+        }
+    }
+    mp4meta::write_replaygain_tags(path, &rg).context("Failed to write MP4 ReplayGain atoms")
+}
+
+fn parse_gain(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let number = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    number.trim().parse().ok()
+}
+
+/// Vorbis comment block: vendor string plus `KEY=value` entries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VorbisComments {
+    pub vendor: String,
+    pub comments: Vec<String>,
+}
+
+impl VorbisComments {
+    /// Parse the little-endian, length-prefixed layout shared by FLAC and Ogg.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        Self::parse_prefix(data).map(|(comments, _)| comments)
+    }
+
+    /// Parse from the start of `data`; also returns the bytes consumed.
+    pub fn parse_prefix(data: &[u8]) -> Result<(Self, usize)> {
+        let mut pos = 0;
+        let mut take = |len: usize| -> Result<&[u8]> {
+            let slice = data
+                .get(pos..pos + len)
+                .ok_or_else(|| anyhow!("Truncated Vorbis comment block"))?;
+            pos += len;
+            Ok(slice)
+        };
+        let read_u32 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize;
+
+        let vendor_len = read_u32(take(4)?);
+        let vendor = String::from_utf8_lossy(take(vendor_len)?).into_owned();
+        let count = read_u32(take(4)?);
+        let mut comments = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            let len = read_u32(take(4)?);
+            comments.push(String::from_utf8_lossy(take(len)?).into_owned());
+        }
+        Ok((Self { vendor, comments }, pos))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&(self.vendor.len() as u32).to_le_bytes());
+        out.extend_from_slice(self.vendor.as_bytes());
+        out.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+        for comment in &self.comments {
+            out.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            out.extend_from_slice(comment.as_bytes());
+        }
+        out
+    }
+
+    /// Set each `KEY=value`, replacing existing entries case-insensitively.
+    pub fn set(&mut self, fields: &[(&str, String)]) {
+        self.comments.retain(|c| {
+            let key = c.split('=').next().unwrap_or("");
+            !fields.iter().any(|(k, _)| k.eq_ignore_ascii_case(key))
+        });
+        for (key, value) in fields {
+            self.comments.push(format!("{}={}", key, value));
+        }
+    }
+}
+
+/// Rewrite a FLAC file's Vorbis comment block. All other metadata blocks and
+/// the audio frames are copied unchanged.
+fn write_flac_comments(path: &Path, fields: &[(&str, String)]) -> Result<()> {
+    flac::rewrite_blocks(path, |blocks| {
+        match blocks
+            .iter_mut()
+            .find(|(t, _)| *t == flac::BLOCK_VORBIS_COMMENT)
+        {
+            Some((_, body)) => {
+                let mut comments = VorbisComments::parse(body)?;
+                comments.set(fields);
+                *body = comments.to_bytes();
+            }
+            None => {
+                let mut comments = VorbisComments {
+                    vendor: format!("headroom {}", env!("CARGO_PKG_VERSION")),
+                    comments: Vec::new(),
+                };
+                comments.set(fields);
+                // STREAMINFO must stay first.
+                blocks.insert(
+                    1.min(blocks.len()),
+                    (flac::BLOCK_VORBIS_COMMENT, comments.to_bytes()),
+                );
+            }
+        }
+        Ok(())
+    })
+    .context("Failed to write FLAC tags")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::detect;
+    use std::fs;
+
+    fn flac_with_comments(comments: &[&str]) -> Vec<u8> {
+        let vc = VorbisComments {
+            vendor: "test".into(),
+            comments: comments.iter().map(|c| c.to_string()).collect(),
+        }
+        .to_bytes();
+        let mut data = flac::MAGIC.to_vec();
+        data.extend_from_slice(&[0x00, 0, 0, 34]);
+        data.extend_from_slice(&[0u8; 34]);
+        let len = (vc.len() as u32).to_be_bytes();
+        data.extend_from_slice(&[0x80 | flac::BLOCK_VORBIS_COMMENT, len[1], len[2], len[3]]);
+        data.extend_from_slice(&vc);
+        data.extend_from_slice(b"\xff\xf8audio frames");
+        data
+    }
+
+    fn read_comments(path: &Path) -> Vec<String> {
+        let data = fs::read(path).unwrap();
+        let len = u32::from_be_bytes([0, data[43], data[44], data[45]]) as usize;
+        VorbisComments::parse(&data[46..46 + len]).unwrap().comments
+    }
+
+    const TAGS: GainTags = GainTags {
+        track_gain: 2.5,
+        track_peak: 0.5,
+        album: None,
+    };
+
+    #[test]
+    fn flac_tags_replace_existing_and_keep_audio() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.flac");
+        fs::write(
+            &path,
+            flac_with_comments(&["ARTIST=Someone", "replaygain_track_gain=-6.00 dB"]),
+        )
+        .unwrap();
+
+        write_gain_tags(&path, detect(&path), &TAGS).unwrap();
+        write_gain_tags(&path, detect(&path), &TAGS).unwrap();
+
+        let comments = read_comments(&path);
+        assert_eq!(
+            comments,
+            [
+                "ARTIST=Someone",
+                "REPLAYGAIN_TRACK_GAIN=+2.50 dB",
+                "REPLAYGAIN_TRACK_PEAK=0.500000",
+                "R128_TRACK_GAIN=640",
+            ]
+        );
+        assert!(fs::read(&path).unwrap().ends_with(b"\xff\xf8audio frames"));
+    }
+
+    #[test]
+    fn wav_gets_id3_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.wav");
+        let mut wav = b"RIFF\x28\x00\x00\x00WAVEfmt \x10\x00\x00\x00".to_vec();
+        wav.extend_from_slice(&[1, 0, 1, 0, 0x44, 0xac, 0, 0, 0x88, 0x58, 1, 0, 2, 0, 16, 0]);
+        wav.extend_from_slice(b"data\x04\x00\x00\x00\x00\x00\x00\x00");
+        fs::write(&path, wav).unwrap();
+        let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 0);
+        filetime::set_file_mtime(&path, mtime).unwrap();
+
+        write_gain_tags(&path, detect(&path), &TAGS).unwrap();
+
+        // Written on a staged copy, which takes over the original's times.
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&meta),
+            mtime
+        );
+        let tag = id3::Tag::read_from_path(&path).unwrap();
+        let gain = tag
+            .extended_texts()
+            .find(|t| t.description == TRACK_GAIN)
+            .map(|t| t.value.clone());
+        assert_eq!(gain.as_deref(), Some("+2.50 dB"));
+    }
+
+    #[test]
+    fn ape_items_are_replaced_and_id3v1_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.wv");
+        let mut data = b"wvpk audio".to_vec();
+        data.extend(ape_tag(&[
+            ("Artist".into(), 0, b"someone".to_vec()),
+            ("replaygain_track_gain".into(), 0, b"-3.00 dB".to_vec()),
+        ]));
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, 0);
+        data.extend_from_slice(&id3v1);
+        fs::write(&path, data).unwrap();
+
+        write_gain_tags(&path, detect(&path), &TAGS).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        assert!(data.starts_with(b"wvpk audio"));
+        assert!(data.ends_with(&id3v1));
+        let tag = &data[10..data.len() - 128];
+        let count = u32::from_le_bytes(tag[16..20].try_into().unwrap());
+        let items = parse_ape_items(&tag[32..tag.len() - 32], count).unwrap();
+        let keys: Vec<&str> = items.iter().map(|(k, ..)| k.as_str()).collect();
+        assert_eq!(keys, ["Artist", TRACK_GAIN, TRACK_PEAK]);
+        assert_eq!(items[1].2, b"+2.50 dB");
+    }
+
+    #[test]
+    fn dsf_tag_goes_to_the_metadata_pointer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.dsf");
+        let mut data = b"DSD ".to_vec();
+        data.extend_from_slice(&28u64.to_le_bytes());
+        data.extend_from_slice(&40u64.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(b"fmt data....");
+        fs::write(&path, &data).unwrap();
+
+        write_gain_tags(&path, detect(&path), &TAGS).unwrap();
+        // A second run replaces the tag rather than appending another.
+        write_gain_tags(&path, detect(&path), &TAGS).unwrap();
+
+        let out = fs::read(&path).unwrap();
+        assert_eq!(&out[..12], &data[..12]);
+        assert_eq!(
+            u64::from_le_bytes(out[12..20].try_into().unwrap()),
+            out.len() as u64
+        );
+        assert_eq!(u64::from_le_bytes(out[20..28].try_into().unwrap()), 40);
+        assert_eq!(&out[28..40], b"fmt data....");
+        let tag = id3_or_new(&out[40..]).unwrap();
+        assert_eq!(tag.extended_texts().count(), 2);
+    }
+
+    #[test]
+    fn dff_id3_chunk_is_replaced_through_a_staged_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.dff");
+        let mut data = b"FRM8".to_vec();
+        data.extend_from_slice(&(4u64 + 12 + 6).to_be_bytes());
+        data.extend_from_slice(b"DSD DSD ");
+        data.extend_from_slice(&6u64.to_be_bytes());
+        data.extend_from_slice(b"audio!");
+        fs::write(&path, &data).unwrap();
+
+        write_gain_tags(&path, detect(&path), &TAGS).unwrap();
+        write_gain_tags(&path, detect(&path), &TAGS).unwrap();
+
+        let out = fs::read(&path).unwrap();
+        assert_eq!(&out[16..34], &data[16..]);
+        assert_eq!(&out[34..38], b"ID3 ");
+        assert_eq!(
+            12 + u64::from_be_bytes(out[4..12].try_into().unwrap()),
+            out.len() as u64
+        );
+        let tag = id3_or_new(&out[46..]).unwrap();
+        assert_eq!(tag.extended_texts().count(), 2);
+        assert!(!replace::staging_path(&path).exists());
+    }
+
+    #[test]
+    fn r128_is_q78_fixed_point() {
+        assert_eq!(format_r128(-1.5), "-384");
+        assert_eq!(format_r128(1000.0), "32767");
+    }
+}
@@ -0,0 +1,185 @@
+//! Post-processing verification (`--verify`).
+//!
+//! Every processed file is measured again and checked against what the
+//! analysis promised: True Peak at or below `target_tp`, and loudness moved
+//! by `effective_gain`. Re-encodes can overshoot the ceiling because the
+//! encoder reshapes inter-sample peaks; such files are flagged and, with
+//! `--rollback-overshoot`, restored from their backup.
+
+use chrono::Local;
+use serde::Serialize;
+use std::path::PathBuf;
+
+use crate::analyzer::{self, AudioAnalysis, Measurement};
+use crate::journal::JournalEntry;
+use crate::restore;
+
+/// Allowed True Peak excess over the ceiling; covers measurement jitter.
+pub const TP_TOLERANCE_DB: f64 = 0.1;
+
+/// Allowed difference between measured and intended gain. Native steps are
+/// 2^(1/4) ≈ 1.505 dB rather than exactly 1.5, so this must absorb a few
+/// hundredths per step.
+pub const GAIN_TOLERANCE_DB: f64 = 0.2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VerifyOutcome {
+    Ok,
+    /// Under the ceiling, but the loudness change differs from the plan.
+    GainMismatch,
+    /// True Peak above the ceiling.
+    Overshoot,
+    /// Overshoot, undone with `--rollback-overshoot`.
+    RolledBack,
+    /// The file could not be re-measured or rolled back.
+    Error,
+}
+
+impl VerifyOutcome {
+    pub fn label(self) -> &'static str {
+        match self {
+            VerifyOutcome::Ok => "ok",
+            VerifyOutcome::GainMismatch => "gain-mismatch",
+            VerifyOutcome::Overshoot => "overshoot",
+            VerifyOutcome::RolledBack => "rolled-back",
+            VerifyOutcome::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Verification {
+    /// Absolute path, as in the journal.
+    pub path: PathBuf,
+    pub outcome: VerifyOutcome,
+    pub measured_i: Option<f64>,
+    pub measured_tp: Option<f64>,
+    /// Measured minus intended loudness change.
+    pub gain_error: Option<f64>,
+    pub error: Option<String>,
+}
+
+/// Judge a re-measurement of a processed file against its analysis and the
+/// gain actually applied (which the overshoot loop may have reduced).
+pub fn check(analysis: &AudioAnalysis, gain_db: f64, measured: &Measurement) -> VerifyOutcome {
+    let gain_error = measured.input_i - analysis.input_i - gain_db;
+    if measured.input_tp > analysis.target_tp + TP_TOLERANCE_DB {
+        VerifyOutcome::Overshoot
+    } else if gain_error.abs() > GAIN_TOLERANCE_DB {
+        VerifyOutcome::GainMismatch
+    } else {
+        VerifyOutcome::Ok
+    }
+}
+
+/// Re-measure the file behind `entry` and, if it overshoots and `rollback`
+/// is set, restore it and mark the entry restored.
+pub fn verify_entry(
+    entry: &mut JournalEntry,
+    analysis: &AudioAnalysis,
+    rollback: bool,
+) -> Verification {
+    let mut verification = Verification {
+        path: entry.path.clone(),
+        outcome: VerifyOutcome::Error,
+        measured_i: None,
+        measured_tp: None,
+        gain_error: None,
+        error: None,
+    };
+
+    let measured = match analyzer::measure_file(&entry.path, analysis.format, analysis.stream) {
+        Ok(m) => m,
+        Err(e) => {
+            verification.error = Some(format!("{:#}", e));
+            return verification;
+        }
+    };
+    verification.measured_i = Some(measured.input_i);
+    verification.measured_tp = Some(measured.input_tp);
+    verification.gain_error = Some(measured.input_i - analysis.input_i - entry.gain_db);
+    verification.outcome = check(analysis, entry.gain_db, &measured);
+
+    if verification.outcome == VerifyOutcome::Overshoot && rollback {
+        match restore::restore_entry(entry, false) {
+            Ok(_) => {
+                verification.outcome = VerifyOutcome::RolledBack;
+                entry.restored_at = Some(Local::now().to_rfc3339());
+            }
+            Err(e) => verification.error = Some(format!("Rollback failed: {:#}", e)),
+        }
+    }
+
+    verification
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::GainTarget;
+    use crate::clipping::Clipping;
+    use crate::scanner::AudioFormat;
+    use crate::silence::Silence;
+    use std::path::Path;
+
+    fn processed(input_i: f64, input_tp: f64) -> (AudioAnalysis, Measurement) {
+        let before = Measurement {
+            input_i: -14.0,
+            input_tp: -5.0,
+            bitrate_kbps: Some(320),
+            duration_secs: 180.0,
+            channels: Vec::new(),
+            channel_imbalance: None,
+            lra: Some(6.0),
+            max_short_term: Some(-8.0),
+            max_momentary: Some(-6.5),
+            sample_peak: -1.5,
+            dc_offset: 0.0,
+            stream: 0,
+            clipping: Clipping::default(),
+            silence: Silence::default(),
+            format: Some(AudioFormat::Mp3),
+            streams: Vec::new(),
+            encoder: None,
+            sample_format: None,
+        };
+        let analysis =
+            analyzer::analyze_measurement(Path::new("a.mp3"), &before, GainTarget::default());
+        let after = Measurement {
+            input_i,
+            input_tp,
+            ..before.clone()
+        };
+        (analysis, after)
+    }
+
+    #[test]
+    fn native_steps_within_tolerance_pass() {
+        // 3 steps of 2^(1/4) is 4.52 dB, planned as 4.5.
+        let (analysis, after) = processed(-9.48, -0.48);
+        assert_eq!(analysis.lossless_gain_steps, 3);
+        assert_eq!(
+            check(&analysis, analysis.effective_gain, &after),
+            VerifyOutcome::Ok
+        );
+    }
+
+    #[test]
+    fn peak_over_the_ceiling_is_an_overshoot() {
+        let (analysis, after) = processed(-9.5, -0.2);
+        assert_eq!(
+            check(&analysis, analysis.effective_gain, &after),
+            VerifyOutcome::Overshoot
+        );
+    }
+
+    #[test]
+    fn wrong_loudness_change_is_a_mismatch() {
+        let (analysis, after) = processed(-12.0, -3.0);
+        assert_eq!(
+            check(&analysis, analysis.effective_gain, &after),
+            VerifyOutcome::GainMismatch
+        );
+    }
+}